@@ -0,0 +1,90 @@
+/* The interpreter against threaded code (VmConfig::threaded), on a few small programs that
+ * spend their time in different instructions. Run with `cargo bench --bench dispatch`. Each
+ * program is run a few times both ways and the best time kept. */
+
+use std::time::{Duration, Instant};
+
+use vm::asm::assemble;
+use vm::harness;
+use vm::{VirtualMachine, VmConfig};
+
+const RUNS: usize = 5;
+
+/* Name and source. Each loops enough to run for a while. */
+const PROGRAMS: [(&str, &str); 3] = [
+    ("arithmetic loop", "
+        push 2000000
+    loop:
+        push 3
+        push 5
+        mul
+        push 7
+        add
+        pop
+        push 1
+        sub
+        ifnz loop
+        exit
+    "),
+    ("calls", "
+        push 1000000
+    loop:
+        call work
+        push 1
+        sub
+        ifnz loop
+        exit
+    work:
+        push 3
+        push 4
+        mul
+        pop
+        return
+    "),
+    ("stack shuffling", "
+        push 0
+        push 2000000
+    loop:
+        swap
+        push 1
+        add
+        swap
+        dup
+        pop
+        push 1
+        sub
+        ifnz loop
+        exit
+    "),
+];
+
+/* The best of a few runs, and how many instructions a run takes. */
+fn time(image: &[u8], config: &VmConfig) -> (Duration, u64) {
+    let mut best = Duration::MAX;
+    let mut instructions = 0;
+
+    for _ in 0..RUNS {
+        let vm = VirtualMachine::from_bytes(image.to_vec(), config.clone()).expect("the program loads");
+        let start = Instant::now();
+        let run = harness::run_captured(vm, b"");
+        best = best.min(start.elapsed());
+        assert_eq!(run.result, Ok(0), "the program runs");
+        instructions = run.instructions;
+    }
+
+    (best, instructions)
+}
+
+fn main() {
+    println!("{:<16} {:>12} {:>14} {:>14} {:>8}", "program", "instructions", "interpreted", "threaded", "speedup");
+
+    for (name, source) in PROGRAMS {
+        let image = assemble(source).expect("the program assembles").image();
+        let (interpreted, instructions) = time(&image, &VmConfig::default());
+        let (threaded, _) = time(&image, &VmConfig { threaded: true, ..VmConfig::default() });
+
+        let per = |duration: Duration| duration.as_nanos() as f64 / instructions as f64;
+        println!("{:<16} {:>12} {:>11.2} ns {:>11.2} ns {:>7.2}x",
+            name, instructions, per(interpreted), per(threaded), interpreted.as_secs_f64() / threaded.as_secs_f64());
+    }
+}
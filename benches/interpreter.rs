@@ -0,0 +1,76 @@
+/* Criterion benchmarks for the interpreter: whole runs of the programs in benches/programs, both
+ * interpreted and as threaded code, single steps, and decoding. For weighing changes to dispatch
+ * and caching against each other; criterion keeps the last results and says what moved. Run
+ * with `cargo bench --bench interpreter`. */
+
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use vm::isa::Instruction;
+use vm::{StepResult, VirtualMachine, VmConfig};
+
+mod programs;
+
+/* Small enough for criterion to take a hundred samples of each in a few seconds. */
+fn programs() -> [(&'static str, Vec<u8>); 3] {
+    [
+        ("arithmetic", programs::arithmetic(10_000)),
+        ("recursion", programs::recursion(15)),
+        ("strings", programs::strings(2_000)),
+    ]
+}
+
+fn load(image: &[u8], config: &VmConfig) -> VirtualMachine {
+    VirtualMachine::from_bytes(image.to_vec(), config.clone()).expect("the benchmark program loads")
+}
+
+/* run from start to exit. Loading isn't timed. */
+fn run(c: &mut Criterion) {
+    let mut group = c.benchmark_group("run");
+    for (name, image) in programs() {
+        for (dispatch, threaded) in [("interpreted", false), ("threaded", true)] {
+            let config = VmConfig { threaded, ..VmConfig::default() };
+            group.bench_function(format!("{}/{}", name, dispatch), |b| {
+                b.iter_batched(|| load(&image, &config), |mut vm| assert_eq!(vm.run(), Ok(0)), BatchSize::SmallInput)
+            });
+        }
+    }
+    group.finish();
+}
+
+/* One step at a time, the way a debugger drives the machine, starting over when it exits. */
+fn step(c: &mut Criterion) {
+    let mut group = c.benchmark_group("step");
+    for (name, image) in programs() {
+        let mut vm = load(&image, &VmConfig::default());
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                if let Ok(StepResult::Exited(_)) = black_box(vm.step()) {
+                    vm.reset();
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+/* Every word of the programs decoded, which the interpreter does once an instruction. */
+fn decode(c: &mut Criterion) {
+    let words: Vec<u32> = programs().iter()
+        .flat_map(|(_, image)| {
+            let vm = load(image, &VmConfig::default());
+            vm.code().chunks_exact(4).map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]])).collect::<Vec<_>>()
+        })
+        .collect();
+
+    c.bench_function("decode", |b| {
+        b.iter(|| {
+            for &word in &words {
+                black_box(Instruction::decode(black_box(word)));
+            }
+        })
+    });
+}
+
+criterion_group!(benches, run, step, decode);
+criterion_main!(benches);
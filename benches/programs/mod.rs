@@ -0,0 +1,80 @@
+/* Guest programs for the benchmarks, each built by the assembler from source put together
+ * here, so the amount of work is a parameter rather than a constant in the text. They print
+ * nothing, so the timings are of the interpreter and not of the terminal, and all exit 0. */
+
+use vm::asm::assemble;
+
+/* A loop of pushes, arithmetic and a conditional branch, about 10 instructions a turn. */
+pub fn arithmetic(iterations: u32) -> Vec<u8> {
+    image(&format!("
+        push {}
+    loop:
+        push 3
+        push 5
+        mul
+        push 7
+        add
+        pop
+        push 1
+        sub
+        ifnz loop
+        exit
+    ", iterations))
+}
+
+/* The Fibonacci numbers the slow way, so nearly everything is call, return and stack
+ * shuffling. fib(n) makes about 2 * fib(n + 1) calls. */
+pub fn recursion(n: u32) -> Vec<u8> {
+    image(&format!("
+        push {}
+        call fib
+        pop
+        exit
+    fib:                    # ret n
+        dup 4
+        push 2
+        iflt base
+        pop 8
+        dup 4
+        push 1
+        sub
+        call fib            # fib(n-1) ret n
+        dup 8
+        push 2
+        sub
+        call fib            # fib(n-2) fib(n-1) ret n
+        add
+        swap 0 8            # n ret fib(n)
+        pop
+        return
+    base:                   # 2 n ret n, and fib(n) is n
+        pop 8
+        return
+    ", n))
+}
+
+/* Strings made, compared, parsed and thrown away: itoa, stpush, strcmp and atoi, which spend
+ * their time packing and unpacking characters. */
+pub fn strings(iterations: u32) -> Vec<u8> {
+    image(&format!("
+        push {}
+    loop:
+        dup
+        itoa
+        stpush \"500\"
+        strcmp
+        pop
+        dup
+        itoa
+        atoi
+        pop 8
+        push 1
+        sub
+        ifnz loop
+        exit
+    ", iterations))
+}
+
+fn image(source: &str) -> Vec<u8> {
+    assemble(source).expect("the benchmark program assembles").image()
+}
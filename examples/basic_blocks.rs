@@ -0,0 +1,60 @@
+/* Looking at a program without running it, through vm::Program: its instructions, where its
+ * branches go and the basic blocks they cut it into. Prints each block with its instructions,
+ *
+ *     block 0000..0008
+ *       0000: push 3
+ *       0004: call 32
+ *     ...
+ *
+ * and checks the blocks start where they should. */
+
+use std::process;
+
+use vm::asm::assemble;
+use vm::Program;
+
+/* A loop, a call and a jumptable, which between them start most kinds of block. */
+const PROGRAM: &str = "
+        push 3
+        call count
+        push 1
+        jumptable 2
+        .table one two
+        exit 2
+one:    exit 0
+two:    exit 1
+count:  push -1
+        add
+        dup
+        ifnz count
+        return
+";
+
+fn main() {
+    let image = assemble(PROGRAM).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1);
+    }).image();
+    let program = Program::from_image(&image).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1);
+    });
+
+    let blocks = program.basic_blocks();
+    for block in &blocks {
+        println!("block {:04x}..{:04x}", block.start, block.end);
+        for (address, instruction) in program.instructions().filter(|&(address, _)| block.contains(address)) {
+            println!("  {:04x}: {}", address, instruction);
+        }
+    }
+    for (from, to) in program.branch_targets() {
+        println!("{:04x} -> {:04x}", from, to);
+    }
+
+    /* After the call, after the jumptable's table, and at each label. */
+    let starts: Vec<i32> = blocks.iter().map(|block| block.start).collect();
+    if starts != [0x00, 0x08, 0x18, 0x1c, 0x20, 0x24, 0x34] {
+        eprintln!("the blocks start at {:x?}", starts);
+        process::exit(1);
+    }
+}
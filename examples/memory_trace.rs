@@ -0,0 +1,84 @@
+/* Following a program's memory from outside with an observer, the way a visualization would.
+ * The observer here keeps a line per event, which main prints once the program is done:
+ *
+ *     write 0ffc  00000000 -> 00000003
+ *     pc    0000 -> 0004
+ *     ...
+ *
+ * and then checks the stores the program made to its variable at 0x800 are the ones expected. */
+
+use std::process;
+use std::sync::{Arc, Mutex};
+
+use vm::asm::assemble;
+use vm::harness;
+use vm::{MemoryObserver, VirtualMachine, VmConfig};
+
+/* Shared with main, since the machine owns the observer. */
+#[derive(Clone, Default)]
+struct Trace {
+    events: Arc<Mutex<Vec<String>>>,
+    /* The words stored to the variable. */
+    stores: Arc<Mutex<Vec<u32>>>,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+impl MemoryObserver for Trace {
+    fn read(&mut self, address: i32, bytes: &[u8]) {
+        self.events.lock().unwrap().push(format!("read  {:04x}  {}", address, hex(bytes)));
+    }
+
+    fn write(&mut self, address: i32, old: &[u8], new: &[u8]) {
+        self.events.lock().unwrap().push(format!("write {:04x}  {} -> {}", address, hex(old), hex(new)));
+        if address == VARIABLE {
+            self.stores.lock().unwrap().push(u32::from_be_bytes([new[0], new[1], new[2], new[3]]));
+        }
+    }
+
+    fn pc_changed(&mut self, from: i32, to: i32) {
+        self.events.lock().unwrap().push(format!("pc    {:04x} -> {:04x}", from, to));
+    }
+}
+
+/* Where the program counts down from 3. */
+const VARIABLE: i32 = 0x800;
+
+const PROGRAM: &str = "
+        push 3
+loop:   dup
+        push 0x800
+        store
+        push -1
+        add
+        dup
+        ifnz loop
+        exit
+";
+
+fn main() {
+    let image = assemble(PROGRAM).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1);
+    }).image();
+
+    let mut vm = VirtualMachine::from_bytes(image, VmConfig::default()).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1);
+    });
+    let trace = Trace::default();
+    vm.add_observer(Box::new(trace.clone()));
+
+    let run = harness::run_captured(vm, b"");
+    for event in trace.events.lock().unwrap().iter() {
+        println!("{}", event);
+    }
+
+    let stores = trace.stores.lock().unwrap().clone();
+    if run.result != Ok(0) || stores != [3, 2, 1] {
+        eprintln!("unexpected run: {:?}, stores {:?}", run.result, stores);
+        process::exit(1);
+    }
+}
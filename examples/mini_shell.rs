@@ -0,0 +1,201 @@
+/* A tiny command shell running as a guest program, driven from the host through the harness.
+ *
+ * The guest prints a prompt, reads a line with stinput and looks at the first word of it:
+ *
+ *     hi      says hello
+ *     add     reads two numbers with input and prints their sum
+ *     quit    exits with code 0
+ *
+ * Anything else gets a "?". Run it with --interactive to talk to it yourself; otherwise it plays
+ * a scripted session and checks the transcript, which makes it an end-to-end test of loading,
+ * string input and output, branching and the captured I/O harness. */
+
+use std::collections::HashMap;
+use std::env;
+use std::process;
+
+use vm::harness;
+use vm::{VirtualMachine, VmConfig};
+
+/* Just enough of an assembler to write the shell: instructions go into a list, and branches can
+ * point at labels that get filled in at the end. */
+#[derive(Default)]
+struct Program {
+    words: Vec<u32>,
+    labels: HashMap<&'static str, i32>,
+    fixups: Vec<(usize, &'static str)>,
+}
+
+impl Program {
+    fn here(&self) -> i32 {
+        (self.words.len() * 4) as i32
+    }
+
+    fn label(&mut self, name: &'static str) {
+        self.labels.insert(name, self.here());
+    }
+
+    fn emit(&mut self, word: u32) {
+        self.words.push(word);
+    }
+
+    fn branch(&mut self, word: u32, label: &'static str) {
+        self.fixups.push((self.words.len(), label));
+        self.words.push(word);
+    }
+
+    fn exit(&mut self, code: u32) { self.emit(code & 0x00FF_FFFF); }
+    fn input(&mut self) { self.emit(0x0400_0000); }
+    fn stinput(&mut self, max: u32) { self.emit(0x0500_0000 | max); }
+    fn pop(&mut self) { self.emit(0x1000_0004); }
+    fn add(&mut self) { self.emit(0x2000_0000); }
+    fn and(&mut self) { self.emit(0x2500_0000); }
+    fn stprint(&mut self) { self.emit(0x4000_0000); }
+    fn dup(&mut self) { self.emit(0xC000_0000); }
+    fn print(&mut self) { self.emit(0xD000_0000); }
+    fn push(&mut self, value: i32) { self.emit(0xF000_0000 | (value as u32 & 0x0FFF_FFFF)); }
+    fn goto(&mut self, label: &'static str) { self.branch(0x7000_0000, label); }
+    fn if_eq(&mut self, label: &'static str) { self.branch(0x8000_0000, label); }
+    fn if_zero(&mut self, label: &'static str) { self.branch(0x9000_0000, label); }
+
+    /* Push a string in the packed format stprint wants (three characters a word, first
+     * characters on top) and return how many words it took. */
+    fn push_string(&mut self, text: &str) -> usize {
+        let words = pack(text);
+        for &word in words.iter().rev() {
+            self.push(word);
+        }
+        words.len()
+    }
+
+    /* Print a string and take it back off the stack. */
+    fn say(&mut self, text: &str) {
+        let words = self.push_string(text);
+        self.stprint();
+        for _ in 0..words {
+            self.pop();
+        }
+    }
+
+    /* Resolve the labels and produce the contents of a .v file. */
+    fn assemble(mut self) -> Vec<u8> {
+        for (index, label) in self.fixups.drain(..) {
+            let offset = self.labels[label] - (index * 4) as i32;
+            let word = self.words[index];
+
+            self.words[index] = match word >> 28 {
+                7 => word | (offset as u32 & 0x0FFF_FFFC),
+                _ => word | (offset as u32 & 0x01FF_FFFF),
+            };
+        }
+
+        let mut bytes = vec![0xde, 0xad, 0xbe, 0xef];
+        for word in self.words {
+            bytes.extend_from_slice(&word.to_le_bytes());
+        }
+        bytes
+    }
+}
+
+/* Pack a string the way stinput does. */
+fn pack(text: &str) -> Vec<i32> {
+    let bytes = text.as_bytes();
+    let mut words: Vec<i32> = bytes.chunks(3)
+        .map(|chunk| chunk.iter().enumerate().fold(0, |word, (i, &b)| word | (b as i32) << (8 * i)))
+        .collect();
+
+    let count = words.len();
+    for word in &mut words[..count.saturating_sub(1)] {
+        *word |= 1 << 24;
+    }
+
+    if bytes.len().is_multiple_of(3) {
+        words.push(0);
+    }
+    words
+}
+
+fn shell() -> Vec<u8> {
+    let mut p = Program::default();
+
+    p.label("prompt");
+    p.say("> ");
+    p.stinput(60);
+
+    /* The first word of the line is on top; compare it against each command. */
+    for (command, label) in [("hi", "hi"), ("add", "add"), ("quit", "quit")] {
+        p.push(pack(command)[0]);
+        p.if_eq(label);
+        p.pop();
+    }
+
+    p.say("?\n");
+    p.goto("clean_up");
+
+    p.label("hi");
+    p.pop();
+    p.say("hello!\n");
+    p.goto("clean_up");
+
+    p.label("add");
+    p.pop();
+    p.input();
+    p.input();
+    p.add();
+    p.print();
+    p.pop();
+    p.goto("clean_up");
+
+    p.label("quit");
+    p.exit(0);
+
+    /* Throw away the line: keep popping words until one without the continuation bit goes. */
+    p.label("clean_up");
+    p.dup();
+    p.push(1 << 24);
+    p.and();
+    p.if_zero("last_word");
+    p.pop();
+    p.pop();
+    p.goto("clean_up");
+
+    p.label("last_word");
+    p.pop();
+    p.pop();
+    p.goto("prompt");
+
+    p.assemble()
+}
+
+fn main() {
+    let image = shell();
+    let vm = VirtualMachine::from_bytes(image, VmConfig::default()).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1);
+    });
+
+    if env::args().any(|arg| arg == "--interactive") {
+        let mut vm = vm;
+        match vm.run() {
+            Ok(code) => process::exit(code),
+            Err(err) => {
+                eprintln!("{}", err);
+                process::exit(1);
+            }
+        }
+    }
+
+    let script = "hi\nadd\n2\n40\nls -la\nquit\n";
+    let expected = "> hello!\n> 42\n> ?\n> ";
+
+    let run = harness::run_captured(vm, script.as_bytes());
+    print!("{}", run.stdout);
+    println!();
+
+    if run.result != Ok(0) || run.stdout != expected {
+        eprintln!("unexpected session: {:?}, {:?}", run.result, run.stdout);
+        process::exit(1);
+    }
+
+    println!("session matched after {} instructions", run.instructions);
+}
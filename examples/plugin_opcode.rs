@@ -0,0 +1,78 @@
+/* Adding an instruction from outside the crate. Opcode 10 is free, so this claims it for two
+ * instructions of its own, picked by the low bits of the word:
+ *
+ *     0xA0000000  gcd    replace the two numbers on top with their greatest common divisor
+ *     0xA0000001  emit   pop a number and print it as a character
+ *
+ * The guest program, written with .word for the new instructions, prints the gcd of 84 and 36
+ * as a digit and checks it comes out as "12". */
+
+use std::process;
+
+use vm::asm::assemble;
+use vm::harness;
+use vm::plugin::{Machine, OpcodeHandler};
+use vm::{VirtualMachine, VmConfig, VmError};
+
+struct Arithmetic;
+
+impl OpcodeHandler for Arithmetic {
+    fn execute(&mut self, instruction: u32, machine: &mut Machine<'_>) -> Result<(), VmError> {
+        match instruction & 0x0FFF_FFFF {
+            0 => {
+                let (mut a, mut b) = (machine.pop()?.abs(), machine.pop()?.abs());
+                while b != 0 {
+                    (a, b) = (b, a % b);
+                }
+                machine.push(a)
+            },
+            1 => {
+                let c = machine.pop()?;
+                let c = char::from_u32(c as u32).ok_or_else(|| VmError::from(format!("{} isn't a character", c)))?;
+                machine.print(&c.to_string())
+            },
+            _ => Err(VmError::from(format!("Bad arithmetic plugin instruction {:#010x}.", instruction))),
+        }
+    }
+}
+
+const PROGRAM: &str = "
+        push 84
+        push 36
+        .word 0xA0000000    # gcd
+        dup
+        push 10
+        div
+        push 48
+        add
+        .word 0xA0000001    # emit the tens
+        push 10
+        rem
+        push 48
+        add
+        .word 0xA0000001    # emit the units
+        exit
+";
+
+fn main() {
+    let image = assemble(PROGRAM).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1);
+    }).image();
+
+    let mut vm = VirtualMachine::from_bytes(image, VmConfig::default()).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1);
+    });
+    if let Err(err) = vm.register_opcode(10, Box::new(Arithmetic)) {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+
+    let run = harness::run_captured(vm, b"");
+    println!("{}", run.stdout);
+    if run.result != Ok(0) || run.stdout != "12" {
+        eprintln!("unexpected run: {:?}, {:?}", run.result, run.stdout);
+        process::exit(1);
+    }
+}
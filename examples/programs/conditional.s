@@ -0,0 +1,34 @@
+# Conditional assembly. Nothing is defined when this runs as it is, so DEBUG, VERBOSE and
+# WORDS64 are all 0; vm asm -D DEBUG -D WORDS64 conditional.s puts the other lines in instead.
+
+.macro show value
+.if DEBUG
+        stpush "value "
+        stprint 0
+        pop 8
+.endif
+        push \value
+        print
+        pop 4
+.endm
+
+.if WORDS64
+        .feature words64
+        push 8
+.else
+        push 4                  # the size of a word
+.endif
+        print
+        pop 4
+
+.if DEBUG
+        show 100
+.else
+.if VERBOSE == 0
+        show 1
+.else
+        show 2
+.endif
+.endif
+        show 3
+        exit
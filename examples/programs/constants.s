@@ -0,0 +1,25 @@
+# Named constants and expressions, with the length of a string worked out from labels.
+
+        .equ COUNT, 3
+        .equ STEP, COUNT*2+1
+        .equ LENGTH, message_end - message - 1
+
+        push STEP<<1            # 14
+        print
+        push (COUNT+1)*-2       # -8
+        print
+        push LENGTH
+        print
+        lea message_end         # the same length from the addresses as it runs
+        lea message
+        sub
+        push LENGTH+1
+        ifeq same
+        exit 1
+same:   goto done
+        exit 2
+done:   exit COUNT-3
+
+.data
+message: .string "constant"
+message_end:
@@ -0,0 +1,28 @@
+# Constants in a data region. lea finds each one where the assembler put it, straight after the
+# code, and readonly_data keeps the program from writing over them.
+
+        .feature readonly_data
+        push 6          # the length, without the 0 .string puts after it
+        lea greeting
+        stprintn
+        lea squares
+        push 12
+        add
+        load            # squares[3]
+        dup
+        push 9
+        cmpeq
+        assert wrong    # a failed one stops with the message
+        print
+        exit
+
+.data
+greeting:
+        .string "hello\n"
+squares:
+        .word 0
+        .word 1
+        .word 4
+        .word 9
+wrong:
+        .string "squares[3] isn't 9"
@@ -0,0 +1,15 @@
+# Echo each line of input back, up to the end of the input or an empty line. The lines pile up
+# on the stack, since there's no telling how big each one is to pop it.
+
+loop:
+        stinput 80
+        strlen
+        ifez done
+        pop
+        stprint
+        stpush "\n"
+        stprint
+        pop 4
+        goto loop
+done:
+        exit
@@ -0,0 +1,14 @@
+# Starting somewhere other than 0. .entry skips over the word of data at the front, and .sp
+# starts the stack two words below the top of memory, so there are two zeros on it already.
+
+        .entry main
+        .sp 4088
+        .word 0x12345678
+main:
+        print
+        pop
+        print
+        pop
+        push 42
+        print
+        exit
@@ -0,0 +1,24 @@
+# The first 20 Fibonacci numbers.
+
+        push 20             # how many are left to print
+        push 0              # a
+        push 1              # b
+loop:
+        print 4             # a
+        dup 4
+        dup 4
+        add                 # count a b a+b
+        swap 8 0
+        pop                 # count a+b b
+        swap                # count b a+b
+        dup 8
+        push 1
+        sub
+        swap 12 0
+        pop                 # count-1 b a+b
+        dup 8
+        ifnz more
+        exit
+more:
+        pop
+        goto loop
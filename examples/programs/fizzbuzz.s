@@ -0,0 +1,47 @@
+# FizzBuzz from 1 to 15.
+
+        push 1              # n
+loop:
+        dup
+        push 15
+        rem
+        ifez fizzbuzz
+        pop
+        dup
+        push 3
+        rem
+        ifez fizz
+        pop
+        dup
+        push 5
+        rem
+        ifez buzz
+        pop
+        print
+        goto next
+fizzbuzz:
+        pop
+        stpush "FizzBuzz\n"
+        stprint
+        pop 16
+        goto next
+fizz:
+        pop
+        stpush "Fizz\n"
+        stprint
+        pop 8
+        goto next
+buzz:
+        pop
+        stpush "Buzz\n"
+        stprint
+        pop 8
+next:
+        push 1
+        add
+        push 16
+        iflt more
+        exit
+more:
+        pop
+        goto loop
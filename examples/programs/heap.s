@@ -0,0 +1,51 @@
+# A heap of 256 bytes straight after the program. The squares of 0 to 9 go into it a word at a
+# time and then come back out to be added up. With a heap the stack has a segment of its own,
+# so stackdepth counts the room down to the top of the heap rather than to the bottom of memory.
+
+        .heap 256
+        push 0              # i
+fill:
+        push 10
+        ifge filled
+        pop 4
+        dup
+        dup
+        mul                 # i squared
+        pick 1
+        push 4
+        mul
+        lea heap
+        add                 # goes at heap + 4i
+        store
+        push 1
+        add
+        goto fill
+filled:
+        pop 8
+        push 0              # the sum
+        push 0              # i
+sum:
+        push 10
+        ifge summed
+        pop 4
+        dup
+        push 4
+        mul
+        lea heap
+        add
+        load
+        roll 3
+        add                 # sum, i
+        roll 2
+        push 1
+        add
+        goto sum
+summed:
+        pop 8
+        print
+        stackdepth
+        print
+        halt
+
+.data
+heap:
@@ -0,0 +1,10 @@
+# Using what another file defines, by including it. Macros have to be defined before they're
+# used, so the include comes first and .entry skips past its code.
+
+        .entry main
+.include "lib/answers.s"
+
+main:   show 1
+        call answer
+        show ANSWER+1
+        exit
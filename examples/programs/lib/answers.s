@@ -0,0 +1,12 @@
+# Included by include.s: a constant, a macro and a routine for it to use.
+
+        .equ ANSWER, 42
+
+.macro show value
+        push \value
+        print
+        pop 4
+.endm
+
+answer: show ANSWER
+        return
@@ -0,0 +1,27 @@
+# Words in little-endian byte order, least significant byte first, the same as instructions.
+# The word 0x636261 is the bytes "abc" and a 0 in memory, so printing its first three bytes
+# spells it out, and load still gives the word back as it was written.
+
+        .feature little_endian
+        push 3
+        lea letters
+        stprintn
+        push 1
+        lea newline
+        stprintn
+        lea letters
+        load
+        dup
+        push 0x636261
+        cmpeq
+        assert wrong
+        printh
+        exit
+
+.data
+letters:
+        .word 0x636261
+newline:
+        .string "\n"
+wrong:
+        .string "load turned the word round"
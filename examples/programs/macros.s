@@ -0,0 +1,26 @@
+# Counting down with a macro for the loop and the aliases for the branches.
+
+.macro countdown from
+        push \from
+loop\@:
+        dup
+        print
+        push -1
+        add
+        jnz loop\@
+        pop 4
+.endm
+
+.macro twice from
+        countdown \from
+        countdown \from
+.endm
+
+        twice 3
+        push 2
+        push 2
+        if.eq done
+        exit 1
+done:   jmp end
+        exit 2
+end:    exit
@@ -0,0 +1,27 @@
+# Summing 1 to 10 and then reporting what it cost, the way a benchmark would: how many
+# instructions the loop took, how many branches, and how deep the stack got.
+
+        perfctr instructions
+        push 0              # start sum
+        push 10             # start sum n
+loop:
+        dup 4
+        dup 4
+        add                 # start sum n sum+n
+        swap 8 0
+        pop                 # start sum+n n
+        push -1
+        add                 # start sum n-1
+        ifnz loop
+        pop                 # start sum
+        print
+        pop
+        perfctr instructions
+        swap
+        sub                 # instructions from the first perfctr up to this one
+        print
+        perfctr branches
+        print
+        perfctr stackmax
+        print
+        halt
@@ -0,0 +1,17 @@
+# Filling the stack until stackdepth says there's only 1K left, then stopping there, rather
+# than running into the code and faulting.
+
+        push 0              # how many words have been pushed
+fill:
+        stackdepth
+        push 1024
+        iflt full           # the room left, below 1K
+        pop 8
+        dup
+        push 1
+        add
+        goto fill
+full:
+        pop 8
+        print
+        exit
@@ -0,0 +1,54 @@
+# Every routine in the standard library, each called on a few cases.
+
+        push -7
+        call abs
+        print
+        push 7
+        call abs
+        print
+        push 3
+        push -4
+        call min
+        print
+        push 3
+        push -4
+        call max
+        print
+        push 12
+        push 34
+        call mulshift
+        print
+        push -6
+        push 7
+        call mulshift
+        print
+        lea greeting
+        call strlen.z
+        print
+        lea empty
+        call strlen.z
+        print
+        push 1
+        push 31
+        lsl                     # the smallest word, too big for a push
+        call putnum
+        push 10
+        call putchar
+        push 0
+        call putnum
+        push 32
+        call putchar
+        push 1905
+        call putnum
+        push 10
+        call putchar
+        pop 32
+        exit
+
+.include "stdlib/math.s"
+.include "stdlib/print.s"
+.include "stdlib/string.s"
+
+.data
+greeting: .string "hello, world"
+empty:    .string ""
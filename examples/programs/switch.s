@@ -0,0 +1,29 @@
+# A switch on 0 to 5 with a jump table, three cases and a default.
+
+        push 0              # n
+loop:
+        dup
+        jumptable 3
+        .table zero one two
+        push -1             # anything else
+        goto next
+zero:
+        push 100
+        goto next
+one:
+        push 200
+        goto next
+two:
+        push 300
+next:
+        print
+        pop                 # n
+        push 1
+        add
+        dup
+        push 6
+        ifeq done
+        pop
+        goto loop
+done:
+        exit
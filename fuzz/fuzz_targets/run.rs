@@ -0,0 +1,15 @@
+/* cargo fuzz run run: feed arbitrary bytes to the VM as a .v file. The first byte says how many
+ * of the bytes after it are the program's stdin; the rest is the file. */
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&size, rest)) = data.split_first() else {
+        return;
+    };
+
+    let (input, file) = rest.split_at((size as usize).min(rest.len()));
+    vm::fuzz::execute(file, input);
+});
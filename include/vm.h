@@ -0,0 +1,69 @@
+/* C API for the VM. Build the library with
+ *
+ *     cargo rustc --release --lib --crate-type cdylib --features capi
+ *
+ * and see src/ffi.rs for more on each function. These declarations are stable: functions can
+ * be added, but these keep their meaning. */
+
+#ifndef VM_H
+#define VM_H
+
+#include <stddef.h>
+#include <stdint.h>
+#include <sys/types.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* A machine, from vm_create. */
+typedef struct vm vm_t;
+
+/* What vm_step returns. */
+#define VM_RUNNING 0
+#define VM_EXITED 1
+#define VM_BREAKPOINT 2
+#define VM_FAULT (-1)
+#define VM_NO_PROGRAM (-2)
+
+/* Called with each chunk of output the program writes. */
+typedef void (*vm_write_fn)(void *user, const uint8_t *bytes, size_t len);
+/* Called for a line of input: write up to cap bytes of it into buf and return how many, or -1
+ * at the end of the input. */
+typedef ssize_t (*vm_read_line_fn)(void *user, uint8_t *buf, size_t cap);
+
+/* A machine with nothing loaded. Free it with vm_destroy. */
+vm_t *vm_create(void);
+
+/* Load the contents of a .v file. 0 if it loaded, -1 if not. */
+int32_t vm_load(vm_t *vm, const uint8_t *bytes, size_t len);
+
+/* Send output to write and take input from read_line; either can be NULL. user is passed to
+ * both. Until this is called, the program uses the process's stdin and stdout. */
+int32_t vm_register_io(vm_t *vm, vm_write_fn write, vm_read_line_fn read_line, void *user);
+
+/* Run one instruction: VM_RUNNING, VM_BREAKPOINT after a brk, VM_EXITED, VM_FAULT or
+ * VM_NO_PROGRAM. */
+int32_t vm_step(vm_t *vm);
+
+/* Copy len bytes of memory from address into buf. 0 if they're all in memory, -1 if not. */
+int32_t vm_read_mem(vm_t *vm, uint32_t address, uint8_t *buf, size_t len);
+
+/* The code the program exited with, 0 until it has. */
+int32_t vm_exit_code(const vm_t *vm);
+
+/* Where the program and its stack are, or -1 with nothing loaded. */
+int32_t vm_program_counter(const vm_t *vm);
+int32_t vm_stack_pointer(const vm_t *vm);
+
+/* Why the last call that failed did; empty if nothing has. */
+const char *vm_last_error(const vm_t *vm);
+
+/* Free a machine. NULL is fine. */
+void vm_destroy(vm_t *vm);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
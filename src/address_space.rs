@@ -0,0 +1,180 @@
+/* The machine's memory cut up into segments, from the bottom up:
+ *
+ *     code      the program's instructions, from 0 up to the end of its code
+ *     globals   its data region, if it has one
+ *     heap      zeroes for the program to keep things in, as much as VmConfig::heap_size or
+ *               the header's heap size asks for
+ *     stack     up to the top of memory, where the stack starts and grows down from
+ *
+ * Memory is still the one run of bytes with the same addresses; the address space says which
+ * part is which and what may be done to each. Only the code can be run. The code can't be
+ * written with protect_code on, nor the globals with the READONLY_DATA feature, and when the
+ * heap or the stack has a size of its own, the stack stops at the bottom of its segment rather
+ * than growing down over the heap.
+ *
+ * Without either size there's no heap, and the stack's segment is everything above the
+ * program, the way memory has always been laid out. With only the stack's size, the heap is
+ * whatever the stack leaves. With both, whatever is left between the two belongs to no segment,
+ * and writing there is a fault. The heap starts wherever the program
+ * ends, so a program finds it with a label after its data. */
+
+use alloc::format;
+use alloc::string::String;
+use core::fmt;
+use core::ops::Range;
+
+use crate::VmError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Segment {
+    Code,
+    Globals,
+    Heap,
+    Stack,
+}
+
+impl Segment {
+    pub const ALL: [Segment; 4] = [Segment::Code, Segment::Globals, Segment::Heap, Segment::Stack];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Segment::Code => "code",
+            Segment::Globals => "globals",
+            Segment::Heap => "heap",
+            Segment::Stack => "stack",
+        }
+    }
+}
+
+/* What may be done to the bytes in a segment. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Access {
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+}
+
+/* See the top of the module. */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddressSpace {
+    code: Range<i32>,
+    globals: Range<i32>,
+    heap: Range<i32>,
+    stack: Range<i32>,
+    protect_code: bool,
+    readonly_globals: bool,
+    /* Whether the heap or the stack was given a size. */
+    sized: bool,
+}
+
+impl AddressSpace {
+    /* Lay out memory of memory_size bytes for a program whose code ends at code_end and data at
+     * data_end. */
+    pub fn new(code_end: usize, data_end: usize, heap_size: Option<usize>, stack_size: Option<usize>, memory_size: usize)
+        -> Result<AddressSpace, String> {
+        let needed = data_end as u64 + heap_size.unwrap_or(0) as u64 + stack_size.unwrap_or(0) as u64;
+        if needed > memory_size as u64 {
+            return Err(format!("The program, its heap and its stack need {} bytes, but there are only {}.", needed, memory_size));
+        }
+
+        let stack_start = match (heap_size, stack_size) {
+            (_, Some(stack_size)) => memory_size - stack_size,
+            (Some(heap_size), None) => data_end + heap_size,
+            (None, None) => data_end,
+        };
+        let heap_end = data_end + heap_size.unwrap_or(stack_start - data_end);
+
+        Ok(AddressSpace {
+            code: 0..code_end as i32,
+            globals: code_end as i32..data_end as i32,
+            heap: data_end as i32..heap_end as i32,
+            stack: stack_start as i32..memory_size as i32,
+            protect_code: true,
+            readonly_globals: false,
+            sized: heap_size.is_some() || stack_size.is_some(),
+        })
+    }
+
+    /* Whether writes to the code are stopped. */
+    pub fn protect_code(mut self, protect: bool) -> AddressSpace {
+        self.protect_code = protect;
+        self
+    }
+
+    /* Whether writes to the globals are stopped. */
+    pub fn readonly_globals(mut self, readonly: bool) -> AddressSpace {
+        self.readonly_globals = readonly;
+        self
+    }
+
+    /* The addresses a segment covers, which may be none. */
+    pub fn segment(&self, segment: Segment) -> Range<i32> {
+        match segment {
+            Segment::Code => self.code.clone(),
+            Segment::Globals => self.globals.clone(),
+            Segment::Heap => self.heap.clone(),
+            Segment::Stack => self.stack.clone(),
+        }
+    }
+
+    pub fn access(&self, segment: Segment) -> Access {
+        match segment {
+            Segment::Code => Access { read: true, write: !self.protect_code, execute: true },
+            Segment::Globals => Access { read: true, write: !self.readonly_globals, execute: false },
+            Segment::Heap | Segment::Stack => Access { read: true, write: true, execute: false },
+        }
+    }
+
+    /* Which segment an address is in and how far into it, or None for one in no segment. */
+    pub fn translate(&self, address: i32) -> Option<(Segment, i32)> {
+        Segment::ALL.into_iter()
+            .find(|&segment| self.segment(segment).contains(&address))
+            .map(|segment| (segment, address - self.segment(segment).start))
+    }
+
+    /* Whether the stack has a segment of its own to stay inside, rather than the run of
+     * everything above the program. */
+    pub fn stack_bounded(&self) -> bool {
+        self.sized
+    }
+
+    /* Stop a write to size bytes at address that lands anywhere it mayn't. Anything past the
+     * top of memory is left for the bounds check to catch. */
+    pub fn check_write(&self, address: i32, size: i32) -> Result<(), VmError> {
+        let touched = address..address.saturating_add(size);
+        let overlaps = |range: &Range<i32>| touched.start < range.end && range.start < touched.end;
+
+        if !self.access(Segment::Code).write && overlaps(&self.code) {
+            return Err(VmError::from(format!("Write to the code at {:#x}.", address)));
+        }
+        if !self.access(Segment::Globals).write && overlaps(&self.globals) {
+            return Err(VmError::from(format!("Write to read-only data at {:#x}.", address)));
+        }
+        if overlaps(&(self.heap.end..self.stack.start)) {
+            return Err(VmError::from(format!("Write to {:#x}, between the heap and the stack.", address)));
+        }
+        Ok(())
+    }
+}
+
+/* A line for each segment with anything in it:
+ *
+ *     code     0000..0040  r-x
+ *     heap     0040..0440  rw-
+ *     stack    0440..1000  rw-
+ */
+impl fmt::Display for AddressSpace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for segment in Segment::ALL {
+            let range = self.segment(segment);
+            if range.is_empty() {
+                continue;
+            }
+            let access = self.access(segment);
+            let flag = |on: bool, c: char| if on { c } else { '-' };
+            writeln!(f, "{:<8} {:04x}..{:04x}  {}{}{}", segment.name(), range.start, range.end,
+                flag(access.read, 'r'), flag(access.write, 'w'), flag(access.execute, 'x'))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,235 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+/* A call that jumps back to the start of the function it's in and whose return value is handed
+ * straight back to the caller. These could reuse the current frame instead of pushing a new
+ * return address. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TailCallCandidate {
+    /* Address of the call instruction. */
+    pub call_site: i32,
+    /* Start of the function, which is also where the call goes. */
+    pub function: i32,
+    /* Address of the ret the call falls through to. */
+    pub ret_site: i32,
+    /* How many bytes that ret frees before popping the return address. */
+    pub frame_size: i32,
+}
+
+impl fmt::Display for TailCallCandidate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04x}: self-recursive tail call to {:04x} (returns via {:04x}",
+            self.call_site, self.function, self.ret_site)?;
+
+        if self.frame_size != 0 {
+            write!(f, ", frees {} bytes", self.frame_size)?;
+        }
+
+        write!(f, ")")
+    }
+}
+
+/* A stretch of code that starts at address 0 or at a call target and runs until the next one
+ * starts (or the code ends). */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Function {
+    pub start: i32,
+    pub end: i32,
+}
+
+impl Function {
+    /* How many instructions the function spans. */
+    pub fn size(&self) -> usize {
+        ((self.end - self.start) / 4) as usize
+    }
+}
+
+/* Grab the instruction at an address, if there is one. */
+pub(crate) fn instruction_at(code: &[u8], address: i32) -> Option<u32> {
+    if address < 0 || address % 4 != 0 {
+        return None;
+    }
+
+    let start = address as usize;
+    let bytes = code.get(start..start + 4)?;
+
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/* Where a branching instruction at an address ends up. Calls, tail calls and gotos keep a
+ * signed word offset in bits 27-2; the ifs keep a signed byte offset in bits 24-0, and spawn in
+ * bits 19-0. lea counts too, with its offset where spawn's is, since the address it takes may be
+ * code that gets jumped to later. Anything else doesn't branch, jumpi included, since only the
+ * stack knows where it goes. */
+pub(crate) fn branch_target(address: i32, instruction: u32) -> Option<i32> {
+    match instruction >> 28 {
+        5 | 7 => {
+            let extracted = (instruction >> 2) & 0x03FF_FFFF;
+            let offset = if extracted & (1 << 25) != 0 {
+                (extracted | !0x03FF_FFFF) as i32
+            } else {
+                extracted as i32
+            };
+
+            Some(address + (offset << 2))
+        },
+        8 | 9 => {
+            let offset_mask = (1 << 25) - 1;
+            let mut offset = instruction as i32 & offset_mask;
+            if instruction & (1 << 24) != 0 {
+                offset |= !offset_mask;
+            }
+
+            Some(address + offset)
+        },
+        11 if is_spawn(instruction) || is_lea(instruction) || is_assert(instruction) => Some(address + (((instruction << 12) as i32) >> 12)),
+        _ => None,
+    }
+}
+
+/* Which of the whole words of some code are the entries of a jumptable rather than
+ * instructions: the n words after each jumptable n. */
+pub fn table_entries(code: &[u8]) -> Vec<bool> {
+    let words: Vec<u32> = (0..code.len() as i32 / 4).filter_map(|i| instruction_at(code, i * 4)).collect();
+    let mut entries = vec![false; words.len()];
+    let mut i = 0;
+
+    while i < words.len() {
+        if words[i] >> 20 == 0xB1B {
+            let count = (words[i] & 0xF_FFFF) as usize;
+            let end = (i + 1 + count).min(words.len());
+            entries[i + 1..end].fill(true);
+            i = end;
+        } else {
+            i += 1;
+        }
+    }
+
+    entries
+}
+
+/* A call with bit 0 set is a tailcall. */
+pub(crate) fn is_tail_call(instruction: u32) -> bool {
+    instruction >> 28 == 5 && instruction & 3 == 1
+}
+
+/* spawn is an extended instruction, but it goes somewhere like a branch does. */
+fn is_spawn(instruction: u32) -> bool {
+    instruction >> 20 == 0xB0E
+}
+
+fn is_lea(instruction: u32) -> bool {
+    instruction >> 20 == 0xB1C
+}
+
+/* assert's offset is to its message, which moves with the code like lea's label. */
+fn is_assert(instruction: u32) -> bool {
+    instruction >> 20 == 0xB24
+}
+
+/* Point a branching instruction somewhere else, keeping its opcode and condition bits. */
+pub(crate) fn retarget(address: i32, instruction: u32, target: i32) -> u32 {
+    let offset = target - address;
+
+    match instruction >> 28 {
+        5 | 7 => (instruction & !0x0FFF_FFFC) | ((offset as u32) & 0x0FFF_FFFC),
+        8 | 9 => (instruction & !0x01FF_FFFF) | ((offset as u32) & 0x01FF_FFFF),
+        11 if is_spawn(instruction) || is_lea(instruction) || is_assert(instruction) => (instruction & !0x000F_FFFF) | ((offset as u32) & 0x000F_FFFF),
+        _ => instruction,
+    }
+}
+
+/* Every call in the program as (call site, target) pairs, in address order. */
+pub fn call_graph(code: &[u8]) -> Vec<(i32, i32)> {
+    let mut calls = Vec::new();
+
+    for address in (0..code.len() as i32).step_by(4) {
+        let Some(instruction) = instruction_at(code, address) else {
+            break;
+        };
+
+        if instruction >> 28 == 5 {
+            if let Some(target) = branch_target(address, instruction) {
+                calls.push((address, target));
+            }
+        }
+    }
+
+    calls
+}
+
+/* Split the program into functions using the call targets, in address order. */
+pub fn functions(code: &[u8]) -> Vec<Function> {
+    let code_end = (code.len() / 4 * 4) as i32;
+    let mut starts = vec![0];
+
+    for (_, target) in call_graph(code) {
+        if (0..code_end).contains(&target) && !starts.contains(&target) {
+            starts.push(target);
+        }
+    }
+
+    starts.sort();
+
+    let mut functions = Vec::new();
+    for (i, &start) in starts.iter().enumerate() {
+        let end = starts.get(i + 1).copied().unwrap_or(code_end);
+        functions.push(Function { start, end });
+    }
+
+    functions
+}
+
+/* Follow unconditional gotos from an address until something else turns up. Returns the ret
+ * that's reached along with its frame size, or None if it's anything but a ret. */
+fn reaches_ret(code: &[u8], mut address: i32) -> Option<(i32, i32)> {
+    let mut visited = Vec::new();
+
+    loop {
+        let instruction = instruction_at(code, address)?;
+
+        match instruction >> 28 {
+            6 => return Some((address, (instruction & 0x0FFF_FFFC) as i32)),
+            7 => {
+                /* A loop of gotos never gets anywhere. */
+                if visited.contains(&address) {
+                    return None;
+                }
+
+                visited.push(address);
+                address = branch_target(address, instruction)?;
+            },
+            _ => return None,
+        }
+    }
+}
+
+/* Look through a program for self-recursive calls in tail position. */
+pub fn find_tail_recursion(code: &[u8]) -> Vec<TailCallCandidate> {
+    let functions = functions(code);
+    let mut candidates = Vec::new();
+
+    for (call_site, target) in call_graph(code) {
+        /* Already one. */
+        if instruction_at(code, call_site).is_some_and(is_tail_call) {
+            continue;
+        }
+
+        let enclosing = functions.iter().rev().find(|function| function.start <= call_site);
+        if enclosing.map(|function| function.start) != Some(target) {
+            continue;
+        }
+
+        if let Some((ret_site, frame_size)) = reaches_ret(code, call_site + 4) {
+            candidates.push(TailCallCandidate {
+                call_site,
+                function: target,
+                ret_site,
+                frame_size,
+            });
+        }
+    }
+
+    candidates
+}
@@ -0,0 +1,1159 @@
+/* The assembler: turns .s source into code for the VM. One instruction per line, with labels
+ * ending in a colon and # starting a comment:
+ *
+ *     main:   push 10
+ *     loop:   print
+ *             push -1
+ *             add
+ *             ifnz loop       # a label or a byte offset from this instruction
+ *             exit
+ *
+ * The mnemonics, with optional operands in brackets:
+ *
+ *     exit [code]  halt  swap [from] [to]  nop  input  stinput [max]  debug debugb [bytes]
+ *     pop [bytes]  add sub mul div rem and or xor lsl lsr asr rol ror divu remu
+ *     cmpeq cmpne cmplt cmpgt cmple cmpge cmpltu cmpgtu cmpleu cmpgeu  neg not
+ *     stprint [offset]  call <target>  tailcall <target>  return [bytes]  goto <target>  jumpi
+ *     ifeq ifne iflt ifgt ifle ifge ifltu ifgtu ifleu ifgeu <target>  ifez ifnz ifmi ifpl <target>
+ *     >r  r>  strlen [offset]  strcat  strcmp  readfile  writefile [bytes]  arg  getenv
+ *     clock  cycles  rand  load  store  spawn <target>  yield  join  cas  fetchadd  lock  unlock
+ *     stprintn  pushb  pushh  popb  popbu  poph  pophu
+ *     dup [offset]  print printh printb printo [offset]  dump  push <value>
+ *     jumptable <entries>  lea <target>  pick <depth>  roll <count>  drop [count]  dup2
+ *     atoi  itoa  printf <spec> [nonl]  brk  assert [message]  stackdepth  perfctr <counter>
+ *     stpush "<text>"  .word <value>  .table <target>...  .feature <name>
+ *     .entry <target>  .sp <address>  .heap <bytes>  .data  .byte <value>...  .string "<text>"
+ *     .equ <name>, <value>
+ *
+ * Offsets and sizes are in bytes. An exit code goes up to 0xffffff, all the instruction has room
+ * for, and halt is another way to write exit 0. stpush isn't a real instruction: it pushes a
+ * string in the packed format stprint reads, one push per three characters, or with the
+ * byte_strings feature a length-prefixed one, building each word that's too big for a push out
+ * of shifts and ors.
+ * Nor is dup2, which copies the top two words as two pick 1s. pick, roll and drop count words
+ * rather than bytes, so they mean the same with words64, and pop, return and swap count 4 bytes
+ * to a word whatever its size, so they do too. printf's spec is C's without the length (see
+ * isa::PrintSpec), as in printf %08x, and nonl leaves off the newline. assert's message is a
+ * label on a .string, usually in the data; without one a failed assert only says where it was.
+ * .word puts a raw 32-bit word in the code, for anything the mnemonics can't say. .table puts
+ * one word per target, each the byte offset from the word to the target, which is the table a
+ * jumptable wants straight after it:
+ *
+ *             jumptable 3
+ *             .table zero one two
+ *             goto other      # anything but 0, 1 or 2
+ *
+ * .entry says where the program starts, a label or an address, when it isn't 0, and .sp where
+ * the stack pointer starts, when it isn't the top of memory. Either puts them in the header.
+ *
+ * Everything after .data goes in the data region, which is loaded straight after the code but
+ * never run. Only labels, .word, .byte and .string go there. A .word in the data is a word of
+ * memory the way load reads it, big-endian unless the program is little_endian and 8 bytes with
+ * words64, rather than an instruction word; .byte puts a byte per value, and .string the bytes
+ * of the text and a 0 after them. lea
+ * pushes the address of a label, data or code, so a program can find its data wherever it
+ * lands:
+ *
+ *             lea greeting
+ *             ...
+ *     .data
+ *     greeting: .string "hello"
+ *
+ * .feature sets a feature in the file's header: words64, heap, debug_info, dual_stack,
+ * byte_strings, readonly_data, which makes writing to the data region a fault, or
+ * little_endian, which puts the program's words in memory least significant byte first.
+ *
+ * .heap asks for a heap of so many bytes, straight after the program, and sets the heap
+ * feature; heap on its own gets the VM's default size. A label at the end of the data is where
+ * the heap starts:
+ *
+ *             .heap 512
+ *             lea heap
+ *             ...
+ *     .data
+ *     table: .word 1 2 3
+ *     heap:
+ *
+ * .equ names a constant, and anywhere a number goes an expression can go instead, with C's
+ * operators and precedence, the constants, and labels standing for their addresses. They're
+ * worked out once every label is known, so a constant can use labels further on, which is how
+ * to get the length of something in the data. Spaces split operands, so an expression in one
+ * can't have any, though the value of a .equ is the rest of its line:
+ *
+ *             .equ BUFSIZE, 64
+ *             .equ GREETING_LENGTH, greeting_end - greeting - 1
+ *             push BUFSIZE*4+2
+ *     .data
+ *     greeting: .string "hello"
+ *     greeting_end:
+ *
+ * A target with a label in it is an address, as in goto table+8, and one without is an offset
+ * like a plain number.
+ *
+ * Before any of this, .macro and .endm define macros, .include "file" brings in another file's
+ * lines, .if, .else and .endif leave lines out depending on the names defined for the assembly,
+ * and aliases such as jmp for goto and if.eq for ifeq become the mnemonics they stand for; see
+ * the preprocess module. Labels are shared between every file included, so a label
+ * defined in two of them is an error, as it would be in one. The debug info's line table says
+ * which file each instruction came from as well as which line. */
+
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cell::Cell;
+use core::fmt;
+
+use crate::analysis::TailCallCandidate;
+use crate::debug_info::{self, DebugInfo};
+use crate::expr::{Expr, State};
+use crate::isa::{BinaryOp, Condition, EofMode, Instruction, OffsetField, PerfCounter, PrintFormat, PrintSpec, UnaryOp, ZeroCondition};
+use crate::linker::{Object, Relocation};
+use crate::optimize::{self, InlinedCall, Peephole};
+use crate::preprocess;
+use crate::strings;
+use crate::Header;
+
+/* Something wrong with the source, and which line it's on. */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub line: usize,
+    /* The file included that the line is in, or None for the one assembled. */
+    pub file: Option<String>,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "line {} of {}: {}", self.line, file, self.message),
+            None => write!(f, "line {}: {}", self.line, self.message),
+        }
+    }
+}
+
+/* Where the files .include names come from. */
+pub trait Includes {
+    /* The file a .include in the file called from names, as the name to call it by and its
+     * text. */
+    fn read(&mut self, name: &str, from: &str) -> Result<(String, String), String>;
+}
+
+/* For source that came from nowhere in particular, with nothing to include. */
+struct NoIncludes;
+
+impl Includes for NoIncludes {
+    fn read(&mut self, _: &str, _: &str) -> Result<(String, String), String> {
+        Err(String::from("there are no files to include from here"))
+    }
+}
+
+/* Files on disk: next to the file doing the including, or failing that in each directory
+ * given, in order. */
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Default)]
+pub struct IncludePath {
+    pub dirs: Vec<std::path::PathBuf>,
+}
+
+#[cfg(feature = "std")]
+impl Includes for IncludePath {
+    fn read(&mut self, name: &str, from: &str) -> Result<(String, String), String> {
+        let beside = std::path::Path::new(from).parent().map(|dir| dir.join(name));
+        let path = beside.into_iter()
+            .chain(self.dirs.iter().map(|dir| dir.join(name)))
+            .find(|path| path.is_file())
+            .ok_or_else(|| String::from("not found"))?;
+
+        std::fs::read_to_string(&path)
+            .map(|text| (path.display().to_string(), text))
+            .map_err(|e| e.to_string())
+    }
+}
+
+impl core::error::Error for AsmError {}
+
+/* An assembled program. */
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Assembled {
+    pub code: Vec<u8>,
+    /* Every label and the address it stands for. */
+    pub labels: BTreeMap<String, i32>,
+    /* The source files, the one assembled first and then each it included. */
+    pub files: Vec<String>,
+    /* The file, as an index into files, and line each word of code came from. */
+    pub lines: Vec<(usize, usize)>,
+    /* Header features asked for with .feature. */
+    pub features: u32,
+    /* Where the program starts, from .entry. */
+    pub entry: i32,
+    /* Where the stack pointer starts, from .sp. */
+    pub stack_pointer: Option<i32>,
+    /* How big the heap is, from .heap. */
+    pub heap_size: Option<u32>,
+    /* What goes after the code, from .data. */
+    pub data: Vec<u8>,
+}
+
+impl Assembled {
+    /* The contents of a .v file holding the program, with its debug info after the code if it
+     * asked for the DEBUG_INFO feature. */
+    pub fn image(&self) -> Vec<u8> {
+        let mut header = Header::new(self.features);
+        header.entry = self.entry as u32;
+        if let Some(stack_pointer) = self.stack_pointer {
+            header.stack_pointer = stack_pointer as u32;
+        }
+        if !self.data.is_empty() {
+            header.code_size = Some(self.code.len() as u32);
+        }
+        header.heap_size = self.heap_size;
+        let mut image = header.image(&self.code);
+        image.extend_from_slice(&self.data);
+        if header.has(Header::DEBUG_INFO) {
+            self.debug_info().append_to(&mut image);
+        }
+        image
+    }
+
+    /* The labels and source lines, to go in a .v file. */
+    pub fn debug_info(&self) -> DebugInfo {
+        DebugInfo { labels: self.labels.clone(), files: self.files.clone(), rows: DebugInfo::line_table(&self.lines) }
+    }
+
+    /* Run the peephole pass over the code (see optimize::peephole), keeping the labels and
+     * lines in step. Code with a word in it that isn't an instruction, data put there with
+     * .word say, is left alone, since there's no telling what relies on where it is. Labels in
+     * the data region move with the end of the code, but values worked out from labels, as in
+     * push end-start, stay as they were. */
+    pub fn peephole(&mut self) -> Result<Peephole, String> {
+        let mut program = Vec::new();
+        for (address, word) in self.code.chunks(4).enumerate() {
+            let word = <[u8; 4]>::try_from(word).map(u32::from_le_bytes).ok();
+            match word.and_then(Instruction::decode) {
+                Some(Instruction::JumpI | Instruction::JumpTable(_)) => {
+                    return Err(format!("the jump at {:#06x} only finds out where it goes as it runs, so nothing was optimized", address * 4));
+                },
+                Some(instruction) => program.push(instruction),
+                None => return Err(format!("the word at {:#06x} isn't an instruction, so nothing was optimized", address * 4)),
+            }
+        }
+
+        let report = optimize::peephole(&program);
+        let shrunk_by = self.code.len() as i32 - report.instructions.len() as i32 * 4;
+        let moved = |address: i32| match report.moved.get(address as usize / 4) {
+            Some(&index) => index as i32 * 4,
+            None => address - shrunk_by,
+        };
+
+        self.code = report.instructions.iter().flat_map(|instruction| instruction.encode().to_le_bytes()).collect();
+        for address in self.labels.values_mut() {
+            *address = moved(*address);
+        }
+        self.entry = moved(self.entry);
+
+        let mut lines = vec![(0, 0); report.instructions.len()];
+        for (old, &new) in report.moved.iter().enumerate().rev() {
+            if let (Some(line), Some(&old_line)) = (lines.get_mut(new), self.lines.get(old)) {
+                *line = old_line;
+            }
+        }
+        self.lines = lines;
+
+        Ok(report)
+    }
+
+    /* Paste functions of at most max_instructions instructions in place of the calls to them
+     * (see optimize::inline_small_functions), then leave out the functions nothing calls any
+     * more, keeping the labels and lines in step. Labels in what's left out go with it. Code
+     * that isn't all instructions and jumptable entries, or that has a jumpi whose target can't
+     * be seen until it runs, is left alone, as with peephole. */
+    pub fn inline(&mut self, max_instructions: usize) -> Result<Vec<InlinedCall>, String> {
+        /* A jumptable's entries are offsets rather than instructions. */
+        let mut entries = 0;
+        for (address, word) in self.code.chunks(4).enumerate() {
+            let word = <[u8; 4]>::try_from(word).map(u32::from_le_bytes).ok();
+            match word.and_then(Instruction::decode) {
+                _ if entries > 0 => entries -= 1,
+                Some(Instruction::JumpI) => {
+                    return Err(format!("the jump at {:#06x} only finds out where it goes as it runs, so nothing was inlined", address * 4));
+                },
+                Some(Instruction::JumpTable(count)) => entries = count,
+                Some(_) => (),
+                None => return Err(format!("the word at {:#06x} isn't an instruction, so nothing was inlined", address * 4)),
+            }
+        }
+
+        /* Each call inlined grows the code by all but the one word it took. */
+        let (code, report) = optimize::inline_small_functions(&self.code, max_instructions);
+        let inlined = |address: i32| {
+            address + report.iter()
+                .filter(|call| call.call_site < address)
+                .map(|call| (call.instructions as i32 - 1) * 4)
+                .sum::<i32>()
+        };
+        let mut lines = Vec::new();
+        for (i, &line) in self.lines.iter().enumerate() {
+            let copies = report.iter().find(|call| call.call_site == i as i32 * 4).map_or(1, |call| call.instructions);
+            lines.extend(core::iter::repeat_n(line, copies));
+        }
+
+        /* And each piece left out shrinks it by its size. */
+        let (code, removed) = optimize::remove_unreachable_functions(&code, &[0, inlined(self.entry)]);
+        let is_removed = |address: i32| removed.iter().any(|piece| (piece.start..piece.end).contains(&address));
+        let kept = |address: i32| {
+            address - removed.iter()
+                .filter(|piece| piece.end <= address)
+                .map(|piece| piece.end - piece.start)
+                .sum::<i32>()
+        };
+
+        self.code = code;
+        self.labels = core::mem::take(&mut self.labels).into_iter()
+            .map(|(name, address)| (name, inlined(address)))
+            .filter(|&(_, address)| !is_removed(address))
+            .map(|(name, address)| (name, kept(address)))
+            .collect();
+        self.entry = kept(inlined(self.entry));
+        self.lines = lines.into_iter().enumerate()
+            .filter(|&(i, _)| !is_removed(i as i32 * 4))
+            .map(|(_, line)| line)
+            .collect();
+
+        Ok(report)
+    }
+
+    /* Turn self-recursive calls in tail position into tailcalls (see
+     * optimize::rewrite_tail_calls). Nothing moves, so the labels and lines stay as they are. */
+    pub fn tail_calls(&mut self) -> Vec<TailCallCandidate> {
+        let (code, rewritten) = optimize::rewrite_tail_calls(&self.code);
+        self.code = code;
+        rewritten
+    }
+
+    /* The label an address falls under, and how far past it the address is. */
+    pub fn symbolize(&self, address: i32) -> Option<(&str, i32)> {
+        debug_info::symbolize(&self.labels, address)
+    }
+}
+
+/* One line of source, picked apart. */
+pub(crate) struct Line<'a> {
+    pub(crate) number: usize,
+    pub(crate) mnemonic: &'a str,
+    pub(crate) operands: Vec<&'a str>,
+}
+
+pub(crate) fn error(line: usize, message: String) -> AsmError {
+    AsmError { line, file: None, message }
+}
+
+/* Cut a comment off the end of a line, minding # inside a string. */
+pub(crate) fn strip_comment(text: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for (i, c) in text.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &text[..i],
+            _ => (),
+        }
+    }
+
+    text
+}
+
+/* A number with an optional sign, in decimal or with a 0x, 0b or 0o prefix. The input
+ * instruction reads numbers this way too. */
+pub fn parse_number(text: &str) -> Option<i64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, text.strip_prefix('+').unwrap_or(text)),
+    };
+
+    let (radix, digits) = match digits.get(..2) {
+        Some("0x" | "0X") => (16, &digits[2..]),
+        Some("0b" | "0B") => (2, &digits[2..]),
+        Some("0o" | "0O") => (8, &digits[2..]),
+        _ => (10, digits),
+    };
+
+    /* from_str_radix would take a second sign. */
+    if digits.starts_with(['+', '-']) {
+        return None;
+    }
+
+    let magnitude = u64::from_str_radix(digits, radix).ok()?;
+    if negative {
+        0i64.checked_sub_unsigned(magnitude)
+    } else {
+        i64::try_from(magnitude).ok()
+    }
+}
+
+/* The text of a "string" operand, escapes and all. */
+pub(crate) fn parse_string(text: &str, line: usize) -> Result<String, AsmError> {
+    let inner = text.strip_prefix('"').and_then(|t| t.strip_suffix('"'))
+        .ok_or_else(|| error(line, String::from("expected a quoted string")))?;
+
+    let mut string = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            string.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('n') => string.push('\n'),
+            Some('t') => string.push('\t'),
+            Some('"') => string.push('"'),
+            Some('\\') => string.push('\\'),
+            other => return Err(error(line, format!("unknown escape \\{}", other.unwrap_or(' ')))),
+        }
+    }
+
+    Ok(string)
+}
+
+/* The words stpush puts on the stack, in the order they're pushed: the last chunk goes first so
+ * the first chunk ends up on top. */
+pub(crate) fn packed_string(text: &str) -> Vec<u32> {
+    let mut words = strings::pack(text.as_bytes());
+    words.reverse();
+    words
+}
+
+/* The instructions stpush assembles to, for a program with the given header features. */
+pub(crate) fn string_pushes(text: &str, features: u32) -> Vec<Instruction> {
+    if features & Header::BYTE_STRINGS == 0 {
+        return packed_string(text).into_iter().map(|word| Instruction::Push(word as i32)).collect();
+    }
+
+    let bits: u32 = if features & Header::WORDS_64 != 0 { 64 } else { 32 };
+    let mut pushes = Vec::new();
+    for word in strings::prefixed(text.as_bytes(), bits as usize / 8, Header::new(features).endianness()).into_iter().rev() {
+        push_word(&mut pushes, word, bits);
+    }
+    pushes
+}
+
+/* Push a word of the given width, a byte at a time from the top if it won't go in one push. */
+fn push_word(pushes: &mut Vec<Instruction>, word: u64, bits: u32) {
+    let signed = ((word << (64 - bits)) as i64) >> (64 - bits);
+    if (-(1 << 27)..1 << 27).contains(&signed) {
+        pushes.push(Instruction::Push(signed as i32));
+        return;
+    }
+
+    push_word(pushes, word >> 8, bits);
+    pushes.extend([Instruction::Push(8), Instruction::Binary(BinaryOp::Lsl),
+        Instruction::Push((word & 0xff) as i32), Instruction::Binary(BinaryOp::Or)]);
+}
+
+/* The header feature a .feature line names. */
+fn feature(line: &Line) -> Result<u32, AsmError> {
+    match line.operands.as_slice() {
+        [name] => Header::FEATURE_NAMES.iter()
+            .find(|(feature, _)| feature == name)
+            .map(|&(_, bit)| bit)
+            .ok_or_else(|| error(line.number, format!("unknown feature {}", name))),
+        _ => Err(error(line.number, String::from(".feature needs one feature name"))),
+    }
+}
+
+/* How many words a line assembles to. */
+fn size_of(line: &Line, features: u32) -> Result<usize, AsmError> {
+    if line.mnemonic == "stpush" {
+        let text = parse_string(line.operands.first().copied().unwrap_or(""), line.number)?;
+        return Ok(string_pushes(&text, features).len());
+    }
+    if line.mnemonic == ".table" {
+        return Ok(line.operands.len());
+    }
+    if line.mnemonic == "dup2" {
+        return Ok(2);
+    }
+
+    Ok(1)
+}
+
+/* How many bytes a line in the data region puts there, which doesn't hang on any label. */
+fn data_length(line: &Line, features: u32) -> Result<usize, AsmError> {
+    match line.mnemonic {
+        ".word" => Ok(if features & Header::WORDS_64 != 0 { 8 } else { 4 }),
+        ".byte" => Ok(line.operands.len()),
+        ".string" => Ok(parse_string(line.operands.first().copied().unwrap_or(""), line.number)?.len() + 1),
+        other => Err(error(line.number, format!("{} can't go in the data region", other))),
+    }
+}
+
+/* How deep constants can be defined in terms of each other before it's taken to be one defined
+ * in terms of itself. */
+const MAX_NESTING: usize = 64;
+
+/* The names an operand's expression can use: the labels, as addresses, and the constants from
+ * .equ, which are only worked out once every label is known. */
+struct Names<'a> {
+    labels: &'a BTreeMap<String, i32>,
+    constants: &'a BTreeMap<String, Expr>,
+    nesting: Cell<usize>,
+    /* Whether a label came into the value, making it an address rather than a plain number. */
+    named_label: Cell<bool>,
+}
+
+impl State for Names<'_> {
+    fn variable(&self, name: &str) -> Result<i64, String> {
+        if let Some(&address) = self.labels.get(name) {
+            self.named_label.set(true);
+            return Ok(address as i64);
+        }
+        let Some(value) = self.constants.get(name) else {
+            return Err(format!("unknown label or constant {}", name));
+        };
+        if self.nesting.get() == MAX_NESTING {
+            return Err(format!("{} is defined in terms of itself", name));
+        }
+
+        self.nesting.set(self.nesting.get() + 1);
+        let value = value.evaluate(self);
+        self.nesting.set(self.nesting.get() - 1);
+        value
+    }
+
+    fn word(&self, _: i64) -> Result<i64, String> {
+        Err(String::from("there's no memory to read while assembling"))
+    }
+
+    fn byte(&self, _: i64) -> Result<i64, String> {
+        Err(String::from("there's no memory to read while assembling"))
+    }
+
+    fn slot(&self, _: i64) -> Result<i64, String> {
+        Err(String::from("there's no stack to read while assembling"))
+    }
+}
+
+struct Encoder<'a> {
+    labels: &'a BTreeMap<String, i32>,
+    constants: &'a BTreeMap<String, Expr>,
+    /* Whether branches can go to labels from other files, for an object file. */
+    relocatable: bool,
+    features: u32,
+}
+
+impl Encoder<'_> {
+    /* The label a branch goes to, if it isn't in this file and the linker can fill it in. */
+    fn external<'l>(&self, line: &Line<'l>) -> Option<&'l str> {
+        let is_branch = matches!(line.mnemonic, "call" | "tailcall" | "goto" | "spawn" | "lea" | "assert") || line.mnemonic.starts_with("if");
+        let &target = line.operands.first()?;
+
+        let is_name = target.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.');
+
+        (self.relocatable && is_branch && is_name && parse_number(target).is_none() && !target.starts_with('.')
+            && !self.labels.contains_key(target) && !self.constants.contains_key(target))
+            .then_some(target)
+    }
+
+    /* What an operand's number or expression comes to, and whether a label came into it. */
+    fn evaluate(&self, number: usize, text: &str) -> Result<(i64, bool), AsmError> {
+        if let Some(value) = parse_number(text) {
+            return Ok((value, false));
+        }
+
+        let names = Names { labels: self.labels, constants: self.constants, nesting: Cell::new(0), named_label: Cell::new(false) };
+        let expr = Expr::parse(text).map_err(|err| error(number, format!("bad operand {}: {}", text, err)))?;
+        let value = expr.evaluate(&names).map_err(|err| match expr {
+            Expr::Variable(_) => error(number, err),
+            _ => error(number, format!("{}: {}", text, err)),
+        })?;
+        Ok((value, names.named_label.get()))
+    }
+
+    fn operand(&self, line: &Line, index: usize, default: Option<i64>) -> Result<i64, AsmError> {
+        match line.operands.get(index) {
+            Some(text) => self.evaluate(line.number, text).map(|(value, _)| value),
+            None => default.ok_or_else(|| error(line.number, format!("{} needs an operand", line.mnemonic))),
+        }
+    }
+
+    /* A number that has to fit in bits bits, signed or not. */
+    fn ranged(&self, line: &Line, value: i64, bits: u32, signed: bool) -> Result<i64, AsmError> {
+        let (min, max) = if signed {
+            (-(1i64 << (bits - 1)), (1i64 << (bits - 1)) - 1)
+        } else {
+            (0, (1i64 << bits) - 1)
+        };
+
+        if !(min..=max).contains(&value) {
+            return Err(error(line.number, format!("{} doesn't fit in {} ({}..{})", value, line.mnemonic, min, max)));
+        }
+
+        Ok(value)
+    }
+
+    fn multiple_of_four(&self, line: &Line, value: i64) -> Result<i64, AsmError> {
+        if value % 4 != 0 {
+            return Err(error(line.number, format!("{} needs a multiple of 4, not {}", line.mnemonic, value)));
+        }
+
+        Ok(value)
+    }
+
+    /* A branch target, as a byte offset from the instruction at address. */
+    fn target(&self, line: &Line, address: i32) -> Result<i64, AsmError> {
+        let Some(&text) = line.operands.first() else {
+            return Err(error(line.number, format!("{} needs a target", line.mnemonic)));
+        };
+
+        /* Left for the linker. */
+        if self.external(line).is_some() {
+            return Ok(0);
+        }
+
+        self.offset_to(line, text, address)
+    }
+
+    /* A branch target as a byte offset that goes in the field as it is, or an error saying how
+     * far away it is and how many bits that would take. */
+    fn branch(&self, line: &Line, address: i32, field: OffsetField) -> Result<i32, AsmError> {
+        let offset = self.target(line, address)?;
+        field.check(offset).map_err(|why| error(line.number, format!("{} {}: {}", line.mnemonic, line.operands[0], why)))?;
+        Ok(offset as i32)
+    }
+
+    /* The byte offset from address to a target: anything with a label in it is an address, and
+     * anything without, a number say, is already an offset. */
+    fn offset_to(&self, line: &Line, text: &str, address: i32) -> Result<i64, AsmError> {
+        match self.evaluate(line.number, text)? {
+            (value, true) => Ok(value - address as i64),
+            (offset, false) => Ok(offset),
+        }
+    }
+
+    /* The bytes a line in the data region puts there. */
+    fn data(&self, line: &Line) -> Result<Vec<u8>, AsmError> {
+        match line.mnemonic {
+            ".word" => {
+                let [text] = line.operands.as_slice() else {
+                    return Err(error(line.number, String::from(".word needs one value")));
+                };
+                let (value, _) = self.evaluate(line.number, text)?;
+                let size = data_length(line, self.features)?;
+                if size == 4 && !(i32::MIN as i64..=u32::MAX as i64).contains(&value) {
+                    return Err(error(line.number, format!("{} doesn't fit in a word", value)));
+                }
+                let mut bytes = vec![0; size];
+                Header::new(self.features).endianness().write(value as u64, &mut bytes);
+                Ok(bytes)
+            },
+            ".byte" => {
+                if line.operands.is_empty() {
+                    return Err(error(line.number, String::from(".byte needs at least one value")));
+                }
+                line.operands.iter()
+                    .map(|text| match self.evaluate(line.number, text)? {
+                        (value @ -128..=255, _) => Ok(value as u8),
+                        (value, _) => Err(error(line.number, format!("{} doesn't fit in a byte", value))),
+                    })
+                    .collect()
+            },
+            ".string" => {
+                let mut bytes = parse_string(line.operands.first().copied().unwrap_or(""), line.number)?.into_bytes();
+                bytes.push(0);
+                Ok(bytes)
+            },
+            other => Err(error(line.number, format!("{} can't go in the data region", other))),
+        }
+    }
+
+    fn encode(&self, line: &Line, address: i32) -> Result<Vec<u32>, AsmError> {
+        let expected_operands = match line.mnemonic {
+            "swap" | "input" | "printf" => 2,
+            ".table" => usize::MAX,
+            _ => 1,
+        };
+        if line.operands.len() > expected_operands {
+            return Err(error(line.number, format!("too many operands for {}", line.mnemonic)));
+        }
+
+        let mnemonic = line.mnemonic;
+        let instruction = match mnemonic {
+            "exit" => {
+                let code = self.operand(line, 0, Some(0))?;
+                Instruction::Exit(self.ranged(line, code, 24, false)? as u32)
+            },
+            "swap" => {
+                let from = self.multiple_of_four(line, self.operand(line, 0, Some(4))?)? / 4;
+                let to = self.multiple_of_four(line, self.operand(line, 1, Some(0))?)? / 4;
+                Instruction::Swap {
+                    from: self.ranged(line, from, 12, true)? as i32,
+                    to: self.ranged(line, to, 12, true)? as i32,
+                }
+            },
+            "halt" => Instruction::Exit(0),
+            "nop" => Instruction::Nop,
+            "input" => {
+                let mut eof = EofMode::Fault;
+                let mut retry = false;
+                for &operand in &line.operands {
+                    match operand {
+                        "sentinel" if eof == EofMode::Fault => eof = EofMode::Sentinel,
+                        "flag" if eof == EofMode::Fault => eof = EofMode::Flag,
+                        "retry" if !retry => retry = true,
+                        _ => return Err(error(line.number, format!("input takes sentinel or flag, and retry, not {}", operand))),
+                    }
+                }
+                Instruction::Input { eof, retry }
+            },
+            "stinput" => {
+                let max = self.operand(line, 0, Some(0xFF_FFFF))?;
+                Instruction::StInput(self.ranged(line, max, 24, false)? as u32)
+            },
+            "debug" | "debugb" => {
+                let bytes = self.operand(line, 0, Some(0))?;
+                Instruction::Debug { bytes: self.ranged(line, bytes, 23, false)? as u32, binary: line.mnemonic == "debugb" }
+            },
+            "pop" => {
+                let bytes = self.multiple_of_four(line, self.operand(line, 0, Some(4))?)?;
+                Instruction::Pop(self.ranged(line, bytes, 28, false)? as u32)
+            },
+            "stprint" => {
+                let offset = self.operand(line, 0, Some(0))?;
+                Instruction::StPrint(self.ranged(line, offset, 28, true)? as i32)
+            },
+            "call" | "tailcall" | "goto" => {
+                let offset = self.branch(line, address, OffsetField::JUMP)?;
+                match mnemonic {
+                    "call" => Instruction::Call(offset),
+                    "tailcall" => Instruction::TailCall(offset),
+                    _ => Instruction::Goto(offset),
+                }
+            },
+            "return" => {
+                let bytes = self.multiple_of_four(line, self.operand(line, 0, Some(0))?)?;
+                Instruction::Return(self.ranged(line, bytes, 28, false)? as u32)
+            },
+            ">r" => Instruction::ToReturnStack,
+            "r>" => Instruction::FromReturnStack,
+            "strlen" => {
+                let offset = self.operand(line, 0, Some(0))?;
+                Instruction::StrLen(self.ranged(line, offset, 20, true)? as i32)
+            },
+            "strcat" => Instruction::StrCat,
+            "strcmp" => Instruction::StrCmp,
+            "readfile" => Instruction::ReadFile,
+            "arg" => Instruction::Arg,
+            "getenv" => Instruction::GetEnv,
+            "clock" => Instruction::Clock,
+            "cycles" => Instruction::Cycles,
+            "rand" => Instruction::Rand,
+            "load" => Instruction::Load,
+            "store" => Instruction::Store,
+            "spawn" => Instruction::Spawn(self.branch(line, address, OffsetField::SPAWN)?),
+            "yield" => Instruction::Yield,
+            "join" => Instruction::Join,
+            "cas" => Instruction::Cas,
+            "fetchadd" => Instruction::FetchAdd,
+            "lock" => Instruction::Lock,
+            "unlock" => Instruction::Unlock,
+            "stprintn" => Instruction::StPrintN,
+            "pushb" => Instruction::PushByte,
+            "pushh" => Instruction::PushHalf,
+            "popb" | "popbu" => Instruction::PopByte { signed: line.mnemonic == "popb" },
+            "poph" | "pophu" => Instruction::PopHalf { signed: line.mnemonic == "poph" },
+            "jumpi" => Instruction::JumpI,
+            "printf" => Instruction::PrintF(self.print_spec(line)?),
+            "atoi" => Instruction::Atoi,
+            "itoa" => Instruction::Itoa,
+            "brk" => Instruction::Brk,
+            "stackdepth" => Instruction::StackDepth,
+            "perfctr" => {
+                let name = line.operands.first().copied().unwrap_or_default();
+                let counter = match PerfCounter::ALL.into_iter().find(|counter| counter.name() == name) {
+                    Some(counter) => Some(counter),
+                    None => PerfCounter::ALL.get(self.operand(line, 0, None)? as usize).copied(),
+                };
+                let names: Vec<&str> = PerfCounter::ALL.iter().map(|counter| counter.name()).collect();
+                Instruction::PerfCtr(counter.ok_or_else(|| {
+                    error(line.number, format!("perfctr takes {} or their numbers, not {}", names.join(", "), name))
+                })?)
+            },
+            "assert" => {
+                let offset = if line.operands.is_empty() { 0 } else { self.branch(line, address, OffsetField::ADDRESS)? };
+                Instruction::Assert(offset)
+            },
+            "lea" => Instruction::Lea(self.branch(line, address, OffsetField::ADDRESS)?),
+            "pick" | "roll" | "drop" => {
+                let default = if mnemonic == "drop" { Some(1) } else { None };
+                let count = self.ranged(line, self.operand(line, 0, default)?, 20, false)? as u32;
+                match mnemonic {
+                    "pick" => Instruction::Pick(count),
+                    "roll" => Instruction::Roll(count),
+                    _ => Instruction::Drop(count),
+                }
+            },
+            "dup2" => {
+                if !line.operands.is_empty() {
+                    return Err(error(line.number, String::from("too many operands for dup2")));
+                }
+                return Ok(Vec::from([Instruction::Pick(1).encode(); 2]));
+            },
+            "jumptable" => {
+                let entries = self.operand(line, 0, None)?;
+                Instruction::JumpTable(self.ranged(line, entries, 20, false)? as u32)
+            },
+            "writefile" => {
+                let bytes = self.operand(line, 0, Some(0))?;
+                Instruction::WriteFile(self.ranged(line, bytes, 20, false)? as u32)
+            },
+            "dup" => {
+                let offset = self.operand(line, 0, Some(0))?;
+                Instruction::Dup(self.ranged(line, offset, 28, true)? as i32)
+            },
+            "dump" => Instruction::Dump,
+            "push" => {
+                let value = self.operand(line, 0, None)?;
+                Instruction::Push(self.ranged(line, value, 28, true)? as i32)
+            },
+            ".word" => {
+                let value = self.operand(line, 0, None)?;
+                if !(i32::MIN as i64..=u32::MAX as i64).contains(&value) {
+                    return Err(error(line.number, format!("{} doesn't fit in a word", value)));
+                }
+                return Ok(Vec::from([value as u32]));
+            },
+            ".table" => {
+                if line.operands.is_empty() {
+                    return Err(error(line.number, String::from(".table needs at least one target")));
+                }
+
+                let mut words = Vec::new();
+                for (i, &text) in line.operands.iter().enumerate() {
+                    let offset = self.offset_to(line, text, address + i as i32 * 4)?;
+                    words.push(self.ranged(line, self.multiple_of_four(line, offset)?, 32, true)? as u32);
+                }
+                return Ok(words);
+            },
+            "stpush" => {
+                let text = parse_string(line.operands.first().copied().unwrap_or(""), line.number)?;
+                return Ok(string_pushes(&text, self.features).iter().map(Instruction::encode).collect());
+            },
+            _ => self.family(line, address)?,
+        };
+
+        Ok(Vec::from([instruction.encode()]))
+    }
+
+    /* printf's operands: a spec like %08x, and nonl to leave off the newline. */
+    fn print_spec(&self, line: &Line) -> Result<PrintSpec, AsmError> {
+        let bad = || error(line.number, format!("printf takes a spec like %d or %08x, not {}", line.operands.join(" ")));
+        let newline = match line.operands.get(1) {
+            None => true,
+            Some(&"nonl") => false,
+            Some(_) => return Err(bad()),
+        };
+
+        let spec = line.operands.first().and_then(|spec| spec.strip_prefix('%')).ok_or_else(bad)?;
+        let (flags, conversion) = spec.split_at(spec.len().saturating_sub(1));
+        let (zero_pad, width) = match flags.strip_prefix('0') {
+            Some(width) => (true, width),
+            None => (false, flags),
+        };
+        let width = match width {
+            "" => 0,
+            digits if digits.bytes().all(|b| b.is_ascii_digit()) => digits.parse::<u8>().map_err(|_| bad())?,
+            _ => return Err(bad()),
+        };
+        let (format, unsigned) = match conversion {
+            "d" => (PrintFormat::Decimal, false),
+            "u" => (PrintFormat::Decimal, true),
+            "x" => (PrintFormat::Hex, false),
+            "b" => (PrintFormat::Binary, false),
+            "o" => (PrintFormat::Octal, false),
+            _ => return Err(bad()),
+        };
+
+        Ok(PrintSpec { format, unsigned, width, zero_pad, newline })
+    }
+
+    /* The instructions whose mnemonics are built from a table: the arithmetic, cmp, the ifs and
+     * the print formats. */
+    fn family(&self, line: &Line, address: i32) -> Result<Instruction, AsmError> {
+        let mnemonic = line.mnemonic;
+
+        if let Some(op) = BinaryOp::ALL.iter().find(|op| op.mnemonic() == mnemonic) {
+            return Ok(Instruction::Binary(*op));
+        }
+        if let Some(op) = UnaryOp::ALL.iter().find(|op| op.mnemonic() == mnemonic) {
+            return Ok(Instruction::Unary(*op));
+        }
+        if let Some(condition) = mnemonic.strip_prefix("cmp").and_then(Condition::from_suffix) {
+            return Ok(Instruction::Cmp(condition));
+        }
+        if let Some(format) = mnemonic.strip_prefix("print").and_then(PrintFormat::from_suffix) {
+            let offset = self.multiple_of_four(line, self.operand(line, 0, Some(0))?)?;
+            return Ok(Instruction::Print(self.ranged(line, offset, 26, true)? as i32, format));
+        }
+
+        let Some(suffix) = mnemonic.strip_prefix("if") else {
+            return Err(error(line.number, format!("unknown instruction {}", mnemonic)));
+        };
+
+        if let Some(condition) = ZeroCondition::ALL.iter().find(|c| c.suffix() == suffix) {
+            return Ok(Instruction::UnaryIf(*condition, self.branch(line, address, OffsetField::IF)?));
+        }
+
+        match Condition::from_suffix(suffix) {
+            Some(condition) => Ok(Instruction::BinaryIf(condition, self.branch(line, address, OffsetField::IF)?)),
+            None => Err(error(line.number, format!("unknown instruction {}", mnemonic))),
+        }
+    }
+}
+
+/* Split a line into its label (if any) and the instruction after it. */
+pub(crate) fn parse_line(number: usize, text: &str) -> Result<(Option<&str>, Option<Line<'_>>), AsmError> {
+    let mut text = strip_comment(text).trim();
+    let mut label = None;
+
+    if let Some((name, rest)) = text.split_once(':') {
+        let name = name.trim();
+        if !name.is_empty() && !name.contains(char::is_whitespace) && !name.contains('"') {
+            if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.') {
+                return Err(error(number, format!("bad label name {}", name)));
+            }
+            label = Some(name);
+            text = rest.trim();
+        }
+    }
+
+    if text.is_empty() {
+        return Ok((label, None));
+    }
+
+    let (mnemonic, rest) = match text.split_once(char::is_whitespace) {
+        Some((mnemonic, rest)) => (mnemonic, rest.trim()),
+        None => (text, ""),
+    };
+
+    /* A string operand is kept whole; anything else splits on spaces and commas. */
+    let operands = if rest.starts_with('"') {
+        Vec::from([rest])
+    } else {
+        rest.split(|c: char| c.is_whitespace() || c == ',').filter(|s| !s.is_empty()).collect()
+    };
+
+    Ok((label, Some(Line { number, mnemonic, operands })))
+}
+
+/* Assemble a single line on its own, for isa::assemble_line. There are no labels, so targets
+ * have to be offsets. */
+pub(crate) fn assemble_one(text: &str) -> Result<Vec<u32>, AsmError> {
+    let expanded = preprocess::expand(text, "", &mut NoIncludes, &BTreeMap::new())?;
+    let [(_, text)] = expanded.lines.as_slice() else {
+        return Err(error(1, String::from("expected one line")));
+    };
+    match parse_line(1, text)? {
+        (Some(label), _) => Err(error(1, format!("{}: a single line can't define a label", label))),
+        (None, None) => Err(error(1, String::from("no instruction"))),
+        (None, Some(line)) => {
+            Encoder { labels: &BTreeMap::new(), constants: &BTreeMap::new(), relocatable: false, features: 0 }.encode(&line, 0)
+        },
+    }
+}
+
+/* Assemble a whole program. */
+pub fn assemble(source: &str) -> Result<Assembled, AsmError> {
+    assemble_with(source, "", &mut NoIncludes, &BTreeMap::new())
+}
+
+/* Assemble a whole program that can include files, with name saying where it is so the files it
+ * includes can be found beside it, and defines giving the names its .ifs can use. */
+pub fn assemble_with(source: &str, name: &str, includes: &mut dyn Includes, defines: &BTreeMap<String, i64>)
+    -> Result<Assembled, AsmError> {
+    assemble_source(source, name, includes, defines, false).map(|(assembled, _)| assembled)
+}
+
+/* Assemble a file into an object file for the linker. Branches to labels the file doesn't
+ * define become relocations, and every label not starting with a dot is exported. */
+pub fn assemble_object(source: &str) -> Result<Object, AsmError> {
+    assemble_object_with(source, "", &mut NoIncludes, &BTreeMap::new())
+}
+
+/* An object file from a file that can include others, as assemble_with. */
+pub fn assemble_object_with(source: &str, name: &str, includes: &mut dyn Includes, defines: &BTreeMap<String, i64>)
+    -> Result<Object, AsmError> {
+    let (assembled, relocations) = assemble_source(source, name, includes, defines, true)?;
+
+    Ok(Object {
+        code: assembled.code,
+        symbols: assembled.labels.into_iter().filter(|(name, _)| !name.starts_with('.')).collect(),
+        relocations,
+        features: assembled.features,
+    })
+}
+
+fn assemble_source(source: &str, name: &str, includes: &mut dyn Includes, defines: &BTreeMap<String, i64>, relocatable: bool)
+    -> Result<(Assembled, Vec<Relocation>), AsmError> {
+    let expanded = preprocess::expand(source, name, includes, defines)?;
+    assemble_expanded(&expanded, relocatable).map_err(|err| expanded.trace(err))
+}
+
+fn assemble_expanded(expanded: &preprocess::Expanded, relocatable: bool) -> Result<(Assembled, Vec<Relocation>), AsmError> {
+    let parsed = expanded.lines.iter()
+        .map(|(number, text)| parse_line(*number, text).map(|(label, line)| (*number, label, line)))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    /* The features come first, since they can change how big stpush is. */
+    let mut features = 0;
+    for line in parsed.iter().filter_map(|(_, _, line)| line.as_ref()) {
+        if line.mnemonic == ".feature" {
+            features |= feature(line)?;
+        }
+    }
+
+    /* First pass: find out where every label lands. */
+    let mut labels = BTreeMap::new();
+    /* The line each label is on. */
+    let mut defined = BTreeMap::new();
+    /* The constants from .equ, and the lines they're on. */
+    let (mut constants, mut equs) = (BTreeMap::new(), Vec::new());
+    let mut lines = Vec::new();
+    let mut address = 0i32;
+    let (mut entry, mut stack_pointer, mut heap) = (None, None, None);
+    /* The data region's lines and labels, with the labels as offsets into it until the code
+     * is all counted. */
+    let (mut data_lines, mut data_labels, mut data_size) = (Vec::new(), Vec::new(), 0i32);
+    let mut in_data = false;
+
+    for (number, label, line) in parsed {
+        if let Some(label) = label {
+            let address = if in_data { data_size } else { address };
+            if labels.insert(String::from(label), address).is_some() {
+                return Err(error(number, format!("{} is already defined, on {}", label, expanded.describe(defined[label]))));
+            }
+            defined.insert(label, number);
+            if in_data {
+                data_labels.push(label);
+            }
+        }
+
+        match line {
+            Some(line) if line.mnemonic == ".feature" => (),
+            Some(line) if line.mnemonic == ".equ" => {
+                let Some((&name, value)) = line.operands.split_first().filter(|(_, value)| !value.is_empty()) else {
+                    return Err(error(number, String::from(".equ needs a name and a value")));
+                };
+                if name.starts_with(|c: char| c.is_ascii_digit()) || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.') {
+                    return Err(error(number, format!("bad constant name {}", name)));
+                }
+                let value = Expr::parse(&value.join(" ")).map_err(|err| error(number, format!("bad value for {}: {}", name, err)))?;
+                if constants.insert(String::from(name), value).is_some() {
+                    return Err(error(number, format!("{} is already defined, on {}", name, expanded.describe(defined[name]))));
+                }
+                defined.insert(name, number);
+                equs.push((name, number));
+            },
+            Some(line) if matches!(line.mnemonic, ".entry" | ".sp" | ".heap") => {
+                if relocatable {
+                    return Err(error(line.number, format!("{} only goes in a program, not an object file", line.mnemonic)));
+                }
+                if line.operands.len() != 1 {
+                    return Err(error(line.number, format!("{} needs one operand", line.mnemonic)));
+                }
+                let start = match line.mnemonic {
+                    ".entry" => &mut entry,
+                    ".sp" => &mut stack_pointer,
+                    _ => &mut heap,
+                };
+                if let Some(earlier) = start.replace(line) {
+                    return Err(error(number, format!("{} was already given on {}", earlier.mnemonic, expanded.describe(earlier.number))));
+                }
+            },
+            Some(line) if line.mnemonic == ".data" => {
+                if relocatable {
+                    return Err(error(line.number, String::from(".data only goes in a program, not an object file")));
+                }
+                if in_data || !line.operands.is_empty() {
+                    return Err(error(line.number, String::from(".data comes once, on a line of its own")));
+                }
+                in_data = true;
+            },
+            Some(line) if in_data => {
+                data_size += data_length(&line, features)? as i32;
+                data_lines.push(line);
+            },
+            Some(line) => {
+                address += size_of(&line, features)? as i32 * 4;
+                lines.push(line);
+            },
+            None => (),
+        }
+    }
+
+    for label in data_labels {
+        if let Some(at) = labels.get_mut(label) {
+            *at += address;
+        }
+    }
+
+    /* Second pass: encode, now that every target is known. Every constant is worked out first,
+     * used or not, so a bad one is reported on its own line. */
+    let encoder = Encoder { labels: &labels, constants: &constants, relocatable, features };
+    for &(name, number) in &equs {
+        if labels.contains_key(name) {
+            return Err(error(number, format!("{} is already defined as a label", name)));
+        }
+        encoder.evaluate(number, name)?;
+    }
+    let mut assembled = Assembled { labels: labels.clone(), files: expanded.files(), features, ..Assembled::default() };
+    if let Some(line) = &entry {
+        let target = encoder.multiple_of_four(line, encoder.target(line, 0)?)?;
+        if target < 0 || target + 4 > address as i64 {
+            return Err(error(line.number, format!("the entry point {:#x} isn't an instruction in the code", target)));
+        }
+        assembled.entry = target as i32;
+    }
+    if let Some(line) = &stack_pointer {
+        let value = encoder.multiple_of_four(line, encoder.operand(line, 0, None)?)?;
+        if !(0..=crate::MEMORY_SIZE as i64).contains(&value) {
+            return Err(error(line.number, format!("the stack pointer {:#x} is outside memory", value)));
+        }
+        assembled.stack_pointer = Some(value as i32);
+    }
+    if let Some(line) = &heap {
+        let size = encoder.operand(line, 0, None)?;
+        if !(0..=crate::MEMORY_SIZE as i64).contains(&size) {
+            return Err(error(line.number, format!("a heap of {} bytes doesn't fit in memory", size)));
+        }
+        assembled.heap_size = Some(size as u32);
+        assembled.features |= Header::HEAP;
+    }
+    let mut relocations = Vec::new();
+
+    for line in &lines {
+        let address = assembled.code.len() as i32;
+        if let Some(symbol) = encoder.external(line) {
+            relocations.push(Relocation { address, symbol: String::from(symbol) });
+        }
+
+        for word in encoder.encode(line, address)? {
+            assembled.code.extend_from_slice(&word.to_le_bytes());
+            assembled.lines.push(expanded.position(line.number));
+        }
+    }
+    for line in &data_lines {
+        assembled.data.extend(encoder.data(line)?);
+    }
+
+    if assembled.code.len() + assembled.data.len() + assembled.heap_size.unwrap_or(0) as usize > crate::MEMORY_SIZE {
+        let last = data_lines.last().or(lines.last());
+        return Err(error(last.map_or(0, |l| l.number), String::from("program doesn't fit in memory")));
+    }
+
+    Ok((assembled, relocations))
+}
@@ -0,0 +1,287 @@
+/* The control-flow graph of a program: its basic blocks and the ways control gets from one to
+ * another, worked out from the code alone. On top of that, the things in a program that are
+ * almost certainly mistakes, which vm analyze reports:
+ *
+ *     code nothing can reach, going from the entry point along every edge
+ *     calls to functions with no way back to a return
+ *     branches that go outside the code, or between two instructions
+ *
+ * A function is whatever starts at a call target. jumpi goes wherever the stack says, so the
+ * graph has no edges out of one; instead anything a lea takes the address of counts as
+ * reachable once the lea is, which is how a program gets an address to jump to. A jumpi might
+ * be a return in disguise, so a function with one is taken to return. */
+
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::isa::Instruction;
+use crate::program::{BasicBlock, Program};
+
+/* How control goes along an edge. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EdgeKind {
+    /* On to the next block: falling off the end of one, an if not taken, or back from a call. */
+    Next,
+    /* goto, a taken if, a tailcall or a jumptable entry. */
+    Jump,
+    Call,
+    /* To where a spawned context starts. */
+    Spawn,
+    /* To an address a lea takes, which a jumpi may go to later. */
+    Address,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Edge {
+    /* Indexes into Cfg::blocks. */
+    pub from: usize,
+    pub to: usize,
+    pub kind: EdgeKind,
+}
+
+/* Something vm analyze thinks is wrong with a program. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Problem {
+    /* The instructions from start up to end can't be reached. */
+    Unreachable { start: i32, end: i32 },
+    /* The function called has no path to a return. */
+    NoReturn { call_site: i32, target: i32 },
+    /* A branch to somewhere that isn't the start of an instruction in the code. */
+    BadTarget { from: i32, to: i32 },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Problem::Unreachable { start, end } => write!(f, "{:04x}..{:04x}: unreachable", start, end),
+            Problem::NoReturn { call_site, target } => {
+                write!(f, "{:04x}: call to {:04x}, which never returns", call_site, target)
+            },
+            Problem::BadTarget { from, to } => write!(f, "{:04x}: branch to {:#x}, which isn't an instruction in the code", from, to),
+        }
+    }
+}
+
+/* See the top of the module. */
+#[derive(Debug, Clone)]
+pub struct Cfg {
+    pub blocks: Vec<BasicBlock>,
+    pub edges: Vec<Edge>,
+    /* Whether each block can be reached from the entry point. */
+    pub reachable: Vec<bool>,
+    /* Every call as (call site, target), and whether the target can return. */
+    pub calls: Vec<(i32, i32, bool)>,
+    /* Branches as (from, to) whose target isn't an instruction boundary inside the code. */
+    pub bad_targets: Vec<(i32, i32)>,
+}
+
+/* Build the graph of a program. */
+pub fn build(program: &Program) -> Cfg {
+    let blocks = program.basic_blocks();
+    let block_at = |address: i32| -> Option<usize> {
+        let i = blocks.partition_point(|block| block.start <= address).checked_sub(1)?;
+        Some(i).filter(|&i| blocks[i].contains(address) && address % 4 == 0)
+    };
+    let starting_at = |address: i32| block_at(address).filter(|&i| blocks[i].start == address);
+
+    let bad_targets: Vec<(i32, i32)> = program.branch_targets().into_iter()
+        .filter(|&(_, to)| starting_at(to).is_none())
+        .collect();
+
+    /* Everything in each block that matters for the graph, found once. */
+    let mut last = vec![None; blocks.len()];
+    let mut taken = vec![Vec::new(); blocks.len()];
+    for (address, instruction) in program.instructions() {
+        let Some(i) = block_at(address) else {
+            continue;
+        };
+        last[i] = Some((address, instruction));
+        match instruction {
+            Instruction::Lea(offset) => taken[i].extend(starting_at(address.wrapping_add(offset)).map(|to| (to, EdgeKind::Address))),
+            Instruction::Spawn(offset) => taken[i].extend(starting_at(address.wrapping_add(offset)).map(|to| (to, EdgeKind::Spawn))),
+            _ => (),
+        }
+    }
+
+    let targets: BTreeMap<i32, Vec<i32>> = program.branch_targets().into_iter()
+        .fold(BTreeMap::new(), |mut targets, (from, to)| {
+            targets.entry(from).or_insert_with(Vec::new).push(to);
+            targets
+        });
+
+    /* The edges out of each block, leaving the way back from calls till it's known which
+     * functions return. */
+    let mut edges = Vec::new();
+    let mut calls = Vec::new();
+    for (i, block) in blocks.iter().enumerate() {
+        let next = starting_at(block.end);
+        let mut add = |to: Option<usize>, kind| {
+            if let Some(to) = to {
+                edges.push(Edge { from: i, to, kind });
+            }
+        };
+
+        let Some((address, instruction)) = last[i] else {
+            add(next, EdgeKind::Next);
+            continue;
+        };
+        let jumps = targets.get(&address).map_or(&[][..], Vec::as_slice);
+        match instruction {
+            Instruction::Exit(_) | Instruction::Return(_) | Instruction::JumpI => (),
+            Instruction::Goto(_) | Instruction::TailCall(_) => jumps.iter().for_each(|&to| add(starting_at(to), EdgeKind::Jump)),
+            Instruction::Call(_) => {
+                /* A call somewhere that isn't code is a bad target, not a function. */
+                for &to in jumps.iter().filter(|&&to| starting_at(to).is_some()) {
+                    add(starting_at(to), EdgeKind::Call);
+                    calls.push((address, to));
+                }
+            },
+            /* A jumptable goes past its table for a selector it has no entry for. */
+            Instruction::BinaryIf(..) | Instruction::UnaryIf(..) | Instruction::JumpTable(_) => {
+                jumps.iter().for_each(|&to| add(starting_at(to), EdgeKind::Jump));
+                add(next, EdgeKind::Next);
+            },
+            _ => add(next, EdgeKind::Next),
+        }
+        for &(to, kind) in &taken[i] {
+            add(Some(to), kind);
+        }
+    }
+
+    /* Which functions can get back to their caller, worked out over and over until nothing
+     * changes, since whether one does can hang on whether the ones it calls do. */
+    let mut returns: BTreeMap<i32, bool> = calls.iter().map(|&(_, target)| (target, false)).collect();
+    loop {
+        let mut changed = false;
+        for target in returns.keys().copied().collect::<Vec<_>>() {
+            let can_return = starting_at(target).is_some_and(|start| {
+                function_returns(start, &blocks, &edges, &last, &returns, &starting_at)
+            });
+            if can_return && !returns[&target] {
+                returns.insert(target, true);
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+
+    for &(call_site, target) in &calls {
+        if returns.get(&target) == Some(&true) {
+            let from = block_at(call_site).expect("a call is in a block");
+            if let Some(to) = starting_at(call_site + 4) {
+                edges.push(Edge { from, to, kind: EdgeKind::Next });
+            }
+        }
+    }
+
+    let mut reachable = vec![false; blocks.len()];
+    let mut work: Vec<usize> = starting_at(program.entry()).into_iter().collect();
+    while let Some(i) = work.pop() {
+        if reachable[i] {
+            continue;
+        }
+        reachable[i] = true;
+        work.extend(edges.iter().filter(|edge| edge.from == i).map(|edge| edge.to));
+    }
+
+    let calls = calls.into_iter().map(|(call_site, target)| (call_site, target, returns.get(&target) == Some(&true))).collect();
+    Cfg { blocks, edges, reachable, calls, bad_targets }
+}
+
+/* Whether there's a way from a function's first block to a return without leaving it, going
+ * past calls only to functions already known to return. A tailcall returns if what it goes to
+ * does, and a jumpi might. */
+fn function_returns(
+    start: usize,
+    blocks: &[BasicBlock],
+    edges: &[Edge],
+    last: &[Option<(i32, Instruction)>],
+    returns: &BTreeMap<i32, bool>,
+    starting_at: &dyn Fn(i32) -> Option<usize>,
+) -> bool {
+    let mut seen = vec![false; blocks.len()];
+    let mut work = vec![start];
+
+    while let Some(i) = work.pop() {
+        if seen[i] {
+            continue;
+        }
+        seen[i] = true;
+
+        match last[i] {
+            Some((_, Instruction::Return(_) | Instruction::JumpI)) => return true,
+            Some((address, Instruction::TailCall(offset))) if returns.get(&address.wrapping_add(offset)) == Some(&true) => return true,
+            Some((address, Instruction::Call(offset))) if returns.get(&address.wrapping_add(offset)) == Some(&true) => {
+                work.extend(starting_at(address + 4));
+            },
+            _ => (),
+        }
+        work.extend(edges.iter().filter(|edge| edge.from == i && matches!(edge.kind, EdgeKind::Next | EdgeKind::Jump)).map(|edge| edge.to));
+    }
+
+    false
+}
+
+impl Cfg {
+    /* Everything wrong with the program, in address order within each kind: unreachable code,
+     * then calls that never return, then bad branch targets. Unreachable blocks next to each
+     * other are reported together. */
+    pub fn problems(&self) -> Vec<Problem> {
+        let mut problems: Vec<Problem> = Vec::new();
+
+        for (block, _) in self.blocks.iter().zip(&self.reachable).filter(|(_, &reachable)| !reachable) {
+            match problems.last_mut() {
+                Some(Problem::Unreachable { end, .. }) if *end == block.start => *end = block.end,
+                _ => problems.push(Problem::Unreachable { start: block.start, end: block.end }),
+            }
+        }
+        problems.extend(self.calls.iter()
+            .filter(|&&(_, _, returns)| !returns)
+            .map(|&(call_site, target, _)| Problem::NoReturn { call_site, target }));
+        problems.extend(self.bad_targets.iter().map(|&(from, to)| Problem::BadTarget { from, to }));
+
+        problems
+    }
+
+    /* The blocks that control can go to straight from a block. */
+    pub fn successors(&self, block: usize) -> impl Iterator<Item = &Edge> {
+        self.edges.iter().filter(move |edge| edge.from == block)
+    }
+}
+
+/* The graph a block at a time, with where each one can go:
+ *
+ *     0000..0008  -> call 0024
+ *     0008..0018  -> 001c 0020 next 0018
+ */
+impl fmt::Display for Cfg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, block) in self.blocks.iter().enumerate() {
+            write!(f, "{:04x}..{:04x}", block.start, block.end)?;
+            if !self.reachable[i] {
+                write!(f, "  (unreachable)")?;
+            }
+            let mut edges: Vec<&Edge> = self.successors(i).collect();
+            edges.sort_by_key(|edge| (edge.kind == EdgeKind::Next, self.blocks[edge.to].start));
+            if !edges.is_empty() {
+                write!(f, "  ->")?;
+            }
+            for edge in edges {
+                let to = self.blocks[edge.to].start;
+                match edge.kind {
+                    EdgeKind::Next => write!(f, " next {:04x}", to)?,
+                    EdgeKind::Jump => write!(f, " {:04x}", to)?,
+                    EdgeKind::Call => write!(f, " call {:04x}", to)?,
+                    EdgeKind::Spawn => write!(f, " spawn {:04x}", to)?,
+                    EdgeKind::Address => write!(f, " lea {:04x}", to)?,
+                }
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,269 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ops::{BitOr, Range};
+use core::time::Duration;
+
+/* Width of a single stack word. Instructions are always 4 bytes wide regardless of this
+ * setting; it only changes how much space a pushed value takes up and how wide arithmetic is. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WordSize {
+    #[default]
+    Bits32,
+    Bits64,
+}
+
+impl WordSize {
+    /* Number of bytes a word takes up on the stack. */
+    pub fn bytes(self) -> i32 {
+        match self {
+            WordSize::Bits32 => 4,
+            WordSize::Bits64 => 8,
+        }
+    }
+
+    /* Number of bits in a word. */
+    pub fn bits(self) -> i64 {
+        self.bytes() as i64 * 8
+    }
+
+    /* Smallest signed value a word can hold. */
+    pub fn min(self) -> i64 {
+        match self {
+            WordSize::Bits32 => i32::MIN as i64,
+            WordSize::Bits64 => i64::MIN,
+        }
+    }
+
+    /* Largest signed value a word can hold. */
+    pub fn max(self) -> i64 {
+        match self {
+            WordSize::Bits32 => i32::MAX as i64,
+            WordSize::Bits64 => i64::MAX,
+        }
+    }
+}
+
+/* What add, sub, mul, div and neg do when the true result doesn't fit in a word. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ArithmeticMode {
+    /* Two's complement wraparound. */
+    #[default]
+    Wrapping,
+    /* Clamp to the largest or smallest word. */
+    Saturating,
+    /* Stop the machine with VmError::ArithmeticOverflow. */
+    Trapping,
+}
+
+/* What happens when the program counter runs off the end of the code, or branches past it, into
+ * the rest of memory. A pc outside memory altogether is always VmError::PcOutOfRange. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PcOverrun {
+    /* Stop as though the program had run exit 0. */
+    #[default]
+    Exit,
+    /* Stop the machine with VmError::PcOutOfRange. */
+    Error,
+}
+
+/* Where the debug instruction's dumps go. Whichever it is, debug still pops its address and
+ * faults on a bad one, so a program does the same thing with its dumps muted. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DebugMode {
+    /* To the diagnostics, with everything else the VM has to say. */
+    #[default]
+    Enabled,
+    /* Nowhere. */
+    Silent,
+    /* Straight to the host's stderr, even when the diagnostics have been sent somewhere else.
+     * Without std there's no stderr, and it's the same as Silent. */
+    ToStderr,
+}
+
+/* How strings are laid out on the stack, for every instruction that reads or pushes one (see
+ * the strings module). */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StringFormat {
+    /* Three characters to a word, with a flag for more to come. */
+    #[default]
+    Packed,
+    /* A word holding the length, then the bytes, one after another. */
+    LengthPrefixed,
+}
+
+/* The order the bytes of a word go in memory, for every word the program pushes, pops, loads or
+ * stores, and .word in its data. Instructions are little-endian whatever this says, like the
+ * header and jumptable entries, which belong to the code rather than to the program's data. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Endianness {
+    /* The most significant byte at the lowest address, as the original machine had it. */
+    #[default]
+    Big,
+    /* The least significant byte first, the same as instructions (the little_endian header
+     * feature asks for this). */
+    Little,
+}
+
+impl Endianness {
+    /* The word in some bytes, zero extended. */
+    pub fn read(self, bytes: &[u8]) -> u64 {
+        match self {
+            Endianness::Big => bytes.iter().fold(0, |word, &byte| (word << 8) | byte as u64),
+            Endianness::Little => bytes.iter().rev().fold(0, |word, &byte| (word << 8) | byte as u64),
+        }
+    }
+
+    /* Fill some bytes with the low bytes of a value. */
+    pub fn write(self, value: u64, bytes: &mut [u8]) {
+        let size = bytes.len();
+        match self {
+            Endianness::Big => bytes.copy_from_slice(&value.to_be_bytes()[8 - size..]),
+            Endianness::Little => bytes.copy_from_slice(&value.to_le_bytes()[..size]),
+        }
+    }
+}
+
+/* Groups of instructions a program can be kept from running, for VmConfig::allowed_ops. Each
+ * is a bit, so they combine with |. Anything in none of them is always allowed. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ops(u32);
+
+impl Ops {
+    /* input and stinput, and everything that prints: the program's input and output. */
+    pub const IO: Ops = Ops(1 << 0);
+    /* debug and dump, which write the machine's insides to the diagnostics. */
+    pub const DEBUG: Ops = Ops(1 << 1);
+    /* readfile and writefile. */
+    pub const FILES: Ops = Ops(1 << 2);
+    /* getenv and clock, which tell the program about the host it's running on. */
+    pub const HOST: Ops = Ops(1 << 3);
+    pub const NONE: Ops = Ops(0);
+    pub const ALL: Ops = Ops((1 << 4) - 1);
+
+    /* Whether every group in ops is in this one. */
+    pub fn contains(self, ops: Ops) -> bool {
+        self.0 & ops.0 == ops.0
+    }
+
+    pub fn without(self, ops: Ops) -> Ops {
+        Ops(self.0 & !ops.0)
+    }
+}
+
+impl BitOr for Ops {
+    type Output = Ops;
+
+    fn bitor(self, other: Ops) -> Ops {
+        Ops(self.0 | other.0)
+    }
+}
+
+/* Knobs for building a VirtualMachine. Everything defaults to the behaviour of the original
+ * 32-bit machine, except that the code is write-protected. */
+#[derive(Debug, Clone)]
+pub struct VmConfig {
+    pub word_size: WordSize,
+    pub arithmetic_mode: ArithmeticMode,
+    /* How many calls deep a program may go before it's stopped. None means no limit. */
+    pub max_call_depth: Option<usize>,
+    /* When set, the program's functions are shuffled with this seed as it's loaded. Running a
+     * test suite under a few different seeds catches programs that rely on where code lives.
+     * Programs with an entry point other than 0 aren't shuffled. */
+    pub layout_seed: Option<u64>,
+    /* How many instructions a program may execute before it's stopped. None means no limit. */
+    pub fuel: Option<u64>,
+    /* Forth-style dual-stack mode: when set, call and ret keep return addresses on a separate
+     * return stack holding up to this many entries, so data pushes can't clobber them. None
+     * keeps them on the data stack. */
+    pub return_stack_depth: Option<usize>,
+    /* Count how often each instruction runs and each function is called, for
+     * VirtualMachine::profile. Off by default since it costs a little on every step. */
+    pub profile: bool,
+    /* Hash the pc, stack pointer and instruction of every step as it runs, for
+     * VirtualMachine::state_hash, so two runs can be shown to have gone exactly the same way. */
+    pub state_hash: bool,
+    pub on_pc_overrun: PcOverrun,
+    /* Bytes kept free between the end of the code and the lowest the stack may grow, so a
+     * runaway stack stops with VmError::StackOverflow instead of writing over instructions.
+     * None lets the stack grow all the way down to the code, and with protect_code off,
+     * over it. */
+    pub stack_guard: Option<usize>,
+    /* Arguments for the program. When set, they're pushed as strings before it starts,
+     * the last one first, and then their count, so the count is on top with the first
+     * argument under it and the strings fill the top of memory. The arg instruction fetches
+     * any of them again later. They're also the only files readfile and writefile will touch.
+     * None starts the program on an empty stack. */
+    pub args: Option<Vec<String>>,
+    /* The environment variables getenv may read. Anything else faults. */
+    pub env_allowlist: Vec<String>,
+    /* Seed for the rand instruction, so runs can be repeated. None seeds it from the clock, or
+     * with 0 where there isn't one. */
+    pub seed: Option<u64>,
+    /* How long a program may run, from its first instruction, before it's stopped with
+     * VmError::Timeout. The clock is only looked at every so many instructions, so it can run
+     * a little over. Needs std; without it there's no clock and this is ignored. */
+    pub timeout: Option<Duration>,
+    /* Addresses, outside memory, set aside for devices mapped with VirtualMachine::map_device.
+     * load and store there go to the device instead of memory. None has no devices. */
+    pub mmio: Option<Range<i32>>,
+    /* Bytes of stack each context started by spawn gets. None gives them 256. */
+    pub context_stack: Option<usize>,
+    /* Switch to the next context after this many instructions, as well as at yield, join and
+     * exit. None only switches at those. */
+    pub time_slice: Option<u64>,
+    /* Run the program as threaded code (see the threaded module): decoded once as it's
+     * loaded rather than every time an instruction runs. Behaves exactly the same. */
+    pub threaded: bool,
+    pub string_format: StringFormat,
+    pub endianness: Endianness,
+    /* Stop any write below code_end with an error, so a program can't change its own code by
+     * accident. Programs that mean to, through store or swap, need it off; the VM then notices
+     * the code changed and runs the new instructions. On by default. */
+    pub protect_code: bool,
+    /* The groups of instructions the program may run. Running one from a group that isn't
+     * here stops it with VmError::PermissionDenied, which is how a server running programs it
+     * doesn't trust keeps them to the input and output it gives them. Everything by default. */
+    pub allowed_ops: Ops,
+    /* Bytes set aside between the program and the stack for it to keep things in (see the
+     * address_space module). None gives it none, unless its header asks for some. */
+    pub heap_size: Option<usize>,
+    /* Bytes at the top of memory for the stack. None gives it everything above the program
+     * and its heap. With this or heap_size set, pushing below the bottom of the stack's
+     * segment is VmError::StackOverflow rather than a write over the heap. */
+    pub stack_size: Option<usize>,
+    /* Where the debug instruction writes, for running programs that still have debug dumps in
+     * them without those ending up in what gets checked. */
+    pub debug_instruction: DebugMode,
+}
+
+impl Default for VmConfig {
+    fn default() -> VmConfig {
+        VmConfig {
+            word_size: WordSize::default(),
+            arithmetic_mode: ArithmeticMode::default(),
+            max_call_depth: None,
+            layout_seed: None,
+            fuel: None,
+            return_stack_depth: None,
+            profile: false,
+            state_hash: false,
+            on_pc_overrun: PcOverrun::default(),
+            stack_guard: None,
+            args: None,
+            env_allowlist: Vec::new(),
+            seed: None,
+            timeout: None,
+            mmio: None,
+            context_stack: None,
+            time_slice: None,
+            threaded: false,
+            string_format: StringFormat::default(),
+            endianness: Endianness::default(),
+            protect_code: true,
+            allowed_ops: Ops::ALL,
+            heap_size: None,
+            stack_size: None,
+            debug_instruction: DebugMode::default(),
+        }
+    }
+}
@@ -0,0 +1,207 @@
+/* Debug info carried in a .v file with the DEBUG_INFO feature, so errors can say where in the
+ * source they happened instead of giving a bare pc. It goes after the code, and its length goes
+ * after it, as the last four bytes of the file, so the loader can find where the code stops:
+ *
+ *     label count, then for each: address, name length, name
+ *     line count, then the source line of each word of code (0 for none)
+ *     file count, then for each: name length, name
+ *     row count, then for each: address, file, line
+ *     length of everything above
+ *
+ * All little-endian. The rows are the line table, in the manner of DWARF's: each says that the
+ * code from its address up to the next row's came from that line of that file, numbered from 0
+ * in the order of the file names, the file assembled first. A row with line 0 starts code from
+ * no line. Files from before the line table stop after the lines, which were for the file
+ * assembled only, a word at a time; they're read as a line table with no file names, and that's
+ * all they have. Newer files leave the line count at 0.
+ *
+ * A linked program keeps its labels but has no lines to give. */
+
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DebugInfo {
+    pub labels: BTreeMap<String, i32>,
+    /* The source files the rows' file numbers index, or none if the file didn't say. */
+    pub files: Vec<String>,
+    /* The line table, in address order. */
+    pub rows: Vec<LineRow>,
+}
+
+/* Where the code from address on came from. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineRow {
+    pub address: i32,
+    pub file: usize,
+    pub line: usize,
+}
+
+/* Pulls the fields of a debug-info section out in order. */
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, size: usize) -> Result<&'a [u8], String> {
+        if self.bytes.len() < size {
+            return Err(String::from("Debug info is truncated."));
+        }
+
+        let (taken, rest) = self.bytes.split_at(size);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn name(&mut self, what: &str) -> Result<String, String> {
+        let size = self.u32()? as usize;
+        String::from_utf8(Vec::from(self.take(size)?)).map_err(|_| format!("Debug info has a bad {} name.", what))
+    }
+}
+
+/* The label in a map of them that an address falls under, the closest at or before it, and
+ * how far past it the address is. Where two labels are at the same place, the one that sorts
+ * last. */
+pub fn symbolize(labels: &BTreeMap<String, i32>, address: i32) -> Option<(&str, i32)> {
+    labels.iter()
+        .filter(|(_, &label)| label <= address)
+        .max_by_key(|(_, &label)| label)
+        .map(|(name, &label)| (name.as_str(), address - label))
+}
+
+impl DebugInfo {
+    /* The line table for code given a word at a time, as (file, line) for each word. A last
+     * row with line 0 marks where the code ends. */
+    pub fn line_table(words: &[(usize, usize)]) -> Vec<LineRow> {
+        let mut rows: Vec<LineRow> = Vec::new();
+        let end = if words.is_empty() { None } else { Some(&(0, 0)) };
+        for (i, &(file, line)) in words.iter().chain(end).enumerate() {
+            if rows.last().is_none_or(|row| (row.file, row.line) != (file, line)) {
+                rows.push(LineRow { address: i as i32 * 4, file, line });
+            }
+        }
+        rows
+    }
+
+    /* Add the section to the end of a .v image. */
+    pub fn append_to(&self, image: &mut Vec<u8>) {
+        let start = image.len();
+
+        image.extend_from_slice(&(self.labels.len() as u32).to_le_bytes());
+        for (name, &address) in &self.labels {
+            image.extend_from_slice(&(address as u32).to_le_bytes());
+            image.extend_from_slice(&(name.len() as u32).to_le_bytes());
+            image.extend_from_slice(name.as_bytes());
+        }
+
+        /* The old word-at-a-time lines, left empty. */
+        image.extend_from_slice(&0u32.to_le_bytes());
+
+        image.extend_from_slice(&(self.files.len() as u32).to_le_bytes());
+        for name in &self.files {
+            image.extend_from_slice(&(name.len() as u32).to_le_bytes());
+            image.extend_from_slice(name.as_bytes());
+        }
+
+        image.extend_from_slice(&(self.rows.len() as u32).to_le_bytes());
+        for row in &self.rows {
+            for value in [row.address as u32, row.file as u32, row.line as u32] {
+                image.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+
+        let size = (image.len() - start) as u32;
+        image.extend_from_slice(&size.to_le_bytes());
+    }
+
+    /* Split what follows the header into the code and the debug info at the end of it. */
+    pub fn split_off(body: &[u8]) -> Result<(&[u8], DebugInfo), String> {
+        let Some(size_at) = body.len().checked_sub(4) else {
+            return Err(String::from("Debug info is truncated."));
+        };
+        let size = u32::from_le_bytes([body[size_at], body[size_at + 1], body[size_at + 2], body[size_at + 3]]) as usize;
+        let Some(start) = size_at.checked_sub(size) else {
+            return Err(String::from("Debug info is truncated."));
+        };
+
+        let mut reader = Reader { bytes: &body[start..size_at] };
+        let mut labels = BTreeMap::new();
+        for _ in 0..reader.u32()? {
+            let address = reader.u32()? as i32;
+            let name = reader.name("label")?;
+            labels.insert(name, address);
+        }
+
+        let count = reader.u32()?;
+        let words: Vec<(usize, usize)> = (0..count).map(|_| reader.u32().map(|line| (0, line as usize))).collect::<Result<_, _>>()?;
+        let mut info = DebugInfo { labels, files: Vec::new(), rows: DebugInfo::line_table(&words) };
+
+        if !reader.bytes.is_empty() {
+            for _ in 0..reader.u32()? {
+                info.files.push(reader.name("file")?);
+            }
+            for _ in 0..reader.u32()? {
+                let (address, file, line) = (reader.u32()? as i32, reader.u32()? as usize, reader.u32()? as usize);
+                if file >= info.files.len() {
+                    return Err(format!("Debug info has a line in file {}, but only {} files.", file, info.files.len()));
+                }
+                if info.rows.last().is_some_and(|row| row.address > address) {
+                    return Err(String::from("Debug info has its line table out of order."));
+                }
+                info.rows.push(LineRow { address, file, line });
+            }
+        }
+
+        if !reader.bytes.is_empty() {
+            return Err(String::from("Debug info has junk at the end."));
+        }
+
+        Ok((&body[..start], info))
+    }
+
+    /* The label an address falls under, and how far past it the address is. */
+    pub fn symbolize(&self, address: i32) -> Option<(&str, i32)> {
+        symbolize(&self.labels, address)
+    }
+
+    /* The source file and line of the instruction at an address. The file is None when the
+     * debug info doesn't name its files, or names the file assembled as nothing. */
+    pub fn location(&self, address: i32) -> Option<(Option<&str>, usize)> {
+        let i = self.rows.partition_point(|row| row.address <= address).checked_sub(1)?;
+        let row = self.rows[i];
+        let file = self.files.get(row.file).map(String::as_str).filter(|name| !name.is_empty());
+        (row.line != 0 && address >= 0).then_some((file, row.line))
+    }
+
+    /* The source line of the instruction at an address. */
+    pub fn line(&self, address: i32) -> Option<usize> {
+        self.location(address).map(|(_, line)| line)
+    }
+
+    /* Where an address is, in terms of the source: "label `loop_body` (line 42 of main.s)". */
+    pub fn describe(&self, address: i32) -> Option<String> {
+        let label = match self.symbolize(address) {
+            Some((label, 0)) => Some(format!("label `{}`", label)),
+            Some((label, offset)) => Some(format!("label `{}`+{}", label, offset)),
+            None => None,
+        };
+        let line = self.location(address).map(|location| match location {
+            (Some(file), line) => format!("line {} of {}", line, file),
+            (None, line) => format!("line {}", line),
+        });
+
+        match (label, line) {
+            (Some(label), Some(line)) => Some(format!("{} ({})", label, line)),
+            (Some(label), None) => Some(label),
+            (None, Some(line)) => Some(line),
+            (None, None) => None,
+        }
+    }
+}
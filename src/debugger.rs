@@ -0,0 +1,613 @@
+/* An interactive debugger for `vm debug`. It reads commands a line at a time and stops the
+ * program at breakpoints and whenever a watched word of memory is written. Anywhere a command
+ * takes an address or a value, it takes a watch expression (see the expr module), so
+ * `break 0x40`, `watch sp` and `print mem[sp] + 1` all work.
+ *
+ * Debugging a .s file rather than a .v adds its labels to the expressions, and the file is
+ * checked for changes before every prompt. A change that only touches code that hasn't run yet
+ * is patched straight into memory; anything else waits for `restart`, which reloads the program
+ * and sets the breakpoints and watchpoints up again from what was typed, so `break loop` follows
+ * the loop label to wherever it moved.
+ *
+ * `break <addr> if <expr>` only stops when the expression is true, as in `break loop if
+ * stack[0] == 10`. Commands can also come from a script instead of the terminal, one per line,
+ * which makes a debugging session something CI can replay.
+ *
+ * The program reads stdin a line at a time, taking turns with the prompt, unless it's been given
+ * input of its own with with_input. */
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{Cursor, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use crate::asm::{self, Assembled};
+use crate::expr::{Expr, State};
+use crate::{analysis, isa, ContextState, Header, Input, StepResult, VirtualMachine, VmConfig, VmError, WatchHit};
+
+const HELP: &str = "commands:
+  step [n], s        execute n instructions (default 1)
+  continue, c        run until a breakpoint, a watchpoint, or the end
+  break <addr>, b    stop before executing the instruction at addr
+  break <addr> if <expr>
+                     stop there only when expr is true
+  delete <addr>      remove a breakpoint
+  watch <addr>       stop after any store to the word at addr
+  unwatch <addr>     remove a watchpoint
+  print <expr>, p    evaluate an expression
+  dump <addr>[, n]   show n bytes of memory from addr in hex (default 64)
+  dumpb <addr>[, n]  the same in binary
+  find <expr>        list where the word expr is in memory
+  find [<byte>...]   list where a sequence of bytes in hex is, as in find [de ad 0xbe]
+  info               show the registers, breakpoints and watchpoints
+  backtrace, bt      show the calls that haven't returned, innermost first
+  restart            start the program over, picking up any changes to its source
+  quit, q            leave the debugger
+";
+
+/* How much dump shows when it isn't told, and how many places find lists. */
+const DUMP_BYTES: i64 = 64;
+const FIND_LIMIT: usize = 32;
+
+/* Why running stopped. */
+enum Stop {
+    Breakpoint,
+    /* The program ran a brk of its own. */
+    Brk,
+    Watchpoint(Vec<WatchHit>),
+    Exited(i32),
+    Fault(String),
+    /* A breakpoint's condition couldn't be worked out, which stops rather than guessing. */
+    ConditionFailed(String, String),
+    /* Ctrl-C. */
+    Interrupted,
+    Finished,
+}
+
+/* A breakpoint, along with the text it was set with so restart can work it out again. */
+struct Breakpoint {
+    address: i32,
+    text: String,
+    condition: Option<String>,
+}
+
+/* The labels of the program being debugged on top of everything the VM itself offers. */
+struct Symbols<'a> {
+    vm: &'a VirtualMachine,
+    labels: Option<&'a BTreeMap<String, i32>>,
+}
+
+impl State for Symbols<'_> {
+    fn variable(&self, name: &str) -> Result<i64, String> {
+        match self.labels.and_then(|labels| labels.get(name)) {
+            Some(&address) => Ok(address as i64),
+            None => self.vm.variable(name),
+        }
+    }
+
+    fn word(&self, address: i64) -> Result<i64, String> {
+        self.vm.word(address)
+    }
+
+    fn byte(&self, address: i64) -> Result<i64, String> {
+        self.vm.byte(address)
+    }
+
+    fn slot(&self, n: i64) -> Result<i64, String> {
+        self.vm.slot(n)
+    }
+}
+
+/* The .s file a program came from. */
+struct Source {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    program: Assembled,
+}
+
+impl Source {
+    fn modified(path: &PathBuf) -> Option<SystemTime> {
+        fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+    }
+
+    /* Assembled with debug info, so faults can point at the line. */
+    fn assemble(path: &PathBuf) -> Result<Assembled, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("Couldn't read {}: {}", path.display(), e))?;
+        let mut program = asm::assemble_with(&text, &path.to_string_lossy(), &mut asm::IncludePath::default(), &BTreeMap::new())
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        program.features |= Header::DEBUG_INFO;
+        Ok(program)
+    }
+}
+
+pub struct Debugger {
+    vm: VirtualMachine,
+    /* What the program was loaded from, for restarting it. */
+    image: Vec<u8>,
+    source: Option<Source>,
+    /* A reassembled program that couldn't be patched in, waiting for a restart. */
+    pending: Option<Assembled>,
+    /* Which instruction words have run, so edits to the rest can be patched in place. */
+    executed: Vec<bool>,
+    breakpoints: Vec<Breakpoint>,
+    /* Each watchpoint along with the expression it was set with. */
+    watches: Vec<(i32, String)>,
+    /* Set by Ctrl-C, if the debugger's been given one (see set_interrupt). */
+    interrupt: Option<Arc<AtomicBool>>,
+    /* What the machine is built with, every time the program is loaded. */
+    config: VmConfig,
+    /* The program's input, from the start again on every restart, if it isn't reading stdin. */
+    input: Option<Vec<u8>>,
+}
+
+impl Debugger {
+    /* Debug a program from the contents of a .v file. */
+    pub fn new(image: Vec<u8>) -> Result<Debugger, String> {
+        let vm = VirtualMachine::from_bytes(image.clone(), VmConfig::default())?;
+
+        Ok(Debugger {
+            vm,
+            image,
+            source: None,
+            pending: None,
+            executed: vec![false; vm_words()],
+            breakpoints: Vec::new(),
+            watches: Vec::new(),
+            interrupt: None,
+            config: VmConfig::default(),
+            input: None,
+        })
+    }
+
+    /* Load the program again on a machine built with config, for this and every restart. */
+    pub fn with_config(mut self, config: VmConfig) -> Result<Debugger, String> {
+        self.config = config;
+        self.vm = self.load()?;
+        Ok(self)
+    }
+
+    /* Give the program this as its input rather than stdin, which leaves stdin to the commands.
+     * Every restart reads it from the start again. */
+    pub fn with_input(mut self, input: Vec<u8>) -> Debugger {
+        self.vm.set_input(Box::new(Cursor::new(input.clone())));
+        self.input = Some(input);
+        self
+    }
+
+    /* A fresh machine with the program on it, hooked up the way the last one was. */
+    fn load(&self) -> Result<VirtualMachine, String> {
+        let mut vm = VirtualMachine::from_bytes(self.image.clone(), self.config.clone())?;
+        if let Some(flag) = &self.interrupt {
+            vm.set_interrupt(flag.clone());
+        }
+        if let Some(input) = &self.input {
+            vm.set_input(Box::new(Cursor::new(input.clone())));
+        }
+        Ok(vm)
+    }
+
+    /* Debug a program straight from its assembly source, keeping an eye on the file. */
+    pub fn from_source(path: PathBuf) -> Result<Debugger, String> {
+        let modified = Source::modified(&path);
+        let program = Source::assemble(&path)?;
+
+        let mut debugger = Debugger::new(program.image())?;
+        debugger.source = Some(Source { path, modified, program });
+        Ok(debugger)
+    }
+
+    pub fn vm(&self) -> &VirtualMachine {
+        &self.vm
+    }
+
+    /* Stop running and go back to the prompt whenever the flag is set, as the SIGINT handler
+     * in the interrupt module does. */
+    pub fn set_interrupt(&mut self, flag: Arc<AtomicBool>) {
+        self.vm.set_interrupt(flag.clone());
+        self.interrupt = Some(flag);
+    }
+
+    /* Take commands from input until it runs out or says quit, writing what happens to out. A
+     * line at a time, so a program reading the same stdin gets the lines typed while it runs. */
+    pub fn repl(&mut self, input: &mut dyn Input, out: &mut dyn Write) -> Result<(), String> {
+        self.show_location(out).map_err(write_err)?;
+
+        loop {
+            write!(out, "(vm) ").and_then(|_| out.flush()).map_err(write_err)?;
+
+            let mut line = String::new();
+            match input.read_line(&mut line) {
+                Ok(0) => return Ok(()),
+                Ok(_) => (),
+                Err(e) => return Err(format!("Couldn't read input: {}", e)),
+            }
+
+            match self.line(&line, out) {
+                Ok(true) => (),
+                Ok(false) => return Ok(()),
+                Err(message) => writeln!(out, "{}", message).map_err(write_err)?,
+            }
+        }
+    }
+
+    /* Carry out the commands in a script, echoing each one so the output reads like a session
+     * at the prompt. Unlike at the prompt, a command that fails ends the script with an error
+     * saying which line it was. Blank lines and lines starting with # are skipped. */
+    pub fn script(&mut self, script: &str, out: &mut dyn Write) -> Result<(), String> {
+        self.show_location(out).map_err(write_err)?;
+
+        for (number, line) in script.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            writeln!(out, "(vm) {}", line).map_err(write_err)?;
+            match self.line(line, out) {
+                Ok(true) => (),
+                Ok(false) => return Ok(()),
+                Err(message) => return Err(format!("line {}: {}", number + 1, message)),
+            }
+        }
+
+        Ok(())
+    }
+
+    /* Carry out one line of commands. Ok(false) means it said quit. */
+    fn line(&mut self, line: &str, out: &mut dyn Write) -> Result<bool, String> {
+        let line = line.trim();
+        let (command, argument) = match line.split_once(char::is_whitespace) {
+            Some((command, argument)) => (command, argument.trim()),
+            None => (line, ""),
+        };
+
+        if matches!(command, "quit" | "q") {
+            return Ok(false);
+        }
+
+        if let Err(message) = self.check_source(out) {
+            writeln!(out, "{}", message).map_err(write_err)?;
+        }
+
+        self.command(command, argument, out).map(|_| true)
+    }
+
+    /* Carry out one command. Errors are for the user, not fatal. */
+    fn command(&mut self, command: &str, argument: &str, out: &mut dyn Write) -> Result<(), String> {
+        match command {
+            "" => Ok(()),
+            "help" | "h" => write!(out, "{}", HELP).map_err(|e| e.to_string()),
+            "step" | "s" => {
+                let count = if argument.is_empty() { 1 } else { self.evaluate(argument)? };
+                let stop = self.run(Some(count.max(0) as u64));
+                self.report(stop, out)
+            },
+            "continue" | "c" => {
+                let stop = self.run(None);
+                self.report(stop, out)
+            },
+            "break" | "b" => {
+                let (text, condition) = match argument.split_once(" if ") {
+                    Some((text, condition)) => (text.trim(), Some(String::from(condition.trim()))),
+                    None => (argument, None),
+                };
+                if let Some(condition) = &condition {
+                    Expr::parse(condition)?;
+                }
+
+                let address = self.address(text)?;
+                self.breakpoints.retain(|b| b.address != address);
+                self.breakpoints.push(Breakpoint { address, text: String::from(text), condition: condition.clone() });
+                match condition {
+                    Some(condition) => writeln!(out, "breakpoint at {} if {}", self.describe(address), condition),
+                    None => writeln!(out, "breakpoint at {}", self.describe(address)),
+                }.map_err(|e| e.to_string())
+            },
+            "delete" => {
+                let address = self.address(argument)?;
+                self.breakpoints.retain(|b| b.address != address);
+                Ok(())
+            },
+            "watch" => {
+                let address = self.address(argument)?;
+                self.vm.watch(address);
+                if !self.watches.iter().any(|&(w, _)| w == address) {
+                    self.watches.push((address, String::from(argument)));
+                }
+                writeln!(out, "watching {:04x}", address).map_err(|e| e.to_string())
+            },
+            "unwatch" => {
+                let address = self.address(argument)?;
+                self.vm.unwatch(address);
+                self.watches.retain(|&(w, _)| w != address);
+                Ok(())
+            },
+            "restart" => {
+                self.restart(out)?;
+                self.show_location(out).map_err(|e| e.to_string())
+            },
+            "print" | "p" => {
+                let value = self.evaluate(argument)?;
+                writeln!(out, "{} ({:#x})", value, value).map_err(|e| e.to_string())
+            },
+            "info" => self.info(out).map_err(|e| e.to_string()),
+            "backtrace" | "bt" => write!(out, "{}", self.vm.backtrace()).map_err(|e| e.to_string()),
+            "dump" | "dumpb" => {
+                let (address, bytes) = match argument.rsplit_once(',') {
+                    Some((address, bytes)) => (self.address(address)?, self.evaluate(bytes)?),
+                    /* Stopping short at the end of memory rather than running past it. */
+                    None => {
+                        let address = self.address(argument)?;
+                        (address, DUMP_BYTES.min(self.vm.memory().len() as i64 - address as i64))
+                    },
+                };
+                let bytes = usize::try_from(bytes).map_err(|_| format!("can't show {} bytes", bytes))?;
+                let text = self.vm.memory().dump(address, bytes, command == "dumpb").map_err(|e| e.to_string())?;
+                write!(out, "{}", text).map_err(|e| e.to_string())
+            },
+            "find" => self.find(argument, out),
+            _ => Err(format!("unknown command {} (try help)", command)),
+        }
+    }
+
+    /* Where a word, or a list of bytes in brackets, turns up in memory. */
+    fn find(&self, argument: &str, out: &mut dyn Write) -> Result<(), String> {
+        let pattern = match argument.strip_prefix('[').and_then(|rest| rest.strip_suffix(']')) {
+            Some(bytes) => bytes.split_whitespace()
+                .map(|byte| {
+                    let digits = byte.strip_prefix("0x").unwrap_or(byte);
+                    u8::from_str_radix(digits, 16).map_err(|_| format!("{} isn't a byte in hex", byte))
+                })
+                .collect::<Result<Vec<u8>, String>>()?,
+            None => {
+                let word = self.evaluate(argument)? as u64;
+                let mut bytes = vec![0; self.vm.config().word_size.bytes() as usize];
+                self.vm.memory().endianness().write(word, &mut bytes);
+                bytes
+            },
+        };
+        if pattern.is_empty() {
+            return Err(String::from("find needs something to look for"));
+        }
+
+        let found = self.vm.memory().find(&pattern);
+        if found.is_empty() {
+            return writeln!(out, "not found").map_err(|e| e.to_string());
+        }
+        for &address in found.iter().take(FIND_LIMIT) {
+            writeln!(out, "  {}", self.describe(address)).map_err(|e| e.to_string())?;
+        }
+        if found.len() > FIND_LIMIT {
+            writeln!(out, "  ...and {} more", found.len() - FIND_LIMIT).map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+
+    fn evaluate(&self, text: &str) -> Result<i64, String> {
+        let symbols = Symbols {
+            vm: &self.vm,
+            labels: self.source.as_ref().map(|source| &source.program.labels),
+        };
+
+        Expr::parse(text)?.evaluate(&symbols)
+    }
+
+    /* An address, along with the label it's under when there's source or debug info to say. */
+    fn describe(&self, address: i32) -> String {
+        let label = match &self.source {
+            Some(source) => source.program.symbolize(address),
+            None => self.vm.debug_info().and_then(|info| info.symbolize(address)),
+        };
+
+        match label {
+            Some((label, 0)) => format!("{:04x} ({})", address, label),
+            Some((label, offset)) => format!("{:04x} ({}+{})", address, label, offset),
+            None => format!("{:04x}", address),
+        }
+    }
+
+    /* Reassemble the source if it's been saved since it was last looked at, and patch the
+     * changes in if none of them are to code that's already run. */
+    fn check_source(&mut self, out: &mut dyn Write) -> Result<(), String> {
+        let Some(source) = &mut self.source else {
+            return Ok(());
+        };
+
+        let modified = Source::modified(&source.path);
+        if modified == source.modified {
+            return Ok(());
+        }
+        source.modified = modified;
+
+        let program = Source::assemble(&source.path).map_err(|e| format!("reload failed: {}", e))?;
+        if program.code == source.program.code {
+            source.program = program;
+            return Ok(());
+        }
+
+        let changed: Vec<usize> = (0..program.code.len().max(source.program.code.len()) / 4)
+            .filter(|&word| program.code.get(word * 4..word * 4 + 4) != source.program.code.get(word * 4..word * 4 + 4))
+            .collect();
+        let patchable = program.code.len() == source.program.code.len()
+            && changed.iter().all(|&word| !self.executed[word]);
+
+        if !patchable {
+            self.pending = Some(program);
+            return writeln!(out, "source changed; `restart` to run the new version").map_err(|e| e.to_string());
+        }
+
+        for &word in &changed {
+            self.vm.patch((word * 4) as i32, &program.code[word * 4..word * 4 + 4]).map_err(|e| e.to_string())?;
+        }
+        self.image = program.image();
+        source.program = program;
+        self.pending = None;
+
+        writeln!(out, "source changed; patched {} instruction(s) in place", changed.len()).map_err(|e| e.to_string())
+    }
+
+    /* Start over from the top, with the newest version of the program if there is one. The
+     * breakpoints and watchpoints are worked out again from the expressions they were set
+     * with. */
+    fn restart(&mut self, out: &mut dyn Write) -> Result<(), String> {
+        if let Some(program) = self.pending.take() {
+            self.image = program.image();
+            if let Some(source) = &mut self.source {
+                source.program = program;
+            }
+        }
+
+        self.vm = self.load()?;
+        self.executed = vec![false; vm_words()];
+
+        let breakpoints = std::mem::take(&mut self.breakpoints);
+        for breakpoint in breakpoints {
+            match self.address(&breakpoint.text) {
+                Ok(address) => self.breakpoints.push(Breakpoint { address, ..breakpoint }),
+                Err(err) => writeln!(out, "dropped breakpoint {:04x} ({}): {}", breakpoint.address, breakpoint.text, err)
+                    .map_err(|e| e.to_string())?,
+            }
+        }
+
+        let watches = std::mem::take(&mut self.watches);
+        for (old, text) in watches {
+            match self.address(&text) {
+                Ok(address) => {
+                    self.vm.watch(address);
+                    self.watches.push((address, text));
+                },
+                Err(err) => writeln!(out, "dropped watchpoint {:04x} ({}): {}", old, text, err).map_err(|e| e.to_string())?,
+            }
+        }
+
+        writeln!(out, "restarted").map_err(|e| e.to_string())
+    }
+
+    fn address(&self, text: &str) -> Result<i32, String> {
+        let value = self.evaluate(text)?;
+        i32::try_from(value).map_err(|_| format!("{:#x} isn't an address", value))
+    }
+
+    /* Step until something worth stopping for, or until count instructions have run. */
+    fn run(&mut self, count: Option<u64>) -> Stop {
+        let mut executed = 0;
+
+        /* A Ctrl-C at the prompt was meant for whatever was running before. */
+        if let Some(flag) = &self.interrupt {
+            flag.store(false, Ordering::SeqCst);
+        }
+
+        loop {
+            if count.is_some_and(|count| executed >= count) {
+                return Stop::Finished;
+            }
+
+            if let Some(executed) = self.executed.get_mut((self.vm.program_counter() / 4) as usize) {
+                *executed = true;
+            }
+
+            match self.vm.step() {
+                Ok(StepResult::Exited(code)) => return Stop::Exited(code),
+                Ok(StepResult::Running) => (),
+                Ok(StepResult::Breakpoint) => return Stop::Brk,
+                Err(VmError::Interrupted { .. }) => return Stop::Interrupted,
+                Err(err) => return Stop::Fault(self.vm.describe_error(&err)),
+            }
+            executed += 1;
+
+            let hits = self.vm.take_watch_hits();
+            if !hits.is_empty() {
+                return Stop::Watchpoint(hits);
+            }
+
+            let pc = self.vm.program_counter();
+            for breakpoint in self.breakpoints.iter().filter(|b| b.address == pc) {
+                match &breakpoint.condition {
+                    None => return Stop::Breakpoint,
+                    Some(condition) => match self.evaluate(condition) {
+                        Ok(0) => (),
+                        Ok(_) => return Stop::Breakpoint,
+                        Err(err) => return Stop::ConditionFailed(condition.clone(), err),
+                    },
+                }
+            }
+        }
+    }
+
+    fn report(&self, stop: Stop, out: &mut dyn Write) -> Result<(), String> {
+        let result = match stop {
+            Stop::Exited(code) => writeln!(out, "program exited with code {}", code),
+            Stop::Fault(message) => writeln!(out, "program stopped: {}", message),
+            Stop::Breakpoint => writeln!(out, "breakpoint"),
+            Stop::Brk => writeln!(out, "brk"),
+            Stop::ConditionFailed(condition, err) => writeln!(out, "breakpoint condition {} failed: {}", condition, err),
+            Stop::Watchpoint(hits) => {
+                let mut result = Ok(());
+                for hit in hits {
+                    result = result.and_then(|_| writeln!(out, "watchpoint {:04x}: {:#x} -> {:#x} (written by {:04x})",
+                        hit.address, hit.old, hit.new, hit.pc));
+                }
+                result
+            },
+            Stop::Interrupted => writeln!(out, "interrupted"),
+            Stop::Finished => Ok(()),
+        };
+
+        result.and_then(|_| self.show_location(out)).map_err(|e| e.to_string())
+    }
+
+    /* Where the program is stopped, and what it'll do next. */
+    fn show_location(&self, out: &mut dyn Write) -> std::io::Result<()> {
+        let pc = self.vm.program_counter();
+        match analysis::instruction_at(self.vm.memory().as_slice(), pc) {
+            Some(instruction) => writeln!(out, "{}: {:08x}  {}", self.describe(pc), instruction, isa::disassemble(instruction)),
+            None => writeln!(out, "{:04x}: (outside memory)", pc),
+        }
+    }
+
+    fn info(&self, out: &mut dyn Write) -> std::io::Result<()> {
+        writeln!(out, "pc {:04x}  sp {:04x}  depth {}  instructions {}  stack room {}",
+            self.vm.program_counter(), self.vm.stack_pointer(), self.vm.call_stack().len(), self.vm.instruction_count(),
+            self.vm.stack_room())?;
+
+        let list = |addresses: &[i32]| {
+            addresses.iter().map(|a| format!("{:04x}", a)).collect::<Vec<_>>().join(" ")
+        };
+        let breakpoints: Vec<String> = self.breakpoints.iter()
+            .map(|b| match &b.condition {
+                Some(condition) => format!("{:04x} if {}", b.address, condition),
+                None => format!("{:04x}", b.address),
+            })
+            .collect();
+        writeln!(out, "breakpoints: {}", breakpoints.join(", "))?;
+        writeln!(out, "watchpoints: {}", list(self.vm.watchpoints()))?;
+        write!(out, "{}", self.vm.address_space())?;
+
+        let contexts = self.vm.contexts();
+        if contexts.len() > 1 {
+            let running = self.vm.state().context;
+            let contexts: Vec<String> = contexts.iter()
+                .map(|&(id, state)| match state {
+                    _ if id == running => format!("{} running", id),
+                    ContextState::Ready => format!("{} ready", id),
+                    ContextState::Joining(target) => format!("{} joining {}", id, target),
+                    ContextState::Finished(code) => format!("{} exited {}", id, code),
+                })
+                .collect();
+            writeln!(out, "contexts: {}", contexts.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+fn write_err(e: std::io::Error) -> String {
+    format!("Couldn't write output: {}", e)
+}
+
+/* How many instruction words fit in the VM's memory. */
+fn vm_words() -> usize {
+    crate::MEMORY_SIZE / 4
+}
@@ -0,0 +1,107 @@
+/* Memory-mapped devices. VmConfig::mmio reserves a range of addresses, outside memory, that the
+ * load and store instructions hand to devices instead: a device mapped with
+ * VirtualMachine::map_device at base gets every load and store from base up to base + size,
+ * with the address made relative to base. Anything else in the range faults. Only load and
+ * store reach devices; pushes, pops and the rest only ever touch memory.
+ *
+ * Two devices come with the VM: a console, and a timer. Anything else, implement Device. */
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+
+use crate::{host, Input, Output, VmError};
+
+pub trait Device {
+    /* How many bytes of address space it takes up. */
+    fn size(&self) -> i32;
+
+    /* A load from offset bytes into the device. */
+    fn load(&mut self, offset: i32) -> Result<i64, VmError>;
+
+    /* A store to offset bytes into the device. */
+    fn store(&mut self, offset: i32, value: i64) -> Result<(), VmError>;
+}
+
+/* A character at a time in and out.
+ *
+ *     0  load: the next byte of input, or -1 at the end   store: write a byte
+ */
+pub struct Console {
+    input: Box<dyn Input + Send>,
+    output: Box<dyn Output + Send>,
+    line: String,
+    position: usize,
+}
+
+impl Console {
+    pub fn new(input: Box<dyn Input + Send>, output: Box<dyn Output + Send>) -> Console {
+        Console { input, output, line: String::new(), position: 0 }
+    }
+}
+
+impl Device for Console {
+    fn size(&self) -> i32 {
+        4
+    }
+
+    fn load(&mut self, _offset: i32) -> Result<i64, VmError> {
+        if self.position == self.line.len() {
+            self.line.clear();
+            self.position = 0;
+            self.input.read_line(&mut self.line).map_err(|e| VmError::from(format!("Console: {}", e)))?;
+        }
+
+        match self.line.as_bytes().get(self.position) {
+            Some(&byte) => {
+                self.position += 1;
+                Ok(byte as i64)
+            },
+            None => Ok(-1),
+        }
+    }
+
+    fn store(&mut self, _offset: i32, value: i64) -> Result<(), VmError> {
+        self.output.write_all(&[value as u8])
+            .and_then(|_| self.output.flush())
+            .map_err(|e| VmError::from(format!("Console: {}", e)))
+    }
+}
+
+/* Milliseconds since the timer was made, from the host's monotonic clock.
+ *
+ *     0  load: milliseconds   store: start counting again from 0
+ */
+pub struct Timer {
+    start: u64,
+}
+
+impl Timer {
+    pub fn new() -> Timer {
+        Timer { start: host::monotonic_millis().unwrap_or(0) }
+    }
+}
+
+impl Default for Timer {
+    fn default() -> Timer {
+        Timer::new()
+    }
+}
+
+impl Device for Timer {
+    fn size(&self) -> i32 {
+        4
+    }
+
+    fn load(&mut self, _offset: i32) -> Result<i64, VmError> {
+        match host::monotonic_millis() {
+            Some(now) => Ok(now.saturating_sub(self.start) as i64),
+            None => Err(VmError::from(String::from("Timer: there's no clock to read."))),
+        }
+    }
+
+    fn store(&mut self, _offset: i32, _value: i64) -> Result<(), VmError> {
+        self.start = host::monotonic_millis().unwrap_or(0);
+        Ok(())
+    }
+}
@@ -0,0 +1,282 @@
+/* A program written back out as text, for vm disasm. On its own a program only has offsets to
+ * show, so each word gets a line with its address and bits in front of it:
+ *
+ *     # version 2  features 0x0  entry 0004  sp 1000
+ *     # big-endian words
+ *     0000: 60000000  return 0
+ *     0004: 5ffffffc  call -4  # entry
+ *
+ * Given its labels, from the program's own debug info or from another build of it, the listing
+ * is assembly instead. Each label goes on a line of its own where it points, and an instruction
+ * that names an address names it by the label it's at or the closest one before, as fact or
+ * fact+8. The header comes back as directives and the data as .byte lines, so what the
+ * assembler makes of the listing is the same code and data, laid out the way vm fmt would:
+ *
+ *     # version 2  features 0x4  entry 0004  sp 1000
+ *             .feature debug_info
+ *             .entry main
+ *     fact:
+ *             return 0                # 0000  60000000
+ *     main:
+ *             call fact               # 0004  5ffffffc
+ *
+ * A word the assembler wouldn't write the same way, one that doesn't decode, has bits set that
+ * its instruction ignores or has an operand the assembler won't take, goes down as a .word, and
+ * the words after a jumptable as .table.
+ * With color, mnemonics, labels and comments are picked out with ANSI escapes for a terminal,
+ * which the assembler won't take back. */
+
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::analysis;
+use crate::asm;
+use crate::fmt::{COMMENT_COLUMN, INDENT};
+use crate::isa::{self, Instruction};
+use crate::{Endianness, Header, MEMORY_SIZE};
+
+const MNEMONIC: &str = "\x1b[36m";
+const LABEL: &str = "\x1b[33m";
+const COMMENT: &str = "\x1b[90m";
+const RESET: &str = "\x1b[0m";
+
+/* How many bytes of data a .byte line takes at most. */
+const BYTES_PER_LINE: usize = 8;
+
+/* The listing of a .v file, as assembly if there are labels to go by. */
+pub fn listing(image: &[u8], labels: Option<&BTreeMap<String, i32>>, color: bool) -> Result<String, String> {
+    let (header, body, _) = Header::parse(image)?;
+    /* Header::parse has checked there's as much code as the header says. */
+    let (code, data) = body.split_at(header.code_size.map_or(body.len(), |size| size as usize));
+
+    let mut listing = Listing { text: String::new(), color, labels: BTreeMap::new() };
+    listing.comment(&format!("{}", header));
+    match labels {
+        Some(labels) => listing.source(&header, code, data, labels),
+        None => listing.words(&header, code, data),
+    }
+    Ok(listing.text)
+}
+
+/* Check a .v file comes through being listed and assembled again unchanged: the listing, with
+ * the file's own labels if it has debug info, has to assemble to the same code and data byte
+ * for byte, the same labels, and a header that says the same. The header is compared field by
+ * field rather than byte by byte, since the assembler writes the oldest version that holds what
+ * a program needs, where the file may have the old magic or a newer version than it had to.
+ * Err says where the two first differ. */
+pub fn roundtrip(image: &[u8]) -> Result<(), String> {
+    let (header, body, debug_info) = Header::parse(image)?;
+    let labels = debug_info.map(|debug_info| debug_info.labels).unwrap_or_default();
+    let listing = listing(image, Some(&labels), false)?;
+    let reassembled = asm::assemble(&listing).map_err(|err| format!("the listing doesn't assemble: {}", err))?.image();
+    let (new_header, new_body, new_debug_info) = Header::parse(&reassembled)?;
+
+    let fields = |header: &Header| (header.features, header.entry, header.stack_pointer, header.code_size, header.heap_size);
+    if fields(&new_header) != fields(&header) {
+        return Err(format!("the header was {} and is now {}", header, new_header));
+    }
+    if let Some(at) = body.iter().zip(new_body).position(|(old, new)| old != new) {
+        return Err(format!("the byte at {:04x} was {:02x} and is now {:02x}", at, body[at], new_body[at]));
+    }
+    if new_body.len() != body.len() {
+        return Err(format!("the code and data were {} bytes and are now {}", body.len(), new_body.len()));
+    }
+    let new_labels = new_debug_info.map(|debug_info| debug_info.labels).unwrap_or_default();
+    if let Some((name, address)) = labels.iter().find(|&(name, address)| new_labels.get(name) != Some(address)) {
+        return Err(format!("the label {} at {:04x} didn't come back there", name, address));
+    }
+    if let Some(name) = new_labels.keys().find(|name| !labels.contains_key(*name)) {
+        return Err(format!("the label {} wasn't there before", name));
+    }
+    Ok(())
+}
+
+/* A jumptable entry at an address, as the address it sends execution to, the way vm disasm
+ * and vm hexdump show one when there are no labels to go by. */
+pub fn table_entry(address: i32, word: u32) -> String {
+    format!(".table {:04x}", address.wrapping_add(word as i32))
+}
+
+struct Listing<'a> {
+    text: String,
+    color: bool,
+    /* The labels that can go in the listing, by where they point: ones in the code on a word,
+     * and ones in the data or just past it anywhere. */
+    labels: BTreeMap<i32, Vec<&'a str>>,
+}
+
+impl<'a> Listing<'a> {
+    fn paint(&self, color: &str, text: &str) -> String {
+        match self.color {
+            true => format!("{}{}{}", color, text, RESET),
+            false => String::from(text),
+        }
+    }
+
+    fn line(&mut self, line: &str) {
+        self.text.push_str(line);
+        self.text.push('\n');
+    }
+
+    fn comment(&mut self, text: &str) {
+        let line = self.paint(COMMENT, &format!("# {}", text));
+        self.line(&line);
+    }
+
+    /* An instruction or directive, indented, with a comment lined up after it if there is one.
+     * symbolic says whether the operand is a label to paint as one. */
+    fn instruction(&mut self, mnemonic: &str, operand: &str, symbolic: bool, comment: Option<&str>) {
+        let mut line = format!("{:2$}{}", "", self.paint(MNEMONIC, mnemonic), INDENT);
+        let mut width = INDENT + mnemonic.len();
+        if !operand.is_empty() {
+            line.push(' ');
+            line.push_str(&if symbolic { self.paint(LABEL, operand) } else { String::from(operand) });
+            width += operand.len() + 1;
+        }
+        if let Some(comment) = comment {
+            line.push_str(&format!("{:1$}", "", COMMENT_COLUMN.max(width + 1) - width));
+            line.push_str(&self.paint(COMMENT, &format!("# {}", comment)));
+        }
+        self.line(&line);
+    }
+
+    /* The labels that point at an address, each on a line of its own. */
+    fn labels_at(&mut self, address: i32) {
+        let names = self.labels.get(&address).cloned().unwrap_or_default();
+        for name in names {
+            let line = self.paint(LABEL, &format!("{}:", name));
+            self.line(&line);
+        }
+    }
+
+    /* An address as the label at it or the closest one before it, if there is one. */
+    fn reference(&self, address: i32) -> Option<String> {
+        let (&at, names) = self.labels.range(..=address).next_back()?;
+        Some(match address - at {
+            0 => String::from(names[0]),
+            offset => format!("{}+{}", names[0], offset),
+        })
+    }
+
+    /* Each word with its address and bits, and the data in rows after it. */
+    fn words(&mut self, header: &Header, code: &[u8], data: &[u8]) {
+        self.comment(match header.endianness() {
+            Endianness::Big => "big-endian words",
+            Endianness::Little => "little-endian words",
+        });
+
+        let entries = analysis::table_entries(code);
+        for (i, word) in code.chunks(4).enumerate() {
+            let Ok(word) = <[u8; 4]>::try_from(word).map(u32::from_le_bytes) else {
+                self.line(&format!("{:04x}: {:02x?}", i * 4, word));
+                continue;
+            };
+            let text = match entries.get(i) {
+                Some(true) => table_entry(i as i32 * 4, word),
+                _ => isa::disassemble(word),
+            };
+            let (mnemonic, operands) = text.split_once(' ').unwrap_or((&text, ""));
+
+            let mut line = format!("{}  {}", self.paint(COMMENT, &format!("{:04x}: {:08x}", i * 4, word)), self.paint(MNEMONIC, mnemonic));
+            if !operands.is_empty() {
+                line.push(' ');
+                line.push_str(operands);
+            }
+            if i * 4 == header.entry as usize && header.entry != 0 {
+                line.push_str(&self.paint(COMMENT, "  # entry"));
+            }
+            self.line(&line);
+        }
+        for (i, bytes) in data.chunks(8).enumerate() {
+            self.line(&format!("{:04x}: {:02x?}", code.len() + i * 8, bytes));
+        }
+    }
+
+    /* The program as assembly that assembles back to it. */
+    fn source(&mut self, header: &Header, code: &[u8], data: &[u8], labels: &'a BTreeMap<String, i32>) {
+        let words = code.len() / 4 * 4;
+        let end = code.len() + data.len();
+        for (name, &address) in labels {
+            let in_code = address % 4 == 0 && (0..words as i32).contains(&address);
+            let in_data = (code.len() as i32..=end as i32).contains(&address);
+            if in_code || in_data {
+                self.labels.entry(address).or_default().push(name);
+            }
+        }
+
+        for (name, feature) in Header::FEATURE_NAMES {
+            if header.has(feature) {
+                self.instruction(".feature", name, false, None);
+            }
+        }
+        if header.entry != 0 {
+            let entry = self.reference(header.entry as i32);
+            self.instruction(".entry", &entry.clone().unwrap_or_else(|| format!("{}", header.entry)), entry.is_some(), None);
+        }
+        if header.stack_pointer != MEMORY_SIZE as u32 {
+            self.instruction(".sp", &format!("{:#x}", header.stack_pointer), false, None);
+        }
+        if let Some(heap_size) = header.heap_size {
+            self.instruction(".heap", &format!("{}", heap_size), false, None);
+        }
+
+        let entries = analysis::table_entries(code);
+        for (i, word) in code[..words].chunks(4).enumerate() {
+            let address = i as i32 * 4;
+            let word = u32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+            let comment = format!("{:04x}  {:08x}", address, word);
+            self.labels_at(address);
+
+            if entries[i] {
+                let target = address.checked_add(word as i32).filter(|_| word % 4 == 0).and_then(|target| self.reference(target));
+                match target {
+                    Some(target) => self.instruction(".table", &target, true, Some(&comment)),
+                    None => self.instruction(".word", &format!("{:#010x}", word), false, Some(&comment)),
+                }
+                continue;
+            }
+
+            let assembles = |instruction: &Instruction| isa::assemble_line(&format!("{}", instruction)) == Ok(word);
+            let Some(instruction) = Instruction::decode(word).filter(assembles) else {
+                self.instruction(".word", &format!("{:#010x}", word), false, Some(&comment));
+                continue;
+            };
+
+            /* An assert with no message has an offset of 0 rather than an address. */
+            let target = instruction.branch_offset()
+                .filter(|_| instruction != Instruction::Assert(0))
+                .and_then(|offset| address.checked_add(offset))
+                .and_then(|target| self.reference(target));
+            let text = format!("{}", instruction);
+            let (mnemonic, operands) = text.split_once(' ').unwrap_or((&text, ""));
+            match target {
+                Some(target) => self.instruction(mnemonic, &target, true, Some(&comment)),
+                None => self.instruction(mnemonic, operands, false, Some(&comment)),
+            }
+        }
+        if words < code.len() {
+            self.comment(&format!("{:04x}  {:02x?} (not a whole word)", words, &code[words..]));
+        }
+
+        if !data.is_empty() {
+            let line = self.paint(MNEMONIC, ".data");
+            self.line(&line);
+        }
+        let mut start = 0;
+        while start < data.len() {
+            let address = (code.len() + start) as i32;
+            self.labels_at(address);
+
+            let next_label = self.labels.range(address + 1..).next().map_or(usize::MAX, |(&at, _)| at as usize - code.len());
+            let stop = (start + BYTES_PER_LINE).min(data.len()).min(next_label);
+            let bytes = &data[start..stop];
+            let operand: Vec<String> = bytes.iter().map(|byte| format!("{:#04x}", byte)).collect();
+            let text: String = bytes.iter().map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' }).collect();
+            self.instruction(".byte", &operand.join(" "), false, Some(&format!("{:04x}  |{}|", address, text)));
+            start = stop;
+        }
+        self.labels_at(end as i32);
+    }
+}
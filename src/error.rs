@@ -0,0 +1,88 @@
+use alloc::string::String;
+use core::fmt;
+
+use crate::isa;
+
+/* Everything that can go wrong while running a program. Most failures are still plain messages;
+ * the ones callers might want to react to get their own variant. */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VmError {
+    Message(String),
+    ArithmeticOverflow { pc: i32 },
+    CallDepthExceeded { depth: usize, pc: i32 },
+    OutOfFuel { executed: u64, pc: i32 },
+    ReturnStackOverflow { pc: i32 },
+    OutOfBounds { address: i32, size: usize },
+    PcOutOfRange { pc: i32 },
+    StackOverflow { pc: i32 },
+    /* The interrupt flag was set. Nothing was half done, so running again carries on. */
+    Interrupted { pc: i32 },
+    Timeout { millis: u64, pc: i32 },
+    /* Every context is waiting in join for another one. */
+    Deadlock { pc: i32 },
+    /* The program exited with code but an on_exit hook stopped it. Running again carries on
+     * after the exit. */
+    Paused { code: i32, pc: i32 },
+    /* An assert popped a 0. message is the one it points at, empty if it has none. */
+    AssertionFailed { message: String, pc: i32 },
+    /* The instruction word opcode is in a group VmConfig::allowed_ops leaves out. */
+    PermissionDenied { opcode: u32, pc: i32 },
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VmError::Message(message) => write!(f, "{}", message),
+            VmError::ArithmeticOverflow { pc } => {
+                write!(f, "Arithmetic overflow at pc {:#x}.", pc)
+            },
+            VmError::CallDepthExceeded { depth, pc } => {
+                write!(f, "Call depth {} exceeded the limit at pc {:#x}.", depth, pc)
+            },
+            VmError::OutOfFuel { executed, pc } => {
+                write!(f, "Ran out of fuel after {} instructions at pc {:#x}.", executed, pc)
+            },
+            VmError::ReturnStackOverflow { pc } => {
+                write!(f, "Return stack overflow at pc {:#x}.", pc)
+            },
+            VmError::OutOfBounds { address, size } => {
+                write!(f, "Memory access out of bounds: {} bytes at {:#x}.", size, address)
+            },
+            VmError::PcOutOfRange { pc } => {
+                write!(f, "Program counter {:#x} is outside the code.", pc)
+            },
+            VmError::StackOverflow { pc } => {
+                write!(f, "Stack overflow past its limit at pc {:#x}.", pc)
+            },
+            VmError::Interrupted { pc } => {
+                write!(f, "Interrupted at pc {:#x}.", pc)
+            },
+            VmError::Timeout { millis, pc } => {
+                write!(f, "Timed out after {} ms at pc {:#x}.", millis, pc)
+            },
+            VmError::Deadlock { pc } => {
+                write!(f, "Deadlock: every context is waiting to join another, at pc {:#x}.", pc)
+            },
+            VmError::Paused { code, pc } => {
+                write!(f, "Paused instead of exiting with code {} at pc {:#x}.", code, pc)
+            },
+            VmError::AssertionFailed { message, pc } if message.is_empty() => {
+                write!(f, "Assertion failed at pc {:#x}.", pc)
+            },
+            VmError::AssertionFailed { message, pc } => {
+                write!(f, "Assertion failed at pc {:#x}: {}", pc, message)
+            },
+            VmError::PermissionDenied { opcode, pc } => {
+                write!(f, "{} isn't allowed, at pc {:#x}.", isa::disassemble(*opcode), pc)
+            },
+        }
+    }
+}
+
+impl core::error::Error for VmError {}
+
+impl From<String> for VmError {
+    fn from(message: String) -> VmError {
+        VmError::Message(message)
+    }
+}
@@ -0,0 +1,288 @@
+/* Watch expressions: little C-style expressions over the state of a machine, like
+ *
+ *     mem[0x0ffc] == 42 && exit == 0
+ *
+ * Numbers can be decimal, 0x hex, 0o octal or 0b binary. The names are exit (once the program has
+ * exited), sp, pc, instructions (executed so far) and depth (calls that haven't returned), and
+ * mem[addr] and byte[addr] read a word or a byte of memory, and stack[n] reads the word n slots
+ * down from the top of the stack, so stack[0] is the top. The operators are C's, with C's
+ * precedence; comparisons and logic give 1 or 0. Everything is done in 64 bits. */
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::VirtualMachine;
+
+/* Everything an expression can look at. */
+pub trait State {
+    /* One of the names, or an error if it isn't one or has no value yet. */
+    fn variable(&self, name: &str) -> Result<i64, String>;
+
+    fn word(&self, address: i64) -> Result<i64, String>;
+
+    fn byte(&self, address: i64) -> Result<i64, String>;
+
+    /* The word n slots down from the top of the stack. */
+    fn slot(&self, n: i64) -> Result<i64, String>;
+}
+
+impl State for VirtualMachine {
+    fn variable(&self, name: &str) -> Result<i64, String> {
+        match name {
+            "exit" => self.exit_code()
+                .map(|code| code as i64)
+                .ok_or_else(|| String::from("exit: the program hasn't exited")),
+            "sp" => Ok(self.stack_pointer() as i64),
+            "pc" => Ok(self.program_counter() as i64),
+            "instructions" => Ok(self.instruction_count() as i64),
+            "depth" => Ok(self.call_stack().len() as i64),
+            _ => Err(format!("unknown name {}", name)),
+        }
+    }
+
+    fn word(&self, address: i64) -> Result<i64, String> {
+        i32::try_from(address).ok()
+            .and_then(|address| self.word_at(address))
+            .ok_or_else(|| format!("mem[{:#x}] is out of range", address))
+    }
+
+    fn byte(&self, address: i64) -> Result<i64, String> {
+        i32::try_from(address).ok()
+            .and_then(|address| self.memory().read_u8(address).ok())
+            .map(|byte| byte as i64)
+            .ok_or_else(|| format!("byte[{:#x}] is out of range", address))
+    }
+
+    fn slot(&self, n: i64) -> Result<i64, String> {
+        n.checked_mul(self.word_bytes() as i64)
+            .and_then(|offset| offset.checked_add(self.stack_pointer() as i64))
+            .and_then(|address| i32::try_from(address).ok())
+            .and_then(|address| self.word_at(address))
+            .ok_or_else(|| format!("stack[{}] is out of range", n))
+    }
+}
+
+/* A parsed expression. */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    Number(i64),
+    Variable(String),
+    Word(Box<Expr>),
+    Byte(Box<Expr>),
+    Slot(Box<Expr>),
+    Unary(&'static str, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Number(i64),
+    Name(String),
+    Symbol(&'static str),
+}
+
+/* Longest first, so << isn't read as two <s. */
+const SYMBOLS: [&str; 24] = [
+    "||", "&&", "==", "!=", "<=", ">=", "<<", ">>",
+    "<", ">", "+", "-", "*", "/", "%", "&", "|", "^", "!", "~", "(", ")", "[", "]",
+];
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = text.trim_start();
+
+    while let Some(c) = rest.chars().next() {
+        if c.is_ascii_digit() {
+            let end = rest.find(|c: char| !c.is_ascii_alphanumeric()).unwrap_or(rest.len());
+            let literal = &rest[..end];
+            let number = if let Some(hex) = literal.strip_prefix("0x").or_else(|| literal.strip_prefix("0X")) {
+                i64::from_str_radix(hex, 16)
+            } else if let Some(binary) = literal.strip_prefix("0b").or_else(|| literal.strip_prefix("0B")) {
+                i64::from_str_radix(binary, 2)
+            } else if let Some(octal) = literal.strip_prefix("0o").or_else(|| literal.strip_prefix("0O")) {
+                i64::from_str_radix(octal, 8)
+            } else {
+                literal.parse::<i64>()
+            };
+
+            tokens.push(Token::Number(number.map_err(|_| format!("bad number {}", literal))?));
+            rest = &rest[end..];
+        } else if c.is_ascii_alphabetic() || c == '_' || c == '.' {
+            /* Dots are for the assembler's labels, as in .loop. */
+            let end = rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.')).unwrap_or(rest.len());
+            tokens.push(Token::Name(String::from(&rest[..end])));
+            rest = &rest[end..];
+        } else {
+            let Some(symbol) = SYMBOLS.iter().find(|symbol| rest.starts_with(**symbol)) else {
+                return Err(format!("unexpected {}", c));
+            };
+
+            tokens.push(Token::Symbol(symbol));
+            rest = &rest[symbol.len()..];
+        }
+
+        rest = rest.trim_start();
+    }
+
+    Ok(tokens)
+}
+
+/* How tightly a binary operator binds. Higher goes first. The little language (see the lang
+ * module) uses the same table. */
+pub(crate) fn precedence(symbol: &str) -> Option<u8> {
+    match symbol {
+        "||" => Some(1),
+        "&&" => Some(2),
+        "|" => Some(3),
+        "^" => Some(4),
+        "&" => Some(5),
+        "==" | "!=" => Some(6),
+        "<" | ">" | "<=" | ">=" => Some(7),
+        "<<" | ">>" => Some(8),
+        "+" | "-" => Some(9),
+        "*" | "/" | "%" => Some(10),
+        _ => None,
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn expect(&mut self, symbol: &str) -> Result<(), String> {
+        match self.next() {
+            Some(Token::Symbol(s)) if s == symbol => Ok(()),
+            _ => Err(format!("expected {}", symbol)),
+        }
+    }
+
+    /* Binary operators, by precedence climbing. */
+    fn binary(&mut self, min_precedence: u8) -> Result<Expr, String> {
+        let mut left = self.unary()?;
+
+        while let Some(&Token::Symbol(symbol)) = self.peek() {
+            let Some(precedence) = precedence(symbol).filter(|&p| p >= min_precedence) else {
+                break;
+            };
+
+            self.position += 1;
+            let right = self.binary(precedence + 1)?;
+            left = Expr::Binary(symbol, Box::new(left), Box::new(right));
+        }
+
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        match self.peek() {
+            Some(&Token::Symbol(symbol)) if matches!(symbol, "-" | "!" | "~") => {
+                self.position += 1;
+                Ok(Expr::Unary(symbol, Box::new(self.unary()?)))
+            },
+            _ => self.primary(),
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Number(n)) => Ok(Expr::Number(n)),
+            Some(Token::Name(name)) if name == "mem" || name == "byte" || name == "stack" => {
+                self.expect("[")?;
+                let index = Box::new(self.binary(1)?);
+                self.expect("]")?;
+                Ok(match name.as_str() {
+                    "mem" => Expr::Word(index),
+                    "byte" => Expr::Byte(index),
+                    _ => Expr::Slot(index),
+                })
+            },
+            Some(Token::Name(name)) => Ok(Expr::Variable(name)),
+            Some(Token::Symbol("(")) => {
+                let inner = self.binary(1)?;
+                self.expect(")")?;
+                Ok(inner)
+            },
+            Some(Token::Symbol(symbol)) => Err(format!("unexpected {}", symbol)),
+            None => Err(String::from("unexpected end of expression")),
+        }
+    }
+}
+
+impl Expr {
+    pub fn parse(text: &str) -> Result<Expr, String> {
+        let mut parser = Parser { tokens: tokenize(text)?, position: 0 };
+        let expr = parser.binary(1)?;
+
+        match parser.peek() {
+            None => Ok(expr),
+            Some(Token::Number(n)) => Err(format!("unexpected {}", n)),
+            Some(Token::Name(name)) => Err(format!("unexpected {}", name)),
+            Some(Token::Symbol(symbol)) => Err(format!("unexpected {}", symbol)),
+        }
+    }
+
+    pub fn evaluate(&self, state: &impl State) -> Result<i64, String> {
+        match self {
+            Expr::Number(n) => Ok(*n),
+            Expr::Variable(name) => state.variable(name),
+            Expr::Word(address) => state.word(address.evaluate(state)?),
+            Expr::Byte(address) => state.byte(address.evaluate(state)?),
+            Expr::Slot(n) => state.slot(n.evaluate(state)?),
+            Expr::Unary(symbol, operand) => {
+                let value = operand.evaluate(state)?;
+                Ok(match *symbol {
+                    "-" => value.wrapping_neg(),
+                    "!" => (value == 0) as i64,
+                    _ => !value,
+                })
+            },
+            /* && and || don't look at the right side unless they need it, so
+             * `exit == 0 || mem[sp] == 1` is fine on a program that never exited. */
+            Expr::Binary("&&", left, right) => {
+                Ok((left.evaluate(state)? != 0 && right.evaluate(state)? != 0) as i64)
+            },
+            Expr::Binary("||", left, right) => {
+                Ok((left.evaluate(state)? != 0 || right.evaluate(state)? != 0) as i64)
+            },
+            Expr::Binary(symbol, left, right) => {
+                let (left, right) = (left.evaluate(state)?, right.evaluate(state)?);
+                if matches!(*symbol, "/" | "%") && right == 0 {
+                    return Err(String::from("division by zero"));
+                }
+
+                Ok(match *symbol {
+                    "|" => left | right,
+                    "^" => left ^ right,
+                    "&" => left & right,
+                    "==" => (left == right) as i64,
+                    "!=" => (left != right) as i64,
+                    "<" => (left < right) as i64,
+                    ">" => (left > right) as i64,
+                    "<=" => (left <= right) as i64,
+                    ">=" => (left >= right) as i64,
+                    "<<" => left.wrapping_shl(right as u32),
+                    ">>" => left.wrapping_shr(right as u32),
+                    "+" => left.wrapping_add(right),
+                    "-" => left.wrapping_sub(right),
+                    "*" => left.wrapping_mul(right),
+                    "/" => left.wrapping_div(right),
+                    _ => left.wrapping_rem(right),
+                })
+            },
+        }
+    }
+}
@@ -0,0 +1,241 @@
+/* C API, for embedding the VM in C, C++ or anything else that can call C, Python's ctypes
+ * say. Build a shared library with
+ *
+ *     cargo rustc --release --lib --crate-type cdylib --features capi
+ *
+ * and include include/vm.h, which declares everything here:
+ *
+ *     vm_t *vm = vm_create();
+ *     if (vm_load(vm, bytes, len) != 0)
+ *         fprintf(stderr, "%s\n", vm_last_error(vm));
+ *     vm_register_io(vm, write_output, read_line, user);
+ *     int32_t status;
+ *     while ((status = vm_step(vm)) == VM_RUNNING || status == VM_BREAKPOINT) {}
+ *     printf("exited with %d\n", vm_exit_code(vm));
+ *     vm_destroy(vm);
+ *
+ * These signatures are stable: functions can be added, but the ones here keep their meaning.
+ * Every function takes a handle vm_create gave back that hasn't been destroyed, which is what
+ * makes them safe to call; a null handle is an error rather than a crash. A handle mustn't be
+ * used from two threads at once. */
+
+/* What each function needs to be safe is in its comment, which clippy doesn't read. */
+#![allow(clippy::missing_safety_doc)]
+
+use std::ffi::{c_char, c_void, CString};
+use std::ptr;
+use std::slice;
+
+use crate::io::{Input, Output};
+use crate::{StepResult, VirtualMachine, VmConfig};
+
+/* What vm_step gives back. Anything else that fails is -1 too. */
+pub const VM_RUNNING: i32 = 0;
+pub const VM_EXITED: i32 = 1;
+/* The instruction was a brk; stepping again carries on after it. */
+pub const VM_BREAKPOINT: i32 = 2;
+pub const VM_FAULT: i32 = -1;
+/* Nothing has been loaded yet. */
+pub const VM_NO_PROGRAM: i32 = -2;
+
+/* Called with each chunk of output the program writes. */
+pub type WriteCallback = unsafe extern "C" fn(user: *mut c_void, bytes: *const u8, len: usize);
+/* Called for a line of input: write up to cap bytes of it into buf and return how many, or -1
+ * at the end of the input. */
+pub type ReadLineCallback = unsafe extern "C" fn(user: *mut c_void, buf: *mut u8, cap: usize) -> isize;
+
+/* The longest line a read callback is asked for. */
+const LINE_MAX: usize = 4096;
+
+/* The callbacks from vm_register_io and the pointer they get handed. */
+#[derive(Clone, Copy)]
+struct CallbackIo {
+    write: Option<WriteCallback>,
+    read_line: Option<ReadLineCallback>,
+    user: *mut c_void,
+}
+
+/* The callbacks are only called from inside vm_step, on whatever thread that was called on, so
+ * keeping them safe is the caller's side of the bargain. */
+unsafe impl Send for CallbackIo {}
+
+impl Output for CallbackIo {
+    fn write_all(&mut self, bytes: &[u8]) -> Result<(), String> {
+        if let Some(write) = self.write {
+            unsafe { write(self.user, bytes.as_ptr(), bytes.len()) };
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+impl Input for CallbackIo {
+    fn read_line(&mut self, buf: &mut String) -> Result<usize, String> {
+        let Some(read_line) = self.read_line else {
+            return Ok(0);
+        };
+
+        let mut line = vec![0u8; LINE_MAX];
+        let n = unsafe { read_line(self.user, line.as_mut_ptr(), line.len()) };
+        if n < 0 {
+            return Ok(0);
+        }
+        line.truncate(n as usize);
+
+        let mut text = String::from_utf8_lossy(&line).into_owned();
+        if !text.ends_with('\n') {
+            text.push('\n');
+        }
+        buf.push_str(&text);
+        Ok(text.len())
+    }
+}
+
+/* What a vm_t points at. */
+pub struct CVm {
+    vm: Option<VirtualMachine>,
+    io: Option<CallbackIo>,
+    exit_code: i32,
+    error: CString,
+}
+
+impl CVm {
+    fn fail(&mut self, message: &str) -> i32 {
+        self.error = CString::new(message.replace('\0', " ")).unwrap_or_default();
+        -1
+    }
+
+    fn hook_up(&mut self) {
+        if let (Some(vm), Some(io)) = (&mut self.vm, self.io) {
+            vm.set_output(Box::new(io));
+            vm.set_input(Box::new(io));
+        }
+    }
+}
+
+/* A machine with nothing loaded. Free it with vm_destroy. */
+#[no_mangle]
+pub extern "C" fn vm_create() -> *mut CVm {
+    Box::into_raw(Box::new(CVm { vm: None, io: None, exit_code: 0, error: CString::default() }))
+}
+
+/* Load the contents of a .v file, replacing whatever was loaded before. 0 if it loaded, -1 if
+ * not, with vm_last_error saying why.
+ * bytes has to point at len readable bytes. */
+#[no_mangle]
+pub unsafe extern "C" fn vm_load(vm: *mut CVm, bytes: *const u8, len: usize) -> i32 {
+    let Some(vm) = vm.as_mut() else {
+        return -1;
+    };
+    if bytes.is_null() {
+        return vm.fail("No program given.");
+    }
+
+    match VirtualMachine::from_bytes(slice::from_raw_parts(bytes, len).to_vec(), VmConfig::default()) {
+        Ok(loaded) => {
+            vm.vm = Some(loaded);
+            vm.exit_code = 0;
+            vm.hook_up();
+            0
+        },
+        Err(err) => vm.fail(&err),
+    }
+}
+
+/* Send the program's output to write and take its input from read_line, either of which can
+ * be null to throw the output away or give it no input. user is passed to both. Until this is
+ * called, a program uses the process's stdin and stdout.
+ * The callbacks have to be safe to call with user for as long as the machine is used. */
+#[no_mangle]
+pub unsafe extern "C" fn vm_register_io(vm: *mut CVm, write: Option<WriteCallback>, read_line: Option<ReadLineCallback>, user: *mut c_void) -> i32 {
+    let Some(vm) = vm.as_mut() else {
+        return -1;
+    };
+
+    vm.io = Some(CallbackIo { write, read_line, user });
+    vm.hook_up();
+    0
+}
+
+/* Run one instruction: VM_RUNNING, VM_BREAKPOINT if it was a brk, VM_EXITED once the program
+ * has exited, VM_FAULT if it stopped with an error (see vm_last_error), or VM_NO_PROGRAM. */
+#[no_mangle]
+pub unsafe extern "C" fn vm_step(vm: *mut CVm) -> i32 {
+    let Some(vm) = vm.as_mut() else {
+        return VM_FAULT;
+    };
+    let Some(machine) = &mut vm.vm else {
+        return VM_NO_PROGRAM;
+    };
+
+    match machine.step() {
+        Ok(StepResult::Running) => VM_RUNNING,
+        Ok(StepResult::Breakpoint) => VM_BREAKPOINT,
+        Ok(StepResult::Exited(code)) => {
+            vm.exit_code = code;
+            VM_EXITED
+        },
+        Err(err) => vm.fail(&err.to_string()),
+    }
+}
+
+/* Copy len bytes of the machine's memory from address into buf. 0 if they're all in memory,
+ * -1 if not.
+ * buf has to point at len writable bytes. */
+#[no_mangle]
+pub unsafe extern "C" fn vm_read_mem(vm: *mut CVm, address: u32, buf: *mut u8, len: usize) -> i32 {
+    let Some(vm) = vm.as_mut() else {
+        return -1;
+    };
+    let Some(machine) = &vm.vm else {
+        return vm.fail("No program is loaded.");
+    };
+
+    let memory = i32::try_from(address).ok().and_then(|address| machine.memory().slice(address, len).ok());
+    match memory {
+        Some(memory) if !buf.is_null() => {
+            ptr::copy_nonoverlapping(memory.as_ptr(), buf, len);
+            0
+        },
+        _ => vm.fail(&format!("There aren't {} bytes of memory at {:#x}.", len, address)),
+    }
+}
+
+/* The code the program exited with, 0 until it has. */
+#[no_mangle]
+pub unsafe extern "C" fn vm_exit_code(vm: *const CVm) -> i32 {
+    vm.as_ref().map_or(0, |vm| vm.exit_code)
+}
+
+/* Where the program is and where its stack is, or -1 with nothing loaded. */
+#[no_mangle]
+pub unsafe extern "C" fn vm_program_counter(vm: *const CVm) -> i32 {
+    vm.as_ref().and_then(|vm| vm.vm.as_ref()).map_or(-1, |vm| vm.program_counter())
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn vm_stack_pointer(vm: *const CVm) -> i32 {
+    vm.as_ref().and_then(|vm| vm.vm.as_ref()).map_or(-1, |vm| vm.stack_pointer())
+}
+
+/* Why the last call that failed did, as a string that lasts until another one fails or the
+ * machine is destroyed; empty if nothing has failed. */
+#[no_mangle]
+pub unsafe extern "C" fn vm_last_error(vm: *const CVm) -> *const c_char {
+    match vm.as_ref() {
+        Some(vm) => vm.error.as_ptr(),
+        None => c"No machine given.".as_ptr(),
+    }
+}
+
+/* Free a machine from vm_create. Null is fine.
+ * It can't be used again after. */
+#[no_mangle]
+pub unsafe extern "C" fn vm_destroy(vm: *mut CVm) {
+    if !vm.is_null() {
+        drop(Box::from_raw(vm));
+    }
+}
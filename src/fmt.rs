@@ -0,0 +1,88 @@
+/* The assembler's source put in one shape, for vm fmt: each label on a line of its own at the
+ * left, instructions indented under them, comments after code lined up in a column, and no more
+ * than one blank line in a row. Lines are read with the assembler's own parser, so what comes
+ * out assembles to the same code as what went in:
+ *
+ *     # Count down.
+ *     main:
+ *             push 10         # n
+ *     loop:
+ *             print
+ *
+ * The operands are put back with one space between them, as the assembler splits them, except
+ * that a .equ keeps the comma after its name; a string operand is kept as it was written. The
+ * directives that shape the file rather than adding to it, .data, .macro, .endm, .include, .if,
+ * .else and .endif, go at the left like labels. A line the parser can't make sense of, such as
+ * one in a macro with a label made with \@, is only trimmed. */
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::asm::{parse_line, strip_comment};
+
+/* Where instructions start. */
+pub(crate) const INDENT: usize = 8;
+
+/* Where a comment after code starts, unless the code runs past it. */
+pub(crate) const COMMENT_COLUMN: usize = 32;
+
+/* The directives that go at the left. */
+const OUTDENTED: [&str; 7] = [".data", ".macro", ".endm", ".include", ".if", ".else", ".endif"];
+
+/* The source formatted. It always ends in a newline, unless there's nothing in it. */
+pub fn format(source: &str) -> String {
+    let mut lines: Vec<String> = Vec::new();
+
+    for (i, text) in source.lines().enumerate() {
+        let code = strip_comment(text);
+        let comment = text[code.len()..].trim_end();
+
+        let mut formatted = match parse_line(i + 1, code) {
+            Ok((label, instruction)) => {
+                let instruction = instruction.map(|line| {
+                    let operands = match (line.mnemonic, line.operands.split_first()) {
+                        (".equ", Some((name, value))) if !value.is_empty() => format!("{}, {}", name, value.join(" ")),
+                        _ => line.operands.join(" "),
+                    };
+                    let indent = if OUTDENTED.contains(&line.mnemonic) { 0 } else { INDENT };
+                    let text = format!("{:indent$}{} {}", "", line.mnemonic, operands, indent = indent);
+                    String::from(text.trim_end())
+                });
+
+                match (label, instruction) {
+                    (Some(label), Some(instruction)) => {
+                        lines.push(format!("{}:", label));
+                        instruction
+                    },
+                    (Some(label), None) => format!("{}:", label),
+                    (None, Some(instruction)) => instruction,
+                    /* A comment on its own stays at the left if it was there. */
+                    (None, None) if !comment.is_empty() && text.starts_with(char::is_whitespace) => format!("{:1$}", "", INDENT),
+                    (None, None) => String::new(),
+                }
+            },
+            Err(_) => String::from(code.trim_end()),
+        };
+
+        if !comment.is_empty() {
+            let column = if formatted.trim().is_empty() { formatted.len() } else { COMMENT_COLUMN.max(formatted.len() + 1) };
+            formatted = format!("{:2$}{}", formatted, comment, column);
+        }
+
+        /* One blank line at most, and none at the start. */
+        if !formatted.is_empty() || lines.last().is_some_and(|last| !last.is_empty()) {
+            lines.push(formatted);
+        }
+    }
+
+    while lines.last().is_some_and(|last| last.is_empty()) {
+        lines.pop();
+    }
+
+    let mut out = lines.join("\n");
+    if !out.is_empty() {
+        out.push('\n');
+    }
+    out
+}
@@ -0,0 +1,242 @@
+/* Fuzzing for `vm fuzz` and the cargo-fuzz target in fuzz/. Untrusted bytecode is allowed to
+ * fault, but never to panic: every way a program can go wrong should come back from run as a
+ * VmError. The fuzzer throws random instruction streams at the VM, with a fuel limit so loops
+ * end and with its I/O captured, and catches any panic. A program that panics is then shrunk
+ * down to as little as still panics the same way, a word at a time and then an operand at a
+ * time, so what comes out is the few instructions that actually matter. */
+
+use std::io::Cursor;
+use std::panic::{self, AssertUnwindSafe};
+use std::time::{Duration, Instant};
+
+use crate::harness::SharedBuffer;
+use crate::isa::{BinaryOp, Condition, EofMode, Instruction, PerfCounter, PrintFormat, PrintSpec, UnaryOp, ZeroCondition};
+use crate::rng::Rng;
+use crate::{Header, VirtualMachine, VmConfig};
+
+/* How many instructions a fuzzed program gets before it's stopped. */
+const FUEL: u64 = 10_000;
+
+/* The longest program the fuzzer writes, in words. */
+const MAX_WORDS: u64 = 64;
+
+/* A program that panicked the VM, and what the panic said. */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Crash {
+    pub program: Vec<u32>,
+    /* Header features it ran with. */
+    pub features: u32,
+    pub input: Vec<u8>,
+    pub message: String,
+}
+
+/* How a fuzzing session went. */
+#[derive(Debug, Clone)]
+pub struct FuzzReport {
+    pub runs: u64,
+    /* The first panic found, already minimized. */
+    pub crash: Option<Crash>,
+}
+
+/* Run the contents of a .v file to the end, with I/O captured and a fuel limit. Panics if the
+ * VM does, which is what the cargo-fuzz target wants. */
+pub fn execute(file: &[u8], input: &[u8]) {
+    let config = VmConfig { fuel: Some(FUEL), ..VmConfig::default() };
+    let Ok(mut vm) = VirtualMachine::from_bytes(file.to_vec(), config) else {
+        return;
+    };
+
+    vm.set_input(Box::new(Cursor::new(input.to_vec())));
+    vm.set_output(Box::new(SharedBuffer::new()));
+    vm.set_diagnostics(Box::new(SharedBuffer::new()));
+    let _ = vm.run();
+}
+
+/* Run a program and catch any panic, returning what it said. */
+pub fn check(program: &[u32], features: u32, input: &[u8]) -> Option<String> {
+    let code: Vec<u8> = program.iter().flat_map(|word| word.to_le_bytes()).collect();
+    let image = Header::new(features).image(&code);
+
+    panic::catch_unwind(AssertUnwindSafe(|| execute(&image, input)))
+        .err()
+        .map(|payload| {
+            if let Some(message) = payload.downcast_ref::<&str>() {
+                String::from(*message)
+            } else if let Some(message) = payload.downcast_ref::<String>() {
+                message.clone()
+            } else {
+                String::from("(panic without a message)")
+            }
+        })
+}
+
+/* Small numbers and the edges are where the bugs are. */
+fn operand(rng: &mut Rng) -> i32 {
+    match rng.below(4) {
+        0 => rng.below(9) as i32 - 4,
+        1 => (rng.below(16) as i32 - 8) * 4,
+        2 => [i32::MIN, i32::MAX, -1 << 19, (1 << 19) - 1, -1 << 27, (1 << 27) - 1][rng.below(6) as usize],
+        _ => rng.next_u64() as i32,
+    }
+}
+
+fn pick<T: Copy>(rng: &mut Rng, items: &[T]) -> T {
+    items[rng.below(items.len() as u64) as usize]
+}
+
+/* One instruction, mostly well formed so programs get past their first word, sometimes just
+ * any 32 bits. */
+fn random_word(rng: &mut Rng) -> u32 {
+    let instruction = match rng.below(16) {
+        0 => return rng.next_u64() as u32,
+        1 => Instruction::Exit(rng.below(4) as u32),
+        2 => Instruction::Swap { from: operand(rng), to: operand(rng) },
+        3 => {
+            let max = rng.below(8) as u32;
+            let input = Instruction::Input { eof: pick(rng, &EofMode::ALL), retry: rng.below(2) == 1 };
+            pick(rng, &[Instruction::Nop, input, Instruction::StInput(max), Instruction::Debug { bytes: 0, binary: false }])
+        },
+        4 => Instruction::Pop(rng.below(16) as u32 * 4),
+        5 => Instruction::Binary(pick(rng, &BinaryOp::ALL)),
+        6 => Instruction::Cmp(pick(rng, &Condition::ALL)),
+        7 => Instruction::Unary(pick(rng, &UnaryOp::ALL)),
+        8 => Instruction::StPrint(operand(rng)),
+        9 => {
+            let (offset, bytes) = (operand(rng), rng.below(4) as u32 * 4);
+            pick(rng, &[Instruction::Call(offset), Instruction::TailCall(offset), Instruction::Goto(offset), Instruction::Return(bytes)])
+        },
+        /* binary_if only has room for the first eight conditions. */
+        10 => Instruction::BinaryIf(pick(rng, &Condition::ALL), operand(rng)),
+        11 => Instruction::UnaryIf(pick(rng, &ZeroCondition::ALL), operand(rng)),
+        12 => {
+            let offset = operand(rng);
+            let bytes = rng.below(16) as u32;
+            let counter = pick(rng, &PerfCounter::ALL);
+            pick(rng, &[Instruction::StrLen(offset), Instruction::StrCat, Instruction::StrCmp, Instruction::ReadFile, Instruction::WriteFile(bytes), Instruction::Arg, Instruction::GetEnv, Instruction::Clock, Instruction::Cycles, Instruction::Rand, Instruction::Load, Instruction::Store, Instruction::Spawn(offset), Instruction::Yield, Instruction::Join, Instruction::Cas, Instruction::FetchAdd, Instruction::Lock, Instruction::Unlock, Instruction::StPrintN,
+                Instruction::PushByte, Instruction::PushHalf, Instruction::PopByte { signed: true }, Instruction::PopHalf { signed: false }, Instruction::JumpI, Instruction::JumpTable(bytes), Instruction::Lea(offset),
+                Instruction::Pick(bytes), Instruction::Roll(bytes), Instruction::Drop(bytes), Instruction::Atoi, Instruction::Itoa,
+                Instruction::Brk, Instruction::Assert(offset), Instruction::StackDepth,
+                Instruction::PerfCtr(counter)])
+        },
+        13 => Instruction::Dup(operand(rng)),
+        14 => {
+            let (offset, format) = (operand(rng), pick(rng, &PrintFormat::ALL));
+            let spec = PrintSpec { format, unsigned: rng.below(2) == 0, width: rng.below(40) as u8, zero_pad: rng.below(2) == 0, newline: rng.below(2) == 0 };
+            pick(rng, &[Instruction::Print(offset, format), Instruction::PrintF(spec)])
+        },
+        _ => Instruction::Push(operand(rng)),
+    };
+
+    instruction.encode()
+}
+
+/* A few lines of input: numbers in the forms input takes, junk, and text that isn't ASCII. */
+fn random_input(rng: &mut Rng) -> Vec<u8> {
+    let mut input = Vec::new();
+    for _ in 0..rng.below(4) {
+        let line: &[u8] = pick(rng, &[b"0", b"-1", b"0x7fffffff", b"-0x10", b"0o17", b"0b101", b"99999999999999999999", b"0x", b"abc", "héllo wörld".as_bytes(), b"\xff\xfe", b""]);
+        input.extend_from_slice(line);
+        input.push(b'\n');
+    }
+    input
+}
+
+fn random_program(rng: &mut Rng) -> Vec<u32> {
+    (0..1 + rng.below(MAX_WORDS)).map(|_| random_word(rng)).collect()
+}
+
+/* The same instruction with its operand pulled towards zero, if it has one. */
+fn simpler(word: u32) -> Option<u32> {
+    let operand = word & 0x0fff_ffff;
+    if operand == 0 {
+        return None;
+    }
+
+    let opcode = word & 0xf000_0000;
+    Some(opcode | (operand / 2))
+}
+
+/* Shrink a crashing program for as long as it still panics with the same message: first drop
+ * runs of words, halving the run length down to one word, then turn single words into nops,
+ * then shrink operands. */
+pub fn minimize(crash: Crash) -> Crash {
+    let still_crashes = |program: &[u32], input: &[u8]| {
+        check(program, crash.features, input).as_deref() == Some(crash.message.as_str())
+    };
+    let mut program = crash.program.clone();
+    let mut input = crash.input.clone();
+
+    if !input.is_empty() && still_crashes(&program, &[]) {
+        input.clear();
+    }
+
+    let mut run = program.len().div_ceil(2);
+    while run > 0 {
+        let mut start = 0;
+        while start < program.len() {
+            let end = (start + run).min(program.len());
+            let mut shorter = program.clone();
+            shorter.drain(start..end);
+
+            if !shorter.is_empty() && still_crashes(&shorter, &input) {
+                program = shorter;
+            } else {
+                start += run;
+            }
+        }
+        run /= 2;
+    }
+
+    let nop = Instruction::Nop.encode();
+    for i in 0..program.len() {
+        if program[i] == nop {
+            continue;
+        }
+
+        let mut replaced = program.clone();
+        replaced[i] = nop;
+        if still_crashes(&replaced, &input) {
+            program = replaced;
+        }
+    }
+
+    for i in 0..program.len() {
+        while let Some(word) = simpler(program[i]) {
+            let mut replaced = program.clone();
+            replaced[i] = word;
+            if !still_crashes(&replaced, &input) {
+                break;
+            }
+            program = replaced;
+        }
+    }
+
+    Crash { program, input, ..crash }
+}
+
+/* Run random programs until time runs out or one of them panics. */
+pub fn fuzz(seed: u64, duration: Duration) -> FuzzReport {
+    let mut rng = Rng::new(seed);
+    let start = Instant::now();
+    let mut runs = 0;
+
+    /* The default hook would print every panic on the way to catching it. */
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| ()));
+
+    let mut crash = None;
+    while crash.is_none() && start.elapsed() < duration {
+        let program = random_program(&mut rng);
+        let features = pick(&mut rng, &[0, Header::WORDS_64, Header::DUAL_STACK, Header::WORDS_64 | Header::DUAL_STACK,
+            Header::LITTLE_ENDIAN, Header::WORDS_64 | Header::LITTLE_ENDIAN]);
+        let input = random_input(&mut rng);
+        runs += 1;
+
+        if let Some(message) = check(&program, features, &input) {
+            crash = Some(minimize(Crash { program, features, input, message }));
+        }
+    }
+
+    panic::set_hook(hook);
+    FuzzReport { runs, crash }
+}
@@ -0,0 +1,527 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{self, Cursor, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::asm;
+use crate::disasm;
+use crate::expr::Expr;
+use crate::pool::VmPool;
+use crate::{Header, VirtualMachine, VmConfig, VmError};
+
+/* A Write that keeps everything in memory so it can be looked at after a run. Clones share the
+ * same buffer, so hand one to the VM and keep the other. */
+#[derive(Debug, Clone, Default)]
+pub struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl SharedBuffer {
+    pub fn new() -> SharedBuffer {
+        SharedBuffer::default()
+    }
+
+    /* Everything written so far. */
+    pub fn contents(&self) -> Vec<u8> {
+        self.0.lock().expect("output buffer poisoned").clone()
+    }
+
+    /* Everything written so far, as text. */
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.contents()).into_owned()
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().expect("output buffer poisoned").extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/* What happened when a program was run with its I/O captured. */
+#[derive(Debug)]
+pub struct CapturedRun {
+    pub result: Result<i32, VmError>,
+    pub stdout: String,
+    pub instructions: u64,
+}
+
+/* Run a loaded program with the given bytes as its stdin, keeping its stdout. */
+pub fn run_captured(mut vm: VirtualMachine, input: &[u8]) -> CapturedRun {
+    let output = SharedBuffer::new();
+    vm.set_input(Box::new(Cursor::new(input.to_vec())));
+    vm.set_output(Box::new(output.clone()));
+
+    let result = vm.run();
+
+    CapturedRun {
+        result,
+        stdout: output.text(),
+        instructions: vm.instruction_count(),
+    }
+}
+
+/* Everything run_program needs to know besides the program itself. */
+#[derive(Debug, Clone)]
+pub struct RunOptions {
+    pub config: VmConfig,
+    /* What the program reads as its stdin. */
+    pub input: Vec<u8>,
+    /* Keep the program's output in RunOutcome::output rather than printing it. */
+    pub capture_output: bool,
+}
+
+impl Default for RunOptions {
+    fn default() -> RunOptions {
+        RunOptions {
+            config: VmConfig::default(),
+            input: Vec::new(),
+            capture_output: true,
+        }
+    }
+}
+
+/* How a run_program call went. A program that couldn't even be loaded comes back with no exit
+ * code, no instructions executed, and the load error as its fault. */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunOutcome {
+    pub exit_code: Option<i32>,
+    /* Everything the program wrote, if it was captured. */
+    pub output: Vec<u8>,
+    pub instructions: u64,
+    pub stack_pointer: i32,
+    pub program_counter: i32,
+    pub fault: Option<VmError>,
+}
+
+/* Load and run a program (the whole .v file, header and all) in one go. Limits like fuel and
+ * call depth come from the options' config. */
+pub fn run_program(bytes: &[u8], options: RunOptions) -> RunOutcome {
+    let mut vm = match VirtualMachine::from_bytes(bytes.to_vec(), options.config) {
+        Ok(vm) => vm,
+        Err(err) => {
+            return RunOutcome {
+                exit_code: None,
+                output: Vec::new(),
+                instructions: 0,
+                stack_pointer: 0,
+                program_counter: 0,
+                fault: Some(VmError::from(err)),
+            };
+        },
+    };
+
+    let output = SharedBuffer::new();
+    vm.set_input(Box::new(Cursor::new(options.input)));
+    if options.capture_output {
+        vm.set_output(Box::new(output.clone()));
+    }
+
+    let result = vm.run();
+
+    RunOutcome {
+        exit_code: result.as_ref().ok().copied(),
+        output: output.contents(),
+        instructions: vm.instruction_count(),
+        stack_pointer: vm.stack_pointer(),
+        program_counter: vm.program_counter(),
+        fault: result.err(),
+    }
+}
+
+/* What a program in a batch is supposed to do. Anything left out isn't checked. */
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Expectation {
+    pub exit_code: Option<i32>,
+    pub stdout: Option<String>,
+    pub stdin: Option<String>,
+}
+
+/* Pull a TOML-ish string value apart: "basic" strings with escapes, 'literal' strings, and
+ * """multi-line""" strings, which carry on over the following lines. */
+fn parse_string(value: &str, lines: &mut std::str::Lines, line_no: &mut usize) -> Result<String, String> {
+    let start_line = *line_no;
+
+    if let Some(rest) = value.strip_prefix("\"\"\"") {
+        /* Multi-line: keep reading until the closing quotes. A newline straight after the
+         * opening quotes doesn't count. */
+        let mut raw = String::from(rest);
+        while !raw.contains("\"\"\"") {
+            match lines.next() {
+                Some(line) => {
+                    *line_no += 1;
+                    raw.push('\n');
+                    raw.push_str(line);
+                },
+                None => return Err(format!("line {}: unterminated multi-line string", start_line)),
+            }
+        }
+
+        let end = raw.find("\"\"\"").expect("just checked");
+        let raw = raw[..end].strip_prefix('\n').unwrap_or(&raw[..end]);
+        return unescape(raw, start_line);
+    }
+
+    if let Some(rest) = value.strip_prefix('\'') {
+        return match rest.find('\'') {
+            Some(end) => Ok(String::from(&rest[..end])),
+            None => Err(format!("line {}: unterminated string", start_line)),
+        };
+    }
+
+    if let Some(rest) = value.strip_prefix('"') {
+        let mut end = None;
+        let mut escaped = false;
+        for (i, c) in rest.char_indices() {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => {
+                    end = Some(i);
+                    break;
+                },
+                _ => (),
+            }
+        }
+
+        return match end {
+            Some(end) => unescape(&rest[..end], start_line),
+            None => Err(format!("line {}: unterminated string", start_line)),
+        };
+    }
+
+    Err(format!("line {}: expected a string", start_line))
+}
+
+/* Turn the backslash escapes in a basic string into the characters they stand for. */
+fn unescape(raw: &str, line_no: usize) -> Result<String, String> {
+    let mut unescaped = String::new();
+    let mut chars = raw.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some('t') => unescaped.push('\t'),
+            Some('r') => unescaped.push('\r'),
+            Some('0') => unescaped.push('\0'),
+            Some('"') => unescaped.push('"'),
+            Some('\\') => unescaped.push('\\'),
+            Some(other) => return Err(format!("line {}: unknown escape \\{}", line_no, other)),
+            None => return Err(format!("line {}: dangling backslash", line_no)),
+        }
+    }
+
+    Ok(unescaped)
+}
+
+/* Read an expectations file. Each program gets a [section] named after its file (with or
+ * without the .v) holding any of exit_code, stdout and stdin:
+ *
+ *     [fizzbuzz]
+ *     exit_code = 0
+ *     stdout = """
+ *     1
+ *     2
+ *     Fizz
+ *     """
+ */
+pub fn parse_expectations(text: &str) -> Result<HashMap<String, Expectation>, String> {
+    let mut expectations = HashMap::new();
+    let mut current: Option<String> = None;
+    let mut lines = text.lines();
+    let mut line_no = 0;
+
+    while let Some(line) = lines.next() {
+        line_no += 1;
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if let Some(section) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            let name = section.trim().trim_matches('"').to_string();
+            expectations.entry(name.clone()).or_insert_with(Expectation::default);
+            current = Some(name);
+            continue;
+        }
+
+        let Some((key, value)) = line.split_once('=') else {
+            return Err(format!("line {}: expected key = value", line_no));
+        };
+        let Some(name) = &current else {
+            return Err(format!("line {}: value outside of a [program] section", line_no));
+        };
+
+        let (key, value) = (key.trim(), value.trim());
+        let expectation = expectations.get_mut(name).expect("section was added");
+
+        match key {
+            "exit_code" => {
+                let code = value.parse::<i32>()
+                    .map_err(|_| format!("line {}: exit_code should be a number", line_no))?;
+                expectation.exit_code = Some(code);
+            },
+            "stdout" => expectation.stdout = Some(parse_string(value, &mut lines, &mut line_no)?),
+            "stdin" => expectation.stdin = Some(parse_string(value, &mut lines, &mut line_no)?),
+            _ => return Err(format!("line {}: unknown key {}", line_no, key)),
+        }
+    }
+
+    Ok(expectations)
+}
+
+/* How one program in a batch went. */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchResult {
+    pub name: String,
+    pub passed: bool,
+    pub exit_code: Option<i32>,
+    /* Why it failed, or None if it passed. */
+    pub failure: Option<String>,
+}
+
+fn failed(name: &str, exit_code: Option<i32>, failure: String) -> BatchResult {
+    BatchResult {
+        name: String::from(name),
+        passed: false,
+        exit_code,
+        failure: Some(failure),
+    }
+}
+
+/* Check one program against what it was expected to do. Programs with no expectation pass as
+ * long as they finish without an error. */
+fn check(name: &str, vm: Result<VirtualMachine, String>, expectation: Option<&Expectation>) -> BatchResult {
+    let vm = match vm {
+        Ok(vm) => vm,
+        Err(err) => return failed(name, None, format!("couldn't load: {}", err)),
+    };
+
+    let input = expectation.and_then(|e| e.stdin.as_deref()).unwrap_or("");
+    let run = run_captured(vm, input.as_bytes());
+    judge(name, run.result, &run.stdout, expectation)
+}
+
+/* Check how a run went against what it was expected to do. */
+fn judge(name: &str, result: Result<i32, VmError>, stdout: &str, expectation: Option<&Expectation>) -> BatchResult {
+    let fail = |exit_code, failure| failed(name, exit_code, failure);
+
+    let exit_code = match result {
+        Ok(code) => code,
+        Err(err) => return fail(None, format!("error: {}", err)),
+    };
+
+    if let Some(expectation) = expectation {
+        if let Some(expected) = expectation.exit_code {
+            if expected != exit_code {
+                return fail(Some(exit_code), format!("exit code {}, expected {}", exit_code, expected));
+            }
+        }
+
+        if let Some(expected) = &expectation.stdout {
+            if expected != stdout {
+                return fail(Some(exit_code), format!("stdout {:?}, expected {:?}", stdout, expected));
+            }
+        }
+    }
+
+    BatchResult {
+        name: String::from(name),
+        passed: true,
+        exit_code: Some(exit_code),
+        failure: None,
+    }
+}
+
+/* The programs in a directory, .v files and .s files both, in name order. */
+fn programs(dir: &Path) -> Result<Vec<PathBuf>, String> {
+    let entries = fs::read_dir(dir).map_err(|e| format!("Couldn't read {}: {}", dir.display(), e))?;
+
+    let mut paths: Vec<_> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "v" || ext == "s"))
+        .collect();
+    paths.sort();
+    Ok(paths)
+}
+
+/* Load a program, assembling it first if it's a .s file. */
+fn load(path: &Path, config: &VmConfig) -> Result<VirtualMachine, String> {
+    if path.extension().is_some_and(|ext| ext == "s") {
+        let source = fs::read_to_string(path).map_err(|e| format!("Couldn't read {}: {}", path.display(), e))?;
+        let program = asm::assemble_with(&source, &path.to_string_lossy(), &mut asm::IncludePath::default(), &BTreeMap::new())
+            .map_err(|e| e.to_string())?;
+        return VirtualMachine::from_bytes(program.image(), config.clone());
+    }
+
+    VirtualMachine::from_file(&path.to_string_lossy(), config.clone())
+}
+
+/* What a program's golden files say about it: <name>.in next to it is its stdin and <name>.out
+ * what it should print. */
+fn golden(path: &Path) -> Expectation {
+    Expectation {
+        exit_code: None,
+        stdout: fs::read_to_string(path.with_extension("out")).ok(),
+        stdin: fs::read_to_string(path.with_extension("in")).ok(),
+    }
+}
+
+/* Run every program in a directory, in name order, against its golden files and the
+ * expectations, which win where they both say something. jobs of them run at once, on a VmPool,
+ * or as many as there are cores for 0. */
+pub fn run_batch(dir: &Path, expectations: &HashMap<String, Expectation>, config: &VmConfig, jobs: usize)
+    -> Result<Vec<BatchResult>, String> {
+    let mut results = Vec::new();
+    let mut pool = VmPool::new(jobs);
+    let mut pooled = Vec::new();
+    for path in programs(dir)? {
+        let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+        let stem = path.file_stem().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+
+        let mut expectation = golden(&path);
+        if let Some(given) = expectations.get(&name).or_else(|| expectations.get(&stem)) {
+            expectation.exit_code = given.exit_code.or(expectation.exit_code);
+            expectation.stdout = given.stdout.clone().or(expectation.stdout);
+            expectation.stdin = given.stdin.clone().or(expectation.stdin);
+        }
+
+        match load(&path, config) {
+            Ok(vm) => {
+                pool.add(&name, vm, expectation.stdin.clone().unwrap_or_default().into_bytes());
+                pooled.push((results.len(), expectation));
+                results.push(None);
+            },
+            Err(err) => results.push(Some(failed(&name, None, format!("couldn't load: {}", err)))),
+        }
+    }
+
+    for (run, (index, expectation)) in pool.run().into_iter().zip(pooled) {
+        let result = run.report.map(|report| report.exit_code);
+        results[index] = Some(judge(&run.name, result, &run.stdout, Some(&expectation)));
+    }
+
+    Ok(results.into_iter().map(|result| result.expect("every program has a result")).collect())
+}
+
+/* Write each program's output to its .out file, for when a change to the output is meant.
+ * Gives back the files written. */
+pub fn bless(dir: &Path, config: &VmConfig) -> Result<Vec<PathBuf>, String> {
+    let mut written = Vec::new();
+    for path in programs(dir)? {
+        let input = golden(&path).stdin.unwrap_or_default();
+        let run = run_captured(load(&path, config)?, input.as_bytes());
+        if let Err(err) = run.result {
+            return Err(format!("{}: {}", path.display(), err));
+        }
+
+        let out = path.with_extension("out");
+        fs::write(&out, run.stdout).map_err(|e| format!("Couldn't write {}: {}", out.display(), e))?;
+        written.push(out);
+    }
+
+    Ok(written)
+}
+
+/* The programs in examples/programs, with their golden files, built in so vm selftest can run
+ * them from anywhere: name, source, stdin and stdout. */
+const SHIPPED: [(&str, &str, &str, &str); 8] = [
+    ("data.s", include_str!("../examples/programs/data.s"), "", include_str!("../examples/programs/data.out")),
+    ("echo.s", include_str!("../examples/programs/echo.s"), include_str!("../examples/programs/echo.in"),
+        include_str!("../examples/programs/echo.out")),
+    ("entry.s", include_str!("../examples/programs/entry.s"), "", include_str!("../examples/programs/entry.out")),
+    ("fibonacci.s", include_str!("../examples/programs/fibonacci.s"), "", include_str!("../examples/programs/fibonacci.out")),
+    ("fizzbuzz.s", include_str!("../examples/programs/fizzbuzz.s"), "", include_str!("../examples/programs/fizzbuzz.out")),
+    ("heap.s", include_str!("../examples/programs/heap.s"), "", include_str!("../examples/programs/heap.out")),
+    ("stdlib.s", include_str!("../examples/programs/stdlib.s"), "", include_str!("../examples/programs/stdlib.out")),
+    ("switch.s", include_str!("../examples/programs/switch.s"), "", include_str!("../examples/programs/switch.out")),
+];
+
+/* How many layout seeds vm selftest runs each built-in program under, besides its own layout. */
+const LAYOUT_SEEDS: u64 = 4;
+
+/* Run the built-in programs against their golden files, once each has been through vm disasm
+ * with its labels and come back the same, and then again with their functions shuffled by each
+ * of the layout seeds. */
+pub fn run_shipped() -> Vec<BatchResult> {
+    let mut results = Vec::new();
+    for &(name, source, stdin, stdout) in &SHIPPED {
+        let expectation = Expectation { exit_code: Some(0), stdout: Some(String::from(stdout)), stdin: Some(String::from(stdin)) };
+        let program = asm::assemble(source).map_err(|e| e.to_string());
+        let vm = program.clone().and_then(|program| {
+            let labelled = asm::Assembled { features: program.features | Header::DEBUG_INFO, ..program.clone() };
+            disasm::roundtrip(&labelled.image()).map_err(|err| format!("disassembly: {}", err))?;
+            VirtualMachine::from_bytes(program.image(), VmConfig::default())
+        });
+        results.push(check(name, vm, Some(&expectation)));
+
+        for seed in 1..=LAYOUT_SEEDS {
+            let config = VmConfig { layout_seed: Some(seed), ..VmConfig::default() };
+            let vm = program.clone().and_then(|program| VirtualMachine::from_bytes(program.image(), config));
+            results.push(check(&format!("{} (layout seed {})", name, seed), vm, Some(&expectation)));
+        }
+    }
+    results
+}
+
+/* Lay the results of a batch out as a table with a summary line underneath. */
+pub fn format_table(results: &[BatchResult]) -> String {
+    let width = results.iter().map(|r| r.name.len()).max().unwrap_or(0).max("PROGRAM".len());
+    let mut table = format!("{:<width$}  RESULT  EXIT  DETAILS\n", "PROGRAM", width = width);
+
+    for result in results {
+        let exit_code = result.exit_code.map(|c| c.to_string()).unwrap_or_else(|| String::from("-"));
+        let row = format!(
+            "{:<width$}  {:<6}  {:<4}  {}",
+            result.name,
+            if result.passed { "pass" } else { "FAIL" },
+            exit_code,
+            result.failure.as_deref().unwrap_or(""),
+            width = width
+        );
+        table.push_str(row.trim_end());
+        table.push('\n');
+    }
+
+    let passed = results.iter().filter(|r| r.passed).count();
+    table.push_str(&format!("{} passed, {} failed\n", passed, results.len() - passed));
+
+    table
+}
+
+/* How one assertion about a finished run went. */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssertionResult {
+    pub expression: String,
+    pub passed: bool,
+    /* Why it failed, or None if it passed. */
+    pub failure: Option<String>,
+}
+
+/* Check watch expressions (see the expr module) against the state a program finished in. An
+ * assertion passes when its expression comes out nonzero. */
+pub fn check_assertions(vm: &VirtualMachine, assertions: &[String]) -> Vec<AssertionResult> {
+    assertions.iter().map(|assertion| {
+        let failure = match Expr::parse(assertion).and_then(|expr| expr.evaluate(vm)) {
+            Ok(0) => Some(String::from("false")),
+            Ok(_) => None,
+            Err(err) => Some(err),
+        };
+
+        AssertionResult {
+            expression: assertion.clone(),
+            passed: failure.is_none(),
+            failure,
+        }
+    }).collect()
+}
@@ -0,0 +1,337 @@
+/* The header at the front of a .v file. Originally it was just the magic de ad be ef with the
+ * code straight after it, which left no room to say anything about the program. Files now start
+ * with a different magic and carry a version and the features the program needs:
+ *
+ *     0   de ad ca fe
+ *     4   version (1)
+ *     5   header length in bytes, counting the magic (12)
+ *     6   two bytes, zero
+ *     8   features, a little-endian bitmask
+ *     12  the code
+ *
+ * Version 2 adds two more fields, for programs that don't start at 0 with an empty stack, and
+ * is only written for those, so everything else still loads on a VM that only knows version 1:
+ *
+ *     12  entry point, the address of the first instruction to run, little-endian
+ *     16  initial stack pointer, little-endian; anything between it and the top of memory is
+ *         on the stack from the start
+ *     20  the code
+ *
+ * Version 3 adds where the code ends and the program's data starts, for a file with a data
+ * region after its code. The data is loaded with the code, but it isn't run, and with the
+ * READONLY_DATA feature it can't be written either:
+ *
+ *     20  size of the code in bytes, little-endian; the rest of the image is data
+ *     24  the code, then the data
+ *
+ * Version 4 adds how big a heap the program wants, for one with the HEAP feature. The size of
+ * the code is there too, as ffffffff when the image is all code:
+ *
+ *     24  heap size in bytes, little-endian
+ *     28  the code, then the data
+ *
+ * The HEAP feature without a version 4 header gets a heap of DEFAULT_HEAP bytes. Where the heap
+ * goes is the address_space module's business.
+ *
+ * A newer version can add fields after the features and make the header longer. Files with the
+ * old magic still load, as version 0 with no features. A file that's a newer version than this
+ * VM knows, or that needs a feature it doesn't know or doesn't have, is turned away rather than
+ * run wrong.
+ *
+ * With the DEBUG_INFO feature, the code is followed by a debug-info section (see the debug_info
+ * module), which parse takes back off. */
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::debug_info::DebugInfo;
+use crate::linker::OBJECT_MAGIC;
+use crate::{Endianness, StringFormat, VmConfig, WordSize, MEMORY_SIZE};
+
+pub const LEGACY_MAGIC: [u8; 4] = [0xde, 0xad, 0xbe, 0xef];
+pub const MAGIC: [u8; 4] = [0xde, 0xad, 0xca, 0xfe];
+
+/* A program written out as hex words, the way the VM reads them, with any amount of whitespace
+ * between:
+ *
+ *     deadbeef f0000001 00000000
+ *
+ * Each word is an instruction and goes into the image little-endian, as the assembler would
+ * put it. The first can be deadbeef, the old magic, which is taken as the bytes it spells;
+ * without it the old magic is put in front, so there's no way to ask for features here. */
+pub fn hex_image(text: &str) -> Result<Vec<u8>, String> {
+    let mut words = text.split_whitespace().peekable();
+    let mut image = Vec::from(LEGACY_MAGIC);
+    if words.peek().is_some_and(|word| word.eq_ignore_ascii_case("deadbeef")) {
+        words.next();
+    }
+
+    for word in words {
+        let digits = word.strip_prefix("0x").unwrap_or(word);
+        let value = match digits.len() {
+            1..=8 => u32::from_str_radix(digits, 16).ok(),
+            _ => None,
+        };
+        let Some(value) = value else {
+            return Err(format!("{} isn't a hex word.", word));
+        };
+        image.extend_from_slice(&value.to_le_bytes());
+    }
+
+    Ok(image)
+}
+
+/* The newest version this VM understands, and what it writes. */
+pub const VERSION: u8 = 4;
+
+const HEADER_SIZE: usize = 12;
+/* With the entry point and stack pointer, from version 2. */
+const HEADER_SIZE_2: usize = 20;
+/* With the size of the code, from version 3. */
+const HEADER_SIZE_3: usize = 24;
+/* With the size of the heap, from version 4. */
+const HEADER_SIZE_4: usize = 28;
+/* What a version 4 header has for the size of the code when there's no data. */
+const ALL_CODE: u32 = u32::MAX;
+
+/* How big the heap is for a program with the HEAP feature that doesn't say. */
+pub const DEFAULT_HEAP: usize = 1024;
+
+/* How deep the return stack is for a DUAL_STACK program run without a return_stack_depth of
+ * its own. */
+const DUAL_STACK_DEPTH: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    /* 0 for a file with the old magic. */
+    pub version: u8,
+    pub features: u32,
+    /* Where the program starts, 0 before version 2. */
+    pub entry: u32,
+    /* Where the stack pointer starts, the top of memory before version 2. */
+    pub stack_pointer: u32,
+    /* How much of the image is code, when some of it is data; None before version 3. */
+    pub code_size: Option<u32>,
+    /* How many bytes of heap the program wants; None before version 4. */
+    pub heap_size: Option<u32>,
+}
+
+impl Header {
+    /* The program's words are 64 bits wide. */
+    pub const WORDS_64: u32 = 1 << 0;
+    /* The program needs a heap, as big as heap_size says. */
+    pub const HEAP: u32 = 1 << 1;
+    /* The file carries debug info. */
+    pub const DEBUG_INFO: u32 = 1 << 2;
+    /* Return addresses go on a separate return stack. */
+    pub const DUAL_STACK: u32 = 1 << 3;
+    /* Strings are length-prefixed bytes rather than packed. */
+    pub const BYTE_STRINGS: u32 = 1 << 4;
+    /* Writing to the data region is a fault. */
+    pub const READONLY_DATA: u32 = 1 << 5;
+    /* Words are little-endian in memory. */
+    pub const LITTLE_ENDIAN: u32 = 1 << 6;
+
+    const KNOWN: u32 = Header::WORDS_64 | Header::HEAP | Header::DEBUG_INFO | Header::DUAL_STACK | Header::BYTE_STRINGS
+        | Header::READONLY_DATA | Header::LITTLE_ENDIAN;
+
+    /* Each feature by the name .feature gives it in assembly. */
+    pub const FEATURE_NAMES: [(&'static str, u32); 7] = [
+        ("words64", Header::WORDS_64), ("heap", Header::HEAP), ("debug_info", Header::DEBUG_INFO), ("dual_stack", Header::DUAL_STACK),
+        ("byte_strings", Header::BYTE_STRINGS), ("readonly_data", Header::READONLY_DATA), ("little_endian", Header::LITTLE_ENDIAN),
+    ];
+
+    /* A current header with the given features, for a program starting at 0 with an empty
+     * stack. */
+    pub fn new(features: u32) -> Header {
+        Header { version: VERSION, features, entry: 0, stack_pointer: MEMORY_SIZE as u32, code_size: None, heap_size: None }
+    }
+
+    /* Whether the program starts somewhere other than 0 or with something on its stack, which
+     * takes a version 2 header, or has data, which takes version 3, or a heap size, which
+     * takes version 4. */
+    fn past_version_1(&self) -> bool {
+        self.entry != 0 || self.stack_pointer != MEMORY_SIZE as u32 || self.code_size.is_some() || self.heap_size.is_some()
+    }
+
+    /* How big a heap the program needs, if it needs one. */
+    pub fn heap(&self) -> Option<usize> {
+        match self.has(Header::HEAP) {
+            true => Some(self.heap_size.map_or(DEFAULT_HEAP, |size| size as usize)),
+            false => None,
+        }
+    }
+
+    pub fn has(&self, feature: u32) -> bool {
+        self.features & feature == feature
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        if self.version == 0 {
+            return Vec::from(LEGACY_MAGIC);
+        }
+
+        let mut bytes = Vec::from(MAGIC);
+        if !self.past_version_1() {
+            bytes.extend_from_slice(&[1, HEADER_SIZE as u8, 0, 0]);
+            bytes.extend_from_slice(&self.features.to_le_bytes());
+            return bytes;
+        }
+
+        let (version, size) = match (self.code_size, self.heap_size) {
+            (_, Some(_)) => (4, HEADER_SIZE_4),
+            (Some(_), None) => (3, HEADER_SIZE_3),
+            (None, None) => (2, HEADER_SIZE_2),
+        };
+        bytes.extend_from_slice(&[version, size as u8, 0, 0]);
+        bytes.extend_from_slice(&self.features.to_le_bytes());
+        bytes.extend_from_slice(&self.entry.to_le_bytes());
+        bytes.extend_from_slice(&self.stack_pointer.to_le_bytes());
+        if version >= 3 {
+            bytes.extend_from_slice(&self.code_size.unwrap_or(ALL_CODE).to_le_bytes());
+        }
+        if let Some(heap_size) = self.heap_size {
+            bytes.extend_from_slice(&heap_size.to_le_bytes());
+        }
+        bytes
+    }
+
+    /* The contents of a .v file with this header and the code. */
+    pub fn image(&self, code: &[u8]) -> Vec<u8> {
+        let mut image = self.to_bytes();
+        image.extend_from_slice(code);
+        image
+    }
+
+    /* Split a .v file into its header, its code and any debug info, checking the header is one
+     * this VM can run. */
+    pub fn parse(file: &[u8]) -> Result<(Header, &[u8], Option<DebugInfo>), String> {
+        if file.starts_with(&OBJECT_MAGIC) {
+            return Err(String::from("This is an object file; link it with vm link first."));
+        }
+
+        let (header, code) = if file.starts_with(&LEGACY_MAGIC) {
+            (Header { version: 0, ..Header::new(0) }, &file[4..])
+        } else if file.starts_with(&MAGIC) {
+            if file.len() < HEADER_SIZE {
+                return Err(String::from("File header is truncated."));
+            }
+
+            let version = file[4];
+            let size = file[5] as usize;
+            if version == 0 || version > VERSION {
+                return Err(format!("File is format version {}; this VM understands up to version {}.", version, VERSION));
+            }
+            if size < HEADER_SIZE || size > file.len() {
+                return Err(String::from("File header is invalid."));
+            }
+
+            let word = |at: usize| u32::from_le_bytes([file[at], file[at + 1], file[at + 2], file[at + 3]]);
+            let mut header = Header { version, ..Header::new(word(8)) };
+            if version >= 2 {
+                if size < HEADER_SIZE_2 {
+                    return Err(String::from("File header is invalid."));
+                }
+                header.entry = word(12);
+                header.stack_pointer = word(16);
+            }
+            if version >= 3 {
+                if size < HEADER_SIZE_3 {
+                    return Err(String::from("File header is invalid."));
+                }
+                header.code_size = Some(word(20));
+            }
+            if version >= 4 {
+                if size < HEADER_SIZE_4 {
+                    return Err(String::from("File header is invalid."));
+                }
+                header.code_size = header.code_size.filter(|&size| size != ALL_CODE);
+                header.heap_size = Some(word(24));
+            }
+            (header, &file[size..])
+        } else {
+            return Err(String::from("File format is invalid."));
+        };
+
+        let unknown = header.features & !Header::KNOWN;
+        if unknown != 0 {
+            return Err(format!("File needs features this VM doesn't know about ({:#x}).", unknown));
+        }
+        if header.heap_size.is_some() && !header.has(Header::HEAP) {
+            return Err(String::from("File gives a heap size without asking for a heap."));
+        }
+
+        let (code, debug_info) = if header.has(Header::DEBUG_INFO) {
+            let (code, debug_info) = DebugInfo::split_off(code)?;
+            (code, Some(debug_info))
+        } else {
+            (code, None)
+        };
+        if code.len() > MEMORY_SIZE {
+            return Err(String::from("File too big."));
+        }
+        if header.heap().is_some_and(|heap| code.len() + heap > MEMORY_SIZE) {
+            return Err(String::from("File needs a bigger heap than fits in memory."));
+        }
+        if header.code_size.is_some_and(|size| size % 4 != 0 || size as usize > code.len()) {
+            return Err(String::from("File says it has more code than it does."));
+        }
+        let code_size = header.code_size.map_or(code.len(), |size| size as usize);
+        if header.entry != 0 && (header.entry % 4 != 0 || header.entry as usize + 4 > code_size) {
+            return Err(format!("The entry point {:#x} isn't an instruction in the code.", header.entry));
+        }
+        if header.stack_pointer % 4 != 0 || header.stack_pointer as usize > MEMORY_SIZE {
+            return Err(format!("The stack pointer {:#x} isn't a word in memory.", header.stack_pointer));
+        }
+
+        Ok((header, code, debug_info))
+    }
+
+    /* How many bytes of a file its header takes, for a file parse accepts. */
+    pub fn length(file: &[u8]) -> usize {
+        if file.starts_with(&MAGIC) { file[5] as usize } else { LEGACY_MAGIC.len() }
+    }
+
+    /* Set a config up the way the program needs it. */
+    pub fn configure(&self, config: &mut VmConfig) {
+        if self.has(Header::WORDS_64) {
+            config.word_size = WordSize::Bits64;
+        }
+        if self.has(Header::DUAL_STACK) && config.return_stack_depth.is_none() {
+            config.return_stack_depth = Some(DUAL_STACK_DEPTH);
+        }
+        if self.has(Header::BYTE_STRINGS) {
+            config.string_format = StringFormat::LengthPrefixed;
+        }
+        if self.has(Header::LITTLE_ENDIAN) {
+            config.endianness = Endianness::Little;
+        }
+        if config.heap_size.is_none() {
+            config.heap_size = self.heap();
+        }
+    }
+
+    /* The order the program's words go in memory. */
+    pub fn endianness(&self) -> Endianness {
+        match self.has(Header::LITTLE_ENDIAN) {
+            true => Endianness::Little,
+            false => Endianness::Big,
+        }
+    }
+}
+
+/* One line, for the top of a listing. */
+impl fmt::Display for Header {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "version {}  features {:#x}  entry {:04x}  sp {:04x}", self.version, self.features, self.entry, self.stack_pointer)?;
+        if let Some(code_size) = self.code_size {
+            write!(f, "  data {:04x}", code_size)?;
+        }
+        if let Some(heap) = self.heap() {
+            write!(f, "  heap {}", heap)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,99 @@
+/* What the VM asks of the machine it's running on beyond input and output: files, the
+ * environment, the time and stderr. Without std there's no file system or clock, and programs
+ * that want one fault, no environment, so every variable reads as unset, and no stderr. In a
+ * browser std is there but its clock panics when read, so wasm32 goes without one too, and rand
+ * starts from a fixed seed unless it's given one. */
+
+use alloc::string::String;
+#[cfg(feature = "std")]
+use alloc::format;
+use alloc::vec::Vec;
+use core::time::Duration;
+
+use crate::VmError;
+
+#[cfg(feature = "std")]
+pub(crate) fn read_file(path: &str) -> Result<Vec<u8>, VmError> {
+    std::fs::read(path).map_err(|e| VmError::from(format!("Couldn't read {}: {}", path, e)))
+}
+
+#[cfg(feature = "std")]
+pub(crate) fn write_file(path: &str, contents: &[u8]) -> Result<(), VmError> {
+    std::fs::write(path, contents).map_err(|e| VmError::from(format!("Couldn't write {}: {}", path, e)))
+}
+
+#[cfg(feature = "std")]
+pub(crate) fn getenv(name: &str) -> Option<String> {
+    std::env::var(name).ok()
+}
+
+/* Something different every run, to seed rand with when there's no seed given. */
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+pub(crate) fn entropy() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |time| time.as_nanos() as u64)
+}
+
+#[cfg(any(not(feature = "std"), target_arch = "wasm32"))]
+pub(crate) fn entropy() -> u64 {
+    0
+}
+
+/* Time on a clock that only goes forward, from some point before the first call. */
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+pub(crate) fn monotonic() -> Option<Duration> {
+    static START: std::sync::OnceLock<std::time::Instant> = std::sync::OnceLock::new();
+    Some(START.get_or_init(std::time::Instant::now).elapsed())
+}
+
+#[cfg(any(not(feature = "std"), target_arch = "wasm32"))]
+pub(crate) fn monotonic() -> Option<Duration> {
+    None
+}
+
+/* The same, in milliseconds. */
+pub(crate) fn monotonic_millis() -> Option<u64> {
+    monotonic().map(|time| time.as_millis() as u64)
+}
+
+/* Milliseconds since 1970. */
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+pub(crate) fn now_millis() -> Result<u64, VmError> {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|time| time.as_millis() as u64)
+        .map_err(|_| VmError::from(String::from("The clock is set before 1970.")))
+}
+
+/* Write to the process's stderr, whatever the VM's diagnostics are set to. */
+#[cfg(feature = "std")]
+pub(crate) fn write_stderr(text: &str) -> Result<(), String> {
+    use std::io::Write;
+    std::io::stderr().write_all(text.as_bytes()).map_err(|e| format!("Couldn't write to stderr: {}", e))
+}
+
+#[cfg(not(feature = "std"))]
+pub(crate) fn write_stderr(_text: &str) -> Result<(), String> {
+    Ok(())
+}
+
+#[cfg(not(feature = "std"))]
+pub(crate) fn read_file(_path: &str) -> Result<Vec<u8>, VmError> {
+    Err(VmError::from(String::from("There's no file system to read from.")))
+}
+
+#[cfg(not(feature = "std"))]
+pub(crate) fn write_file(_path: &str, _contents: &[u8]) -> Result<(), VmError> {
+    Err(VmError::from(String::from("There's no file system to write to.")))
+}
+
+#[cfg(not(feature = "std"))]
+pub(crate) fn getenv(_name: &str) -> Option<String> {
+    None
+}
+
+#[cfg(any(not(feature = "std"), target_arch = "wasm32"))]
+pub(crate) fn now_millis() -> Result<u64, VmError> {
+    Err(VmError::from(String::from("There's no clock to read.")))
+}
@@ -0,0 +1,63 @@
+/* Ctrl-C for `vm run` and `vm debug`. Rather than the process dying on the spot, the SIGINT
+ * handler sets a flag that the VM looks at before every instruction (see
+ * VirtualMachine::set_interrupt), so the program stops between two instructions with its state
+ * intact. A second Ctrl-C before the first has been noticed kills the process, in case the
+ * VM is stuck somewhere it never looks.
+ *
+ * Only Unix has a handler; elsewhere install does nothing and Ctrl-C works as it always did. */
+
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, OnceLock};
+
+static FLAG: OnceLock<Arc<AtomicBool>> = OnceLock::new();
+
+/* Install the handler, returning the flag it sets. Calling it again gives back the same flag. */
+pub fn install() -> Arc<AtomicBool> {
+    let mut installed = false;
+    let flag = FLAG.get_or_init(|| {
+        installed = true;
+        Arc::new(AtomicBool::new(false))
+    });
+
+    if installed {
+        unix::install();
+    }
+    flag.clone()
+}
+
+#[cfg(unix)]
+mod unix {
+    use std::ffi::c_int;
+    use std::sync::atomic::Ordering;
+
+    const SIGINT: c_int = 2;
+
+    extern "C" {
+        fn signal(signum: c_int, handler: extern "C" fn(c_int)) -> usize;
+        fn _exit(status: c_int) -> !;
+    }
+
+    extern "C" fn handler(_signum: c_int) {
+        if let Some(flag) = super::FLAG.get() {
+            if flag.swap(true, Ordering::SeqCst) {
+                exit_now(130);
+            }
+        }
+    }
+
+    pub(super) fn install() {
+        unsafe {
+            signal(SIGINT, handler);
+        }
+    }
+
+    /* Leave without running anything that isn't safe in a signal handler. */
+    fn exit_now(status: c_int) -> ! {
+        unsafe { _exit(status) }
+    }
+}
+
+#[cfg(not(unix))]
+mod unix {
+    pub(super) fn install() {}
+}
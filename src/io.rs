@@ -0,0 +1,76 @@
+use alloc::boxed::Box;
+use alloc::string::String;
+
+/* Where a program's input comes from. With the std feature anything that's BufRead will do;
+ * without it, implement this for whatever the board reads lines from. */
+pub trait Input {
+    /* Read a line, newline and all, onto the end of buf. Returns how many bytes were read, which
+     * is 0 once there's no more input. */
+    fn read_line(&mut self, buf: &mut String) -> Result<usize, String>;
+}
+
+/* Where a program's output goes. With the std feature anything that's Write will do. */
+pub trait Output {
+    fn write_all(&mut self, bytes: &[u8]) -> Result<(), String>;
+
+    fn flush(&mut self) -> Result<(), String>;
+}
+
+/* Where the VM's own diagnostics go, as opposed to what the program prints: the debug and dump
+ * instructions, and VirtualMachine::dump_state. stderr by default, so they don't end up mixed in
+ * with the program's output. */
+pub type DiagnosticSink = Box<dyn Output + Send>;
+
+#[cfg(feature = "std")]
+impl<T: std::io::BufRead + ?Sized> Input for T {
+    fn read_line(&mut self, buf: &mut String) -> Result<usize, String> {
+        std::io::BufRead::read_line(self, buf).map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T: std::io::Write + ?Sized> Output for T {
+    fn write_all(&mut self, bytes: &[u8]) -> Result<(), String> {
+        std::io::Write::write_all(self, bytes).map_err(|e| e.to_string())
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        std::io::Write::flush(self).map_err(|e| e.to_string())
+    }
+}
+
+/* stdin a line at a time, locked only while the line is read and read no further than its
+ * newline, so whatever else reads stdin gets the lines after it. This is how a program shares
+ * stdin with vm debug's prompt. */
+#[cfg(feature = "std")]
+pub struct StdinLines;
+
+#[cfg(feature = "std")]
+impl Input for StdinLines {
+    fn read_line(&mut self, buf: &mut String) -> Result<usize, String> {
+        std::io::stdin().read_line(buf).map_err(|e| e.to_string())
+    }
+}
+
+/* Input that's always empty and output that goes nowhere. This is what a machine starts out
+ * with when there's no stdin or stdout to hook it up to. */
+#[cfg(not(feature = "std"))]
+pub struct Null;
+
+#[cfg(not(feature = "std"))]
+impl Input for Null {
+    fn read_line(&mut self, _buf: &mut String) -> Result<usize, String> {
+        Ok(0)
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl Output for Null {
+    fn write_all(&mut self, _bytes: &[u8]) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+}
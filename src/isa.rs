@@ -0,0 +1,826 @@
+/* The instruction set as data: every instruction the VM understands, and how each one is laid
+ * out in a 32-bit word. The assembler encodes through here and `vm selftest` checks the VM
+ * against it, so this is the place to start when adding an instruction.
+ *
+ * Operands are kept in the units the encoding uses: branch, dup, print and stprint offsets and
+ * pop and return sizes are in bytes, swap slots are in words. Pop and return sizes count 4 bytes
+ * to a word whatever the word size, so pop 4 drops a word with words64 too.
+ *
+ * The apply and holds methods say what the operations do in the default configuration, 32-bit
+ * words with wrapping arithmetic.
+ *
+ * For building programs a word at a time there's assemble_line, which takes the assembler's
+ * syntax, and disassemble to go back the other way:
+ *
+ *     assemble_line("push -5") == Ok(0xfffffffb)
+ *     disassemble(0xf0000005) == "push 5" */
+
+use alloc::format;
+use alloc::string::String;
+use core::fmt;
+use core::ops::RangeInclusive;
+
+use crate::asm::{self, AsmError};
+use crate::Ops;
+
+/* The biggest code exit can give, the 24 bits below its opcode. */
+pub const MAX_EXIT_CODE: u32 = 0xFF_FFFF;
+
+/* The operations of the binary arithmetic instruction (opcode 2), by their identifier. cmp is
+ * identifier 10 and has its own Instruction variant. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add = 0,
+    Sub = 1,
+    Mul = 2,
+    Div = 3,
+    Rem = 4,
+    And = 5,
+    Or = 6,
+    Xor = 7,
+    Lsl = 8,
+    Lsr = 9,
+    Asr = 11,
+    Rol = 12,
+    Ror = 13,
+    Divu = 14,
+    Remu = 15,
+}
+
+impl BinaryOp {
+    pub const ALL: [BinaryOp; 15] = [
+        BinaryOp::Add, BinaryOp::Sub, BinaryOp::Mul, BinaryOp::Div, BinaryOp::Rem, BinaryOp::And,
+        BinaryOp::Or, BinaryOp::Xor, BinaryOp::Lsl, BinaryOp::Lsr, BinaryOp::Asr, BinaryOp::Rol,
+        BinaryOp::Ror, BinaryOp::Divu, BinaryOp::Remu,
+    ];
+
+    fn from_id(id: u32) -> Option<BinaryOp> {
+        BinaryOp::ALL.iter().copied().find(|&op| op as u32 == id)
+    }
+
+    pub fn mnemonic(self) -> &'static str {
+        match self {
+            BinaryOp::Add => "add",
+            BinaryOp::Sub => "sub",
+            BinaryOp::Mul => "mul",
+            BinaryOp::Div => "div",
+            BinaryOp::Rem => "rem",
+            BinaryOp::And => "and",
+            BinaryOp::Or => "or",
+            BinaryOp::Xor => "xor",
+            BinaryOp::Lsl => "lsl",
+            BinaryOp::Lsr => "lsr",
+            BinaryOp::Asr => "asr",
+            BinaryOp::Rol => "rol",
+            BinaryOp::Ror => "ror",
+            BinaryOp::Divu => "divu",
+            BinaryOp::Remu => "remu",
+        }
+    }
+
+    /* left op right, where right was on top of the stack. None when dividing by zero. Shifts
+     * and rotates take the amount modulo 32. */
+    pub fn apply(self, left: i32, right: i32) -> Option<i32> {
+        if matches!(self, BinaryOp::Div | BinaryOp::Rem | BinaryOp::Divu | BinaryOp::Remu) && right == 0 {
+            return None;
+        }
+
+        let amount = right.rem_euclid(32) as u32;
+
+        Some(match self {
+            BinaryOp::Add => left.wrapping_add(right),
+            BinaryOp::Sub => left.wrapping_sub(right),
+            BinaryOp::Mul => left.wrapping_mul(right),
+            BinaryOp::Div => left.wrapping_div(right),
+            BinaryOp::Rem => left.wrapping_rem(right),
+            BinaryOp::And => left & right,
+            BinaryOp::Or => left | right,
+            BinaryOp::Xor => left ^ right,
+            BinaryOp::Lsl => left << amount,
+            BinaryOp::Lsr => ((left as u32) >> amount) as i32,
+            BinaryOp::Asr => left >> amount,
+            BinaryOp::Rol => (left as u32).rotate_left(amount) as i32,
+            BinaryOp::Ror => (left as u32).rotate_right(amount) as i32,
+            BinaryOp::Divu => ((left as u32) / (right as u32)) as i32,
+            BinaryOp::Remu => ((left as u32) % (right as u32)) as i32,
+        })
+    }
+}
+
+/* Conditions for cmp and the binary ifs. The unsigned ones compare the words' bits as unsigned
+ * numbers. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Condition {
+    Eq = 0,
+    Ne = 1,
+    Lt = 2,
+    Gt = 3,
+    Le = 4,
+    Ge = 5,
+    Ltu = 6,
+    Gtu = 7,
+    Leu = 8,
+    Geu = 9,
+}
+
+impl Condition {
+    pub const ALL: [Condition; 10] = [
+        Condition::Eq, Condition::Ne, Condition::Lt, Condition::Gt, Condition::Le, Condition::Ge,
+        Condition::Ltu, Condition::Gtu, Condition::Leu, Condition::Geu,
+    ];
+
+    fn from_id(id: u32) -> Option<Condition> {
+        Condition::ALL.get(id as usize).copied()
+    }
+
+    /* The suffix on cmp and if that picks this condition, as in cmpltu and ifeq. */
+    pub fn suffix(self) -> &'static str {
+        ["eq", "ne", "lt", "gt", "le", "ge", "ltu", "gtu", "leu", "geu"][self as usize]
+    }
+
+    pub fn from_suffix(suffix: &str) -> Option<Condition> {
+        Condition::ALL.iter().copied().find(|c| c.suffix() == suffix)
+    }
+
+    /* left against right, where right is the top of the stack. */
+    pub fn holds(self, left: i32, right: i32) -> bool {
+        let (unsigned_left, unsigned_right) = (left as u32, right as u32);
+
+        match self {
+            Condition::Eq => left == right,
+            Condition::Ne => left != right,
+            Condition::Lt => left < right,
+            Condition::Gt => left > right,
+            Condition::Le => left <= right,
+            Condition::Ge => left >= right,
+            Condition::Ltu => unsigned_left < unsigned_right,
+            Condition::Gtu => unsigned_left > unsigned_right,
+            Condition::Leu => unsigned_left <= unsigned_right,
+            Condition::Geu => unsigned_left >= unsigned_right,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOp {
+    Neg = 0,
+    Not = 1,
+}
+
+impl UnaryOp {
+    pub const ALL: [UnaryOp; 2] = [UnaryOp::Neg, UnaryOp::Not];
+
+    pub fn mnemonic(self) -> &'static str {
+        match self {
+            UnaryOp::Neg => "neg",
+            UnaryOp::Not => "not",
+        }
+    }
+
+    pub fn apply(self, operand: i32) -> i32 {
+        match self {
+            UnaryOp::Neg => operand.wrapping_neg(),
+            UnaryOp::Not => !operand,
+        }
+    }
+}
+
+/* How printf lays a number out, C style but without the %: an optional 0 to pad with zeros
+ * rather than spaces, a width, and d, u, x, b or o. Unlike print's, the hex, binary and octal
+ * have no prefix. In the instruction it's the low 13 bits:
+ *
+ *     bits 2-0   the conversion: d, x, b and o as print's formats are numbered, then u
+ *     bit 3      pad with zeros
+ *     bit 4      leave off the newline
+ *     bits 12-5  the width */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PrintSpec {
+    pub format: PrintFormat,
+    /* Decimal of the word's bits rather than its signed value. Only means anything for d. */
+    pub unsigned: bool,
+    pub width: u8,
+    pub zero_pad: bool,
+    pub newline: bool,
+}
+
+impl PrintSpec {
+    pub fn bits(self) -> u32 {
+        let conversion = if self.unsigned && self.format == PrintFormat::Decimal { 4 } else { self.format as u32 };
+        conversion | (self.zero_pad as u32) << 3 | (!self.newline as u32) << 4 | (self.width as u32) << 5
+    }
+
+    pub fn from_bits(bits: u32) -> Option<PrintSpec> {
+        let (format, unsigned) = match bits & 7 {
+            4 => (PrintFormat::Decimal, true),
+            conversion => (*PrintFormat::ALL.get(conversion as usize)?, false),
+        };
+        Some(PrintSpec { format, unsigned, width: (bits >> 5) as u8, zero_pad: bits & 8 != 0, newline: bits & 16 == 0 })
+    }
+
+    /* The letter after the width, as in %08x. */
+    pub fn conversion(self) -> char {
+        match self.format {
+            PrintFormat::Decimal if self.unsigned => 'u',
+            PrintFormat::Decimal => 'd',
+            PrintFormat::Hex => 'x',
+            PrintFormat::Binary => 'b',
+            PrintFormat::Octal => 'o',
+        }
+    }
+
+    /* What printf writes for a word: its value sign extended, and its bits. */
+    pub fn format(self, value: i64, bits: u64) -> String {
+        let digits = match self.conversion() {
+            'd' => format!("{}", value),
+            'u' => format!("{}", bits),
+            'x' => format!("{:x}", bits),
+            'b' => format!("{:b}", bits),
+            _ => format!("{:o}", bits),
+        };
+
+        let padding = (self.width as usize).saturating_sub(digits.len());
+        let mut text = match (self.zero_pad, digits.strip_prefix('-')) {
+            (true, Some(magnitude)) => format!("-{}{}", "0".repeat(padding), magnitude),
+            (true, None) => format!("{}{}", "0".repeat(padding), digits),
+            (false, _) => format!("{}{}", " ".repeat(padding), digits),
+        };
+        if self.newline {
+            text.push('\n');
+        }
+        text
+    }
+}
+
+impl fmt::Display for PrintSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "%{}", if self.zero_pad { "0" } else { "" })?;
+        if self.width != 0 {
+            write!(f, "{}", self.width)?;
+        }
+        write!(f, "{}{}", self.conversion(), if self.newline { "" } else { " nonl" })
+    }
+}
+
+/* Conditions for the unary ifs, which compare the top of the stack against zero. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ZeroCondition {
+    Zero = 0,
+    NonZero = 1,
+    Negative = 2,
+    Positive = 3,
+}
+
+impl ZeroCondition {
+    pub const ALL: [ZeroCondition; 4] = [
+        ZeroCondition::Zero, ZeroCondition::NonZero, ZeroCondition::Negative, ZeroCondition::Positive,
+    ];
+
+    /* The suffix on if that picks this condition, as in ifez. */
+    pub fn suffix(self) -> &'static str {
+        ["ez", "nz", "mi", "pl"][self as usize]
+    }
+
+    pub fn holds(self, value: i32) -> bool {
+        match self {
+            ZeroCondition::Zero => value == 0,
+            ZeroCondition::NonZero => value != 0,
+            ZeroCondition::Negative => value < 0,
+            ZeroCondition::Positive => value > 0,
+        }
+    }
+}
+
+/* What input does when there's nothing left to read. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EofMode {
+    /* Stop the machine. */
+    Fault = 0,
+    /* Push the smallest word, which no number that fits in a word can be confused with in
+     * practice. */
+    Sentinel = 1,
+    /* Push the number (0 at the end) and then 1 if there was one or 0 if not, ready for an
+     * ifez. */
+    Flag = 2,
+}
+
+impl EofMode {
+    pub const ALL: [EofMode; 3] = [EofMode::Fault, EofMode::Sentinel, EofMode::Flag];
+}
+
+/* What perfctr reads, all of them kept by the VM as it runs and counted from when the program
+ * started (or was last reset), wrapped to a word. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PerfCounter {
+    /* Instructions run before this one, the same as cycles. */
+    Instructions = 0,
+    /* Instructions that went somewhere other than the next one. */
+    Branches = 1,
+    /* The deepest the stack has been, in bytes below where it started. */
+    StackMax = 2,
+    /* Bytes read and written by the I/O instructions. */
+    IoBytes = 3,
+}
+
+impl PerfCounter {
+    pub const ALL: [PerfCounter; 4] = [PerfCounter::Instructions, PerfCounter::Branches, PerfCounter::StackMax, PerfCounter::IoBytes];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            PerfCounter::Instructions => "instructions",
+            PerfCounter::Branches => "branches",
+            PerfCounter::StackMax => "stackmax",
+            PerfCounter::IoBytes => "iobytes",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrintFormat {
+    Decimal = 0,
+    Hex = 1,
+    Binary = 2,
+    Octal = 3,
+}
+
+impl PrintFormat {
+    pub const ALL: [PrintFormat; 4] = [PrintFormat::Decimal, PrintFormat::Hex, PrintFormat::Binary, PrintFormat::Octal];
+
+    /* The suffix on print that picks this format, as in printh. */
+    pub fn suffix(self) -> &'static str {
+        ["", "h", "b", "o"][self as usize]
+    }
+
+    pub fn from_suffix(suffix: &str) -> Option<PrintFormat> {
+        PrintFormat::ALL.iter().copied().find(|f| f.suffix() == suffix)
+    }
+
+    /* The line print writes for a value. */
+    pub fn format(self, value: i32) -> String {
+        let bits = value as u32;
+
+        match self {
+            PrintFormat::Decimal => format!("{}\n", value),
+            PrintFormat::Hex => format!("0x{:x}\n", bits),
+            PrintFormat::Binary => format!("0b{:b}\n", bits),
+            PrintFormat::Octal => format!("0o{:o}\n", bits),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    /* The code is the low 24 bits of the word, so up to MAX_EXIT_CODE. */
+    Exit(u32),
+    Swap { from: i32, to: i32 },
+    Nop,
+    /* With retry, a line that isn't a number is skipped rather than stopping the machine. */
+    Input { eof: EofMode, retry: bool },
+    StInput(u32),
+    /* Dump bytes of memory from the address on top of the stack, or all of it and the
+     * registers for 0. */
+    Debug { bytes: u32, binary: bool },
+    Pop(u32),
+    Binary(BinaryOp),
+    Cmp(Condition),
+    Unary(UnaryOp),
+    StPrint(i32),
+    Call(i32),
+    /* A call that takes over the current frame: it goes to the target without pushing a return
+     * address, so the target's return goes back to whoever called this function. */
+    TailCall(i32),
+    Return(u32),
+    Goto(i32),
+    BinaryIf(Condition, i32),
+    UnaryIf(ZeroCondition, i32),
+    ToReturnStack,
+    FromReturnStack,
+    StrLen(i32),
+    StrCat,
+    StrCmp,
+    ReadFile,
+    /* 0 writes a string, anything else that many bytes of memory. */
+    WriteFile(u32),
+    Arg,
+    GetEnv,
+    Clock,
+    Cycles,
+    Rand,
+    Load,
+    Store,
+    /* A byte offset from the spawn to where the new context starts. */
+    Spawn(i32),
+    Yield,
+    Join,
+    Cas,
+    FetchAdd,
+    Lock,
+    Unlock,
+    StPrintN,
+    PushByte,
+    PushHalf,
+    PopByte { signed: bool },
+    PopHalf { signed: bool },
+    /* Pop an address and carry on from there. */
+    JumpI,
+    /* Pop a selector and go by the entry for it in the table of this many offsets that follows
+     * the instruction, or past the table if there's no such entry. */
+    JumpTable(u32),
+    /* Push the address a byte offset from this instruction. */
+    Lea(i32),
+    /* Push a copy of the word this many below the top, 0 being the top. */
+    Pick(u32),
+    /* Bring the deepest of this many words on top of the stack to the top. */
+    Roll(u32),
+    /* Drop this many words. */
+    Drop(u32),
+    /* Replace the string on top with the number it spells, and a flag for whether it did. */
+    Atoi,
+    /* Replace the word on top with a string of it in decimal. */
+    Itoa,
+    /* Print the word on top laid out as the spec says. */
+    PrintF(PrintSpec),
+    /* Stop for a debugger, or hand StepResult::Breakpoint back to whoever is stepping. */
+    Brk,
+    /* Pop a word and fault if it's 0, with the 0-terminated message at a byte offset from this
+     * instruction, or none for 0. */
+    Assert(i32),
+    /* Push how many more bytes the stack can take. */
+    StackDepth,
+    /* Push one of the VM's counters. */
+    PerfCtr(PerfCounter),
+    Dup(i32),
+    Print(i32, PrintFormat),
+    Dump,
+    Push(i32),
+}
+
+/* Where an instruction that names an address keeps its offset to it: a signed field this many
+ * bits wide, counting words or bytes. An offset that doesn't fit is an error for whoever is
+ * filling the field in, never cut down to fit. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OffsetField {
+    pub bits: u32,
+    /* Whether the field counts words rather than bytes. */
+    pub words: bool,
+    /* Whether the offset has to be a whole number of instructions, which it does for anything
+     * that goes there rather than pointing at data. */
+    pub aligned: bool,
+}
+
+impl OffsetField {
+    /* call, tailcall and goto. */
+    pub const JUMP: OffsetField = OffsetField { bits: 26, words: true, aligned: true };
+    /* The binary and unary ifs. */
+    pub const IF: OffsetField = OffsetField { bits: 25, words: false, aligned: true };
+    pub const SPAWN: OffsetField = OffsetField { bits: 20, words: false, aligned: true };
+    /* lea and assert, which can point at any byte. */
+    pub const ADDRESS: OffsetField = OffsetField { bits: 20, words: false, aligned: false };
+
+    /* The byte offsets that fit. */
+    pub fn range(self) -> RangeInclusive<i64> {
+        let scale = if self.words { 4 } else { 1 };
+        -(1i64 << (self.bits - 1)) * scale..=((1i64 << (self.bits - 1)) - 1) * scale
+    }
+
+    /* Why a byte offset can't go in the field, if it can't: how far it is, and how many bits
+     * that takes against how many there are. */
+    pub fn check(self, offset: i64) -> Result<(), String> {
+        if self.aligned && offset % 4 != 0 {
+            return Err(format!("the target is {} bytes away, which isn't a multiple of 4", offset));
+        }
+        if self.range().contains(&offset) {
+            return Ok(());
+        }
+
+        let (value, distance) = match self.words {
+            true => (offset / 4, format!("{} bytes away, {} words", offset, offset / 4)),
+            false => (offset, format!("{} bytes away", offset)),
+        };
+        /* A sign bit and every bit up to the highest one that differs from it. */
+        let needed = i64::BITS - (value ^ (value >> 63)).leading_zeros() + 1;
+        Err(format!("the target is {}, which takes {} bits, and the field has {}, for {}..{} bytes",
+            distance, needed, self.bits, self.range().start(), self.range().end()))
+    }
+}
+
+/* Sign extend the low bits bits of a word. */
+fn signed(word: u32, bits: u32) -> i32 {
+    ((word << (32 - bits)) as i32) >> (32 - bits)
+}
+
+/* The low bits bits of a value. */
+fn field(value: i64, bits: u32) -> u32 {
+    (value as u32) & (u32::MAX >> (32 - bits))
+}
+
+impl Instruction {
+    /* Lay the instruction out as a word. Operands that don't fit their field are cut down to
+     * it; the assembler checks ranges before it gets here. */
+    pub fn encode(&self) -> u32 {
+        match *self {
+            Instruction::Exit(code) => field(code as i64, 24),
+            Instruction::Swap { from, to } => 0x0100_0000 | (field(from as i64, 12) << 12) | field(to as i64, 12),
+            Instruction::Nop => 0x0200_0000,
+            Instruction::Input { eof, retry } => 0x0400_0000 | ((retry as u32) << 2) | eof as u32,
+            Instruction::StInput(max) => 0x0500_0000 | field(max as i64, 24),
+            Instruction::Debug { bytes, binary } => 0x0F00_0000 | (binary as u32) << 23 | (bytes & 0x7F_FFFF),
+            Instruction::Pop(bytes) => 0x1000_0000 | field(bytes as i64, 28),
+            Instruction::Binary(op) => 0x2000_0000 | ((op as u32) << 24),
+            Instruction::Cmp(condition) => 0x2A00_0000 | ((condition as u32) << 20),
+            Instruction::Unary(op) => 0x3000_0000 | ((op as u32) << 24),
+            Instruction::StPrint(offset) => 0x4000_0000 | field(offset as i64, 28),
+            Instruction::Call(offset) => 0x5000_0000 | (field((offset >> 2) as i64, 26) << 2),
+            Instruction::TailCall(offset) => 0x5000_0001 | (field((offset >> 2) as i64, 26) << 2),
+            Instruction::Return(bytes) => 0x6000_0000 | (field(bytes as i64, 28) & !3),
+            Instruction::Goto(offset) => 0x7000_0000 | (field((offset >> 2) as i64, 26) << 2),
+            /* Bit 28 is the opcode's, so leu and geu go under opcode 9 with bit 27 set. */
+            Instruction::BinaryIf(condition, offset) => match condition as u32 {
+                id @ 0..=7 => 0x8000_0000 | (id << 25) | field(offset as i64, 25),
+                id => 0x9800_0000 | ((id - 8) << 25) | field(offset as i64, 25),
+            },
+            Instruction::UnaryIf(condition, offset) => {
+                0x9000_0000 | ((condition as u32) << 25) | field(offset as i64, 25)
+            },
+            Instruction::ToReturnStack => 0xB000_0000,
+            Instruction::FromReturnStack => 0xB010_0000,
+            Instruction::StrLen(offset) => 0xB020_0000 | field(offset as i64, 20),
+            Instruction::StrCat => 0xB030_0000,
+            Instruction::StrCmp => 0xB040_0000,
+            Instruction::ReadFile => 0xB050_0000,
+            Instruction::WriteFile(bytes) => 0xB060_0000 | field(bytes as i64, 20),
+            Instruction::Arg => 0xB070_0000,
+            Instruction::GetEnv => 0xB080_0000,
+            Instruction::Clock => 0xB090_0000,
+            Instruction::Cycles => 0xB0A0_0000,
+            Instruction::Rand => 0xB0B0_0000,
+            Instruction::Load => 0xB0C0_0000,
+            Instruction::Store => 0xB0D0_0000,
+            Instruction::Spawn(offset) => 0xB0E0_0000 | field(offset as i64, 20),
+            Instruction::Yield => 0xB0F0_0000,
+            Instruction::Join => 0xB100_0000,
+            Instruction::Cas => 0xB110_0000,
+            Instruction::FetchAdd => 0xB120_0000,
+            Instruction::Lock => 0xB130_0000,
+            Instruction::Unlock => 0xB140_0000,
+            Instruction::StPrintN => 0xB150_0000,
+            Instruction::PushByte => 0xB160_0000,
+            Instruction::PushHalf => 0xB170_0000,
+            Instruction::PopByte { signed } => 0xB180_0000 | !signed as u32,
+            Instruction::PopHalf { signed } => 0xB190_0000 | !signed as u32,
+            Instruction::JumpI => 0xB1A0_0000,
+            Instruction::JumpTable(entries) => 0xB1B0_0000 | field(entries as i64, 20),
+            Instruction::Lea(offset) => 0xB1C0_0000 | field(offset as i64, 20),
+            Instruction::Pick(depth) => 0xB1D0_0000 | field(depth as i64, 20),
+            Instruction::Roll(count) => 0xB1E0_0000 | field(count as i64, 20),
+            Instruction::Drop(count) => 0xB1F0_0000 | field(count as i64, 20),
+            Instruction::Atoi => 0xB200_0000,
+            Instruction::Itoa => 0xB210_0000,
+            Instruction::PrintF(spec) => 0xB220_0000 | spec.bits(),
+            Instruction::Brk => 0xB230_0000,
+            Instruction::Assert(offset) => 0xB240_0000 | field(offset as i64, 20),
+            Instruction::StackDepth => 0xB250_0000,
+            Instruction::PerfCtr(counter) => 0xB260_0000 | counter as u32,
+            Instruction::Dup(offset) => 0xC000_0000 | field(offset as i64, 28),
+            Instruction::Print(offset, format) => 0xD000_0000 | (field(offset as i64, 26) & !3) | format as u32,
+            Instruction::Dump => 0xE000_0000,
+            Instruction::Push(value) => 0xF000_0000 | field(value as i64, 28),
+        }
+    }
+
+    /* Work out what a word means, the same way the VM does. None for words the VM would reject.
+     * Bits an instruction doesn't look at are ignored, so encoding the result doesn't always give
+     * back the same word. */
+    pub fn decode(word: u32) -> Option<Instruction> {
+        let instruction = match word >> 28 {
+            0 => match (word >> 24) & 0xF {
+                0x0 => Instruction::Exit(word & 0xFF_FFFF),
+                0x1 => Instruction::Swap { from: signed(word >> 12, 12), to: signed(word, 12) },
+                0x2 => Instruction::Nop,
+                0x4 => Instruction::Input {
+                    eof: *EofMode::ALL.get((word & 3) as usize)?,
+                    retry: word & 4 != 0,
+                },
+                0x5 => Instruction::StInput(word & 0xFF_FFFF),
+                0xF => Instruction::Debug { bytes: word & 0x7F_FFFF, binary: word & (1 << 23) != 0 },
+                _ => return None,
+            },
+            1 if word & 3 != 0 => return None,
+            1 => Instruction::Pop(word & 0x0FFF_FFFF),
+            2 => match (word >> 24) & 0xF {
+                10 => Instruction::Cmp(Condition::from_id((word >> 20) & 0xF)?),
+                id => Instruction::Binary(BinaryOp::from_id(id)?),
+            },
+            3 => match (word >> 24) & 0xF {
+                0 => Instruction::Unary(UnaryOp::Neg),
+                1 => Instruction::Unary(UnaryOp::Not),
+                _ => return None,
+            },
+            4 => Instruction::StPrint(signed(word, 28)),
+            5 if word & 3 == 1 => Instruction::TailCall(signed(word >> 2, 26) << 2),
+            5 => Instruction::Call(signed(word >> 2, 26) << 2),
+            6 => Instruction::Return(word & 0x0FFF_FFFC),
+            7 => Instruction::Goto(signed(word >> 2, 26) << 2),
+            /* The condition field runs into the opcode, so only the first eight fit here, and the
+             * rest are opcode 9 with bit 27 set. */
+            8 => Instruction::BinaryIf(Condition::from_id((word >> 25) & 0x7)?, signed(word, 25)),
+            9 if word & (1 << 27) != 0 => Instruction::BinaryIf(Condition::from_id(8 + ((word >> 25) & 0x3))?, signed(word, 25)),
+            9 => Instruction::UnaryIf(ZeroCondition::ALL[((word >> 25) & 3) as usize], signed(word, 25)),
+            11 => match (word >> 20) & 0xFF {
+                0x00 => Instruction::ToReturnStack,
+                0x01 => Instruction::FromReturnStack,
+                0x02 => Instruction::StrLen(signed(word, 20)),
+                0x03 => Instruction::StrCat,
+                0x04 => Instruction::StrCmp,
+                0x05 => Instruction::ReadFile,
+                0x06 => Instruction::WriteFile(word & 0xF_FFFF),
+                0x07 => Instruction::Arg,
+                0x08 => Instruction::GetEnv,
+                0x09 => Instruction::Clock,
+                0x0A => Instruction::Cycles,
+                0x0B => Instruction::Rand,
+                0x0C => Instruction::Load,
+                0x0D => Instruction::Store,
+                0x0E => Instruction::Spawn(signed(word, 20)),
+                0x0F => Instruction::Yield,
+                0x10 => Instruction::Join,
+                0x11 => Instruction::Cas,
+                0x12 => Instruction::FetchAdd,
+                0x13 => Instruction::Lock,
+                0x14 => Instruction::Unlock,
+                0x15 => Instruction::StPrintN,
+                0x16 => Instruction::PushByte,
+                0x17 => Instruction::PushHalf,
+                0x18 if word & 0xF_FFFE == 0 => Instruction::PopByte { signed: word & 1 == 0 },
+                0x19 if word & 0xF_FFFE == 0 => Instruction::PopHalf { signed: word & 1 == 0 },
+                0x1A => Instruction::JumpI,
+                0x1B => Instruction::JumpTable(word & 0xF_FFFF),
+                0x1C => Instruction::Lea(signed(word, 20)),
+                0x1D => Instruction::Pick(word & 0xF_FFFF),
+                0x1E => Instruction::Roll(word & 0xF_FFFF),
+                0x1F => Instruction::Drop(word & 0xF_FFFF),
+                0x20 => Instruction::Atoi,
+                0x21 => Instruction::Itoa,
+                0x22 if word & 0xF_E000 == 0 => Instruction::PrintF(PrintSpec::from_bits(word & 0x1FFF)?),
+                0x23 => Instruction::Brk,
+                0x24 => Instruction::Assert(signed(word, 20)),
+                0x25 => Instruction::StackDepth,
+                0x26 => Instruction::PerfCtr(*PerfCounter::ALL.get((word & 0xF_FFFF) as usize)?),
+                _ => return None,
+            },
+            12 => Instruction::Dup(signed(word, 28)),
+            13 => {
+                /* print sign extends from bit 25 but keeps bits 26 and 27 when it doesn't. */
+                let offset = (word & 0x0FFF_FFFC) as i32;
+                let offset = if word & (1 << 25) != 0 { offset | !0x03FF_FFFF } else { offset };
+                Instruction::Print(offset, PrintFormat::ALL[(word & 3) as usize])
+            },
+            14 => Instruction::Dump,
+            15 => Instruction::Push(signed(word, 28)),
+            _ => return None,
+        };
+
+        Some(instruction)
+    }
+
+    /* Which of the groups in VmConfig::allowed_ops this belongs to, if any. */
+    pub fn group(&self) -> Option<Ops> {
+        match self {
+            Instruction::Input { .. } | Instruction::StInput(_) | Instruction::StPrint(_) | Instruction::StPrintN
+                | Instruction::Print(..) | Instruction::PrintF(_) => Some(Ops::IO),
+            Instruction::Debug { .. } | Instruction::Dump => Some(Ops::DEBUG),
+            Instruction::ReadFile | Instruction::WriteFile(_) => Some(Ops::FILES),
+            Instruction::GetEnv | Instruction::Clock => Some(Ops::HOST),
+            _ => None,
+        }
+    }
+
+    /* The byte offset from the instruction to the address its operand names, for the ones that
+     * name one: the branches, spawn, lea, and assert with a message. */
+    pub fn branch_offset(&self) -> Option<i32> {
+        match *self {
+            Instruction::Call(offset) | Instruction::TailCall(offset) | Instruction::Goto(offset) | Instruction::BinaryIf(_, offset)
+                | Instruction::UnaryIf(_, offset) | Instruction::Spawn(offset) | Instruction::Lea(offset)
+                | Instruction::Assert(offset) => Some(offset),
+            _ => None,
+        }
+    }
+
+    /* The field the branch_offset goes in. */
+    pub fn offset_field(&self) -> Option<OffsetField> {
+        match self {
+            Instruction::Call(_) | Instruction::TailCall(_) | Instruction::Goto(_) => Some(OffsetField::JUMP),
+            Instruction::BinaryIf(..) | Instruction::UnaryIf(..) => Some(OffsetField::IF),
+            Instruction::Spawn(_) => Some(OffsetField::SPAWN),
+            Instruction::Lea(_) | Instruction::Assert(_) => Some(OffsetField::ADDRESS),
+            _ => None,
+        }
+    }
+}
+
+/* Written the way the assembler reads it, with every operand spelled out and branch targets as
+ * byte offsets. */
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Instruction::Exit(code) => write!(f, "exit {}", code),
+            Instruction::Swap { from, to } => write!(f, "swap {} {}", from * 4, to * 4),
+            Instruction::Nop => write!(f, "nop"),
+            Instruction::Input { eof, retry } => {
+                write!(f, "input")?;
+                match eof {
+                    EofMode::Fault => (),
+                    EofMode::Sentinel => write!(f, " sentinel")?,
+                    EofMode::Flag => write!(f, " flag")?,
+                }
+                if retry {
+                    write!(f, " retry")?;
+                }
+                Ok(())
+            },
+            Instruction::StInput(max) => write!(f, "stinput {}", max),
+            Instruction::Debug { bytes, binary } => {
+                write!(f, "debug{}", if binary { "b" } else { "" })?;
+                match bytes {
+                    0 => Ok(()),
+                    bytes => write!(f, " {}", bytes),
+                }
+            },
+            Instruction::Pop(bytes) => write!(f, "pop {}", bytes),
+            Instruction::Binary(op) => write!(f, "{}", op.mnemonic()),
+            Instruction::Cmp(condition) => write!(f, "cmp{}", condition.suffix()),
+            Instruction::Unary(op) => write!(f, "{}", op.mnemonic()),
+            Instruction::StPrint(offset) => write!(f, "stprint {}", offset),
+            Instruction::Call(offset) => write!(f, "call {}", offset),
+            Instruction::TailCall(offset) => write!(f, "tailcall {}", offset),
+            Instruction::Return(bytes) => write!(f, "return {}", bytes),
+            Instruction::Goto(offset) => write!(f, "goto {}", offset),
+            Instruction::BinaryIf(condition, offset) => write!(f, "if{} {}", condition.suffix(), offset),
+            Instruction::UnaryIf(condition, offset) => write!(f, "if{} {}", condition.suffix(), offset),
+            Instruction::ToReturnStack => write!(f, ">r"),
+            Instruction::FromReturnStack => write!(f, "r>"),
+            Instruction::StrLen(offset) => write!(f, "strlen {}", offset),
+            Instruction::StrCat => write!(f, "strcat"),
+            Instruction::StrCmp => write!(f, "strcmp"),
+            Instruction::ReadFile => write!(f, "readfile"),
+            Instruction::WriteFile(bytes) => write!(f, "writefile {}", bytes),
+            Instruction::Arg => write!(f, "arg"),
+            Instruction::GetEnv => write!(f, "getenv"),
+            Instruction::Clock => write!(f, "clock"),
+            Instruction::Cycles => write!(f, "cycles"),
+            Instruction::Rand => write!(f, "rand"),
+            Instruction::Load => write!(f, "load"),
+            Instruction::Store => write!(f, "store"),
+            Instruction::Spawn(offset) => write!(f, "spawn {}", offset),
+            Instruction::Yield => write!(f, "yield"),
+            Instruction::Join => write!(f, "join"),
+            Instruction::Cas => write!(f, "cas"),
+            Instruction::FetchAdd => write!(f, "fetchadd"),
+            Instruction::Lock => write!(f, "lock"),
+            Instruction::Unlock => write!(f, "unlock"),
+            Instruction::StPrintN => write!(f, "stprintn"),
+            Instruction::PushByte => write!(f, "pushb"),
+            Instruction::PushHalf => write!(f, "pushh"),
+            Instruction::PopByte { signed } => write!(f, "popb{}", if signed { "" } else { "u" }),
+            Instruction::PopHalf { signed } => write!(f, "poph{}", if signed { "" } else { "u" }),
+            Instruction::JumpI => write!(f, "jumpi"),
+            Instruction::JumpTable(entries) => write!(f, "jumptable {}", entries),
+            Instruction::Lea(offset) => write!(f, "lea {}", offset),
+            Instruction::Pick(depth) => write!(f, "pick {}", depth),
+            Instruction::Roll(count) => write!(f, "roll {}", count),
+            Instruction::Drop(count) => write!(f, "drop {}", count),
+            Instruction::Atoi => write!(f, "atoi"),
+            Instruction::Itoa => write!(f, "itoa"),
+            Instruction::PrintF(spec) => write!(f, "printf {}", spec),
+            Instruction::Brk => write!(f, "brk"),
+            Instruction::Assert(offset) => write!(f, "assert {}", offset),
+            Instruction::StackDepth => write!(f, "stackdepth"),
+            Instruction::PerfCtr(counter) => write!(f, "perfctr {}", counter.name()),
+            Instruction::Dup(offset) => write!(f, "dup {}", offset),
+            Instruction::Print(offset, format) => write!(f, "print{} {}", format.suffix(), offset),
+            Instruction::Dump => write!(f, "dump"),
+            Instruction::Push(value) => write!(f, "push {}", value),
+        }
+    }
+}
+
+/* Assemble one line of assembly, in the asm module's syntax, into the word it stands for.
+ * There are no labels to go by, so branch targets are byte offsets from the instruction. */
+pub fn assemble_line(text: &str) -> Result<u32, AsmError> {
+    match asm::assemble_one(text)?.as_slice() {
+        [word] => Ok(*word),
+        words => Err(AsmError {
+            line: 1,
+            file: None,
+            message: format!("{} assembles to {} words, not one", text.trim(), words.len()),
+        }),
+    }
+}
+
+/* The assembly for a word. Words that aren't instructions come out as .word, so the result
+ * always assembles back to the same word, give or take bits the instruction ignores. */
+pub fn disassemble(word: u32) -> String {
+    match Instruction::decode(word) {
+        Some(instruction) => format!("{}", instruction),
+        None => format!(".word {:#010x}", word),
+    }
+}
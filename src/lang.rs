@@ -0,0 +1,639 @@
+/* A tiny language for the VM, compiled by `vm compile`. Integer variables, C-ish expressions,
+ * if/else, while and print:
+ *
+ *     # Count down from ten.
+ *     var n = 10;
+ *     while (n > 0) {
+ *         print n;
+ *         n = n - 1;
+ *     }
+ *     print "liftoff!\n";
+ *
+ * The statements are `var name [= expr];`, `name = expr;`, `if (expr) { ... } [else ...]`,
+ * `while (expr) { ... }`, `print expr;` (the value and a newline), `print "text";` and
+ * `exit [code];`. Expressions have numbers (decimal, 0x hex, 0b binary), variables, `input()`
+ * (a number read from stdin) and C's operators with C's precedence; comparisons and logic give 1
+ * or 0, and both sides of && and || are always evaluated. Values are words, so arithmetic
+ * wraps. # starts a comment.
+ *
+ * The compiler turns the program into assembly and hands it to the asm module. Every variable
+ * gets a slot at the bottom of the stack when the program starts, and the compiler keeps track
+ * of how deep the stack is at each point so it can reach them with dup and swap. */
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::asm::{self, Assembled};
+use crate::expr::precedence;
+use crate::strings;
+
+/* Something wrong with a program, and which line it's on. */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LangError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for LangError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+fn error(line: usize, message: String) -> LangError {
+    LangError { line, message }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Number(i64),
+    Name(String),
+    Text(String),
+    Symbol(&'static str),
+}
+
+/* Longest first, so << isn't read as two <s. */
+const SYMBOLS: [&str; 26] = [
+    "||", "&&", "==", "!=", "<=", ">=", "<<", ">>",
+    "<", ">", "+", "-", "*", "/", "%", "&", "|", "^", "!", "~", "(", ")", "{", "}", "=", ";",
+];
+
+const KEYWORDS: [&str; 7] = ["var", "if", "else", "while", "print", "exit", "input"];
+
+fn parse_text(rest: &str, line: usize) -> Result<(String, usize), LangError> {
+    let mut text = String::new();
+    let mut chars = rest.char_indices().skip(1);
+
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((text, i + 1)),
+            '\n' => break,
+            '\\' => match chars.next().map(|(_, c)| c) {
+                Some('n') => text.push('\n'),
+                Some('t') => text.push('\t'),
+                Some('"') => text.push('"'),
+                Some('\\') => text.push('\\'),
+                other => return Err(error(line, format!("unknown escape \\{}", other.unwrap_or(' ')))),
+            },
+            _ => text.push(c),
+        }
+    }
+
+    Err(error(line, String::from("unterminated string")))
+}
+
+fn tokenize(source: &str) -> Result<Vec<(Token, usize)>, LangError> {
+    let mut tokens = Vec::new();
+    let mut line = 1;
+    let mut rest = source;
+
+    while let Some(c) = rest.chars().next() {
+        if c == '\n' {
+            line += 1;
+            rest = &rest[1..];
+        } else if c.is_whitespace() {
+            rest = &rest[c.len_utf8()..];
+        } else if c == '#' {
+            rest = &rest[rest.find('\n').unwrap_or(rest.len())..];
+        } else if c.is_ascii_digit() {
+            let end = rest.find(|c: char| !c.is_ascii_alphanumeric()).unwrap_or(rest.len());
+            let literal = &rest[..end];
+            let number = asm::parse_number(literal).ok_or_else(|| error(line, format!("bad number {}", literal)))?;
+            tokens.push((Token::Number(number), line));
+            rest = &rest[end..];
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let end = rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(rest.len());
+            tokens.push((Token::Name(String::from(&rest[..end])), line));
+            rest = &rest[end..];
+        } else if c == '"' {
+            let (text, end) = parse_text(rest, line)?;
+            tokens.push((Token::Text(text), line));
+            rest = &rest[end..];
+        } else {
+            let Some(symbol) = SYMBOLS.iter().find(|symbol| rest.starts_with(**symbol)) else {
+                return Err(error(line, format!("unexpected {}", c)));
+            };
+
+            tokens.push((Token::Symbol(symbol), line));
+            rest = &rest[symbol.len()..];
+        }
+    }
+
+    Ok(tokens)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Expr {
+    Number(i64),
+    Variable(String, usize),
+    Input,
+    Unary(&'static str, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Statement {
+    Var(String, Option<Expr>),
+    Assign(String, Expr),
+    If(Expr, Vec<(Statement, usize)>, Vec<(Statement, usize)>),
+    While(Expr, Vec<(Statement, usize)>),
+    Print(Expr),
+    PrintText(String),
+    Exit(u32),
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position).map(|(token, _)| token)
+    }
+
+    /* The line the parser is on, for errors. */
+    fn line(&self) -> usize {
+        self.tokens.get(self.position)
+            .or_else(|| self.tokens.last())
+            .map_or(1, |&(_, line)| line)
+    }
+
+    fn unexpected(&self) -> LangError {
+        match self.peek() {
+            Some(Token::Number(n)) => error(self.line(), format!("unexpected {}", n)),
+            Some(Token::Name(name)) => error(self.line(), format!("unexpected {}", name)),
+            Some(Token::Text(_)) => error(self.line(), String::from("unexpected string")),
+            Some(Token::Symbol(symbol)) => error(self.line(), format!("unexpected {}", symbol)),
+            None => error(self.line(), String::from("unexpected end of program")),
+        }
+    }
+
+    fn at(&self, symbol: &str) -> bool {
+        matches!(self.peek(), Some(Token::Symbol(s)) if *s == symbol)
+    }
+
+    fn at_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Name(name)) if name == keyword)
+    }
+
+    /* Something missing is reported on the line of whatever it should have followed, which is
+     * where a forgotten ; is. */
+    fn expect(&mut self, symbol: &str) -> Result<(), LangError> {
+        if !self.at(symbol) {
+            let line = self.position.checked_sub(1)
+                .and_then(|previous| self.tokens.get(previous))
+                .map_or(self.line(), |&(_, line)| line);
+
+            return Err(match self.peek() {
+                Some(_) => error(line, format!("expected {}", symbol)),
+                None => error(line, format!("expected {} at the end of the program", symbol)),
+            });
+        }
+
+        self.position += 1;
+        Ok(())
+    }
+
+    fn name(&mut self) -> Result<String, LangError> {
+        match self.peek() {
+            Some(Token::Name(name)) if !KEYWORDS.contains(&name.as_str()) => {
+                let name = name.clone();
+                self.position += 1;
+                Ok(name)
+            },
+            _ => Err(error(self.line(), String::from("expected a variable name"))),
+        }
+    }
+
+    fn block(&mut self) -> Result<Vec<(Statement, usize)>, LangError> {
+        self.expect("{")?;
+
+        let mut statements = Vec::new();
+        while !self.at("}") {
+            if self.peek().is_none() {
+                return Err(error(self.line(), String::from("expected } at the end of the program")));
+            }
+            statements.push(self.statement()?);
+        }
+
+        self.position += 1;
+        Ok(statements)
+    }
+
+    fn condition(&mut self) -> Result<Expr, LangError> {
+        self.expect("(")?;
+        let condition = self.expression()?;
+        self.expect(")")?;
+        Ok(condition)
+    }
+
+    fn statement(&mut self) -> Result<(Statement, usize), LangError> {
+        let line = self.line();
+        let keyword = match self.peek() {
+            Some(Token::Name(name)) if KEYWORDS.contains(&name.as_str()) => name.clone(),
+            Some(Token::Name(_)) => {
+                let name = self.name()?;
+                self.expect("=")?;
+                let value = self.expression()?;
+                self.expect(";")?;
+                return Ok((Statement::Assign(name, value), line));
+            },
+            _ => return Err(self.unexpected()),
+        };
+        self.position += 1;
+
+        let statement = match keyword.as_str() {
+            "var" => {
+                let name = self.name()?;
+                let value = if self.at("=") {
+                    self.position += 1;
+                    Some(self.expression()?)
+                } else {
+                    None
+                };
+                self.expect(";")?;
+                Statement::Var(name, value)
+            },
+            "if" => {
+                let condition = self.condition()?;
+                let then = self.block()?;
+                let otherwise = if self.at_keyword("else") {
+                    self.position += 1;
+                    if self.at_keyword("if") {
+                        Vec::from([self.statement()?])
+                    } else {
+                        self.block()?
+                    }
+                } else {
+                    Vec::new()
+                };
+                Statement::If(condition, then, otherwise)
+            },
+            "while" => {
+                let condition = self.condition()?;
+                Statement::While(condition, self.block()?)
+            },
+            "print" => {
+                let statement = match self.peek() {
+                    Some(Token::Text(text)) => {
+                        let text = text.clone();
+                        self.position += 1;
+                        Statement::PrintText(text)
+                    },
+                    _ => Statement::Print(self.expression()?),
+                };
+                self.expect(";")?;
+                statement
+            },
+            "exit" => {
+                let code = match self.peek() {
+                    Some(&Token::Number(code)) => {
+                        self.position += 1;
+                        u32::try_from(code).ok()
+                            .filter(|&code| code <= 0xFF_FFFF)
+                            .ok_or_else(|| error(line, format!("exit code {} is out of range (0..16777215)", code)))?
+                    },
+                    _ => 0,
+                };
+                self.expect(";")?;
+                Statement::Exit(code)
+            },
+            _ => {
+                self.position -= 1;
+                return Err(self.unexpected());
+            },
+        };
+
+        Ok((statement, line))
+    }
+
+    /* Binary operators, by precedence climbing. */
+    fn binary(&mut self, min_precedence: u8) -> Result<Expr, LangError> {
+        let mut left = self.unary()?;
+
+        while let Some(&Token::Symbol(symbol)) = self.peek() {
+            let Some(precedence) = precedence(symbol).filter(|&p| p >= min_precedence) else {
+                break;
+            };
+
+            self.position += 1;
+            let right = self.binary(precedence + 1)?;
+            left = Expr::Binary(symbol, Box::new(left), Box::new(right));
+        }
+
+        Ok(left)
+    }
+
+    fn expression(&mut self) -> Result<Expr, LangError> {
+        self.binary(1)
+    }
+
+    fn unary(&mut self) -> Result<Expr, LangError> {
+        match self.peek() {
+            Some(&Token::Symbol(symbol)) if matches!(symbol, "-" | "!" | "~") => {
+                self.position += 1;
+                Ok(Expr::Unary(symbol, Box::new(self.unary()?)))
+            },
+            _ => self.primary(),
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr, LangError> {
+        let line = self.line();
+
+        match self.peek().cloned() {
+            Some(Token::Number(n)) => {
+                self.position += 1;
+                Ok(Expr::Number(n))
+            },
+            Some(Token::Name(name)) if name == "input" => {
+                self.position += 1;
+                self.expect("(")?;
+                self.expect(")")?;
+                Ok(Expr::Input)
+            },
+            Some(Token::Name(_)) => Ok(Expr::Variable(self.name()?, line)),
+            Some(Token::Symbol("(")) => {
+                self.position += 1;
+                let inner = self.expression()?;
+                self.expect(")")?;
+                Ok(inner)
+            },
+            _ => Err(self.unexpected()),
+        }
+    }
+}
+
+/* Writes the assembly. depth is how many words are on the stack at the current point, counting
+ * the variables' slots. */
+struct Generator {
+    /* Every variable in the program, in slot order. */
+    slots: Vec<String>,
+    /* The ones declared so far. */
+    declared: Vec<String>,
+    depth: usize,
+    labels: usize,
+    assembly: String,
+    /* The source line each line of assembly came from. */
+    lines: Vec<usize>,
+    line: usize,
+}
+
+/* Gather up every variable declared anywhere, so each can have a slot from the start. */
+fn collect_variables(statements: &[(Statement, usize)], slots: &mut Vec<String>) -> Result<(), LangError> {
+    for (statement, line) in statements {
+        match statement {
+            Statement::Var(name, _) if slots.contains(name) => {
+                return Err(error(*line, format!("{} is already declared", name)));
+            },
+            Statement::Var(name, _) => slots.push(name.clone()),
+            Statement::If(_, then, otherwise) => {
+                collect_variables(then, slots)?;
+                collect_variables(otherwise, slots)?;
+            },
+            Statement::While(_, body) => collect_variables(body, slots)?,
+            _ => (),
+        }
+    }
+
+    Ok(())
+}
+
+impl Generator {
+    fn emit(&mut self, text: &str) {
+        self.assembly.push_str(text);
+        self.assembly.push('\n');
+        self.lines.push(self.line);
+    }
+
+    fn label(&mut self, name: &str) -> String {
+        self.labels += 1;
+        format!("_{}{}", name, self.labels)
+    }
+
+    fn place(&mut self, label: &str) {
+        self.emit(&format!("{}:", label));
+    }
+
+    /* How far above the stack pointer a variable's slot is, in bytes. */
+    fn offset(&self, name: &str, line: usize) -> Result<usize, LangError> {
+        if !self.declared.iter().any(|declared| declared == name) {
+            return Err(error(line, format!("{} isn't declared", name)));
+        }
+
+        let slot = self.slots.iter().position(|slot| slot == name).expect("every declared variable has a slot");
+        Ok((self.depth - 1 - slot) * 4)
+    }
+
+    fn push(&mut self, value: i64) {
+        let value = value as i32;
+
+        if (-(1 << 27)..1 << 27).contains(&value) {
+            self.emit(&format!("push {}", value));
+        } else {
+            /* Too big for push's immediate: build it from its two halves. */
+            self.emit(&format!("push {}", value >> 16));
+            self.emit("push 16");
+            self.emit("lsl");
+            self.emit(&format!("push {}", value & 0xFFFF));
+            self.emit("or");
+        }
+
+        self.depth += 1;
+    }
+
+    fn expression(&mut self, expr: &Expr) -> Result<(), LangError> {
+        match expr {
+            Expr::Number(n) => self.push(*n),
+            Expr::Variable(name, line) => {
+                let offset = self.offset(name, *line)?;
+                self.emit(&format!("dup {}", offset));
+                self.depth += 1;
+            },
+            Expr::Input => {
+                self.emit("input");
+                self.depth += 1;
+            },
+            Expr::Unary(symbol, operand) => {
+                self.expression(operand)?;
+                match *symbol {
+                    "-" => self.emit("neg"),
+                    "~" => self.emit("not"),
+                    _ => self.truth("cmpeq"),
+                }
+            },
+            Expr::Binary(symbol @ ("&&" | "||"), left, right) => {
+                self.expression(left)?;
+                self.truth("cmpne");
+                self.expression(right)?;
+                self.truth("cmpne");
+                self.emit(if *symbol == "&&" { "and" } else { "or" });
+                self.depth -= 1;
+            },
+            Expr::Binary(symbol, left, right) => {
+                self.expression(left)?;
+                self.expression(right)?;
+                self.emit(match *symbol {
+                    "+" => "add",
+                    "-" => "sub",
+                    "*" => "mul",
+                    "/" => "div",
+                    "%" => "rem",
+                    "&" => "and",
+                    "|" => "or",
+                    "^" => "xor",
+                    "<<" => "lsl",
+                    ">>" => "asr",
+                    "==" => "cmpeq",
+                    "!=" => "cmpne",
+                    "<" => "cmplt",
+                    ">" => "cmpgt",
+                    "<=" => "cmple",
+                    _ => "cmpge",
+                });
+                self.depth -= 1;
+            },
+        }
+
+        Ok(())
+    }
+
+    /* Compare the top of the stack against zero, leaving 1 or 0. */
+    fn truth(&mut self, comparison: &str) {
+        self.emit("push 0");
+        self.emit(comparison);
+    }
+
+    /* Store the top of the stack in a variable and drop it. */
+    fn store(&mut self, name: &str, line: usize) -> Result<(), LangError> {
+        let offset = self.offset(name, line)?;
+        self.emit(&format!("swap 0 {}", offset));
+        self.emit("pop 4");
+        self.depth -= 1;
+        Ok(())
+    }
+
+    fn statements(&mut self, statements: &[(Statement, usize)]) -> Result<(), LangError> {
+        statements.iter().try_for_each(|(statement, line)| self.statement(statement, *line))
+    }
+
+    fn statement(&mut self, statement: &Statement, line: usize) -> Result<(), LangError> {
+        self.line = line;
+
+        match statement {
+            Statement::Var(name, value) => {
+                self.declared.push(name.clone());
+                if let Some(value) = value {
+                    self.expression(value)?;
+                    self.store(name, line)?;
+                }
+            },
+            Statement::Assign(name, value) => {
+                self.expression(value)?;
+                self.store(name, line)?;
+            },
+            /* The ifs only peek at the condition, so both ways out drop it. */
+            Statement::If(condition, then, otherwise) => {
+                let (skip, end) = (self.label("else"), self.label("endif"));
+                self.expression(condition)?;
+                self.emit(&format!("ifez {}", skip));
+                self.emit("pop 4");
+                self.depth -= 1;
+                self.statements(then)?;
+                self.emit(&format!("goto {}", end));
+                self.place(&skip);
+                self.emit("pop 4");
+                self.statements(otherwise)?;
+                self.place(&end);
+            },
+            Statement::While(condition, body) => {
+                let (top, done) = (self.label("while"), self.label("endwhile"));
+                self.place(&top);
+                self.expression(condition)?;
+                self.emit(&format!("ifez {}", done));
+                self.emit("pop 4");
+                self.depth -= 1;
+                self.statements(body)?;
+                self.emit(&format!("goto {}", top));
+                self.place(&done);
+                self.emit("pop 4");
+            },
+            Statement::Print(value) => {
+                self.expression(value)?;
+                self.emit("print");
+                self.emit("pop 4");
+                self.depth -= 1;
+            },
+            Statement::PrintText(text) => {
+                let mut quoted = String::new();
+                for c in text.chars() {
+                    match c {
+                        '\n' => quoted.push_str("\\n"),
+                        '\t' => quoted.push_str("\\t"),
+                        '"' => quoted.push_str("\\\""),
+                        '\\' => quoted.push_str("\\\\"),
+                        _ => quoted.push(c),
+                    }
+                }
+                self.emit(&format!("stpush \"{}\"", quoted));
+                self.emit("stprint");
+                self.emit(&format!("pop {}", strings::pack(text.as_bytes()).len() * 4));
+            },
+            Statement::Exit(code) => self.emit(&format!("exit {}", code)),
+        }
+
+        Ok(())
+    }
+}
+
+/* Parse a program and generate its assembly, along with the source line behind each line of
+ * it. */
+fn generate(source: &str) -> Result<(String, Vec<usize>), LangError> {
+    let mut parser = Parser { tokens: tokenize(source)?, position: 0 };
+    let mut program = Vec::new();
+    while parser.peek().is_some() {
+        program.push(parser.statement()?);
+    }
+
+    let mut slots = Vec::new();
+    collect_variables(&program, &mut slots)?;
+
+    let mut generator = Generator {
+        slots,
+        declared: Vec::new(),
+        depth: 0,
+        labels: 0,
+        assembly: String::new(),
+        lines: Vec::new(),
+        line: 1,
+    };
+
+    for _ in 0..generator.slots.len() {
+        generator.push(0);
+    }
+    generator.statements(&program)?;
+    generator.emit("exit 0");
+
+    Ok((generator.assembly, generator.lines))
+}
+
+/* Compile a program to assembly for the asm module. */
+pub fn compile_to_assembly(source: &str) -> Result<String, LangError> {
+    generate(source).map(|(assembly, _)| assembly)
+}
+
+/* Compile a program all the way to code for the VM. */
+pub fn compile(source: &str) -> Result<Assembled, LangError> {
+    let (assembly, lines) = generate(source)?;
+
+    /* Anything the assembler turns down, like a program too big for memory or a variable too
+     * deep in the stack to reach, gets blamed on the line that produced it. */
+    asm::assemble(&assembly).map_err(|err| LangError {
+        line: lines.get(err.line.wrapping_sub(1)).copied().unwrap_or(0),
+        message: err.message,
+    })
+}
@@ -1,89 +1,1001 @@
+/* The interpreter itself only needs alloc. Everything that touches files or the terminal is
+ * behind the std feature (on by default), so building with --no-default-features gives a VM
+ * that can run on a microcontroller. */
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::mem;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+#[cfg(feature = "std")]
 use std::fs;
-use std::io::{stdin, stdout, Write};
-use std::str::FromStr;
-use std::collections::VecDeque;
+#[cfg(feature = "std")]
+use std::io::{stderr, stdin, stdout, Read};
+
+pub mod address_space;
+pub mod analysis;
+pub mod asm;
+pub mod cfg;
+pub mod debug_info;
+#[cfg(feature = "std")]
+pub mod debugger;
+pub mod device;
+pub mod disasm;
+pub mod expr;
+pub mod fmt;
+#[cfg(feature = "capi")]
+pub mod ffi;
+#[cfg(feature = "std")]
+pub mod fuzz;
+pub mod isa;
+pub mod lang;
+pub mod linker;
+#[cfg(feature = "std")]
+pub mod lsp;
+#[cfg(feature = "std")]
+pub mod harness;
+#[cfg(feature = "std")]
+pub mod interrupt;
+pub mod header;
+pub mod observer;
+pub mod optimize;
+pub mod plugin;
+#[cfg(feature = "std")]
+pub mod pool;
+pub mod program;
+#[cfg(feature = "python")]
+pub mod python;
+#[cfg(feature = "std")]
+pub mod reference;
+#[cfg(feature = "std")]
+pub mod selftest;
+pub mod stdlib;
+#[cfg(feature = "tui")]
+pub mod tui;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+mod config;
+mod error;
+mod host;
+mod io;
+mod memory;
+mod preprocess;
+mod profile;
+mod rng;
+mod scheduler;
+mod state_hash;
+mod strings;
+mod threaded;
+
+pub use address_space::{AddressSpace, Segment};
+pub use config::{ArithmeticMode, DebugMode, Endianness, Ops, PcOverrun, StringFormat, VmConfig, WordSize};
+pub use error::VmError;
+pub use debug_info::{DebugInfo, LineRow};
+pub use device::Device;
+pub use header::Header;
+#[cfg(feature = "std")]
+pub use harness::{run_program, RunOptions, RunOutcome};
+#[cfg(feature = "std")]
+pub use pool::VmPool;
+pub use io::{DiagnosticSink, Input, Output};
+pub use memory::Memory;
+use memory::MEMORY_SIZE;
+use rng::Rng;
+pub use scheduler::ContextState;
+pub use observer::MemoryObserver;
+pub use plugin::OpcodeHandler;
+pub use program::Program;
+use scheduler::{Registers, Scheduler};
+use state_hash::StateHash;
+use threaded::{Handler, Threaded};
+pub use profile::{OpcodeTime, Profile};
+#[cfg(not(feature = "std"))]
+pub use io::Null;
+#[cfg(feature = "std")]
+pub use io::StdinLines;
+
+/* How many words of the stack dump_state shows. */
+const DUMP_STATE_WORDS: usize = 8;
+
+/* How many of the innermost calls a backtrace shows. Deep recursion gets cut short. */
+const BACKTRACE_FRAMES: usize = 32;
+
+/* How many instructions run between looks at the clock when there's a timeout. */
+const TIMEOUT_INTERVAL: u64 = 1024;
+
+/* One entry in the shadow call stack: where the call happened and where it went. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CallFrame {
+    pub call_site: i32,
+    pub target: i32,
+}
+
+/* A store that touched a watched word. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchHit {
+    /* The watched address. */
+    pub address: i32,
+    /* The instruction that did the store. */
+    pub pc: i32,
+    /* The word at the watched address before and after. */
+    pub old: i64,
+    pub new: i64,
+}
+
+/* A snapshot of the machine's registers and counters. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VmState {
+    pub pc: i32,
+    pub sp: i32,
+    /* Instructions executed so far. Every instruction takes one cycle, so this is also what the
+     * cycles instruction pushes. */
+    pub cycles: u64,
+    /* Calls that haven't returned yet. */
+    pub depth: usize,
+    /* The exit code, once the program has exited. */
+    pub exit_code: Option<i32>,
+    /* Which context is running: 0 unless the program has used spawn. */
+    pub context: usize,
+}
+
+/* What run_with_report gives back: how the program finished and what it got up to on the way. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RunReport {
+    pub exit_code: i32,
+    pub instructions_executed: u64,
+    /* The most bytes there have been on the stack of any one context. */
+    pub max_stack_depth: usize,
+    /* The lowest the stack pointer went, in any context: how close the stack came to the code,
+     * or to the next context's region. */
+    pub lowest_stack_pointer: i32,
+    /* Instructions that carried on somewhere other than the next one: taken ifs, gotos, calls,
+     * returns. */
+    pub branches_taken: u64,
+    /* Bytes read from the program's input and written to its output. */
+    pub io_bytes: u64,
+    /* How long the run took, when there's a clock to tell. */
+    pub duration: Option<Duration>,
+}
+
+/* Called by run when the program exits, with the exit code. Returning false stops it exiting:
+ * run gives back VmError::Paused instead, and running again carries on from there. */
+pub type ExitHook = Box<dyn FnMut(i32) -> bool + Send>;
+
+/* Called by run when the program stops with an error, with where the machine was at. */
+pub type TrapHook = Box<dyn FnMut(&VmError, &VmState) + Send>;
+
+/* The machine as it was loaded, for reset. */
+struct Loaded {
+    memory: Memory,
+    stack_pointer: i32,
+    rng: Rng,
+}
+
+/* A device and the addresses it answers to. */
+struct Mapping {
+    base: i32,
+    size: i32,
+    device: Box<dyn Device + Send>,
+}
+
+/* Where the machine is at after a step. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepResult {
+    Running,
+    /* A brk was just run. The program carries on from the instruction after it. */
+    Breakpoint,
+    Exited(i32),
+}
 
 pub struct VirtualMachine {
-    stack: Vec<u8>,
+    stack: Memory,
     stack_pointer: i32,
     program_counter: i32,
     exit_code: i32,
-    should_exit: bool
+    should_exit: bool,
+    instruction_count: u64,
+    max_stack_depth: usize,
+    lowest_stack_pointer: i32,
+    branches_taken: u64,
+    io_bytes: u64,
+    call_stack: Vec<CallFrame>,
+    return_stack: Vec<i64>,
+    profile: Option<Profile>,
+    state_hash: Option<StateHash>,
+    watchpoints: Vec<i32>,
+    watch_hits: Vec<WatchHit>,
+    address_space: AddressSpace,
+    header: Header,
+    debug_info: Option<DebugInfo>,
+    rng: Rng,
+    interrupt: Option<Arc<AtomicBool>>,
+    /* When the first instruction ran, on the host's monotonic clock, for the timeout. */
+    started_at: Option<u64>,
+    devices: Vec<Mapping>,
+    scheduler: Scheduler,
+    /* Set by yield and join to hand over to another context once the instruction is done. */
+    switch_pending: bool,
+    /* Set by brk, for step to say so once the instruction is done. */
+    break_pending: bool,
+    plugins: BTreeMap<u8, Box<dyn OpcodeHandler + Send>>,
+    threaded: Option<Threaded>,
+    input: Box<dyn Input + Send>,
+    output: Box<dyn Output + Send>,
+    diagnostics: DiagnosticSink,
+    exit_hooks: Vec<ExitHook>,
+    trap_hooks: Vec<TrapHook>,
+    /* In a RefCell because reads tell them too, and reading only needs &self. */
+    observers: RefCell<Vec<Box<dyn MemoryObserver + Send>>>,
+    loaded: Loaded,
+    config: VmConfig
 }
 
 impl VirtualMachine {
     /* Constructor. */
+    #[cfg(feature = "std")]
     pub fn build(args: &[String]) -> Result<VirtualMachine, String> {
+        VirtualMachine::build_with_config(args, VmConfig::default())
+    }
+
+    /* Constructor for when you want something other than the default machine. A path of -
+     * reads the program from stdin. */
+    #[cfg(feature = "std")]
+    pub fn build_with_config(args: &[String], config: VmConfig) -> Result<VirtualMachine, String> {
         if args.len() != 2 {
             return Err(String::from("usage: vm <file.v>"));
         }
 
-        /* Verifying the file is valid. */
+        VirtualMachine::from_bytes(VirtualMachine::read_program(&args[1])?, config)
+    }
 
-        let file_result = fs::read(&args[1]);
-        let mut file_buf = match file_result {
+    /* The contents of a .v file, or of stdin for -. A program read from stdin leaves nothing
+     * there for it to read as input. */
+    #[cfg(feature = "std")]
+    pub fn read_program(path: &str) -> Result<Vec<u8>, String> {
+        if path == "-" {
+            let mut file_buf = Vec::new();
+            stdin().read_to_end(&mut file_buf).map_err(|e| format!("Couldn't read the program from stdin: {}", e))?;
+            return Ok(file_buf);
+        }
+
+        fs::read(path).map_err(|_| String::from("Couldn't open file."))
+    }
+
+    /* Load a program from a .v file. */
+    #[cfg(feature = "std")]
+    pub fn from_file(path: &str, config: VmConfig) -> Result<VirtualMachine, String> {
+        let file_result = fs::read(path);
+        let file_buf = match file_result {
             Ok(file_buf) => file_buf,
             Err(_) => return Err(String::from("Couldn't open file.")),
         };
 
-        if file_buf.len() > (4096 + 4) {
-            return Err(String::from("File too big."));
-        }
+        VirtualMachine::from_bytes(file_buf, config)
+    }
 
-        if file_buf.len() < 4 || file_buf[0..4] != vec![0xde, 0xad, 0xbe, 0xef] {
-            return Err(String::from("File format is invalid."));
-        }
+    /* Load a program written out as hex words (see header::hex_image). */
+    pub fn from_hex(text: &str, config: VmConfig) -> Result<VirtualMachine, String> {
+        VirtualMachine::from_bytes(header::hex_image(text)?, config)
+    }
+
+    /* Load a program from the contents of a .v file. */
+    pub fn from_bytes(file_buf: Vec<u8>, config: VmConfig) -> Result<VirtualMachine, String> {
+        /* Verifying the file is valid. */
+
+        let (header, code, debug_info) = Header::parse(&file_buf)?;
+        let mut config = config;
+        header.configure(&mut config);
 
         /* Creating the stack. */
 
-        let mut stack = file_buf.split_off(4);
-        stack.resize(4096, 0);
+        let mut code = code.to_vec();
+        let mut debug_info = debug_info;
+        let (entry, stack_pointer) = (header.entry as i32, header.stack_pointer as i32);
+        /* A program with an entry point or a data region may have data the shuffle would take
+         * for code, or addresses in it that would move, so it's left where it is. */
+        if let Some(seed) = config.layout_seed.filter(|_| entry == 0 && header.code_size.is_none()) {
+            code = optimize::shuffle_functions(&code, seed);
+            /* The addresses in it no longer mean anything. */
+            debug_info = None;
+        }
+
+        let data_end = code.len();
+        let code_end = header.code_size.map_or(data_end, |size| size as usize);
+        let address_space = AddressSpace::new(code_end, data_end, config.heap_size, config.stack_size, MEMORY_SIZE)?
+            .protect_code(config.protect_code)
+            .readonly_globals(header.has(Header::READONLY_DATA));
+        let stack_start = address_space.segment(Segment::Stack).start;
+        let stack = Memory::load(code, config.endianness);
+        let rng = Rng::new(config.seed.unwrap_or_else(host::entropy));
 
         /* Creating the struct. */
 
-        Ok(VirtualMachine {
-            stack,
-            stack_pointer: 4096,
-            program_counter: 0,
+        let mut vm = VirtualMachine {
+            stack: stack.clone(),
+            stack_pointer,
+            program_counter: entry,
             exit_code: 0,
-            should_exit: false
-        })
+            should_exit: false,
+            instruction_count: 0,
+            max_stack_depth: 0,
+            lowest_stack_pointer: stack_pointer,
+            branches_taken: 0,
+            io_bytes: 0,
+            call_stack: Vec::new(),
+            return_stack: Vec::new(),
+            profile: if config.profile { Some(Profile::new()) } else { None },
+            state_hash: if config.state_hash { Some(StateHash::new()) } else { None },
+            watchpoints: Vec::new(),
+            watch_hits: Vec::new(),
+            address_space,
+            header,
+            debug_info,
+            rng: rng.clone(),
+            interrupt: None,
+            started_at: None,
+            devices: Vec::new(),
+            scheduler: Scheduler::new(stack_start as usize, config.context_stack, MEMORY_SIZE as i32),
+            switch_pending: false,
+            break_pending: false,
+            plugins: BTreeMap::new(),
+            threaded: if config.threaded { Some(Threaded::new()) } else { None },
+            input: VirtualMachine::default_input(),
+            output: VirtualMachine::default_output(),
+            diagnostics: VirtualMachine::default_diagnostics(),
+            exit_hooks: Vec::new(),
+            trap_hooks: Vec::new(),
+            observers: RefCell::new(Vec::new()),
+            loaded: Loaded { memory: stack.clone(), stack_pointer, rng },
+            config
+        };
+
+        if let Some(args) = vm.config.args.clone() {
+            for arg in args.iter().rev() {
+                vm.push_string(arg.as_bytes()).map_err(|e| format!("Couldn't pass the arguments: {}", e))?;
+            }
+            vm.push_int_onto_stack(args.len() as i64).map_err(|e| format!("Couldn't pass the arguments: {}", e))?;
+            vm.loaded.memory = vm.stack.clone();
+            vm.loaded.stack_pointer = vm.stack_pointer;
+        }
+
+        Ok(vm)
     }
 
-    /* Parse and execute instructions from the stack. */
-    pub fn run(&mut self) -> Result<i32, String> {
+    /* Programs talk to the terminal unless told otherwise. Without std there's no terminal, so
+     * they read nothing and write nowhere until set_input, set_output and set_diagnostics are
+     * called. */
+    #[cfg(feature = "std")]
+    fn default_input() -> Box<dyn Input + Send> {
+        Box::new(StdinLines)
+    }
+
+    #[cfg(feature = "std")]
+    fn default_output() -> Box<dyn Output + Send> {
+        Box::new(stdout())
+    }
+
+    #[cfg(feature = "std")]
+    fn default_diagnostics() -> DiagnosticSink {
+        Box::new(stderr())
+    }
+
+    #[cfg(not(feature = "std"))]
+    fn default_input() -> Box<dyn Input + Send> {
+        Box::new(Null)
+    }
+
+    #[cfg(not(feature = "std"))]
+    fn default_output() -> Box<dyn Output + Send> {
+        Box::new(Null)
+    }
+
+    #[cfg(not(feature = "std"))]
+    fn default_diagnostics() -> DiagnosticSink {
+        Box::new(Null)
+    }
+
+    /* Parse and execute instructions from the stack, telling the hooks from on_exit and on_trap
+     * how it ends. A brk doesn't stop it; only something stepping sees those. */
+    pub fn run(&mut self) -> Result<i32, VmError> {
         loop {
-            let instruction = self.get_next_instruction();
-            self.execute_instruction(instruction)?;
-            
-            self.increment_program_counter();
-            if self.should_exit {
-                
-                break;
+            match self.step() {
+                Ok(StepResult::Running | StepResult::Breakpoint) => {},
+                Ok(StepResult::Exited(exit_code)) => return self.exiting(exit_code),
+                Err(e) => {
+                    let state = self.state();
+                    for hook in &mut self.trap_hooks {
+                        hook(&e, &state);
+                    }
+                    return Err(e);
+                },
             }
         }
+    }
 
-        Ok(self.exit_code)
+    /* Ask the exit hooks whether the program can exit. Every one of them is asked, and any one
+     * saying no is enough to pause it instead. */
+    fn exiting(&mut self, exit_code: i32) -> Result<i32, VmError> {
+        let mut allowed = true;
+        for hook in &mut self.exit_hooks {
+            allowed &= hook(exit_code);
+        }
+        if allowed {
+            return Ok(exit_code);
+        }
+
+        self.should_exit = false;
+        Err(VmError::Paused { code: exit_code, pc: self.program_counter })
     }
 
-    /* Grab the next 4 bytes from the stack and pack it into one int. */
-    fn get_next_instruction(&self) -> u32 {
-        let pc = self.program_counter as usize;
-        let bound = pc + 4 as usize;
+    /* Have hook called whenever run ends with the program exiting, in the order they were
+     * added. See ExitHook. */
+    pub fn on_exit(&mut self, hook: ExitHook) {
+        self.exit_hooks.push(hook);
+    }
+
+    /* Have hook called whenever run ends with an error. step on its own doesn't call it. */
+    pub fn on_trap(&mut self, hook: TrapHook) {
+        self.trap_hooks.push(hook);
+    }
+
+    /* Fault if the instruction is in a group the config doesn't allow. Words that aren't
+     * instructions, plugins' among them, are left to fault or not when they run. */
+    fn check_allowed(&self, instruction: u32) -> Result<(), VmError> {
+        let group = isa::Instruction::decode(instruction).and_then(|decoded| decoded.group());
+        match group {
+            Some(group) if !self.config.allowed_ops.contains(group) => {
+                Err(VmError::PermissionDenied { opcode: instruction, pc: self.program_counter })
+            },
+            _ => Ok(()),
+        }
+    }
+
+    /* Tell observer about every read and write the program makes and every move of the pc.
+     * See the observer module. */
+    pub fn add_observer(&mut self, observer: Box<dyn MemoryObserver + Send>) {
+        self.observers.get_mut().push(observer);
+    }
+
+    /* Run the program, like run, and say what it did. The counts are since the machine was
+     * loaded, so they include anything stepped before. */
+    pub fn run_with_report(&mut self) -> Result<RunReport, VmError> {
+        let start = host::monotonic();
+        let exit_code = self.run()?;
+        let duration = host::monotonic().zip(start).map(|(end, start)| end.saturating_sub(start));
+
+        Ok(RunReport {
+            exit_code,
+            instructions_executed: self.instruction_count,
+            max_stack_depth: self.max_stack_depth,
+            lowest_stack_pointer: self.lowest_stack_pointer,
+            branches_taken: self.branches_taken,
+            io_bytes: self.io_bytes,
+            duration,
+        })
+    }
+
+    /* Execute a single instruction. */
+    pub fn step(&mut self) -> Result<StepResult, VmError> {
+        if self.should_exit {
+            return Ok(StepResult::Exited(self.exit_code));
+        }
+
+        if self.interrupt.as_ref().is_some_and(|flag| flag.swap(false, Ordering::SeqCst)) {
+            return Err(VmError::Interrupted { pc: self.program_counter });
+        }
+
+        if let Some(timeout) = self.config.timeout {
+            self.check_timeout(timeout)?;
+        }
+
+        if let Some(fuel) = self.config.fuel {
+            if self.instruction_count >= fuel {
+                return Err(VmError::OutOfFuel { executed: self.instruction_count, pc: self.program_counter });
+            }
+        }
 
-        if pc >= self.stack.len() || bound >= self.stack.len() {
-            panic!("VirtualMachine::get_next_instruction() failed: pc or bound out of range");
+        let pc = self.program_counter;
+        if pc < 0 || pc as usize + 4 > self.stack.len() {
+            return Err(VmError::PcOutOfRange { pc });
+        }
+        let code_end = self.code_end();
+        if pc as usize + 4 > code_end {
+            return match self.config.on_pc_overrun {
+                PcOverrun::Exit => {
+                    self.exit_code = 0;
+                    self.should_exit = true;
+                    self.context_exited()
+                },
+                PcOverrun::Error => Err(VmError::PcOutOfRange { pc }),
+            };
         }
 
-        let instruction_buf = &self.stack[pc..bound];
-        let mut instruction: u32 = 0;
+        /* Threaded code only has a slot for each whole word, so a pc between two goes the slow
+         * way. */
+        let (handler, instruction): (Handler, u32) = match &mut self.threaded {
+            Some(threaded) if pc % 4 == 0 => threaded.op(&self.stack, code_end, pc),
+            _ => (VirtualMachine::execute_instruction, self.get_next_instruction()?),
+        };
+        if self.config.allowed_ops != Ops::ALL {
+            self.check_allowed(instruction)?;
+        }
+        self.instruction_count += 1;
+        if let Some(profile) = &mut self.profile {
+            profile.record_instruction(self.program_counter);
+        }
+        if let Some(state_hash) = &mut self.state_hash {
+            state_hash.record(pc, self.stack_pointer, instruction);
+        }
+        let started = if self.profile.is_some() { host::monotonic() } else { None };
+        handler(self, instruction)?;
+        if let (Some(profile), Some(started)) = (&mut self.profile, started) {
+            profile.record_time(pc, host::monotonic().unwrap_or(started) - started);
+        }
 
-        instruction |= instruction_buf[0] as u32;
-        instruction |= (instruction_buf[1] as u32) << 8;
-        instruction |= (instruction_buf[2] as u32) << 16;
-        instruction |= (instruction_buf[3] as u32) << 24;
+        if self.program_counter != pc {
+            self.branches_taken += 1;
+        }
+        let depth = (self.stack_top() - self.stack_pointer).max(0) as usize;
+        self.max_stack_depth = self.max_stack_depth.max(depth);
+        self.lowest_stack_pointer = self.lowest_stack_pointer.min(self.stack_pointer);
+
+        self.increment_program_counter();
+        self.pc_moved(pc);
+        if self.should_exit {
+            return self.context_exited();
+        }
+        /* Before any switch, so the pc it stopped at is the brk's context's. */
+        if self.break_pending {
+            self.break_pending = false;
+            return Ok(StepResult::Breakpoint);
+        }
+
+        let slice_over = self.config.time_slice.is_some_and(|slice| self.scheduler.tick() >= slice);
+        if self.switch_pending || slice_over {
+            self.switch_pending = false;
+            self.switch_context()?;
+        }
+
+        Ok(StepResult::Running)
+    }
+
+    /* The running context has exited. The main one exiting stops the machine; any other only
+     * stops itself and hands over to the next. */
+    fn context_exited(&mut self) -> Result<StepResult, VmError> {
+        if self.scheduler.current() == 0 {
+            self.flush_output()?;
+            return Ok(StepResult::Exited(self.exit_code));
+        }
+
+        self.scheduler.finish(self.exit_code);
+        self.should_exit = false;
+        self.switch_pending = false;
+        self.switch_context()?;
+        Ok(StepResult::Running)
+    }
+
+    /* Put the running context away and pick up the next one. */
+    fn switch_context(&mut self) -> Result<(), VmError> {
+        let registers = Registers {
+            pc: self.program_counter,
+            sp: self.stack_pointer,
+            call_stack: mem::take(&mut self.call_stack),
+            return_stack: mem::take(&mut self.return_stack),
+        };
+
+        let next = self.scheduler.switch(registers)?;
+        let from = mem::replace(&mut self.program_counter, next.pc);
+        self.pc_moved(from);
+        self.stack_pointer = next.sp;
+        self.call_stack = next.call_stack;
+        self.return_stack = next.return_stack;
+        Ok(())
+    }
+
+    /* Stop before the next instruction, with VmError::Interrupted, whenever the flag is set.
+     * The flag is cleared as the machine stops. */
+    pub fn set_interrupt(&mut self, flag: Arc<AtomicBool>) {
+        self.interrupt = Some(flag);
+    }
+
+    /* Stop the program if it's been running longer than timeout. The clock is only read every
+     * TIMEOUT_INTERVAL instructions, which keeps it cheap. */
+    fn check_timeout(&mut self, timeout: Duration) -> Result<(), VmError> {
+        if !self.instruction_count.is_multiple_of(TIMEOUT_INTERVAL) {
+            return Ok(());
+        }
+        let Some(now) = host::monotonic_millis() else {
+            return Ok(());
+        };
+
+        let started_at = *self.started_at.get_or_insert(now);
+        let millis = now - started_at;
+        if millis > timeout.as_millis() as u64 {
+            return Err(VmError::Timeout { millis, pc: self.program_counter });
+        }
+        Ok(())
+    }
+
+    /* Put a device at base, in the config's mmio range, so load and store from base up to
+     * base + device.size() reach it. */
+    pub fn map_device(&mut self, base: i32, device: Box<dyn Device + Send>) -> Result<(), String> {
+        let size = device.size();
+        let Some(mmio) = self.config.mmio.clone() else {
+            return Err(String::from("There's no mmio range to map devices into."));
+        };
+        if (mmio.start as i64) < MEMORY_SIZE as i64 && mmio.end > 0 {
+            return Err(format!("The mmio range {:#x}..{:#x} overlaps memory.", mmio.start, mmio.end));
+        }
+
+        let end = base.checked_add(size).filter(|_| size > 0);
+        let Some(end) = end.filter(|&end| mmio.start <= base && end <= mmio.end) else {
+            return Err(format!("A {}-byte device at {:#x} doesn't fit in the mmio range {:#x}..{:#x}.", size, base, mmio.start, mmio.end));
+        };
+        if let Some(other) = self.devices.iter().find(|other| other.base < end && base < other.base + other.size) {
+            return Err(format!("A device at {:#x} overlaps the one at {:#x}.", base, other.base));
+        }
+
+        self.devices.push(Mapping { base, size, device });
+        Ok(())
+    }
+
+    /* The device an address in the mmio range belongs to, and how far into it the address is.
+     * None for an address outside the range. */
+    fn device_at(&mut self, address: i32) -> Result<Option<(&mut (dyn Device + Send), i32)>, VmError> {
+        if !self.config.mmio.as_ref().is_some_and(|mmio| mmio.contains(&address)) {
+            return Ok(None);
+        }
+
+        match self.devices.iter_mut().find(|mapping| (mapping.base..mapping.base + mapping.size).contains(&address)) {
+            Some(mapping) => Ok(Some((mapping.device.as_mut(), address - mapping.base))),
+            None => Err(VmError::from(format!("There's no device at {:#x}.", address))),
+        }
+    }
+
+    /* Hand every instruction with an opcode the VM doesn't use to handler (see the plugin
+     * module). Registering the same opcode again replaces the handler. */
+    pub fn register_opcode(&mut self, opcode: u8, handler: Box<dyn OpcodeHandler + Send>) -> Result<(), String> {
+        plugin::check_opcode(opcode)?;
+        self.plugins.insert(opcode, handler);
+        Ok(())
+    }
+
+    /* Run an instruction with a plugin opcode. */
+    fn plugin(&mut self, instruction: u32) -> Result<(), VmError> {
+        let opcode = VirtualMachine::get_op_code(instruction) as u8;
+        let Some(mut handler) = self.plugins.remove(&opcode) else {
+            return Err(VmError::from(String::from("Bad instruction.")));
+        };
+
+        /* Out of the map while it runs, so it can have the machine to itself. */
+        let result = handler.execute(instruction, &mut plugin::Machine::new(self));
+        self.plugins.insert(opcode, handler);
+        result
+    }
+
+    /* Read the program's input from somewhere other than stdin. */
+    pub fn set_input(&mut self, input: Box<dyn Input + Send>) {
+        self.input = input;
+    }
+
+    /* Send the program's output somewhere other than stdout. */
+    pub fn set_output(&mut self, output: Box<dyn Output + Send>) {
+        self.output = output;
+    }
 
-        instruction
+    /* Send the VM's diagnostics somewhere other than stderr. */
+    pub fn set_diagnostics(&mut self, diagnostics: DiagnosticSink) {
+        self.diagnostics = diagnostics;
+    }
+
+    /* Write some diagnostics. Whatever the program has printed goes out first, so the two
+     * come out in the right order when they end up in the same place. */
+    fn write_diagnostic(&mut self, text: &str) -> Result<(), String> {
+        self.flush_output()?;
+        self.diagnostics.write_all(text.as_bytes())
+            .and_then(|_| self.diagnostics.flush())
+            .map_err(|e| format!("Couldn't write diagnostics: {}", e))
+    }
+
+    /* Where the machine is at, for looking into a fault: the registers, the instruction at the
+     * pc and the top few words of the stack. Written to the diagnostics. */
+    pub fn dump_state(&mut self) -> Result<(), String> {
+        let pc = self.program_counter;
+        let instruction = match self.stack.read_u32(pc) {
+            Ok(word) => match isa::Instruction::decode(word) {
+                Some(instruction) => format!("{:08x}  {}", word, instruction),
+                None => format!("{:08x}  (not an instruction)", word),
+            },
+            Err(_) => String::from("(outside memory)"),
+        };
+
+        let mut text = format!("pc {:04x}  sp {:04x}  depth {}  cycles {}\n", pc, self.stack_pointer, self.call_stack.len(), self.instruction_count);
+        text.push_str(&format!("  {:04x}: {}\n", pc, instruction));
+        let word_bytes = self.word_bytes();
+        for address in (self.stack_pointer..self.stack_top()).step_by(word_bytes as usize).take(DUMP_STATE_WORDS) {
+            if let Ok(word) = self.read_word(address) {
+                text.push_str(&format!("  {:04x}: {}\n", address, word));
+            }
+        }
+        if !self.call_stack.is_empty() {
+            text.push_str(&self.backtrace());
+        }
+
+        self.write_diagnostic(&text)
+    }
+
+    /* The calls that got the program to where it is, a line each, innermost first: the pc, then
+     * the call to the function it's in, then the call to that one, and so on out. Each says
+     * where it is in the source when there's debug info. */
+    pub fn backtrace(&self) -> String {
+        let place = |address: i32| match self.debug_info.as_ref().and_then(|info| info.describe(address)) {
+            Some(location) => format!("  {}", location),
+            None => String::new(),
+        };
+
+        let function = |address: i32| match self.debug_info.as_ref().and_then(|info| info.symbolize(address)) {
+            Some((label, 0)) => format!("`{}`", label),
+            _ => format!("{:04x}", address),
+        };
+
+        let mut text = format!("#0  {:04x}{}\n", self.program_counter, place(self.program_counter));
+        for (i, frame) in self.call_stack.iter().rev().enumerate().take(BACKTRACE_FRAMES) {
+            text.push_str(&format!("#{:<2} {:04x}{}  call to {}\n", i + 1, frame.call_site, place(frame.call_site), function(frame.target)));
+        }
+        if self.call_stack.len() > BACKTRACE_FRAMES {
+            text.push_str(&format!("... and {} calls further out\n", self.call_stack.len() - BACKTRACE_FRAMES));
+        }
+        text
+    }
+
+    /* Write some of the program's output. */
+    fn write_output(&mut self, text: &str) -> Result<(), String> {
+        self.write_output_bytes(text.as_bytes())
+    }
+
+    /* The same, for output that needn't be text. */
+    fn write_output_bytes(&mut self, bytes: &[u8]) -> Result<(), String> {
+        self.io_bytes += bytes.len() as u64;
+        self.output.write_all(bytes).map_err(|e| format!("Couldn't write output: {}", e))
+    }
+
+    /* Read a line of the program's input, like Input::read_line. */
+    fn read_input(&mut self, line: &mut String) -> Result<usize, String> {
+        let read = self.input.read_line(line)?;
+        self.io_bytes += read as u64;
+        Ok(read)
+    }
+
+    /* Make sure everything written so far has actually gone out. */
+    fn flush_output(&mut self) -> Result<(), String> {
+        self.output.flush().map_err(|e| format!("Couldn't write output: {}", e))
+    }
+
+    /* How many instructions have been executed so far. */
+    pub fn instruction_count(&self) -> u64 {
+        self.instruction_count
+    }
+
+    /* The counts run_with_report reports, so far. */
+    pub fn max_stack_depth(&self) -> usize {
+        self.max_stack_depth
+    }
+
+    pub fn lowest_stack_pointer(&self) -> i32 {
+        self.lowest_stack_pointer
+    }
+
+    pub fn branches_taken(&self) -> u64 {
+        self.branches_taken
+    }
+
+    pub fn io_bytes(&self) -> u64 {
+        self.io_bytes
+    }
+
+    pub fn stack_pointer(&self) -> i32 {
+        self.stack_pointer
+    }
+
+    pub fn program_counter(&self) -> i32 {
+        self.program_counter
+    }
+
+    /* Note every store to any byte of the word at an address. */
+    pub fn watch(&mut self, address: i32) {
+        if !self.watchpoints.contains(&address) {
+            self.watchpoints.push(address);
+        }
+    }
+
+    pub fn unwatch(&mut self, address: i32) {
+        self.watchpoints.retain(|&watched| watched != address);
+    }
+
+    pub fn watchpoints(&self) -> &[i32] {
+        &self.watchpoints
+    }
+
+    /* The stores to watched words since this was last called. */
+    pub fn take_watch_hits(&mut self) -> Vec<WatchHit> {
+        core::mem::take(&mut self.watch_hits)
+    }
+
+    /* Overwrite some of memory from outside the program, as a debugger patching code does.
+     * Watchpoints don't see it. */
+    pub fn patch(&mut self, address: i32, bytes: &[u8]) -> Result<(), VmError> {
+        self.stack.slice_mut(address, bytes.len())?.copy_from_slice(bytes);
+        self.code_written(address);
+        Ok(())
+    }
+
+    /* The exit code, once the program has exited. */
+    pub fn exit_code(&self) -> Option<i32> {
+        if self.should_exit { Some(self.exit_code) } else { None }
+    }
+
+    pub fn state(&self) -> VmState {
+        VmState {
+            pc: self.program_counter,
+            sp: self.stack_pointer,
+            cycles: self.instruction_count,
+            depth: self.call_stack.len(),
+            exit_code: self.exit_code(),
+            context: self.scheduler.current(),
+        }
+    }
+
+    /* Put the machine back how it was when the program was loaded, arguments and all, to run it
+     * again without loading it again. The counters, the profile and any watchpoint hits start
+     * over, and rand gives the same numbers as before. What was set up from outside stays:
+     * input and output, devices, plugins, hooks and watchpoints. */
+    pub fn reset(&mut self) {
+        self.stack = self.loaded.memory.clone();
+        self.stack_pointer = self.loaded.stack_pointer;
+        self.program_counter = self.header.entry as i32;
+        self.exit_code = 0;
+        self.should_exit = false;
+        self.instruction_count = 0;
+        self.max_stack_depth = 0;
+        self.lowest_stack_pointer = self.loaded.stack_pointer;
+        self.branches_taken = 0;
+        self.io_bytes = 0;
+        self.call_stack.clear();
+        self.return_stack.clear();
+        if let Some(profile) = &mut self.profile {
+            *profile = Profile::new();
+        }
+        if let Some(state_hash) = &mut self.state_hash {
+            *state_hash = StateHash::new();
+        }
+        self.watch_hits.clear();
+        self.rng = self.loaded.rng.clone();
+        self.started_at = None;
+        let stack_start = self.address_space.segment(Segment::Stack).start;
+        self.scheduler = Scheduler::new(stack_start as usize, self.config.context_stack, MEMORY_SIZE as i32);
+        self.switch_pending = false;
+        self.break_pending = false;
+        if let Some(threaded) = &mut self.threaded {
+            threaded.invalidate();
+        }
+    }
+
+    /* Every context the program has spawned and not yet joined, with the main one, by id. */
+    pub fn contexts(&self) -> Vec<(usize, ContextState)> {
+        self.scheduler.states()
+    }
+
+    /* All of the machine's memory, code and stack alike. */
+    pub fn memory(&self) -> &Memory {
+        &self.stack
+    }
+
+    /* The word stored at an address, or None if it doesn't fit in memory. */
+    pub fn word_at(&self, address: i32) -> Option<i64> {
+        self.read_word(address).ok()
+    }
+
+    /* Where the program's code ends, and its data starts if it has any. */
+    pub fn code_end(&self) -> usize {
+        self.address_space.segment(Segment::Code).end as usize
+    }
+
+    /* Where the loaded program ends, data and all. Everything from here up started out as
+     * zeroes. */
+    pub fn data_end(&self) -> usize {
+        self.address_space.segment(Segment::Globals).end as usize
+    }
+
+    /* Which part of memory is code, globals, heap and stack. */
+    pub fn address_space(&self) -> &AddressSpace {
+        &self.address_space
+    }
+
+    /* The lowest the stack pointer may go: the bottom of the stack's segment plus the guard
+     * zone, or the bottom of the segment without one if it has a size of its own, or else the
+     * bottom of memory. A spawned context's stack stops at the bottom of its region, and once
+     * there are any the main context's stops above them. */
+    pub fn stack_limit(&self) -> i32 {
+        let stack_start = self.address_space.segment(Segment::Stack).start;
+        let guarded = match self.config.stack_guard {
+            Some(guard) => (stack_start as usize).saturating_add(guard).min(MEMORY_SIZE) as i32,
+            None if self.address_space.stack_bounded() => stack_start,
+            None => 0,
+        };
+
+        match self.scheduler.limit() {
+            Some(limit) => limit.max(guarded),
+            None => guarded,
+        }
+    }
+
+    /* Where the running context's stack starts out, empty: the end of memory, or the top of
+     * a spawned context's region. */
+    fn stack_top(&self) -> i32 {
+        self.scheduler.top()
+    }
+
+    /* How many more bytes can be pushed before the stack reaches its limit. */
+    pub fn stack_room(&self) -> i32 {
+        self.stack_pointer - self.stack_limit()
+    }
+
+    /* The config the machine was built with, after the header has had its say. */
+    pub fn config(&self) -> &VmConfig {
+        &self.config
+    }
+
+    /* The header the program was loaded with. */
+    pub fn header(&self) -> Header {
+        self.header
+    }
+
+    /* The debug info the program was loaded with, if its file had any. */
+    pub fn debug_info(&self) -> Option<&DebugInfo> {
+        self.debug_info.as_ref()
+    }
+
+    /* An error's message, saying where in the source it happened when there's debug info to
+     * tell. */
+    pub fn describe_error(&self, error: &VmError) -> String {
+        match self.debug_info.as_ref().and_then(|info| info.describe(self.program_counter)) {
+            Some(location) => format!("error at {}: {}", location, error),
+            None => format!("{}", error),
+        }
+    }
+
+    /* The program as it was loaded from the file. */
+    pub fn code(&self) -> &[u8] {
+        &self.stack.as_slice()[..self.code_end()]
+    }
+
+    /* The hash of every step so far (see the state_hash module), if VmConfig::state_hash was
+     * set. */
+    pub fn state_hash(&self) -> Option<u64> {
+        self.state_hash.as_ref().map(StateHash::digest)
+    }
+
+    /* What the program has spent its time on, if VmConfig::profile was set. */
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
+    }
+
+    /* The calls that haven't returned yet, outermost first. */
+    pub fn call_stack(&self) -> &[CallFrame] {
+        &self.call_stack
+    }
+
+    /* Grab the next 4 bytes from the stack and pack it into one int. */
+    fn get_next_instruction(&self) -> Result<u32, VmError> {
+        self.stack.read_u32(self.program_counter)
     }
 
     /* Increment the program counter by one instruction. */
@@ -96,39 +1008,44 @@ impl VirtualMachine {
         instruction >> 28
     }
 
-    /* Print out the current state of the stack. */
-    fn print_stack(&self) {
-        let mut i = 0;
-
-        //print!(" {:04x} ", i);
-        for byte in &self.stack {
-            if i % 16 == 0 {
-                if i != 0 { 
-                    print!("\n");
-                }
-                print!(" {:04x} | ", i);
-            }
-            
-            print!("  {:02x}", byte);
-
-            i += 1;
+    /* The debug instruction: with no operand, print all of memory and then the SP and PC;
+     * with one, pop an address and print that many bytes from it. Bit 23 prints the bytes in
+     * binary instead of hex. Where it prints is up to VmConfig::debug_instruction. */
+    fn debug(&mut self, instruction: u32) -> Result<(), VmError> {
+        let bytes = (instruction & 0x7F_FFFF) as usize;
+        let binary = instruction & (1 << 23) != 0;
+
+        if bytes == 0 {
+            let text = self.stack.dump(0, self.stack.len(), binary)?;
+            self.write_debug(&text)?;
+            return self.print_vm_info();
         }
 
-        print!("\n");
-        
-        stdout().flush().expect("Failed to flush buffer");
+        let address = self.pop_address()?;
+        let text = self.stack.dump(address, bytes, binary)?;
+        Ok(self.write_debug(&text)?)
     }
 
     /* Print the SP and PC. */
-    fn print_vm_info(&self) {
-        println!(" - stack pointer:   {}", self.stack_pointer);
-        println!(" - program counter: {}", self.program_counter);
-        
-        stdout().flush().expect("Failed to flush buffer");
+    fn print_vm_info(&mut self) -> Result<(), VmError> {
+        let text = format!(" - stack pointer:   {}\n - program counter: {}\n", self.stack_pointer, self.program_counter);
+        Ok(self.write_debug(&text)?)
+    }
+
+    /* Write what the debug instruction prints wherever the config says it goes. */
+    fn write_debug(&mut self, text: &str) -> Result<(), String> {
+        match self.config.debug_instruction {
+            DebugMode::Enabled => self.write_diagnostic(text),
+            DebugMode::Silent => Ok(()),
+            DebugMode::ToStderr => {
+                self.flush_output()?;
+                host::write_stderr(text)
+            },
+        }
     }
 
     /* Executes an instruction. */
-    fn execute_instruction(&mut self, instruction: u32) -> Result<(), String> {
+    fn execute_instruction(&mut self, instruction: u32) -> Result<(), VmError> {
         let opcode = VirtualMachine::get_op_code(instruction);
         
         match opcode {
@@ -144,14 +1061,13 @@ impl VirtualMachine {
                     },
                     0x2 => (),
                     0x4 => {
-                        self.input()?
+                        self.input(instruction)?
                     },
                     0x5 => {
                         self.stinput(instruction)?;
                     },
                     0xF => {
-                        self.print_stack();
-                        self.print_vm_info();
+                        self.debug(instruction)?;
 
                         // ---------------------------------------------
                         // I used this for debugging swap might be usefull for something else later:
@@ -168,7 +1084,7 @@ impl VirtualMachine {
                         // println!(" - stack pointer:   {}", self.stack_pointer);
                         // println!(" - program counter: {}", self.program_counter);
                     }
-                    _ => return Err(String::from("Bad instruction.")),
+                    _ => return Err(VmError::from(String::from("Bad instruction."))),
                 }
             },
             1 => {
@@ -207,92 +1123,323 @@ impl VirtualMachine {
             14 => {
                 self.dump()?;
             },
+            10 => {
+                self.plugin(instruction)?;
+            },
+            11 => {
+                self.extended(instruction)?;
+            },
             15 => {
                 self.push(instruction)?;
             },
-            _ => return Err(String::from("Bad instruction.")),
+            _ => return Err(VmError::from(String::from("Bad instruction."))),
+        }
+
+        Ok(())
+    }
+
+    /* Number of bytes in a stack word. */
+    fn word_bytes(&self) -> i32 {
+        self.config.word_size.bytes()
+    }
+
+    /* Chop a value down to the width of a word, sign extending it back out to an i64. In 64-bit
+     * mode this does nothing. */
+    fn wrap_word(&self, n: i64) -> i64 {
+        match self.config.word_size {
+            WordSize::Bits32 => n as i32 as i64,
+            WordSize::Bits64 => n,
+        }
+    }
+
+    /* Turn the exact result of an arithmetic operation into a word according to the configured
+     * arithmetic mode. */
+    fn overflow_checked(&self, exact: i128) -> Result<i64, VmError> {
+        let word_size = self.config.word_size;
+        let min = word_size.min() as i128;
+        let max = word_size.max() as i128;
+
+        if (min..=max).contains(&exact) {
+            return Ok(exact as i64);
+        }
+
+        match self.config.arithmetic_mode {
+            ArithmeticMode::Wrapping => Ok(self.wrap_word(exact as i64)),
+            ArithmeticMode::Saturating => Ok(exact.clamp(min, max) as i64),
+            ArithmeticMode::Trapping => Err(VmError::ArithmeticOverflow { pc: self.program_counter }),
+        }
+    }
+
+    /* Rotate a word's bits left or right within the word size. */
+    fn rotate_word(&self, n: i64, amount: u32, left: bool) -> i64 {
+        match (self.config.word_size, left) {
+            (WordSize::Bits32, true) => (n as u32).rotate_left(amount) as i32 as i64,
+            (WordSize::Bits32, false) => (n as u32).rotate_right(amount) as i32 as i64,
+            (WordSize::Bits64, true) => n.rotate_left(amount),
+            (WordSize::Bits64, false) => n.rotate_right(amount),
+        }
+    }
+
+    /* Reinterpret a word as unsigned. */
+    fn unsigned_word(&self, n: i64) -> u64 {
+        match self.config.word_size {
+            WordSize::Bits32 => n as u32 as u64,
+            WordSize::Bits64 => n as u64,
+        }
+    }
+
+    /* Read a word starting at an address. */
+    fn read_word(&self, address: i32) -> Result<i64, VmError> {
+        let word = self.config.endianness.read(self.read_bytes(address, self.word_bytes() as usize)?);
+        Ok(self.wrap_word(word as i64))
+    }
+
+    /* Read size bytes at an address. Every read the program makes of memory goes through here
+     * so observers see it. */
+    fn read_bytes(&self, address: i32, size: usize) -> Result<&[u8], VmError> {
+        let bytes = self.stack.slice(address, size)?;
+        for observer in self.observers.borrow_mut().iter_mut() {
+            observer.read(address, bytes);
         }
+        Ok(bytes)
+    }
 
-        Ok(())
+    /* Tell the observers the pc has moved from where it was. */
+    fn pc_moved(&self, from: i32) {
+        if from != self.program_counter {
+            for observer in self.observers.borrow_mut().iter_mut() {
+                observer.pc_changed(from, self.program_counter);
+            }
+        }
     }
 
-    /* Fetch four bytes from the stack. */ 
-    fn pop_int_from_stack(&mut self) -> Result<u32, String> {
-        let new_stack_pointer = self.stack_pointer + 4;
+    /* Write a word starting at an address. */
+    fn write_word(&mut self, address: i32, n: i64) -> Result<(), VmError> {
+        /* Only the low bytes of the value make it into memory. */
+        self.store_word(address, |memory, size| memory.write_word(address, size, n as u64))
+    }
+
+    /* Change the word at an address with one of Memory's stores, which gets the word size. */
+    fn store_word<T>(&mut self, address: i32, store: impl FnOnce(&mut Memory, usize) -> Result<T, VmError>) -> Result<T, VmError> {
+        self.store_bytes(address, self.word_bytes(), store)
+    }
+
+    /* Change size bytes at an address with one of Memory's stores. Every store to memory goes
+     * through here so watchpoints and observers see it. */
+    fn store_bytes<T>(&mut self, address: i32, size: i32, store: impl FnOnce(&mut Memory, usize) -> Result<T, VmError>) -> Result<T, VmError> {
+        let word_bytes = self.word_bytes();
+        let touched = address..address + size;
+        self.address_space.check_write(address, size)?;
+        let watched: Vec<(i32, i64)> = self.watchpoints.iter()
+            .filter(|&&watched| watched < touched.end && touched.start < watched + word_bytes)
+            .map(|&watched| (watched, self.word_at(watched).unwrap_or(0)))
+            .collect();
+
+        let observed = !self.observers.get_mut().is_empty();
+        let old = match observed {
+            true => self.stack.slice(address, size as usize)?.to_vec(),
+            false => Vec::new(),
+        };
+
+        let result = store(&mut self.stack, size as usize)?;
+        self.code_written(address);
 
-        if new_stack_pointer > 4096 {
-            return Err(String::from("Failed to pop: stack is empty."));
+        if observed {
+            let new = self.stack.slice(address, size as usize)?;
+            for observer in self.observers.get_mut().iter_mut() {
+                observer.write(address, &old, new);
+            }
         }
 
-        if self.stack_pointer < 0 {
-            panic!("VirtualMachine::pop_int_from_stack() failed: stack_pointer out of range");
+        for (address, old) in watched {
+            self.watch_hits.push(WatchHit {
+                address,
+                pc: self.program_counter,
+                old,
+                new: self.word_at(address).unwrap_or(0),
+            });
         }
 
-        let start = self.stack_pointer as usize;
-        let end = new_stack_pointer as usize;
-        let mut popped = 0u32;
+        Ok(result)
+    }
+
+    /* Let the threaded code know if a write starting at address landed in the code, which
+     * with protect_code on only patch can do. */
+    fn code_written(&mut self, address: i32) {
+        if let Some(threaded) = &mut self.threaded {
+            if address < self.address_space.segment(Segment::Code).end {
+                threaded.invalidate();
+            }
+        }
+    }
 
-        for i in start..end {
-            let offset = i - start; 
-            let byte = (self.stack[i] as u32) << ((3 - offset) * 8);
-            popped |= byte;
+    /* Fetch a word from the stack. */ 
+    fn pop_int_from_stack(&mut self) -> Result<i64, VmError> {
+        let new_stack_pointer = self.stack_pointer + self.word_bytes();
 
-            //println!("{:x} {:x} {:x}", self.stack[i], offset, popped);
+        if new_stack_pointer > self.stack_top() {
+            return Err(VmError::from(String::from("Failed to pop: stack is empty.")));
         }
 
+        let popped = self.read_word(self.stack_pointer)?;
+
         self.stack_pointer = new_stack_pointer;
 
         Ok(popped)
     }
 
-    /* Push a word onto the stack. */
-    fn push_int_onto_stack(&mut self, n: i32) -> Result<(), String> {
-        let new_stack_pointer = self.stack_pointer - 4;
+    /* Where the stack pointer goes to make room for size more bytes, if there's room. */
+    fn grow_stack(&self, size: i32) -> Result<i32, VmError> {
+        let new_stack_pointer = self.stack_pointer - size;
 
-        if new_stack_pointer < 0 { /* TODO: this should be the end of the instruction space. */
-            return Err(String::from("Out of memory."));
+        let limited = self.config.stack_guard.is_some() || self.scheduler.limit().is_some() || self.address_space.stack_bounded();
+        if limited && new_stack_pointer < self.stack_limit() {
+            return Err(VmError::StackOverflow { pc: self.program_counter });
+        }
+        if new_stack_pointer < 0 {
+            return Err(VmError::from(String::from("Out of memory.")));
         }
 
-        let bytes = n.to_be_bytes();
-        let start = new_stack_pointer as usize;
-        let end = start + 4;
+        Ok(new_stack_pointer)
+    }
 
-        if end > self.stack.len() {
-            panic!("VirtualMachine::push_int_onto_stack() failed: end out of range");
-        }
+    /* Push a word onto the stack. */
+    fn push_int_onto_stack(&mut self, n: i64) -> Result<(), VmError> {
+        let new_stack_pointer = self.grow_stack(self.word_bytes())?;
 
         /* Put 'em on there. */
-        
-        for i in start..end {
-            self.stack[i] = bytes[i - start];
-        }
+        self.write_word(new_stack_pointer, n)?;
 
         self.stack_pointer = new_stack_pointer;
 
         Ok(())
     }
 
-    /* Read an int from the stack. */
-    fn peek_int_from_stack(&self, stack_offset: i32) -> Result<u32, String> {
-        let start = (self.stack_pointer + stack_offset) as usize;
-        let end = start + 4; 
+    /* pushb and pushh: pop a word and push just its low size bytes. */
+    fn push_narrow(&mut self, size: i32) -> Result<(), VmError> {
+        let word = self.pop_int_from_stack()?;
+        let new_stack_pointer = self.grow_stack(size)?;
+
+        let endianness = self.config.endianness;
+        self.store_bytes(new_stack_pointer, size, |memory, _| {
+            endianness.write(word as u64, memory.slice_mut(new_stack_pointer, size as usize)?);
+            Ok(())
+        })?;
+
+        self.stack_pointer = new_stack_pointer;
+        Ok(())
+    }
+
+    /* popb and poph: pop size bytes and push them as a word, sign extended or not. */
+    fn pop_narrow(&mut self, size: i32, signed: bool) -> Result<(), VmError> {
+        let new_stack_pointer = self.stack_pointer + size;
+        if new_stack_pointer > self.stack_top() {
+            return Err(VmError::from(format!("Failed to pop {} bytes: the stack doesn't have them.", size)));
+        }
+
+        let value = self.config.endianness.read(self.read_bytes(self.stack_pointer, size as usize)?);
+        let shift = 64 - size * 8;
+        let value = match signed {
+            true => ((value << shift) as i64) >> shift,
+            false => value as i64,
+        };
+
+        self.stack_pointer = new_stack_pointer;
+        self.push_int_onto_stack(value)
+    }
+
+    /* The address just past the top count words of the stack, for pick, roll and drop, which
+     * fault if the stack doesn't have that many. */
+    fn stack_words(&self, name: &str, count: u32) -> Result<i32, VmError> {
+        let end = self.stack_pointer as i64 + count as i64 * self.word_bytes() as i64;
+        if end > self.stack_top() as i64 {
+            return Err(VmError::from(format!("{}: the stack doesn't have {} words.", name, count)));
+        }
+
+        Ok(end as i32)
+    }
+
+    /* Read a word from the stack. */
+    fn peek_int_from_stack(&self, stack_offset: i32) -> Result<i64, VmError> {
+        self.read_word(self.stack_pointer + stack_offset)
+    }
+
+    /* Read the string starting at an address (see the strings module): its characters, and
+     * how many bytes of memory it takes up. Going word by word rather than byte by byte means
+     * this works for any word size. A packed string that runs into the end of memory just
+     * stops there; a length-prefixed one that would is an error. */
+    fn read_string(&self, address: i32) -> Result<(Vec<u8>, i32), VmError> {
+        let word_bytes = self.word_bytes();
+        if self.config.string_format == StringFormat::LengthPrefixed {
+            let length = self.read_word(address)?;
+            let text = usize::try_from(length).ok()
+                .and_then(|length| self.read_bytes(address.checked_add(word_bytes)?, length).ok())
+                .ok_or_else(|| VmError::from(format!("The string at {:#x} says it's {} bytes long, which runs past the end of memory.", address, length)))?;
+            let size = strings::prefixed_size(text.len(), word_bytes as usize) as i32;
+            return Ok((text.to_vec(), size));
+        }
+
+        let memory_end = self.stack.len() as i32;
+        let mut text = Vec::new();
+        let mut end = address;
+
+        loop {
+            let word = self.read_word(end)?;
+            text.extend(strings::chars(word));
+            end += word_bytes;
 
-        if end > 4096 {
-            return Err(String::from("Failed to peek: stack is empty"));
+            if !strings::continues(word) || end + word_bytes > memory_end {
+                break;
+            }
         }
-        if start > 4096 {
-            return Err(String::from("Failed to peek: offset out of range"));
+
+        Ok((text, end - address))
+    }
+
+    /* Take the string on top of the stack off it as a file name, checking it's one of the
+     * program's arguments. */
+    fn pop_file_name(&mut self) -> Result<String, VmError> {
+        let (name, used) = self.read_string(self.stack_pointer)?;
+        let name = String::from_utf8(name).map_err(|_| VmError::from(String::from("File name isn't valid UTF-8.")))?;
+
+        let allowed = self.config.args.as_ref().is_some_and(|args| args.contains(&name));
+        if !allowed {
+            return Err(VmError::from(format!("{} isn't one of the program's arguments.", name)));
         }
 
-        let mut peeked = 0u32;
+        self.stack_pointer += used;
+        Ok(name)
+    }
+
+    /* Take an address for load or store off the stack. */
+    fn pop_address(&mut self) -> Result<i32, VmError> {
+        let address = self.pop_int_from_stack()?;
+        i32::try_from(address).map_err(|_| VmError::from(format!("{:#x} isn't an address.", address)))
+    }
+
+    /* Push a string so that its first chunk, or its length, ends up on top. */
+    fn push_string(&mut self, text: &[u8]) -> Result<(), VmError> {
+        if self.config.string_format == StringFormat::LengthPrefixed {
+            for word in strings::prefixed(text, self.word_bytes() as usize, self.config.endianness).into_iter().rev() {
+                self.push_int_onto_stack(word as i64)?;
+            }
+            return Ok(());
+        }
 
-        for i in start..end {
-            let offset = i - start; 
-            let byte = (self.stack[i] as u32) << ((3 - offset) * 8);
-            peeked |= byte;
+        for word in strings::pack(text).into_iter().rev() {
+            self.push_int_onto_stack(word as i64)?;
         }
 
-        Ok(peeked)
+        Ok(())
+    }
+
+    /* Take the two strings on top of the stack off it: the one underneath, then the one on top. */
+    fn pop_two_strings(&mut self) -> Result<(Vec<u8>, Vec<u8>), VmError> {
+        let (right, right_size) = self.read_string(self.stack_pointer)?;
+        let (left, left_size) = self.read_string(self.stack_pointer + right_size)?;
+        self.stack_pointer += right_size + left_size;
+
+        Ok((left, right))
     }
 
     /* Sign extend partial numbers. 
@@ -317,7 +1464,7 @@ impl VirtualMachine {
     /* INSTRUCTIONS */
     /* TODO: These'll get their own file at some point. */
 
-    fn exit(&mut self, instruction: u32) -> Result<(), String>{
+    fn exit(&mut self, instruction: u32) -> Result<(), VmError>{
         let code = instruction as i32;
         self.exit_code = code;
         self.should_exit = true;
@@ -325,7 +1472,7 @@ impl VirtualMachine {
         Ok(())
     }
 
-    fn swap(&mut self, instruction: u32) -> Result<(), String> {
+    fn swap(&mut self, instruction: u32) -> Result<(), VmError> {
         // from and to are bits 23-12 and 11-0)
         let raw_from = ((instruction >> 12) & 0xFFF) as i32;
         let raw_to   = (instruction & 0xFFF) as i32;
@@ -334,21 +1481,19 @@ impl VirtualMachine {
         let signed_from = (raw_from << 20) >> 20;
         let signed_to   = (raw_to << 20) >> 20;
 
-        // Scale by 4 (shift left two bits)
-        let offset_from = signed_from << 2;
-        let offset_to   = signed_to << 2;
+        // Scale by the word size (shift left two bits in 32-bit mode)
+        let word_bytes = self.word_bytes();
+        let offset_from = signed_from * word_bytes;
+        let offset_to   = signed_to * word_bytes;
 
         // The rest of the swap logic goes here (unchanged)
         // Example:
         let addr_from = self.stack_pointer + offset_from;
         let addr_to = self.stack_pointer + offset_to;
-        // Bounds check
-        if addr_from < 0 || addr_from + 4 > 4096 || addr_to < 0 || addr_to + 4 > 4096 {
-            return Err(String::from("swap: address out of bounds"));
-        }
-        for i in 0..4 {
-            self.stack.swap((addr_from + i) as usize, (addr_to + i) as usize);
-        }
+        let from_word = self.read_word(addr_from)?;
+        let to_word = self.read_word(addr_to)?;
+        self.write_word(addr_from, to_word)?;
+        self.write_word(addr_to, from_word)?;
 
         // println!("----------- SWAP DEBUG -----------");
         // self.print_vm_info();
@@ -357,96 +1502,84 @@ impl VirtualMachine {
         Ok(())
     }
 
-    fn input(&mut self) -> Result<(), String>{
-        let mut ipt = String::new();
-        let read_response = stdin().read_line(&mut ipt);
-
-        match read_response {
-            Err(_) => return Err(String::from("Couldn't read input.")),
-            _ => (),
+    /* Read a number from a line of input. The low two bits say what to do at the end of the
+     * input (see isa::EofMode) and bit 2 says to skip lines that aren't numbers rather than
+     * stop. */
+    fn input(&mut self, instruction: u32) -> Result<(), VmError>{
+        let eof_mode = instruction & 3;
+        let retry = instruction & 4 != 0;
+        if eof_mode == 3 {
+            return Err(VmError::from(String::from("input: unknown end-of-input mode.")));
         }
 
-        let trimmed = ipt.trim();
-        let n: i32;
-        let convert_response;
-        
-        if trimmed.contains("0x") || trimmed.contains("0X") {
-            convert_response = i32::from_str_radix(&trimmed[2..], 16);
-        }
-        else if trimmed.contains("0b") || trimmed.contains("0B") {
-            convert_response = i32::from_str_radix(&trimmed[2..], 2);
-        }
-        else {
-            convert_response = i32::from_str(&trimmed);
-        }
+        self.flush_output()?;
+        let n = loop {
+            let mut ipt = String::new();
+            match self.read_input(&mut ipt) {
+                Ok(0) => break None,
+                Ok(_) => (),
+                Err(_) => return Err(VmError::from(String::from("Couldn't read input."))),
+            }
 
-        n = match convert_response {
-            Ok(n) => n,
-            Err(_) => return Err(String::from("Bad input.")),
+            match self.parse_number(ipt.as_bytes()) {
+                Some(n) => break Some(n),
+                _ if retry => (),
+                _ => return Err(VmError::from(String::from("Bad input."))),
+            }
         };
 
-        self.push_int_onto_stack(n)?;
+        match (n, eof_mode) {
+            (Some(n), 2) => {
+                self.push_int_onto_stack(n)?;
+                self.push_int_onto_stack(1)
+            },
+            (Some(n), _) => self.push_int_onto_stack(n),
+            (None, 1) => self.push_int_onto_stack(self.config.word_size.min()),
+            (None, 2) => {
+                self.push_int_onto_stack(0)?;
+                self.push_int_onto_stack(0)
+            },
+            (None, _) => Err(VmError::from(String::from("Bad input."))),
+        }
+    }
 
-        Ok(())
+    /* A number the way input and atoi read one: decimal or with a radix prefix (see
+     * asm::parse_number), with space around it. Anything that doesn't fit in a word is as bad
+     * as garbage. */
+    fn parse_number(&self, text: &[u8]) -> Option<i64> {
+        let n = asm::parse_number(core::str::from_utf8(text).ok()?.trim())?;
+        (self.wrap_word(n) == n).then_some(n)
     }
 
-    fn stinput(&mut self, instruction: u32) -> Result<(), String>{
+    fn stinput(&mut self, instruction: u32) -> Result<(), VmError>{
         let shifted_mask = (1 << 24) - 1;
         let shifted = instruction & shifted_mask;
 
         let mut input = String::new();
-        let response = stdin().read_line(&mut input);
+        self.flush_output()?;
+        let response = self.read_input(&mut input);
 
-        match response {
-            Err(e) => return Err(format!("Couldn't read input: {}", e)),
-            _ => (),
+        if let Err(e) = response {
+            return Err(VmError::from(format!("Couldn't read input: {}", e)));
         }
 
-        let mut trimmed = input.trim();
-        
+        /* The limit is in bytes, which can land in the middle of a character. */
+        let mut trimmed = input.trim().as_bytes();
+
         if trimmed.len() > shifted as usize {
             trimmed = &trimmed[..shifted as usize];
         }
 
-        /* Convert our string into words we can push onto the stack. */
-        let bytes: Vec<u8> = trimmed.bytes().collect();
-        let len = bytes.len();
-        let mut cur = 0i32;
-        let mut d: VecDeque<i32> = VecDeque::new();
-        let mut byte_index = 0i32;
-
-        for i in 0..len {
-             let byte = bytes[i];
-             cur |= (byte as i32) << 8 * byte_index;
-
-             byte_index += 1;
-             if byte_index == 3 {
-                 if (i + 1) < len {
-                     cur |= 1 << 24;
-                 }
-
-                 d.push_front(cur);
-                 byte_index = 0;
-                 cur = 0;
-             }
-        }
-        
-        if byte_index != 3 {
-             d.push_front(cur);
-        }
-
-        for no in d {
-            self.push_int_onto_stack(no)?;
-        }
+        self.push_string(trimmed)?;
 
         Ok(())
     }
    
-    fn push(&mut self, instruction: u32) -> Result<(), String> {
-        let mut push_value = (instruction & 0x0fffffff) as i32;
+    fn push(&mut self, instruction: u32) -> Result<(), VmError> {
+        let mut push_value = (instruction & 0x0fffffff) as i64;
         if push_value & (1 << 27) != 0 {
             /* Sign extend. */
-            push_value |= 0xf << 28;
+            push_value |= !0x0fffffff;
         }
 
         self.push_int_onto_stack(push_value)?;
@@ -454,28 +1587,33 @@ impl VirtualMachine {
         Ok(())
     }
 
-    fn pop(&mut self, instruction: u32) -> Result<(), String> {
+    fn pop(&mut self, instruction: u32) -> Result<(), VmError> {
         let offset = instruction & 0x0fffffff;
-        let new_stack_pointer = self.stack_pointer + offset as i32;
 
-        if offset % 4 != 0 {
+        if !offset.is_multiple_of(4) {
             /* This shouldn't happen, but just in case. */
-            return Err(String::from("pop: Offset should be a multiple of four."));
+            return Err(VmError::from(String::from("pop: Offset should be a multiple of four.")));
         }
 
+        /* The offset counts 4 bytes to a word, like swap's slots, so pop 4 drops one word with
+         * words64 too. */
+        let new_stack_pointer = self.stack_pointer + (offset / 4) as i32 * self.word_bytes();
+
         /* If the stack pointer is already at the bottom of the memory allocated, this instruction
          * has no effect. If the offset is not given, it is by default 4. If the offset places the
          * stack pointer past the end of the memory space, the stack pointer will be reset to the
          * end of the memory space (e.g., length(memory)). */
 
+        let memory_end = self.stack_top();
+
         /* Stack pointer is at the bottom of the stack. */
-        if self.stack_pointer == 4096 {
+        if self.stack_pointer == memory_end {
             return Ok(());
         } 
 
         /* New SP goes beyond the stack. */
-        if new_stack_pointer > 4096 {
-            self.stack_pointer = 4096;
+        if new_stack_pointer > memory_end {
+            self.stack_pointer = memory_end;
             return Ok(());
         }
 
@@ -483,98 +1621,445 @@ impl VirtualMachine {
         Ok(())
     }
 
-    fn binary_arithmetic(&mut self, instruction: u32) -> Result<i32, String> {
+    fn binary_arithmetic(&mut self, instruction: u32) -> Result<i64, VmError> {
         let which_seperated = instruction & (0xf << 24);
         let which_operation = which_seperated >> 24;
-        let mut right = self.pop_int_from_stack()? as i32;
-        let left = self.pop_int_from_stack()? as i32;
-        let result: i32;
+        let right = self.pop_int_from_stack()?;
+        let left = self.pop_int_from_stack()?;
 
         /* Divide by zero check. */
-        if (which_operation == 3 || which_operation == 4) && right == 0 {
-            return Err(String::from("Attempt to divide by zero."));
-        }
-
-        /* Negative shift check. */
-        if which_operation >= 8 && right < 0 {
-            //return Err(String::from("Attempt to shift by a negative number."));
-            let true_right = right as u32 % 32;
-            right = true_right as i32;
+        if matches!(which_operation, 3 | 4 | 14 | 15) && right == 0 {
+            return Err(VmError::from(String::from("Attempt to divide by zero.")));
         }
 
-        /* Perform calculation. */
-        match which_operation {
-            0 => {
-                result = left + right;
-            },
-            1 => {
-                result = left - right;
-            },
-            2 => {
-                result = left * right;
-            },
-            3 => {
-                result = left / right;
-            },
-            4 => {
-                result = left % right;
-            },
-            5 => {
-                result = left & right;
-            }, 
-            6 => {
-                result = left | right;
-            },
-            7 => {
-                result = left ^ right;
-            },
-            8 => {
-                result = left << right;
-            },
+        /* Shifts and rotates only look at the shift amount modulo the word size, so a negative
+         * or oversized amount wraps around instead of blowing up. */
+        let amount = right.rem_euclid(self.config.word_size.bits()) as u32;
+
+        /* Perform calculation. The operations that can overflow are done exactly and then fitted
+         * back into a word by the arithmetic mode; the bitwise ones are done 64 bits wide and cut
+         * back down to the word size, which gives the same answer as the narrower type. */
+        let (wide_left, wide_right) = (left as i128, right as i128);
+        let result = match which_operation {
+            0 => self.overflow_checked(wide_left + wide_right)?,
+            1 => self.overflow_checked(wide_left - wide_right)?,
+            2 => self.overflow_checked(wide_left * wide_right)?,
+            3 => self.overflow_checked(wide_left / wide_right)?,
+            4 => left.wrapping_rem(right),
+            5 => left & right,
+            6 => left | right,
+            7 => left ^ right,
+            8 => left << amount,
             9 => {
-                let unsigned_left = left as u32;
-                let unsigned_right = right as u32;
-                let lsr = unsigned_left >> unsigned_right;
-                result = lsr as i32; 
+                let unsigned_left = self.unsigned_word(left);
+                let lsr = unsigned_left >> amount;
+                lsr as i64
             },
-            11 => {
-                result = left >> right;
-            }, 
+            10 => self.compare(instruction, left, right)? as i64,
+            11 => left >> amount,
+            12 => self.rotate_word(left, amount, true),
+            13 => self.rotate_word(left, amount, false),
+            14 => (self.unsigned_word(left) / self.unsigned_word(right)) as i64,
+            15 => (self.unsigned_word(left) % self.unsigned_word(right)) as i64,
             _ => {
-                return Err(String::from("Binary arithmetic instruction contained bad identifier."));
+                return Err(VmError::from(String::from("Binary arithmetic instruction contained bad identifier.")));
             },
-        }
+        };
+        let result = self.wrap_word(result);
 
         self.push_int_onto_stack(result)?;
 
         Ok(result)
     }
 
-    fn unary_arithmetic(&mut self, instruction: u32) -> Result<(), String> {
-        let operand = self.pop_int_from_stack()? as i32;
+    /* Work out a comparison for cmp. The condition lives in bits 23-20 and uses the same numbers
+     * as binary_if, with the unsigned versions tacked on the end. */
+    fn compare(&self, instruction: u32, left: i64, right: i64) -> Result<bool, VmError> {
+        let condition = (instruction >> 20) & 0xf;
+        let (unsigned_left, unsigned_right) = (self.unsigned_word(left), self.unsigned_word(right));
+
+        let result = match condition {
+            0 => left == right,
+            1 => left != right,
+            2 => left < right,
+            3 => left > right,
+            4 => left <= right,
+            5 => left >= right,
+            6 => unsigned_left < unsigned_right,
+            7 => unsigned_left > unsigned_right,
+            8 => unsigned_left <= unsigned_right,
+            9 => unsigned_left >= unsigned_right,
+            _ => {
+                return Err(VmError::from(String::from("cmp: faulty condition.")));
+            }
+        };
+
+        Ok(result)
+    }
+
+    fn unary_arithmetic(&mut self, instruction: u32) -> Result<(), VmError> {
+        let operand = self.pop_int_from_stack()?;
         let which_seperated = instruction & (0xf << 24);
         let which_operation = which_seperated >> 24;
-        let result: i32;
 
-        match which_operation {
-            0 => {
-                result = -operand;
-            },
-            1 => { 
-                result = !operand;
-            },
+        let result = match which_operation {
+            0 => self.overflow_checked(-(operand as i128))?,
+            1 => !operand,
             _ => {
-                return Err(String::from("Unary arithmetic instruction contained bad identifier."));
+                return Err(VmError::from(String::from("Unary arithmetic instruction contained bad identifier.")));
             }
-        }
+        };
+        let result = self.wrap_word(result);
 
         self.push_int_onto_stack(result)?;
 
         Ok(())
     }
 
+    /* Save a return address: on the data stack normally, or on the return stack in dual-stack
+     * mode. */
+    fn push_return_address(&mut self, address: i64) -> Result<(), VmError> {
+        let Some(capacity) = self.config.return_stack_depth else {
+            return self.push_int_onto_stack(address);
+        };
+
+        if self.return_stack.len() >= capacity {
+            return Err(VmError::ReturnStackOverflow { pc: self.program_counter });
+        }
+
+        self.return_stack.push(address);
+        Ok(())
+    }
+
+    /* Get back a return address saved by push_return_address. */
+    fn pop_return_address(&mut self) -> Result<i64, VmError> {
+        if self.config.return_stack_depth.is_none() {
+            return self.pop_int_from_stack();
+        }
+
+        match self.return_stack.pop() {
+            Some(address) => Ok(address),
+            None => Err(VmError::from(String::from("Failed to pop: return stack is empty."))),
+        }
+    }
+
+    /* Extended instructions (opcode 11). Bits 27-20 pick the instruction and bits 19-0 are
+     * its operand.
+     *
+     *     0x00  >r    move the top of the data stack onto the return stack
+     *     0x01  r>    move the top of the return stack onto the data stack
+     *     0x02  strlen  push the length of the string at a signed byte offset from the top
+     *     0x03  strcat  replace the two strings on top with the lower one followed by the top one
+     *     0x04  strcmp  replace the two strings on top with -1, 0 or 1 as the lower one sorts
+     *                   before, the same as or after the top one
+     *     0x05  readfile   replace the file name on top with the file's contents
+     *     0x06  writefile  pop a file name, then write the string under it to the file, or with
+     *                      a nonzero operand, that many bytes of memory from the top of the
+     *                      stack, and pop those too
+     *     0x07  arg    replace the number on top with that argument (counting from 0)
+     *     0x08  getenv  replace the variable name on top with its value, or an empty string
+     *                   if it isn't set
+     *     0x09  clock   push the wall-clock time in milliseconds since 1970, wrapped to a word
+     *     0x0a  cycles  push how many instructions have run before this one, wrapped to a word
+     *     0x0b  rand    push a pseudo-random word
+     *     0x0c  load    replace the address on top with the word there
+     *     0x0d  store   pop an address, then write the word under it there, and pop that too
+     *     0x0e  spawn   start a new context at a signed byte offset from here, moving the word
+     *                   on top onto its stack, and push its id (see the scheduler module)
+     *     0x0f  yield   let the next context that can run have a turn
+     *     0x10  join    replace the context id on top with that context's exit code, waiting
+     *                   for it to exit first
+     *     0x11  cas     pop an address, a new word and an old one, write the new one there if
+     *                   the old one is there, and push what was there
+     *     0x12  fetchadd  pop an address and a word, add the word to the one there, and push
+     *                     what was there before
+     *     0x13  lock    pop the address of a mutex word, waiting for it to be 0 and then
+     *                   setting it to the context's id + 1
+     *     0x14  unlock  pop the address of a mutex word this context has locked and set it to 0
+     *     0x15  stprintn  pop an address and then a length, and print exactly that many bytes
+     *                     of memory from the address, whatever they are
+     *     0x16  pushb   replace the word on top with its low byte, taking up one byte
+     *     0x17  pushh   replace the word on top with its low two bytes, taking up two
+     *     0x18  popb    replace the byte on top with a word holding it, sign extended, or zero
+     *                   extended with bit 0 set (popbu)
+     *     0x19  poph    the same for the two bytes on top (pophu)
+     *     0x1A  jumpi   pop the address of an instruction and carry on from there
+     *     0x1B  jumptable  pop a selector, and with one below the entries in bits 19-0, go by
+     *                      that entry in the table of byte offsets following the instruction,
+     *                      each from its own entry; with any other, carry on past the table
+     *     0x1C  lea     push the address a signed byte offset in bits 19-0 from this instruction
+     *     0x1D  pick    push a copy of the word as many words below the top as bits 19-0 say
+     *     0x1E  roll    bring the deepest of as many words on top as bits 19-0 say to the top,
+     *                   moving the others down one; roll 2 is a swap and roll 3 a rot
+     *     0x1F  drop    drop as many words as bits 19-0 say
+     *     0x20  atoi    pop a string and push the number it spells, read the way input reads
+     *                   one, and then 1; or 0 and 0 if it isn't a number that fits in a word
+     *     0x21  itoa    replace the word on top with a string of it in decimal
+     *     0x22  printf  print the word on top laid out the way bits 12-0 say (see
+     *                   isa::PrintSpec), with a width, padding and unsigned decimal
+     *     0x23  brk     nothing, except that step gives back StepResult::Breakpoint
+     *     0x24  assert  pop a word and fault if it's 0, saying the bytes up to a 0 a signed byte
+     *                   offset in bits 19-0 from it, or nothing more for an offset of 0
+     *     0x25  stackdepth  push how many bytes the stack can still grow by before this push,
+     *                   down to the stack limit (see stack_limit)
+     *     0x26  perfctr  push the counter numbered in bits 19-0 (see isa::PerfCounter), as it
+     *                    stood before this instruction
+     *
+     * load and store reach devices for addresses in the config's mmio range.
+     * readfile and writefile only touch files named by one of the program's arguments, and
+     * getenv only reads variables in the config's allowlist.
+     */
+    fn extended(&mut self, instruction: u32) -> Result<(), VmError> {
+        let which_instruction = (instruction >> 20) & 0xff;
+
+        match which_instruction {
+            0x00 | 0x01 if self.config.return_stack_depth.is_none() => {
+                return Err(VmError::from(String::from("The return stack only exists in dual-stack mode.")));
+            },
+            0x00 => {
+                let word = self.pop_int_from_stack()?;
+                self.push_return_address(word)?;
+            },
+            0x01 => {
+                let word = self.pop_return_address()?;
+                self.push_int_onto_stack(word)?;
+            },
+            0x02 => {
+                let offset = ((instruction << 12) as i32) >> 12;
+                let (text, _) = self.read_string(self.stack_pointer + offset)?;
+                self.push_int_onto_stack(text.len() as i64)?;
+            },
+            0x03 => {
+                let (mut left, right) = self.pop_two_strings()?;
+                left.extend(right);
+                self.push_string(&left)?;
+            },
+            0x04 => {
+                let (left, right) = self.pop_two_strings()?;
+                self.push_int_onto_stack(left.cmp(&right) as i64)?;
+            },
+            0x05 => {
+                let path = self.pop_file_name()?;
+                let contents = host::read_file(&path)?;
+                self.push_string(&contents)?;
+            },
+            0x06 => {
+                let path = self.pop_file_name()?;
+                let size = instruction & 0xF_FFFF;
+                let contents = if size == 0 {
+                    let (text, used) = self.read_string(self.stack_pointer)?;
+                    self.stack_pointer += used;
+                    text
+                } else {
+                    let bytes = self.read_bytes(self.stack_pointer, size as usize)?.to_vec();
+                    let words = (size as i32 + self.word_bytes() - 1) / self.word_bytes();
+                    self.stack_pointer += words * self.word_bytes();
+                    bytes
+                };
+                host::write_file(&path, &contents)?;
+            },
+            0x07 => {
+                let index = self.pop_int_from_stack()?;
+                let arg = usize::try_from(index).ok()
+                    .and_then(|index| self.config.args.as_ref()?.get(index).cloned())
+                    .ok_or_else(|| VmError::from(format!("There's no argument {}.", index)))?;
+                self.push_string(arg.as_bytes())?;
+            },
+            0x08 => {
+                let (name, used) = self.read_string(self.stack_pointer)?;
+                let name = String::from_utf8(name).map_err(|_| VmError::from(String::from("Variable name isn't valid UTF-8.")))?;
+                if !self.config.env_allowlist.contains(&name) {
+                    return Err(VmError::from(format!("{} isn't an environment variable the program may read.", name)));
+                }
+
+                self.stack_pointer += used;
+                let value = host::getenv(&name).unwrap_or_default();
+                self.push_string(value.as_bytes())?;
+            },
+            0x09 => {
+                let millis = host::now_millis()?;
+                self.push_int_onto_stack(self.wrap_word(millis as i64))?;
+            },
+            0x0a => {
+                /* The count already includes this instruction. */
+                let cycles = self.instruction_count - 1;
+                self.push_int_onto_stack(self.wrap_word(cycles as i64))?;
+            },
+            0x0b => {
+                let random = self.rng.next_u64();
+                self.push_int_onto_stack(self.wrap_word(random as i64))?;
+            },
+            0x0c => {
+                let address = self.pop_address()?;
+                let word = match self.device_at(address)? {
+                    Some((device, offset)) => device.load(offset)?,
+                    None => self.read_word(address)?,
+                };
+                self.push_int_onto_stack(self.wrap_word(word))?;
+            },
+            0x0d => {
+                let address = self.pop_address()?;
+                let word = self.pop_int_from_stack()?;
+                match self.device_at(address)? {
+                    Some((device, offset)) => device.store(offset, word)?,
+                    None => self.write_word(address, word)?,
+                }
+            },
+            0x0e => {
+                let offset = ((instruction << 12) as i32) >> 12;
+                let argument = self.pop_int_from_stack()?;
+                let start = self.program_counter + offset;
+                let (id, sp) = self.scheduler.spawn(start, self.stack_pointer, self.word_bytes())?;
+                self.write_word(sp, argument)?;
+                self.push_int_onto_stack(id as i64)?;
+            },
+            0x0f => self.switch_pending = true,
+            0x10 => {
+                let id = self.pop_int_from_stack()?;
+                match self.scheduler.join(id)? {
+                    Some(code) => self.push_int_onto_stack(code as i64)?,
+                    None => {
+                        /* Try again on this context's next turn. */
+                        self.push_int_onto_stack(id)?;
+                        self.program_counter -= 4;
+                        self.switch_pending = true;
+                    },
+                }
+            },
+            0x11 => {
+                let address = self.pop_address()?;
+                let new = self.pop_int_from_stack()?;
+                let old = self.pop_int_from_stack()?;
+                let found = self.store_word(address, |memory, size| memory.compare_and_swap(address, size, old as u64, new as u64))?;
+                self.push_int_onto_stack(self.wrap_word(found as i64))?;
+            },
+            0x12 => {
+                let address = self.pop_address()?;
+                let delta = self.pop_int_from_stack()?;
+                let found = self.store_word(address, |memory, size| memory.fetch_add(address, size, delta as u64))?;
+                self.push_int_onto_stack(self.wrap_word(found as i64))?;
+            },
+            0x13 => {
+                let address = self.pop_address()?;
+                let owner = self.scheduler.current() as u64 + 1;
+                let found = self.store_word(address, |memory, size| memory.compare_and_swap(address, size, 0, owner))?;
+                if found == owner {
+                    return Err(VmError::from(format!("Context {} already holds the lock at {:#x}.", owner - 1, address)));
+                }
+                if found != 0 {
+                    /* Someone else has it: try again on this context's next turn. */
+                    self.push_int_onto_stack(address as i64)?;
+                    self.program_counter -= 4;
+                    self.switch_pending = true;
+                }
+            },
+            0x14 => {
+                let address = self.pop_address()?;
+                let owner = self.scheduler.current() as u64 + 1;
+                let found = self.store_word(address, |memory, size| memory.compare_and_swap(address, size, owner, 0))?;
+                if found != owner {
+                    return Err(VmError::from(format!("Context {} doesn't hold the lock at {:#x}.", owner - 1, address)));
+                }
+            },
+            0x15 => {
+                let address = self.pop_int_from_stack()?;
+                let length = self.pop_int_from_stack()?;
+                let bytes = i32::try_from(address).ok()
+                    .zip(usize::try_from(length).ok())
+                    .and_then(|(address, length)| self.read_bytes(address, length).ok())
+                    .ok_or_else(|| VmError::from(format!("stprintn: there aren't {} bytes of memory at {:#x}.", length, address)))?
+                    .to_vec();
+                self.write_output_bytes(&bytes)?;
+                self.flush_output()?;
+            },
+            0x16 => self.push_narrow(1)?,
+            0x17 => self.push_narrow(2)?,
+            0x18 if instruction & 0xF_FFFE == 0 => self.pop_narrow(1, instruction & 1 == 0)?,
+            0x19 if instruction & 0xF_FFFE == 0 => self.pop_narrow(2, instruction & 1 == 0)?,
+            0x1A => self.jump_indirect()?,
+            0x1B => self.jump_table(instruction & 0xF_FFFF)?,
+            0x1C => {
+                let offset = ((instruction << 12) as i32) >> 12;
+                self.push_int_onto_stack(self.program_counter as i64 + offset as i64)?;
+            },
+            0x1D => {
+                let address = self.stack_words("pick", (instruction & 0xF_FFFF) + 1)? - self.word_bytes();
+                let word = self.read_word(address)?;
+                self.push_int_onto_stack(word)?;
+            },
+            0x1E => {
+                let end = self.stack_words("roll", instruction & 0xF_FFFF)?;
+                let (start, word_bytes) = (self.stack_pointer, self.word_bytes() as usize);
+                if end - start > word_bytes as i32 {
+                    self.store_bytes(start, end - start, |memory, size| {
+                        memory.slice_mut(start, size).map(|words| words.rotate_right(word_bytes))
+                    })?;
+                }
+            },
+            0x1F => self.stack_pointer = self.stack_words("drop", instruction & 0xF_FFFF)?,
+            0x20 => {
+                let (text, used) = self.read_string(self.stack_pointer)?;
+                self.stack_pointer += used;
+                match self.parse_number(&text) {
+                    Some(n) => {
+                        self.push_int_onto_stack(n)?;
+                        self.push_int_onto_stack(1)?;
+                    },
+                    None => {
+                        self.push_int_onto_stack(0)?;
+                        self.push_int_onto_stack(0)?;
+                    },
+                }
+            },
+            0x21 => {
+                let n = self.pop_int_from_stack()?;
+                self.push_string(format!("{}", n).as_bytes())?;
+            },
+            0x22 if instruction & 0xF_E000 == 0 => {
+                let spec = isa::PrintSpec::from_bits(instruction & 0x1FFF)
+                    .ok_or_else(|| VmError::from(String::from("printf: bad conversion.")))?;
+                let value = self.peek_int_from_stack(0)?;
+                self.write_output(&spec.format(value, self.unsigned_word(value)))?;
+            },
+            0x23 => self.break_pending = true,
+            0x24 => {
+                if self.pop_int_from_stack()? == 0 {
+                    let offset = ((instruction << 12) as i32) >> 12;
+                    let message = match offset {
+                        0 => String::new(),
+                        /* The bytes .string puts down, up to the 0, whatever the string format. */
+                        _ => {
+                            let address = self.program_counter + offset;
+                            let rest = usize::try_from(address).ok().and_then(|start| self.stack.as_slice().get(start..))
+                                .ok_or(VmError::OutOfBounds { address, size: 1 })?;
+                            let length = rest.iter().position(|&byte| byte == 0).unwrap_or(rest.len());
+                            String::from_utf8_lossy(self.read_bytes(address, length)?).into_owned()
+                        },
+                    };
+                    return Err(VmError::AssertionFailed { message, pc: self.program_counter });
+                }
+            },
+            0x25 => self.push_int_onto_stack(self.stack_room() as i64)?,
+            0x26 => {
+                let number = instruction & 0xF_FFFF;
+                let counter = isa::PerfCounter::ALL.get(number as usize)
+                    .ok_or_else(|| VmError::from(format!("There's no performance counter {}.", number)))?;
+                let count = match counter {
+                    isa::PerfCounter::Instructions => self.instruction_count - 1,
+                    isa::PerfCounter::Branches => self.branches_taken,
+                    isa::PerfCounter::StackMax => self.max_stack_depth as u64,
+                    isa::PerfCounter::IoBytes => self.io_bytes,
+                };
+                self.push_int_onto_stack(self.wrap_word(count as i64))?;
+            },
+            _ => return Err(VmError::from(String::from("Bad instruction."))),
+        }
+
+        Ok(())
+    }
+
     /*call instruction*/
-    fn call(&mut self, instruction: u32) -> Result<(), String> {
+    fn call(&mut self, instruction: u32) -> Result<(), VmError> {
         let og_offset = ((instruction >> 2) & 0x3FFFFFF) as i32;
         let offset = if (og_offset & (1 << 25)) != 0 {
             og_offset | !0x3FFFFFF
@@ -585,12 +2070,36 @@ impl VirtualMachine {
         //final offset in bytes
         let final_offset = offset << 2;
 
+        if instruction & 3 == 1 {
+            self.tail_call(final_offset);
+            return Ok(());
+        }
+
+        //bail before a runaway recursion eats the code
+        if let Some(max_depth) = self.config.max_call_depth {
+            if self.call_stack.len() >= max_depth {
+                return Err(VmError::CallDepthExceeded {
+                    depth: self.call_stack.len() + 1,
+                    pc: self.program_counter,
+                });
+            }
+        }
+
         //push ret addy 
         let red_addy = self.program_counter + 4;
-        self.push_int_onto_stack(red_addy)?;
+        self.push_return_address(red_addy as i64)?;
+
+        //remember the call so ret can unwind it
+        self.call_stack.push(CallFrame {
+            call_site: self.program_counter,
+            target: self.program_counter + final_offset,
+        });
+        if let Some(profile) = &mut self.profile {
+            profile.record_call(self.program_counter + final_offset);
+        }
 
         //jump to new pc
-        self.program_counter = self.program_counter + final_offset;
+        self.program_counter += final_offset;
 
         //prev double increment 
         self.program_counter -= 4;
@@ -598,10 +2107,55 @@ impl VirtualMachine {
         Ok(()) 
     }
        
-    fn ret(&mut self, instruction: u32) -> Result<(), String> {
-        // Extract stack offset from bits 27:2 (always a multiple of 4)
+    /* tailcall: go to the target as if the function being run had been called there instead.
+     * The frame and the return address stay as they are, so there's no depth to check. */
+    fn tail_call(&mut self, offset: i32) {
+        let target = self.program_counter + offset;
+        if let Some(frame) = self.call_stack.last_mut() {
+            frame.target = target;
+        }
+        if let Some(profile) = &mut self.profile {
+            profile.record_call(target);
+        }
+
+        self.program_counter = target - 4;
+    }
+
+    /* jumpi: pop the address of an instruction in the code and carry on from there. */
+    fn jump_indirect(&mut self) -> Result<(), VmError> {
+        let address = self.pop_int_from_stack()?;
+        if address < 0 || address % 4 != 0 || address + 4 > self.code_end() as i64 {
+            return Err(VmError::from(format!("jumpi to {:#x}, which isn't an instruction in the code.", address)));
+        }
+
+        self.program_counter = address as i32 - 4;
+        Ok(())
+    }
+
+    /* jumptable: the table is the entries words straight after the instruction. */
+    fn jump_table(&mut self, entries: u32) -> Result<(), VmError> {
+        let selector = self.pop_int_from_stack()?;
+        let table = self.program_counter + 4;
+        if table as i64 + entries as i64 * 4 > self.code_end() as i64 {
+            return Err(VmError::from(format!("The jumptable at {:#x} runs past the end of the code.", self.program_counter)));
+        }
+
+        if !(0..entries as i64).contains(&selector) {
+            self.program_counter = table + entries as i32 * 4 - 4;
+            return Ok(());
+        }
+
+        let entry = table + selector as i32 * 4;
+        let offset = self.stack.read_u32(entry)? as i32;
+        self.program_counter = entry.wrapping_add(offset) - 4;
+        Ok(())
+    }
+
+    fn ret(&mut self, instruction: u32) -> Result<(), VmError> {
+        // Extract stack offset from bits 27:2 (always a multiple of 4), which counts 4 bytes to
+        // a word as pop's does
         let offset_raw = instruction & 0x0FFF_FFFC;
-        let offset = offset_raw as i32;
+        let offset = (offset_raw / 4) as i32 * self.word_bytes();
 
         // Then pop the return address
         //self.print_stack();
@@ -610,7 +2164,8 @@ impl VirtualMachine {
         // Free the stack frame first
         self.stack_pointer += offset;
 
-        let return_address = self.pop_int_from_stack()? as i32;
+        let return_address = self.pop_return_address()? as i32;
+        self.call_stack.pop();
 
         // Adjust program counter
         self.program_counter = return_address;
@@ -626,17 +2181,16 @@ impl VirtualMachine {
         Ok(())
     }
 
-    fn goto(&mut self, instruction: u32) -> Result<(), String>{
+    fn goto(&mut self, instruction: u32) -> Result<(), VmError>{
         //TODO: make sure offset is signed
         let extracted = (instruction >> 2) & 0x03FF_FFFF; // 26 bits
-        let offset: i32;
         // Check if the sign bit (bit 25 after shift) is set
-        if extracted & (1 << 25) != 0 {
+        let offset = if extracted & (1 << 25) != 0 {
             // Sign-extend: set upper bits to 1
-            offset = (extracted | !0x03FF_FFFF) as i32;
+            (extracted | !0x03FF_FFFF) as i32
         } else {
-            offset = extracted as i32;
-        }
+            extracted as i32
+        };
 
         //TODO: fix offset calc
         /* offset += self.program_counter;
@@ -650,7 +2204,7 @@ impl VirtualMachine {
         Ok(())
     }
 
-    fn print(&mut self, instruction: u32) -> Result<(), String>{
+    fn print(&mut self, instruction: u32) -> Result<(), VmError>{
         let offset_mask = (1 << 26) - 1;
         let mut offset: i32 = (instruction as i32 >> 2) & offset_mask;
         offset <<= 2;
@@ -659,25 +2213,28 @@ impl VirtualMachine {
         }
 
         let fmt: i8 = instruction as i8 & 3;
-        let val = self.peek_int_from_stack(offset)? as i32;
+        let val = self.peek_int_from_stack(offset)?;
 
         // println!("o:{} om:{:x} i:{:x}", offset, offset_mask, instruction);
         //println!("o:{} om:{:x} i:{:x}", offset, offset_mask, instruction);
 
+        /* Hex, binary and octal show the word's own bits, not the sign extended i64's. */
+        let bits = self.unsigned_word(val);
+
         match fmt {
-            0 => println!("{}", val),
-            1 => println!("0x{:x}", val),
-            2 => println!("0b{:b}", val),
-            3 => println!("0o{:o}", val),
+            0 => self.write_output(&format!("{}\n", val))?,
+            1 => self.write_output(&format!("0x{:x}\n", bits))?,
+            2 => self.write_output(&format!("0b{:b}\n", bits))?,
+            3 => self.write_output(&format!("0o{:o}\n", bits))?,
             _ => {
-                return Err(String::from("print: faulty format code."));
+                return Err(VmError::from(String::from("print: faulty format code.")));
             }
         };
 
         Ok(())
     }
 
-    fn binary_if(&mut self, instruction: u32) -> Result<(), String>{
+    fn binary_if(&mut self, instruction: u32) -> Result<(), VmError>{
         /*let offset: i32 = (instruction as i32 >> 2) & 0x3FFFFF;
         let cond: u32 = (instruction >> 25) & 0x7;
         let lhs = self.peek_int_from_stack(4).unwrap_or(0);
@@ -689,37 +2246,34 @@ impl VirtualMachine {
             offset |= !offset_mask;
         }
 
-        let cond_mask = (1 << 4) - 1;
-        let cond = (instruction >> 25) & cond_mask;
-        let lhs = self.peek_int_from_stack(4).unwrap_or(0);
+        /* Bit 28 is the opcode's, so only conditions 0-7 fit under opcode 8. leu and geu, 8 and
+         * 9, go under opcode 9 with bit 27 set, which unary_if hands on to here. */
+        let cond = match instruction >> 28 {
+            8 => (instruction >> 25) & 0x7,
+            _ => 8 + ((instruction >> 25) & 0x3),
+        };
+        let lhs = self.peek_int_from_stack(self.word_bytes()).unwrap_or(0);
         let rhs = self.peek_int_from_stack(0).unwrap_or(0);
-        let result: bool;
-
-        match cond{
-            0 => {
-                result = lhs == rhs;
-            },
-            1 => {
-                result = lhs != rhs;
-            },
-            2 => {
-                result = lhs < rhs;
-            },
-            3 => {
-                result = lhs > rhs; 
-            },
-            4 => {
-                result = lhs <= rhs;
-            },
-            5 => {
-                result = lhs >= rhs;
-            },
+        let (unsigned_lhs, unsigned_rhs) = (self.unsigned_word(lhs), self.unsigned_word(rhs));
+
+        let result = match cond {
+            0 => lhs == rhs,
+            1 => lhs != rhs,
+            2 => lhs < rhs,
+            3 => lhs > rhs,
+            4 => lhs <= rhs,
+            5 => lhs >= rhs,
+            6 => unsigned_lhs < unsigned_rhs,
+            7 => unsigned_lhs > unsigned_rhs,
+            8 => unsigned_lhs <= unsigned_rhs,
+            9 => unsigned_lhs >= unsigned_rhs,
             _ => {
-                return Err(String::from("Binary if: faulty instruction."));
+                return Err(VmError::from(String::from("Binary if: faulty instruction.")));
             }
         };
 
         if result {
+            self.check_branch(instruction, offset)?;
             self.program_counter += offset;
             /* Band-aid fix. :) */
             self.program_counter -= 4;
@@ -728,7 +2282,21 @@ impl VirtualMachine {
         Ok(())
     }
 
-    fn unary_if(&mut self, instruction: u32) -> Result<(), String>{
+    /* An if's offset counts bytes, so nothing but the assembler stops it going to the middle of
+     * a word; here it stops the machine instead. */
+    fn check_branch(&self, instruction: u32, offset: i32) -> Result<(), VmError> {
+        match offset % 4 {
+            0 => Ok(()),
+            _ => Err(VmError::from(format!("{} at {:#x} goes {} bytes, which isn't a whole number of instructions.",
+                isa::disassemble(instruction), self.program_counter, offset))),
+        }
+    }
+
+    fn unary_if(&mut self, instruction: u32) -> Result<(), VmError>{
+        if instruction & (1 << 27) != 0 {
+            return self.binary_if(instruction);
+        }
+
         let offset_mask = (1 << 25) - 1;
         let condition_mask = (1 << 2) - 1;
 
@@ -737,28 +2305,20 @@ impl VirtualMachine {
             offset |= !offset_mask;
         }
         let condition = (instruction >> 25) & condition_mask;
-        let peek = self.peek_int_from_stack(0)? as i32;
-        let result: bool;
+        let peek = self.peek_int_from_stack(0)?;
 
-        match condition {
-            0 => {
-                result = peek == 0;
-            },
-            1 => {
-                result = peek != 0;
-            },
-            2 => {
-                result = peek < 0;
-            },
-            3 => {
-                result = peek > 0;
-            },
+        let result = match condition {
+            0 => peek == 0,
+            1 => peek != 0,
+            2 => peek < 0,
+            3 => peek > 0,
             _ => {
-                return Err(String::from("Unary if: faulty instruction."));
+                return Err(VmError::from(String::from("Unary if: faulty instruction.")));
             },
-        }
+        };
 
         if result {
+            self.check_branch(instruction, offset)?;
             self.program_counter += offset;
             /* Band-aid fix. :) */
             self.program_counter -= 4;
@@ -767,28 +2327,29 @@ impl VirtualMachine {
         Ok(())
     }
 
-    fn dump(&self) -> Result<(), String>{
-        let start = self.stack_pointer as usize;
+    fn dump(&mut self) -> Result<(), VmError>{
+        let start = self.stack_pointer;
+        let memory_end = self.stack_top();
         //if stack empty gtfo
-        if start == 4096 {
+        if start == memory_end {
             return Ok(());
         }
-        //read through stack 4 bytes at a time
+        //read through stack one word at a time
         // let mut offset = 0;
-        for i in (start..4096).step_by(4) {
-            if i + 4 > self.stack.len() {
+        let word_bytes = self.word_bytes() as usize;
+        for i in (start..memory_end).step_by(word_bytes) {
+            if i + word_bytes as i32 > memory_end {
                 break;
             }
             //start converting bytes from i
-            let word_bytes = &self.stack[i..i+4];
-            let word = u32::from_be_bytes(word_bytes.try_into().unwrap());
-            println!("{:04x}: {:08x}", i, word);
+            let word = self.unsigned_word(self.read_word(i)?);
+            self.write_diagnostic(&format!("{:04x}: {:0width$x}\n", i, word, width = word_bytes * 2))?;
             // offset += 1;
         }
         Ok(())
     }
 
-    fn stprint(&self, instruction: u32) -> Result<(), String> {
+    fn stprint(&mut self, instruction: u32) -> Result<(), VmError> {
         let mut stack_offset = (instruction as i32) & !(0xf << 28);
         if stack_offset & (1 << 27) != 0 {
             /* Sign extend. */
@@ -796,54 +2357,16 @@ impl VirtualMachine {
         }
     
         let start_address = self.stack_pointer + stack_offset;
-        if start_address >= 4096 || start_address < 0 {
-            return Err(String::from("stprint: Offset out of range."));
-        }
-
-        /* The actual print loop. */
-        let start_index = start_address as usize;
-        let stack_size = self.stack.len();
-        let mut last_char_set = -1;
-        let mut d = VecDeque::new();
-        for i in start_index..stack_size {
-            let cur = self.stack[i];
-
-            /* Since strings are stored in three-byte chunklets prefaced by numbers, when we hit a
-             * null terminator, we don't die right away, instead we print the last three bytes. */
-            if cur == 0 || last_char_set != -1 {
-                last_char_set += 1;
-            }
-            if last_char_set > 3 {
-                break;
-            }
-            /* Don't add numbers to the deque. */
-            if cur == 0 || cur == 1 {
-                continue;
-            }
-
-            //print!("{}", cur as char);
-            d.push_front(cur as char);
-            if d.len() == 3 {
-                for c in &d {
-                    print!("{}", c);
-                }
-
-                d.clear();
-            }
-        }
 
-        if !d.is_empty() {
-            for c in &d {
-                print!("{}", c);
-            }
-        }
+        let (text, _) = self.read_string(start_address)?;
+        self.write_output(&text.iter().map(|&byte| byte as char).collect::<String>())?;
 
-        stdout().flush().expect("Failed to flush buffer");
+        self.flush_output()?;
 
         Ok(())
     }
 
-    fn dup(&mut self, instruction: u32) -> Result<(), String> {
+    fn dup(&mut self, instruction: u32) -> Result<(), VmError> {
         let offset_mask = (1 << 28) - 1;
 
         /* Marz's handles negative offsets. Sounds horrible to me, but who cares anymore. It's
@@ -853,7 +2376,7 @@ impl VirtualMachine {
             offset |= !offset_mask;
         }
 
-        let peek = self.peek_int_from_stack(offset)? as i32;
+        let peek = self.peek_int_from_stack(offset)?;
         self.push_int_onto_stack(peek)?;
 
         Ok(())
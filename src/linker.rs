@@ -0,0 +1,294 @@
+/* Object files and the linker behind `vm link`. `vm asm -c` assembles a file on its own into a
+ * .vo object file, leaving the branches to labels it doesn't define for the linker to fill in.
+ * The linker puts the objects' code one after another, in the order it's given them, and points
+ * those branches at wherever the labels ended up. Execution starts at the start of the first
+ * object.
+ *
+ * Every label is visible to the other objects except ones starting with a dot, which stay inside
+ * their own file, so two files can both have a .loop. Branches are relative, so nothing that
+ * stays inside one file needs fixing up when it moves.
+ *
+ * The linked program gets every header feature any of the objects asked for.
+ *
+ * With --gc the functions nothing can get to are left out once it's linked, which is how a
+ * program that includes a whole file of routines ends up with just the ones it uses. What can
+ * be got to starts at address 0, where the program starts, and at the symbols exported with
+ * --export for something outside the program to find, and takes in whatever those call, branch
+ * to or take the address of.
+ *
+ * A .vo file is all little-endian:
+ *
+ *     "VOBJ"
+ *     header features
+ *     code length in bytes, then the code
+ *     symbol count, then for each: address, name length, name
+ *     relocation count, then for each: address, name length, name
+ */
+
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::debug_info::DebugInfo;
+use crate::isa::Instruction;
+use crate::optimize;
+use crate::Header;
+
+pub const OBJECT_MAGIC: [u8; 4] = *b"VOBJ";
+
+/* A branch whose target is in another object. */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Relocation {
+    /* Address of the branch, from the start of its object. */
+    pub address: i32,
+    pub symbol: String,
+}
+
+/* Code with the labels it defines for others and the ones it needs from them. */
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Object {
+    pub code: Vec<u8>,
+    pub symbols: BTreeMap<String, i32>,
+    pub relocations: Vec<Relocation>,
+    /* Header features the code needs. */
+    pub features: u32,
+}
+
+/* Pulls the fields of a .vo file out in order. */
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, size: usize) -> Result<&'a [u8], String> {
+        if self.bytes.len() < size {
+            return Err(String::from("Object file is truncated."));
+        }
+
+        let (taken, rest) = self.bytes.split_at(size);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        let size = self.u32()? as usize;
+        String::from_utf8(Vec::from(self.take(size)?)).map_err(|_| String::from("Object file has a bad symbol name."))
+    }
+
+    /* An address and a name, the shape of both symbols and relocations. */
+    fn entries(&mut self) -> Result<Vec<(i32, String)>, String> {
+        let count = self.u32()?;
+        (0..count).map(|_| Ok((self.u32()? as i32, self.name()?))).collect()
+    }
+}
+
+fn write_entry(bytes: &mut Vec<u8>, address: i32, name: &str) {
+    bytes.extend_from_slice(&(address as u32).to_le_bytes());
+    bytes.extend_from_slice(&(name.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(name.as_bytes());
+}
+
+impl Object {
+    /* The contents of a .vo file holding the object. */
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::from(OBJECT_MAGIC);
+        bytes.extend_from_slice(&self.features.to_le_bytes());
+        bytes.extend_from_slice(&(self.code.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&self.code);
+
+        bytes.extend_from_slice(&(self.symbols.len() as u32).to_le_bytes());
+        for (name, &address) in &self.symbols {
+            write_entry(&mut bytes, address, name);
+        }
+
+        bytes.extend_from_slice(&(self.relocations.len() as u32).to_le_bytes());
+        for relocation in &self.relocations {
+            write_entry(&mut bytes, relocation.address, &relocation.symbol);
+        }
+
+        bytes
+    }
+
+    /* Read back a .vo file. */
+    pub fn from_bytes(bytes: &[u8]) -> Result<Object, String> {
+        let mut reader = Reader { bytes };
+        if reader.take(4)? != OBJECT_MAGIC {
+            return Err(String::from("Not an object file."));
+        }
+
+        let features = reader.u32()?;
+        let size = reader.u32()? as usize;
+        let code = Vec::from(reader.take(size)?);
+        let symbols = reader.entries()?.into_iter().map(|(address, name)| (name, address)).collect();
+        let relocations = reader.entries()?.into_iter()
+            .map(|(address, symbol)| Relocation { address, symbol })
+            .collect();
+
+        if !reader.bytes.is_empty() {
+            return Err(String::from("Object file has junk at the end."));
+        }
+
+        Ok(Object { code, symbols, relocations, features })
+    }
+
+    /* The contents of a .v file holding the code. Only makes sense once there's nothing left to
+     * relocate. Debug info, if asked for, only has the symbols, since objects don't keep
+     * lines. */
+    pub fn image(&self) -> Vec<u8> {
+        let header = Header::new(self.features);
+        let mut image = header.image(&self.code);
+        if header.has(Header::DEBUG_INFO) {
+            let debug_info = DebugInfo { labels: self.symbols.clone(), ..DebugInfo::default() };
+            debug_info.append_to(&mut image);
+        }
+        image
+    }
+}
+
+/* Something that stopped a link, and which object it's in (counting from 0) if it's down to
+ * one of them. */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LinkError {
+    pub object: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.object {
+            Some(object) => write!(f, "object {}: {}", object, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+/* Point the branch at address in code at target. */
+fn relocate(code: &mut [u8], address: i32, target: i32, symbol: &str) -> Result<(), String> {
+    let start = address as usize;
+    let Some(bytes) = code.get_mut(start..start + 4) else {
+        return Err(format!("relocation for {} at {:#06x} is outside the code", symbol, address));
+    };
+
+    let word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    let offset = target - address;
+
+    let instruction = match Instruction::decode(word) {
+        Some(Instruction::Call(_)) => Instruction::Call(offset),
+        Some(Instruction::TailCall(_)) => Instruction::TailCall(offset),
+        Some(Instruction::Goto(_)) => Instruction::Goto(offset),
+        Some(Instruction::Spawn(_)) => Instruction::Spawn(offset),
+        Some(Instruction::Lea(_)) => Instruction::Lea(offset),
+        Some(Instruction::Assert(_)) => Instruction::Assert(offset),
+        Some(Instruction::BinaryIf(condition, _)) => Instruction::BinaryIf(condition, offset),
+        Some(Instruction::UnaryIf(condition, _)) => Instruction::UnaryIf(condition, offset),
+        _ => return Err(format!("relocation for {} at {:#06x} isn't on a branch", symbol, address)),
+    };
+
+    if let Some(Err(why)) = instruction.offset_field().map(|field| field.check(offset as i64)) {
+        return Err(format!("the branch to {} at {:#06x} can't reach it: {}", symbol, address, why));
+    }
+
+    bytes.copy_from_slice(&instruction.encode().to_le_bytes());
+    Ok(())
+}
+
+/* Put objects together into one program. */
+pub fn link(objects: &[Object]) -> Result<Object, LinkError> {
+    let in_object = |object, message| LinkError { object: Some(object), message };
+    let mut linked = Object::default();
+    let mut bases = Vec::new();
+
+    for (i, object) in objects.iter().enumerate() {
+        let base = linked.code.len() as i32;
+        bases.push(base);
+        linked.code.extend_from_slice(&object.code);
+        linked.features |= object.features;
+
+        for (name, &address) in &object.symbols {
+            if linked.symbols.insert(name.clone(), base + address).is_some() {
+                return Err(in_object(i, format!("{} is already defined by an earlier object", name)));
+            }
+        }
+    }
+
+    if linked.code.len() > crate::MEMORY_SIZE {
+        return Err(LinkError {
+            object: None,
+            message: format!("the linked program is {} bytes, more than fits in memory", linked.code.len()),
+        });
+    }
+
+    for (i, object) in objects.iter().enumerate() {
+        for relocation in &object.relocations {
+            let Some(&target) = linked.symbols.get(&relocation.symbol) else {
+                return Err(in_object(i, format!("undefined symbol {}", relocation.symbol)));
+            };
+
+            relocate(&mut linked.code, bases[i] + relocation.address, target, &relocation.symbol)
+                .map_err(|message| in_object(i, message))?;
+        }
+    }
+
+    Ok(linked)
+}
+
+/* A piece of code gc left out: where it was, how big it was and the symbols that were in it. */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Removed {
+    pub address: i32,
+    pub size: i32,
+    pub symbols: Vec<String>,
+}
+
+impl fmt::Display for Removed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04x}  {} bytes", self.address, self.size)?;
+        if !self.symbols.is_empty() {
+            write!(f, "  {}", self.symbols.join(" "))?;
+        }
+        Ok(())
+    }
+}
+
+/* Leave out the functions of a linked program that can't be got to from address 0 or the
+ * exported symbols, moving the symbols that are left to match. */
+pub fn gc(linked: &Object, exports: &[String]) -> Result<(Object, Vec<Removed>), LinkError> {
+    let mut entry_points = vec![0];
+    for name in exports {
+        match linked.symbols.get(name) {
+            Some(&address) => entry_points.push(address),
+            None => return Err(LinkError { object: None, message: format!("can't export {}, it isn't defined", name) }),
+        }
+    }
+
+    let (code, pieces) = optimize::remove_unreachable_functions(&linked.code, &entry_points);
+    let removed: Vec<Removed> = pieces.iter()
+        .map(|piece| Removed {
+            address: piece.start,
+            size: piece.end - piece.start,
+            symbols: linked.symbols.iter()
+                .filter(|&(_, &address)| (piece.start..piece.end).contains(&address))
+                .map(|(name, _)| name.clone())
+                .collect(),
+        })
+        .collect();
+
+    /* Everything after a removed piece moves down by its size. */
+    let symbols = linked.symbols.iter()
+        .filter(|&(_, &address)| !pieces.iter().any(|piece| (piece.start..piece.end).contains(&address)))
+        .map(|(name, &address)| {
+            let before: i32 = pieces.iter().filter(|piece| piece.end <= address).map(|piece| piece.end - piece.start).sum();
+            (name.clone(), address - before)
+        })
+        .collect();
+
+    Ok((Object { code, symbols, relocations: Vec::new(), features: linked.features }, removed))
+}
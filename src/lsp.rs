@@ -0,0 +1,842 @@
+/* vm lsp: a language server for the assembler, speaking the Language Server Protocol over
+ * stdin and stdout so an editor can check and explain a .s file as it's written. It knows:
+ *
+ *     diagnostics, published whenever a file is opened or changed: the error that stops it
+ *     assembling, or once it does, what vm analyze finds wrong with the code
+ *     hover over a mnemonic for what it does and the words its line assembles to, or over a
+ *     label or constant for where it is
+ *     go to the definition of a label, .equ constant or macro
+ *     completion of the mnemonics, directives and aliases, and the names the file defines
+ *
+ * Everything goes through the assembler, with each file assembled as vm asm would assemble it
+ * from where it is, includes and all, but with nothing defined for its .ifs. Only whole files
+ * are synced, and positions count UTF-16 code units, as the protocol has them by default.
+ *
+ * The messages are JSON-RPC, each a Content-Length header and a blank line before the JSON;
+ * the little of JSON the protocol needs is read and written here rather than pulling in a
+ * crate for it. */
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+
+use crate::asm::{assemble_with, parse_line, AsmError, Assembled, IncludePath};
+use crate::cfg::{self, Problem};
+use crate::isa;
+use crate::preprocess::alias;
+use crate::program::Program;
+
+/* JSON-RPC's error codes. */
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+
+/* The protocol's numbers for the kinds of things it shows. */
+const ERROR: i64 = 1;
+const WARNING: i64 = 2;
+const FUNCTION: i64 = 3;
+const VARIABLE: i64 = 6;
+const KEYWORD: i64 = 14;
+const CONSTANT: i64 = 21;
+
+/* Every mnemonic, with its operands and what it does, for hover and completion.
+ * Stack effects are written before -> after, with the top of the stack on the right. */
+const MNEMONICS: [(&str, &str, &str); 103] = [
+    ("exit", "[code]", "Stop the program with an exit code, 0 if there isn't one, up to 0xffffff."),
+    ("halt", "", "Stop the program with exit code 0; the same as exit 0."),
+    ("swap", "[from] [to]", "Swap the words at two byte offsets from the top of the stack, 4 and 0 if they aren't given."),
+    ("nop", "", "Do nothing."),
+    ("input", "[sentinel | flag] [retry]", "Read a line of input and push the number on it. At the end of the input it faults, or pushes a sentinel, or pushes a flag after the number; retry skips lines that aren't numbers."),
+    ("stinput", "[max]", "Read a line of input and push it as a string, cut to max characters."),
+    ("debug", "[bytes]", "Dump the registers and memory, or with bytes, that many bytes from the address popped off the stack."),
+    ("debugb", "[bytes]", "debug, with the bytes in binary."),
+    ("pop", "[bytes]", "Drop bytes from the top of the stack, a word if not given."),
+    ("add", "", "a b -> a+b"),
+    ("sub", "", "a b -> a-b"),
+    ("mul", "", "a b -> a*b"),
+    ("div", "", "a b -> a/b, rounding towards zero. Faults on division by zero."),
+    ("rem", "", "a b -> the remainder of a/b, with the sign of a. Faults on division by zero."),
+    ("and", "", "a b -> a&b"),
+    ("or", "", "a b -> a|b"),
+    ("xor", "", "a b -> a^b"),
+    ("lsl", "", "a b -> a shifted left b bits."),
+    ("lsr", "", "a b -> a shifted right b bits, with zeros shifted in."),
+    ("asr", "", "a b -> a shifted right b bits, with copies of the sign bit shifted in."),
+    ("rol", "", "a b -> a rotated left b bits."),
+    ("ror", "", "a b -> a rotated right b bits."),
+    ("divu", "", "a b -> a/b, unsigned."),
+    ("remu", "", "a b -> the remainder of a/b, unsigned."),
+    ("cmpeq", "", "a b -> 1 if a == b, otherwise 0."),
+    ("cmpne", "", "a b -> 1 if a != b, otherwise 0."),
+    ("cmplt", "", "a b -> 1 if a < b, otherwise 0."),
+    ("cmpgt", "", "a b -> 1 if a > b, otherwise 0."),
+    ("cmple", "", "a b -> 1 if a <= b, otherwise 0."),
+    ("cmpge", "", "a b -> 1 if a >= b, otherwise 0."),
+    ("cmpltu", "", "a b -> 1 if a < b unsigned, otherwise 0."),
+    ("cmpgtu", "", "a b -> 1 if a > b unsigned, otherwise 0."),
+    ("cmpleu", "", "a b -> 1 if a <= b unsigned, otherwise 0."),
+    ("cmpgeu", "", "a b -> 1 if a >= b unsigned, otherwise 0."),
+    ("neg", "", "a -> -a"),
+    ("not", "", "a -> ~a, every bit flipped."),
+    ("stprint", "[offset]", "Print the string at a byte offset from the top of the stack, leaving it there."),
+    ("call", "<target>", "Push the address of the next instruction and go to the target."),
+    ("tailcall", "<target>", "Go to the target without pushing a return address, so its return goes back to this function's caller."),
+    ("return", "[bytes]", "Drop bytes from the top of the stack, then pop the return address and go back to it."),
+    ("goto", "<target>", "Go to the target."),
+    ("jumpi", "", "Pop an address and go to it."),
+    ("ifeq", "<target>", "Go to the target if the word under the top equals the top. Neither is popped."),
+    ("ifne", "<target>", "Go to the target if the word under the top doesn't equal the top. Neither is popped."),
+    ("iflt", "<target>", "Go to the target if the word under the top is less than the top. Neither is popped."),
+    ("ifgt", "<target>", "Go to the target if the word under the top is greater than the top. Neither is popped."),
+    ("ifle", "<target>", "Go to the target if the word under the top is at most the top. Neither is popped."),
+    ("ifge", "<target>", "Go to the target if the word under the top is at least the top. Neither is popped."),
+    ("ifltu", "<target>", "iflt, comparing unsigned."),
+    ("ifgtu", "<target>", "ifgt, comparing unsigned."),
+    ("ifez", "<target>", "Go to the target if the top of the stack is 0, without popping it."),
+    ("ifnz", "<target>", "Go to the target if the top of the stack isn't 0, without popping it."),
+    ("ifmi", "<target>", "Go to the target if the top of the stack is negative, without popping it."),
+    ("ifpl", "<target>", "Go to the target if the top of the stack isn't negative, without popping it."),
+    (">r", "", "Move the top of the stack to the return stack, with the dual_stack feature."),
+    ("r>", "", "Move the top of the return stack to the stack, with the dual_stack feature."),
+    ("strlen", "[offset]", "Push the length of the string at a byte offset from the top of the stack."),
+    ("strcat", "", "Pop two strings and push them joined, the lower one first."),
+    ("strcmp", "", "Pop two strings and push -1, 0 or 1 as the lower one sorts before, the same as or after the top one."),
+    ("readfile", "", "Pop a file name and push the file's contents as a string."),
+    ("writefile", "[bytes]", "Pop a file name, then write the string under it to the file, or with bytes, that many bytes off the stack."),
+    ("arg", "", "Pop an index and push that command-line argument as a string."),
+    ("getenv", "", "Pop a name and push that environment variable as a string."),
+    ("clock", "", "Push the time."),
+    ("cycles", "", "Push how many instructions have run before this one."),
+    ("rand", "", "Push a random word."),
+    ("load", "", "address -> the word at the address."),
+    ("store", "", "word address -> ; write the word to the address."),
+    ("spawn", "<target>", "Start a new context running at the target."),
+    ("yield", "", "Let another context run."),
+    ("join", "", "Wait for the other contexts to finish."),
+    ("cas", "", "old new address -> found; write new to the address if it holds old, and push what it held."),
+    ("fetchadd", "", "delta address -> found; add delta to the word at the address, and push what it held."),
+    ("lock", "", "Take the VM's lock, waiting for another context to give it up."),
+    ("unlock", "", "Give up the VM's lock."),
+    ("stprintn", "", "length address -> ; print length bytes of memory from the address."),
+    ("pushb", "", "Pop a word and push its low byte, taking up one byte of the stack."),
+    ("pushh", "", "Pop a word and push its low 16 bits, taking up two bytes of the stack."),
+    ("popb", "", "Pop a byte and push it as a word, sign extended."),
+    ("popbu", "", "Pop a byte and push it as a word."),
+    ("poph", "", "Pop 16 bits and push them as a word, sign extended."),
+    ("pophu", "", "Pop 16 bits and push them as a word."),
+    ("dup", "[offset]", "Push a copy of the word at a byte offset from the top of the stack, the top if not given."),
+    ("print", "[offset]", "Print the word at a byte offset from the top of the stack in decimal, leaving it there."),
+    ("printh", "[offset]", "print, in hex."),
+    ("printb", "[offset]", "print, in binary."),
+    ("printo", "[offset]", "print, in octal."),
+    ("dump", "", "Print the stack."),
+    ("push", "<value>", "Push a number, which has to fit in 28 bits, signed."),
+    ("jumptable", "<entries>", "Pop a selector and go by that entry of the table of offsets after the instruction, or past the table if there's no such entry. See .table."),
+    ("lea", "<target>", "Push the address of a label, in the code or the data."),
+    ("pick", "<depth>", "Push a copy of the word this many words down, 0 being the top."),
+    ("roll", "<count>", "Bring the deepest of the top count words to the top."),
+    ("drop", "[count]", "Drop count words, one if not given."),
+    ("dup2", "", "Copy the top two words, as two pick 1s."),
+    ("atoi", "", "Replace the string on top with the number it spells and a flag for whether it was one."),
+    ("itoa", "", "Replace the word on top with a string of it in decimal."),
+    ("printf", "<spec> [nonl]", "Print the word on top as a C printf spec without the length says, as in %08x, then a newline unless nonl."),
+    ("brk", "", "Stop for the debugger."),
+    ("assert", "[message]", "Pop a word and fault if it's 0, with the message at the label if there is one."),
+    ("stackdepth", "", "Push how many more bytes the stack can take."),
+    ("perfctr", "<counter>", "Push one of the VM's counters: instructions, branches, stack_max or io_bytes."),
+    ("stpush", "\"<text>\"", "Push a string, in pushes of three characters, or length-prefixed with byte_strings."),
+];
+
+/* The directives that aren't instructions, which hover and completion know of too. */
+const DIRECTIVES: [(&str, &str, &str); 16] = [
+    (".word", "<value>", "Put a raw word in the code, or a word of memory in the data."),
+    (".table", "<target>...", "A word for each target, the byte offset from the word to the target, for a jumptable."),
+    (".feature", "<name>", "Set a feature in the header: words64, heap, debug_info, dual_stack, byte_strings, readonly_data or little_endian."),
+    (".entry", "<target>", "Where the program starts, if not at 0."),
+    (".sp", "<address>", "Where the stack pointer starts, if not at the top of memory."),
+    (".heap", "<bytes>", "Ask for a heap of this many bytes after the program."),
+    (".data", "", "Put everything after this in the data region, after the code."),
+    (".byte", "<value>...", "A byte for each value, in the data."),
+    (".string", "\"<text>\"", "The bytes of the text and a 0 after them, in the data."),
+    (".macro", "<name> [params]...", "Start a macro, up to .endm; its parameters are written \\name in it."),
+    (".endm", "", "End a macro."),
+    (".include", "\"<file>\"", "Put in the lines of another file, from beside this one, the include path or the standard library."),
+    (".if", "<condition>", "Keep the lines up to .else or .endif only if the condition, over the names defined for the assembly, isn't 0."),
+    (".else", "", "Keep the lines up to .endif only if the .if's condition was 0."),
+    (".endif", "", "End an .if."),
+    (".equ", "<name>, <value>", "Name a constant, which can be an expression of labels and other constants."),
+];
+
+/* The aliases with names of their own, rather than if. and cmp. in front of a condition. */
+const ALIASES: [&str; 4] = ["jmp", "ret", "jz", "jnz"];
+
+/* A JSON value, with an object's members kept in the order they came. */
+#[derive(Debug, Clone, PartialEq)]
+enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn parse(text: &str) -> Result<Json, String> {
+        let mut parser = JsonParser { bytes: text.as_bytes(), at: 0 };
+        let value = parser.value()?;
+        parser.space();
+        if parser.at < parser.bytes.len() {
+            return Err(String::from("something after the JSON"));
+        }
+        Ok(value)
+    }
+
+    fn object(members: Vec<(&str, Json)>) -> Json {
+        Json::Object(members.into_iter().map(|(name, value)| (String::from(name), value)).collect())
+    }
+
+    fn get(&self, name: &str) -> Option<&Json> {
+        match self {
+            Json::Object(members) => members.iter().find(|(member, _)| member == name).map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    /* A member of a member of ... */
+    fn path(&self, names: &[&str]) -> Option<&Json> {
+        names.iter().try_fold(self, |value, name| value.get(name))
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(text) => Some(text),
+            _ => None,
+        }
+    }
+
+    fn as_usize(&self) -> Option<usize> {
+        match *self {
+            Json::Number(n) if n >= 0.0 && n.fract() == 0.0 => Some(n as usize),
+            _ => None,
+        }
+    }
+}
+
+impl From<&str> for Json {
+    fn from(text: &str) -> Json {
+        Json::String(String::from(text))
+    }
+}
+
+impl From<String> for Json {
+    fn from(text: String) -> Json {
+        Json::String(text)
+    }
+}
+
+impl From<i64> for Json {
+    fn from(n: i64) -> Json {
+        Json::Number(n as f64)
+    }
+}
+
+impl From<usize> for Json {
+    fn from(n: usize) -> Json {
+        Json::Number(n as f64)
+    }
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Number(n) if n.fract() == 0.0 && n.abs() < 1e15 => write!(f, "{}", *n as i64),
+            Json::Number(n) => write!(f, "{}", n),
+            Json::String(text) => {
+                write!(f, "\"")?;
+                for c in text.chars() {
+                    match c {
+                        '"' => write!(f, "\\\"")?,
+                        '\\' => write!(f, "\\\\")?,
+                        '\n' => write!(f, "\\n")?,
+                        '\r' => write!(f, "\\r")?,
+                        '\t' => write!(f, "\\t")?,
+                        c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+                        c => write!(f, "{}", c)?,
+                    }
+                }
+                write!(f, "\"")
+            },
+            Json::Array(values) => {
+                write!(f, "[")?;
+                for (i, value) in values.iter().enumerate() {
+                    write!(f, "{}{}", if i == 0 { "" } else { "," }, value)?;
+                }
+                write!(f, "]")
+            },
+            Json::Object(members) => {
+                write!(f, "{{")?;
+                for (i, (name, value)) in members.iter().enumerate() {
+                    write!(f, "{}{}:{}", if i == 0 { "" } else { "," }, Json::from(name.as_str()), value)?;
+                }
+                write!(f, "}}")
+            },
+        }
+    }
+}
+
+struct JsonParser<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl JsonParser<'_> {
+    fn space(&mut self) {
+        while self.bytes.get(self.at).is_some_and(u8::is_ascii_whitespace) {
+            self.at += 1;
+        }
+    }
+
+    fn next(&mut self) -> Result<u8, String> {
+        let byte = *self.bytes.get(self.at).ok_or("the JSON stops too soon")?;
+        self.at += 1;
+        Ok(byte)
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), String> {
+        self.space();
+        match self.next()? {
+            found if found == byte => Ok(()),
+            found => Err(format!("expected {} in the JSON, not {}", byte as char, found as char)),
+        }
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.space();
+        match self.bytes.get(self.at).copied().ok_or("the JSON stops too soon")? {
+            b'{' => {
+                self.at += 1;
+                let mut members = Vec::new();
+                self.space();
+                if self.bytes.get(self.at) == Some(&b'}') {
+                    self.at += 1;
+                    return Ok(Json::Object(members));
+                }
+                loop {
+                    self.expect(b'"')?;
+                    let name = self.string()?;
+                    self.expect(b':')?;
+                    members.push((name, self.value()?));
+                    self.space();
+                    match self.next()? {
+                        b',' => continue,
+                        b'}' => return Ok(Json::Object(members)),
+                        found => return Err(format!("expected , or }} in the JSON, not {}", found as char)),
+                    }
+                }
+            },
+            b'[' => {
+                self.at += 1;
+                let mut values = Vec::new();
+                self.space();
+                if self.bytes.get(self.at) == Some(&b']') {
+                    self.at += 1;
+                    return Ok(Json::Array(values));
+                }
+                loop {
+                    values.push(self.value()?);
+                    self.space();
+                    match self.next()? {
+                        b',' => continue,
+                        b']' => return Ok(Json::Array(values)),
+                        found => return Err(format!("expected , or ] in the JSON, not {}", found as char)),
+                    }
+                }
+            },
+            b'"' => {
+                self.at += 1;
+                self.string().map(Json::String)
+            },
+            b't' => self.literal("true", Json::Bool(true)),
+            b'f' => self.literal("false", Json::Bool(false)),
+            b'n' => self.literal("null", Json::Null),
+            _ => {
+                let start = self.at;
+                while self.bytes.get(self.at).is_some_and(|byte| b"+-.eE0123456789".contains(byte)) {
+                    self.at += 1;
+                }
+                let text = std::str::from_utf8(&self.bytes[start..self.at]).unwrap_or_default();
+                text.parse().map(Json::Number).map_err(|_| format!("bad number in the JSON: {:?}", text))
+            },
+        }
+    }
+
+    fn literal(&mut self, word: &str, value: Json) -> Result<Json, String> {
+        if !self.bytes[self.at..].starts_with(word.as_bytes()) {
+            return Err(String::from("bad word in the JSON"));
+        }
+        self.at += word.len();
+        Ok(value)
+    }
+
+    /* The rest of a string, its opening quote already read. */
+    fn string(&mut self) -> Result<String, String> {
+        let mut bytes = Vec::new();
+        loop {
+            match self.next()? {
+                b'"' => break,
+                b'\\' => {
+                    let c = match self.next()? {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => {
+                            let high = self.hex()?;
+                            let code = if (0xD800..0xDC00).contains(&high) && self.bytes[self.at..].starts_with(b"\\u") {
+                                self.at += 2;
+                                let low = self.hex()?;
+                                0x10000 + ((high - 0xD800) << 10) + low.wrapping_sub(0xDC00)
+                            } else {
+                                high
+                            };
+                            char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER)
+                        },
+                        found => return Err(format!("bad escape \\{} in the JSON", found as char)),
+                    };
+                    bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                },
+                byte => bytes.push(byte),
+            }
+        }
+        String::from_utf8(bytes).map_err(|_| String::from("a string in the JSON isn't UTF-8"))
+    }
+
+    fn hex(&mut self) -> Result<u32, String> {
+        let digits = self.bytes.get(self.at..self.at + 4).ok_or("the JSON stops too soon")?;
+        self.at += 4;
+        std::str::from_utf8(digits).ok()
+            .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+            .ok_or_else(|| String::from("bad \\u escape in the JSON"))
+    }
+}
+
+/* The next message's JSON, or None when the client has gone. */
+fn read_message(input: &mut dyn BufRead) -> io::Result<Option<String>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+
+    let length = length.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "a message with no Content-Length"))?;
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    Ok(Some(String::from_utf8_lossy(&body).into_owned()))
+}
+
+fn write_message(output: &mut dyn Write, message: &Json) -> io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+fn response(id: Json, result: Json) -> Json {
+    Json::object(vec![("jsonrpc", Json::from("2.0")), ("id", id), ("result", result)])
+}
+
+fn error_response(id: Json, code: i64, message: String) -> Json {
+    let error = Json::object(vec![("code", Json::from(code)), ("message", Json::from(message))]);
+    Json::object(vec![("jsonrpc", Json::from("2.0")), ("id", id), ("error", error)])
+}
+
+fn notification(method: &str, params: Json) -> Json {
+    Json::object(vec![("jsonrpc", Json::from("2.0")), ("method", Json::from(method)), ("params", params)])
+}
+
+/* Answer an editor on input and output until it says to exit, giving the exit code the protocol
+ * asks for: 0 if it shut the server down first, 1 if not or if it went away. */
+pub fn serve(input: &mut dyn BufRead, output: &mut dyn Write) -> io::Result<i32> {
+    let mut server = Server { documents: BTreeMap::new(), shut_down: false, exiting: false };
+
+    while let Some(body) = read_message(input)? {
+        let replies = match Json::parse(&body) {
+            Ok(message) => server.handle(&message),
+            Err(err) => vec![error_response(Json::Null, PARSE_ERROR, err)],
+        };
+        for reply in &replies {
+            write_message(output, reply)?;
+        }
+        if server.exiting {
+            return Ok(if server.shut_down { 0 } else { 1 });
+        }
+    }
+    Ok(1)
+}
+
+struct Server {
+    /* The text of each open file, by its URI. */
+    documents: BTreeMap<String, String>,
+    shut_down: bool,
+    exiting: bool,
+}
+
+/* What a file defines that can be gone to: its labels, .equ constants and macros. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Definition {
+    Label,
+    Constant,
+    Macro,
+}
+
+impl Server {
+    /* The replies to a message, and any notifications it sets off. */
+    fn handle(&mut self, message: &Json) -> Vec<Json> {
+        let Some(method) = message.get("method").and_then(Json::as_str) else {
+            /* A response to something the server asked, which it never does. */
+            return Vec::new();
+        };
+        let params = message.get("params").unwrap_or(&Json::Null);
+        let uri = params.path(&["textDocument", "uri"]).and_then(Json::as_str).map(String::from);
+
+        let Some(id) = message.get("id").cloned() else {
+            match (method, uri) {
+                ("textDocument/didOpen", Some(uri)) => {
+                    let text = params.path(&["textDocument", "text"]).and_then(Json::as_str).unwrap_or_default();
+                    self.documents.insert(uri.clone(), String::from(text));
+                    return Vec::from([self.diagnostics(&uri)]);
+                },
+                ("textDocument/didChange", Some(uri)) => {
+                    let changes = match params.get("contentChanges") {
+                        Some(Json::Array(changes)) => changes.as_slice(),
+                        _ => &[],
+                    };
+                    if let Some(text) = changes.last().and_then(|change| change.get("text")).and_then(Json::as_str) {
+                        self.documents.insert(uri.clone(), String::from(text));
+                    }
+                    return Vec::from([self.diagnostics(&uri)]);
+                },
+                ("textDocument/didClose", Some(uri)) => {
+                    self.documents.remove(&uri);
+                    let params = Json::object(vec![("uri", Json::from(uri)), ("diagnostics", Json::Array(Vec::new()))]);
+                    return Vec::from([notification("textDocument/publishDiagnostics", params)]);
+                },
+                ("exit", _) => self.exiting = true,
+                _ => (),
+            }
+            return Vec::new();
+        };
+
+        if self.shut_down {
+            return Vec::from([error_response(id, INVALID_REQUEST, String::from("the server has been shut down"))]);
+        }
+
+        let position = params.get("position").and_then(|position| {
+            Some((position.get("line")?.as_usize()?, position.get("character")?.as_usize()?))
+        });
+        let document = uri.as_ref().and_then(|uri| Some((uri.as_str(), self.documents.get(uri)?.as_str())));
+
+        let result = match (method, document, position) {
+            ("initialize", _, _) => {
+                let capabilities = Json::object(vec![
+                    ("textDocumentSync", Json::from(1i64)),
+                    ("hoverProvider", Json::Bool(true)),
+                    ("definitionProvider", Json::Bool(true)),
+                    ("completionProvider", Json::object(Vec::new())),
+                ]);
+                let info = Json::object(vec![("name", Json::from("vm")), ("version", Json::from(env!("CARGO_PKG_VERSION")))]);
+                Json::object(vec![("capabilities", capabilities), ("serverInfo", info)])
+            },
+            ("shutdown", _, _) => {
+                self.shut_down = true;
+                Json::Null
+            },
+            ("textDocument/hover", Some((uri, text)), Some((line, character))) => {
+                hover(uri, text, line, character).map_or(Json::Null, |markdown| {
+                    let contents = Json::object(vec![("kind", Json::from("markdown")), ("value", Json::from(markdown))]);
+                    Json::object(vec![("contents", contents)])
+                })
+            },
+            ("textDocument/definition", Some((uri, text)), Some((line, character))) => {
+                let target = text.lines().nth(line).and_then(|source| word_at(source, character));
+                definitions(text).into_iter()
+                    .find(|(name, _, _, _)| Some(*name) == target)
+                    .map_or(Json::Null, |(name, _, line, column)| {
+                        let source = text.lines().nth(line).unwrap_or_default();
+                        let start = utf16_length(&source[..column]);
+                        let range = range((line, start), (line, start + utf16_length(name)));
+                        Json::object(vec![("uri", Json::from(uri)), ("range", range)])
+                    })
+            },
+            ("textDocument/completion", Some((_, text)), _) => Json::Array(completions(text)),
+            ("textDocument/hover" | "textDocument/definition" | "textDocument/completion", _, _) => Json::Null,
+            _ => return Vec::from([error_response(id, METHOD_NOT_FOUND, format!("no method {}", method))]),
+        };
+        Vec::from([response(id, result)])
+    }
+
+    /* The notification of what's wrong with an open file. */
+    fn diagnostics(&self, uri: &str) -> Json {
+        let text = self.documents.get(uri).map_or("", String::as_str);
+        let diagnostics = match assemble(uri, text) {
+            Err(err) => Vec::from([error_diagnostic(text, &err)]),
+            Ok(assembled) => problems(text, &assembled),
+        };
+
+        let params = Json::object(vec![("uri", Json::from(uri)), ("diagnostics", Json::Array(diagnostics))]);
+        notification("textDocument/publishDiagnostics", params)
+    }
+}
+
+/* The path of a file: URI, with any %-escapes undone. */
+fn path(uri: &str) -> String {
+    let encoded = uri.strip_prefix("file://").unwrap_or(uri).as_bytes();
+    let mut bytes = Vec::new();
+    let mut i = 0;
+    while i < encoded.len() {
+        let escaped = (encoded[i] == b'%').then(|| encoded.get(i + 1..i + 3)).flatten()
+            .and_then(|digits| u8::from_str_radix(std::str::from_utf8(digits).ok()?, 16).ok());
+        match escaped {
+            Some(byte) => {
+                bytes.push(byte);
+                i += 3;
+            },
+            None => {
+                bytes.push(encoded[i]);
+                i += 1;
+            },
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+fn assemble(uri: &str, text: &str) -> Result<Assembled, AsmError> {
+    assemble_with(text, &path(uri), &mut IncludePath::default(), &BTreeMap::new())
+}
+
+fn utf16_length(text: &str) -> usize {
+    text.chars().map(char::len_utf16).sum()
+}
+
+/* The byte a UTF-16 position falls on in a line, or the end of it. */
+fn byte_index(line: &str, character: usize) -> usize {
+    let mut units = 0;
+    for (i, c) in line.char_indices() {
+        if units >= character {
+            return i;
+        }
+        units += c.len_utf16();
+    }
+    line.len()
+}
+
+fn range(start: (usize, usize), end: (usize, usize)) -> Json {
+    let position = |(line, character): (usize, usize)| Json::object(vec![("line", Json::from(line)), ("character", Json::from(character))]);
+    Json::object(vec![("start", position(start)), ("end", position(end))])
+}
+
+/* A range over whole lines, from first to last, counting from 0. */
+fn lines_range(text: &str, first: usize, last: usize) -> Json {
+    let end = text.lines().nth(last).map_or(0, utf16_length);
+    range((first, 0), (last, end))
+}
+
+fn diagnostic(range: Json, severity: i64, message: String) -> Json {
+    Json::object(vec![("range", range), ("severity", Json::from(severity)), ("source", Json::from("vm")), ("message", Json::from(message))])
+}
+
+/* Where an error stops a file assembling. One in a file it includes goes on the .include. */
+fn error_diagnostic(text: &str, err: &AsmError) -> Json {
+    let line = match &err.file {
+        None => err.line.saturating_sub(1),
+        Some(file) => instructions(text)
+            .find(|(_, directive, operand)| *directive == ".include" && file.ends_with(operand.trim_matches('"')))
+            .map_or(0, |(line, _, _)| line),
+    };
+    let message = if err.file.is_some() { err.to_string() } else { err.message.clone() };
+    diagnostic(lines_range(text, line, line), ERROR, message)
+}
+
+/* The lines of a file with the mnemonic and first operand of each, numbered from 0. */
+fn instructions(text: &str) -> impl Iterator<Item = (usize, &str, &str)> {
+    text.lines().enumerate().filter_map(|(i, source)| match parse_line(i + 1, source) {
+        Ok((_, Some(line))) => Some((i, line.mnemonic, line.operands.first().copied().unwrap_or_default())),
+        _ => None,
+    })
+}
+
+/* What vm analyze would say about the code from this file, rather than the ones it includes. */
+fn problems(text: &str, assembled: &Assembled) -> Vec<Json> {
+    let Ok(program) = Program::from_image(&assembled.image()) else {
+        return Vec::new();
+    };
+    let line = |address: i32| match assembled.lines.get(address as usize / 4) {
+        Some(&(0, line)) if address >= 0 && line > 0 => Some(line - 1),
+        _ => None,
+    };
+    let name = |address: i32| {
+        let label = assembled.labels.iter().find(|(_, &label)| label == address);
+        label.map_or_else(|| format!("{:#06x}", address), |(name, _)| name.clone())
+    };
+
+    cfg::build(&program).problems().into_iter().filter_map(|problem| match problem {
+        Problem::Unreachable { start, end } => {
+            let (first, last) = (line(start)?, line(end - 4).unwrap_or(line(start)?));
+            Some(diagnostic(lines_range(text, first, last), WARNING, String::from("nothing can reach this code")))
+        },
+        Problem::NoReturn { call_site, target } => {
+            let line = line(call_site)?;
+            Some(diagnostic(lines_range(text, line, line), WARNING, format!("{} never returns", name(target))))
+        },
+        Problem::BadTarget { from, to } => {
+            let line = line(from)?;
+            Some(diagnostic(lines_range(text, line, line), ERROR, format!("this goes to {:#x}, which isn't an instruction in the code", to)))
+        },
+    }).collect()
+}
+
+/* The name a position is on: a mnemonic, label or constant. */
+fn word_at(line: &str, character: usize) -> Option<&str> {
+    let at = byte_index(line, character);
+    let is_name = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '.';
+    let start = line[..at].char_indices().rev().find(|&(_, c)| !is_name(c)).map_or(0, |(i, c)| i + c.len_utf8());
+    let end = line[at..].find(|c: char| !is_name(c)).map_or(line.len(), |i| at + i);
+    (start < end).then(|| &line[start..end])
+}
+
+/* Every label, constant and macro a file defines, with the line it's on and where on it, from 0. */
+fn definitions(text: &str) -> Vec<(&str, Definition, usize, usize)> {
+    let mut found = Vec::new();
+    for (i, source) in text.lines().enumerate() {
+        let Ok((label, line)) = parse_line(i + 1, source) else {
+            continue;
+        };
+        if let Some(label) = label {
+            found.push((label, Definition::Label, i));
+        }
+        match line.as_ref().map(|line| (line.mnemonic, line.operands.first())) {
+            Some((".equ", Some(&name))) => found.push((name, Definition::Constant, i)),
+            Some((".macro", Some(&name))) => found.push((name, Definition::Macro, i)),
+            _ => (),
+        }
+    }
+
+    found.into_iter().map(|(name, kind, i)| {
+        let source = text.lines().nth(i).unwrap_or_default();
+        let column = source.match_indices(name).map(|(column, _)| column)
+            .find(|&column| word_at(source, utf16_length(&source[..column])) == Some(name))
+            .unwrap_or(0);
+        (name, kind, i, column)
+    }).collect()
+}
+
+/* What to say about the name at a position, in Markdown. */
+fn hover(uri: &str, text: &str, line: usize, character: usize) -> Option<String> {
+    let source = text.lines().nth(line)?;
+    let word = word_at(source, character)?;
+    let assembled = assemble(uri, text).ok();
+
+    let mnemonic = match parse_line(line + 1, source) {
+        Ok((_, Some(parsed))) if parsed.mnemonic == word => Some(word),
+        _ => None,
+    };
+    if let Some(mnemonic) = mnemonic {
+        /* The words the line became, which for a macro is everything it expands to. */
+        let words: Vec<String> = assembled.iter().flat_map(|assembled| {
+            assembled.code.chunks_exact(4).zip(&assembled.lines).enumerate()
+                .filter(|&(_, (_, &at))| at == (0, line + 1))
+                .map(|(i, (bytes, _))| {
+                    let word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+                    format!("{:04x}  {:08x}  {}", i * 4, word, isa::disassemble(word))
+                })
+        }).collect();
+
+        /* A macro from another file has nothing to say of it but what it becomes. */
+        let markdown = match (describe(mnemonic, text), words.is_empty()) {
+            (None, true) => return None,
+            (None, false) => format!("**{}**", mnemonic),
+            (Some(markdown), _) => markdown,
+        };
+        if words.is_empty() {
+            return Some(markdown);
+        }
+        return Some(format!("{}\n\n```\n{}\n```", markdown, words.join("\n")));
+    }
+
+    let (_, kind, defined, _) = definitions(text).into_iter().find(|&(name, _, _, _)| name == word)?;
+    let definition = text.lines().nth(defined).unwrap_or_default().trim();
+    Some(match kind {
+        Definition::Label => match assembled.as_ref().and_then(|assembled| assembled.labels.get(word)) {
+            Some(address) => format!("label **{}**, at {:#06x}, on line {}", word, address, defined + 1),
+            None => format!("label **{}**, on line {}", word, defined + 1),
+        },
+        Definition::Constant | Definition::Macro => format!("```\n{}\n```\non line {}", definition, defined + 1),
+    })
+}
+
+/* What a mnemonic, directive, alias or macro is, in Markdown. */
+fn describe(mnemonic: &str, text: &str) -> Option<String> {
+    let real = alias(mnemonic);
+    let name = real.as_deref().unwrap_or(mnemonic);
+
+    let documented = MNEMONICS.iter().chain(DIRECTIVES.iter()).find(|(documented, _, _)| *documented == name);
+    let mut markdown = match documented {
+        Some((name, operands, summary)) => format!("**{}** {}\n\n{}", name, operands, summary),
+        None => {
+            let (_, _, defined, _) = definitions(text).into_iter()
+                .find(|&(defined, kind, _, _)| defined == name && kind == Definition::Macro)?;
+            format!("macro, defined on line {}:\n\n```\n{}\n```", defined + 1, text.lines().nth(defined).unwrap_or_default().trim())
+        },
+    };
+    if real.is_some() {
+        markdown = format!("{} is another name for {}.\n\n{}", mnemonic, name, markdown);
+    }
+    Some(markdown)
+}
+
+/* Everything that could be typed: every mnemonic, directive and alias, and the names the file
+ * defines. */
+fn completions(text: &str) -> Vec<Json> {
+    let item = |label: &str, kind: i64, detail: String, documentation: &str| {
+        Json::object(vec![
+            ("label", Json::from(label)),
+            ("kind", Json::from(kind)),
+            ("detail", Json::from(detail)),
+            ("documentation", Json::from(documentation)),
+        ])
+    };
+
+    let mut items: Vec<Json> = MNEMONICS.iter().chain(DIRECTIVES.iter())
+        .map(|(name, operands, summary)| item(name, KEYWORD, format!("{} {}", name, operands).trim_end().to_string(), summary))
+        .collect();
+    items.extend(ALIASES.iter().filter_map(|&name| {
+        let real = alias(name)?;
+        Some(item(name, KEYWORD, format!("the same as {}", real), ""))
+    }));
+    items.extend(definitions(text).into_iter().map(|(name, kind, line, _)| {
+        let (kind, what) = match kind {
+            Definition::Label => (VARIABLE, "label"),
+            Definition::Constant => (CONSTANT, "constant"),
+            Definition::Macro => (FUNCTION, "macro"),
+        };
+        item(name, kind, format!("{} on line {}", what, line + 1), "")
+    }));
+    items
+}
@@ -1,22 +1,1375 @@
-use std::env;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 use std::process;
-use vm::VirtualMachine;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
+use clap::{Args, CommandFactory, Parser, Subcommand};
+use vm::asm::{assemble_object_with, assemble_with, parse_number, IncludePath};
+use vm::analysis;
+use vm::cfg::{self, Problem};
+use vm::debugger::Debugger;
+use vm::disasm;
+use vm::device::{Console, Timer};
+use vm::harness;
+use vm::interrupt;
+use vm::isa;
+use vm::lang;
+use vm::linker::{self, Object};
+use vm::reference::{self, DiffOptions, DiffOutcome};
+use vm::selftest;
+use vm::{DebugMode, Header, Ops, Program, StepResult, VirtualMachine, VmConfig, VmError};
 
-    let mut vm = VirtualMachine::build(&args).unwrap_or_else(|err| {
-        eprintln!("{}", err);
-        process::exit(1);
-    });
+/* The command line. A program on its own, as in `vm prog.v`, is short for `vm run prog.v`. */
+#[derive(Parser)]
+#[command(name = "vm", version, about = "Run, debug and build programs for the VM.")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    #[command(about = "Run a program")]
+    Run(RunArgs),
+    #[command(about = "Run a directory of programs against their golden files")]
+    Batch(BatchArgs),
+    #[command(about = "Run a program, then check expressions over where it finished")]
+    Assert(AssertArgs),
+    #[command(about = "Step through a program interactively")]
+    Debug(DebugArgs),
+    #[command(about = "Step through a program in the full-screen monitor")]
+    Tui(TuiArgs),
+    #[command(about = "Run a program on the VM and the reference interpreter side by side")]
+    Difftest(DifftestArgs),
+    #[command(about = "Assemble a program")]
+    Asm(AsmArgs),
+    #[command(about = "Put assembly sources in the standard layout")]
+    Fmt(FmtArgs),
+    #[command(about = "List a program's header and code")]
+    Disasm(DisasmArgs),
+    #[command(about = "Check a program's listing assembles back to the same program")]
+    Roundtrip(RoundtripArgs),
+    #[command(about = "Show the bytes of a program, marked with which part of the file each is")]
+    Hexdump(HexdumpArgs),
+    #[command(about = "Look for unreachable code, calls that never return and bad branches")]
+    Analyze(AnalyzeArgs),
+    #[command(about = "Say which source file and line the code at an address came from")]
+    Addr2line(Addr2lineArgs),
+    #[command(about = "Run a language server for editing .s files, on stdin and stdout")]
+    Lsp,
+    #[command(about = "Link object files into a program")]
+    Link(LinkArgs),
+    #[command(about = "Compile a program in the little language")]
+    Compile(CompileArgs),
+    #[command(about = "Check the VM against the instruction set and the example programs")]
+    Selftest(SelftestArgs),
+    #[command(about = "Throw random programs at the VM until one panics it")]
+    Fuzz(FuzzArgs),
+}
+
+/* How the machine is built, for every command that runs a program. */
+#[derive(Args)]
+struct MachineArgs {
+    #[arg(long, value_name = "N", help = "Stop the program after this many instructions")]
+    fuel: Option<u64>,
+    #[arg(long, value_name = "TIME", value_parser = parse_duration, help = "Stop the program after this long, as in 5s or 500ms")]
+    timeout: Option<Duration>,
+    #[arg(long, value_name = "BYTES", help = "Give the program a heap this big, between it and the stack")]
+    heap: Option<usize>,
+    #[arg(long, value_name = "BYTES", help = "Give the stack this much of the top of memory, and no more")]
+    stack: Option<usize>,
+    #[arg(long, value_name = "MODE", value_parser = parse_debug_mode, help = "Where the debug instruction's dumps go: enabled, silent or stderr")]
+    debug_instruction: Option<DebugMode>,
+}
+
+impl MachineArgs {
+    fn config(&self) -> VmConfig {
+        VmConfig {
+            fuel: self.fuel,
+            timeout: self.timeout,
+            heap_size: self.heap,
+            stack_size: self.stack,
+            debug_instruction: self.debug_instruction.unwrap_or_default(),
+            ..VmConfig::default()
+        }
+    }
+}
+
+/* Where a program's input comes from and its output goes, and whether to show every step it
+ * takes. */
+#[derive(Args)]
+struct IoArgs {
+    #[arg(long, value_name = "FILE", help = "Read the program's input from a file rather than stdin")]
+    input: Option<PathBuf>,
+    #[arg(long, value_name = "FILE", help = "Write the program's output to a file rather than stdout")]
+    output: Option<PathBuf>,
+    #[arg(long, help = "Show each instruction on stderr as it runs, with the pc and sp")]
+    trace: bool,
+}
+
+impl IoArgs {
+    /* Hook the machine up to the files asked for. */
+    fn redirect(&self, vm: &mut VirtualMachine) -> Result<(), String> {
+        if let Some(path) = &self.input {
+            let file = File::open(path).map_err(|e| format!("Couldn't read {}: {}", path.display(), e))?;
+            vm.set_input(Box::new(BufReader::new(file)));
+        }
+        if let Some(path) = &self.output {
+            let file = File::create(path).map_err(|e| format!("Couldn't write {}: {}", path.display(), e))?;
+            vm.set_output(Box::new(BufWriter::new(file)));
+        }
+        Ok(())
+    }
 
-    let vm_result = vm.run();
+    /* Run the program, a step at a time with --trace. */
+    fn run(&self, vm: &mut VirtualMachine) -> Result<i32, VmError> {
+        if !self.trace {
+            return vm.run();
+        }
+        loop {
+            let (pc, sp) = (vm.program_counter(), vm.stack_pointer());
+            if let Ok(word) = vm.memory().read_u32(pc) {
+                eprintln!("{:04x}: {:08x}  {:<24} sp {:04x}", pc, word, isa::disassemble(word), sp);
+            }
+            match vm.step()? {
+                StepResult::Running | StepResult::Breakpoint => (),
+                StepResult::Exited(exit_code) => return Ok(exit_code),
+            }
+        }
+    }
+}
+
+#[derive(Args)]
+struct RunArgs {
+    #[arg(value_name = "FILE", required_unless_present = "hex", help = "A .v file, or - for stdin")]
+    program: Option<String>,
+    #[arg(long, value_name = "WORDS", conflicts_with = "program", help = "Run these hex words instead of a file")]
+    hex: Option<String>,
+    #[command(flatten)]
+    machine: MachineArgs,
+    #[command(flatten)]
+    io: IoArgs,
+    #[arg(long, help = "Print a JSON report of the run on stdout")]
+    json: bool,
+    #[arg(long, help = "Show where the program spent its time")]
+    profile: bool,
+    #[arg(long, value_name = "N", help = "Seed for rand")]
+    seed: Option<u64>,
+    #[arg(long, value_name = "N", help = "Shuffle the program's functions with this seed as it's loaded")]
+    layout_seed: Option<u64>,
+    #[arg(long, help = "Map the console and timer devices")]
+    devices: bool,
+    #[arg(long, help = "Run the program as threaded code")]
+    threaded: bool,
+    #[arg(long, help = "Let the program write over its own code")]
+    writable_code: bool,
+    #[arg(long, help = "Show where the machine was if the program faults")]
+    dump_on_error: bool,
+    #[arg(long, value_name = "VALUE", help = "An argument for the program, which it can also open as a file")]
+    arg: Vec<String>,
+    #[arg(long, value_name = "NAME", help = "An environment variable the program may read")]
+    env: Vec<String>,
+    #[arg(long, value_name = "GROUPS", value_parser = parse_ops, help = "Keep the program from running io, debug, files or host instructions")]
+    deny: Vec<Ops>,
+    #[arg(long, help = "Print a hash of every step the program took")]
+    state_hash: bool,
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["input", "profile"], help = "Run the program once with this file as its input; give it more than once for more runs")]
+    stdin_file: Vec<PathBuf>,
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..), conflicts_with = "profile", help = "Run the program this many times, or this many times for each --stdin-file")]
+    repeat: Option<u64>,
+    #[arg(last = true, value_name = "ARGS", help = "More arguments for the program, flags and all")]
+    program_args: Vec<String>,
+}
+
+#[derive(Args)]
+struct BatchArgs {
+    dir: PathBuf,
+    #[arg(long, value_name = "FILE", help = "An expectations.toml with exit codes and stdin for the programs")]
+    expect: Option<PathBuf>,
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..), help = "How many to run at once; one per core by default")]
+    jobs: Option<u64>,
+    #[arg(long, help = "Write the golden .out files instead of checking them")]
+    bless: bool,
+    #[arg(long, value_name = "N", conflicts_with = "bless", help = "Shuffle each program's functions with this seed as it's loaded")]
+    layout_seed: Option<u64>,
+    #[command(flatten)]
+    machine: MachineArgs,
+}
+
+#[derive(Args)]
+struct AssertArgs {
+    program: String,
+    #[arg(long, value_name = "EXPRESSION", required = true, help = "Something that should hold once the program is done")]
+    after_run: Vec<String>,
+    #[command(flatten)]
+    machine: MachineArgs,
+    #[command(flatten)]
+    io: IoArgs,
+}
+
+#[derive(Args)]
+struct DebugArgs {
+    #[arg(value_name = "FILE", help = "A .v file, or a .s file to debug as source")]
+    program: String,
+    #[arg(long, value_name = "FILE", help = "Run the debugger commands in a file instead of asking for them")]
+    script: Option<PathBuf>,
+    #[arg(long, value_name = "FILE", help = "The program's input, instead of sharing stdin with the prompt")]
+    input: Option<PathBuf>,
+    #[command(flatten)]
+    machine: MachineArgs,
+}
+
+#[derive(Args)]
+struct TuiArgs {
+    #[arg(value_name = "FILE", help = "A .v file, or a .s file to show with its labels")]
+    program: String,
+    #[arg(long, value_name = "FILE", help = "The program's input")]
+    input: Option<PathBuf>,
+    #[command(flatten)]
+    machine: MachineArgs,
+}
+
+#[derive(Args)]
+struct DifftestArgs {
+    program: String,
+    #[arg(long, value_name = "FILE", help = "The program's input")]
+    input: Option<PathBuf>,
+    #[arg(long, value_name = "N", default_value_t = 0, help = "Seed for rand")]
+    seed: u64,
+    #[arg(long, help = "Run the VM side as threaded code")]
+    threaded: bool,
+    #[arg(long, value_name = "N", default_value_t = 10_000_000, help = "Give up after this many instructions")]
+    steps: u64,
+}
+
+#[derive(Args)]
+struct AsmArgs {
+    source: PathBuf,
+    #[arg(short, value_name = "FILE", help = "Where to write it; next to the source by default")]
+    output: Option<PathBuf>,
+    #[arg(short = 'c', help = "Make an object file for vm link")]
+    object: bool,
+    #[arg(short = 'g', help = "Put the labels and line numbers in the file")]
+    debug_info: bool,
+    #[arg(long = "opt", help = "Run the peephole optimizer over it")]
+    optimize: bool,
+    #[arg(long, value_name = "N", requires = "optimize", help = "With --opt, paste functions of up to N instructions in place of the calls to them first")]
+    inline: Option<usize>,
+    #[arg(long, requires = "optimize", help = "With --opt, turn self-recursive calls in tail position into tailcalls")]
+    tailcalls: bool,
+    #[arg(short = 'I', value_name = "DIR", help = "Look here for files .include names, after the source's own directory")]
+    include: Vec<PathBuf>,
+    #[arg(short = 'D', long = "define", value_name = "NAME[=VALUE]", value_parser = parse_define,
+          help = "Define a name for .if to test, as 1 if there's no value")]
+    defines: Vec<(String, i64)>,
+}
+
+#[derive(Args)]
+struct FmtArgs {
+    #[arg(required = true)]
+    sources: Vec<PathBuf>,
+    #[arg(long, help = "Only list the files that aren't formatted, and fail if there are any")]
+    check: bool,
+}
+
+#[derive(Args)]
+struct DisasmArgs {
+    program: PathBuf,
+    #[arg(long, value_name = "FILE", help = "Take the labels from here, a .v file assembled with -g or a .s file")]
+    symbols: Option<String>,
+    #[arg(long, help = "Color the listing for a terminal")]
+    color: bool,
+}
+
+#[derive(Args)]
+struct RoundtripArgs {
+    #[arg(help = "A .v file, or a .s file to assemble with its labels")]
+    program: String,
+}
+
+#[derive(Args)]
+struct HexdumpArgs {
+    program: PathBuf,
+}
+
+#[derive(Args)]
+struct AnalyzeArgs {
+    program: String,
+    #[arg(long, help = "Print the control-flow graph first")]
+    graph: bool,
+}
+
+#[derive(Args)]
+struct Addr2lineArgs {
+    #[arg(help = "A .v file assembled with -g, or a .s file")]
+    program: String,
+    #[arg(required = true, value_name = "ADDRESS", value_parser = parse_address, help = "In hex, with or without 0x")]
+    addresses: Vec<i32>,
+}
+
+#[derive(Args)]
+struct LinkArgs {
+    #[arg(required = true, value_name = "OBJECTS")]
+    inputs: Vec<String>,
+    #[arg(short, value_name = "FILE")]
+    output: PathBuf,
+    #[arg(long, help = "Leave out the functions nothing can get to, and list them")]
+    gc: bool,
+    #[arg(long, value_name = "SYMBOL", requires = "gc", help = "Keep a function --gc would leave out, for something outside the program to find")]
+    export: Vec<String>,
+}
+
+#[derive(Args)]
+struct CompileArgs {
+    source: PathBuf,
+    #[arg(short, value_name = "FILE", help = "Where to write it; next to the source by default")]
+    output: Option<PathBuf>,
+    #[arg(long = "asm", help = "Write the assembly it compiles to instead")]
+    emit_asm: bool,
+}
+
+#[derive(Args)]
+struct SelftestArgs {
+    #[arg(long, help = "List every case, not just the ones that fail")]
+    verbose: bool,
+}
+
+#[derive(Args)]
+struct FuzzArgs {
+    #[arg(long, value_name = "N", default_value_t = 10, help = "How long to keep going")]
+    seconds: u64,
+    #[arg(long, value_name = "N", help = "Seed for the programs; the clock by default")]
+    seed: Option<u64>,
+}
+
+/* Where --devices puts things: the console at 0x10000 and the timer at 0x10004. */
+const MMIO: Range<i32> = 0x10000..0x10100;
+const CONSOLE_ADDRESS: i32 = 0x10000;
+const TIMER_ADDRESS: i32 = 0x10004;
+
+/* enabled, silent or stderr, for VmConfig::debug_instruction. */
+fn parse_debug_mode(text: &str) -> Result<DebugMode, String> {
+    match text {
+        "enabled" => Ok(DebugMode::Enabled),
+        "silent" => Ok(DebugMode::Silent),
+        "stderr" => Ok(DebugMode::ToStderr),
+        _ => Err(format!("{} isn't enabled, silent or stderr", text)),
+    }
+}
+
+/* Groups of instructions separated by commas, as in io,files. */
+fn parse_ops(text: &str) -> Result<Ops, String> {
+    text.split(',').try_fold(Ops::NONE, |ops, name| {
+        let group = match name.trim() {
+            "io" => Ops::IO,
+            "debug" => Ops::DEBUG,
+            "files" => Ops::FILES,
+            "host" => Ops::HOST,
+            name => return Err(format!("{} isn't one of io, debug, files and host", name)),
+        };
+        Ok(ops | group)
+    })
+}
+
+/* A time like 5s, 500ms or 2m. A bare number is seconds. */
+fn parse_duration(text: &str) -> Result<Duration, String> {
+    let (number, scale) = if let Some(number) = text.strip_suffix("ms") {
+        (number, 0.001)
+    } else if let Some(number) = text.strip_suffix('s') {
+        (number, 1.0)
+    } else if let Some(number) = text.strip_suffix('m') {
+        (number, 60.0)
+    } else {
+        (text, 1.0)
+    };
+
+    number.parse::<f64>().ok()
+        .and_then(|number| Duration::try_from_secs_f64(number * scale).ok())
+        .ok_or_else(|| format!("{} isn't a time like 5s or 500ms", text))
+}
+
+/* A name for the assembler's .if, as in WORDS64 or BUFSIZE=0x100. */
+fn parse_define(text: &str) -> Result<(String, i64), String> {
+    let (name, value) = match text.split_once('=') {
+        Some((name, value)) => (name, parse_number(value).ok_or_else(|| format!("{} isn't a number", value))?),
+        None => (text, 1),
+    };
+    if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.') {
+        return Err(format!("{} isn't a name", name));
+    }
+    Ok((String::from(name), value))
+}
+
+/* An address in hex, as in 0x128 or 128. */
+fn parse_address(text: &str) -> Result<i32, String> {
+    let digits = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")).unwrap_or(text);
+    u32::from_str_radix(digits, 16).map(|address| address as i32)
+        .map_err(|_| format!("{} isn't an address in hex", text))
+}
+
+/* Quote a string for JSON. */
+fn json_string(s: &str) -> String {
+    let mut quoted = String::from("\"");
+
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+
+    quoted.push('"');
+    quoted
+}
+
+/* Print the result of a run as a single JSON object, for CI systems to pick apart. */
+fn print_json(vm: Option<&VirtualMachine>, result: &Result<i32, String>, start: Instant) {
+    let exit_code = match result {
+        Ok(code) => code.to_string(),
+        Err(_) => String::from("null"),
+    };
+    let error = match result {
+        Ok(_) => String::from("null"),
+        Err(message) => format!("{{\"message\":{}}}", json_string(message)),
+    };
+    let (instructions, sp, pc) = match vm {
+        Some(vm) => (
+            vm.instruction_count().to_string(),
+            vm.stack_pointer().to_string(),
+            vm.program_counter().to_string(),
+        ),
+        None => (String::from("0"), String::from("null"), String::from("null")),
+    };
+    let (max_stack_depth, branches_taken, io_bytes) = match vm {
+        Some(vm) => (vm.max_stack_depth(), vm.branches_taken(), vm.io_bytes()),
+        None => (0, 0, 0),
+    };
+    let lowest_sp = vm.map_or(String::from("null"), |vm| vm.lowest_stack_pointer().to_string());
+    /* A string, since a u64 doesn't survive being a JSON number. */
+    let state_hash = vm.and_then(VirtualMachine::state_hash).map_or(String::from("null"), |hash| format!("\"{:016x}\"", hash));
+
+    println!(
+        "{{\"exit_code\":{},\"instructions\":{},\"sp\":{},\"pc\":{},\"max_stack_depth\":{},\"lowest_sp\":{},\"branches_taken\":{},\"io_bytes\":{},\"state_hash\":{},\"wall_time_ms\":{:.3},\"error\":{}}}",
+        exit_code,
+        instructions,
+        sp,
+        pc,
+        max_stack_depth,
+        lowest_sp,
+        branches_taken,
+        io_bytes,
+        state_hash,
+        start.elapsed().as_secs_f64() * 1000.0,
+        error
+    );
+}
+
+/* Where a program was when Ctrl-C stopped it, and the top of its stack. */
+fn interrupted_state(vm: &VirtualMachine) -> String {
+    let state = vm.state();
+    let mut text = format!("interrupted at pc {:04x}  sp {:04x}  depth {}  cycles {}\n", state.pc, state.sp, state.depth, state.cycles);
+
+    let word_bytes = vm.config().word_size.bytes();
+    for address in (state.sp..vm.memory().len() as i32).step_by(word_bytes as usize).take(8) {
+        if let Some(word) = vm.word_at(address) {
+            text.push_str(&format!("  {:04x}: {}\n", address, word));
+        }
+    }
+    text
+}
+
+/* vm run: run one program. Returns the process exit code. */
+fn run(options: RunArgs) -> i32 {
+    let mut args = options.arg;
+    args.extend(options.program_args);
+    let config = VmConfig {
+        profile: options.profile,
+        args: if args.is_empty() { None } else { Some(args) },
+        env_allowlist: options.env,
+        seed: options.seed,
+        layout_seed: options.layout_seed,
+        mmio: if options.devices { Some(MMIO) } else { None },
+        threaded: options.threaded,
+        protect_code: !options.writable_code,
+        allowed_ops: options.deny.into_iter().fold(Ops::ALL, Ops::without),
+        state_hash: options.state_hash,
+        ..options.machine.config()
+    };
+    let start = Instant::now();
+    let loaded = match (&options.program, &options.hex) {
+        (_, Some(words)) => VirtualMachine::from_hex(words, config),
+        (Some(path), None) => VirtualMachine::read_program(path).and_then(|bytes| VirtualMachine::from_bytes(bytes, config)),
+        (None, None) => unreachable!("clap asks for one or the other"),
+    };
+    let mut vm = match loaded.and_then(|mut vm| options.io.redirect(&mut vm).map(|_| vm)) {
+        Ok(vm) => vm,
+        Err(err) => {
+            if options.json {
+                print_json(None, &Err(err), start);
+            } else {
+                eprintln!("{}", err);
+            }
+            return 1;
+        }
+    };
+
+    if options.devices {
+        let console = Console::new(Box::new(BufReader::new(io::stdin())), Box::new(io::stdout()));
+        let mapped = vm.map_device(CONSOLE_ADDRESS, Box::new(console))
+            .and_then(|_| vm.map_device(TIMER_ADDRESS, Box::new(Timer::new())));
+        if let Err(err) = mapped {
+            eprintln!("{}", err);
+            return 1;
+        }
+    }
+
+    vm.set_interrupt(interrupt::install());
+    if !options.stdin_file.is_empty() || options.repeat.is_some() {
+        return run_repeatedly(&mut vm, &options.stdin_file, options.repeat.unwrap_or(1), &options.io, options.json);
+    }
+    let vm_result = options.io.run(&mut vm);
+    if let Err(VmError::Interrupted { .. }) = vm_result {
+        eprint!("{}", interrupted_state(&vm));
+        return 130;
+    }
+    if vm_result.is_err() && options.dump_on_error {
+        if let Err(err) = vm.dump_state() {
+            eprintln!("{}", err);
+        }
+    }
+    let vm_result = vm_result.map_err(|error| vm.describe_error(&error));
+
+    /* The report goes to stderr so it doesn't get mixed up with the program's own output. */
+    if let Some(profile) = vm.profile() {
+        eprint!("{}", profile.report(vm.code(), 10));
+    }
+    if let (Some(state_hash), false) = (vm.state_hash(), options.json) {
+        eprintln!("state hash {:016x}", state_hash);
+    }
+
+    if options.json {
+        print_json(Some(&vm), &vm_result, start);
+    }
 
     match vm_result {
-        Ok(exit_code) => process::exit(exit_code),
+        Ok(exit_code) => exit_status(exit_code),
         Err(error) => {
-            eprintln!("{}", error);
-            process::exit(1);
+            if !options.json {
+                eprintln!("{}", error);
+            }
+            1
+        }
+    }
+}
+
+/* vm run with --stdin-file or --repeat: run the program again and again from a reset, once per
+ * input file (or once with the usual input if there are none), each of those repeat times. A
+ * line on stderr says how each run went and how long it took, and the last one sums them up:
+ *
+ *       1  a.txt  exit 0     1520 instructions  41.2µs
+ *       2  b.txt  exit 3      877 instructions  30.9µs
+ *     2 runs, 1 exited with something other than 0; fastest 30.9µs, slowest 41.2µs, mean 36.0µs
+ *
+ * With --json it's a report a line for each run instead. Exits 0 if every run exited with 0. */
+fn run_repeatedly(vm: &mut VirtualMachine, fixtures: &[PathBuf], repeat: u64, io: &IoArgs, json: bool) -> i32 {
+    let inputs: Vec<Option<&PathBuf>> = if fixtures.is_empty() { vec![None] } else { fixtures.iter().map(Some).collect() };
+    let mut times = Vec::new();
+    let mut failed = 0;
+
+    for input in inputs {
+        for _ in 0..repeat {
+            vm.reset();
+            if let Some(path) = input {
+                match File::open(path) {
+                    Ok(file) => vm.set_input(Box::new(BufReader::new(file))),
+                    Err(e) => {
+                        eprintln!("Couldn't read {}: {}", path.display(), e);
+                        return 1;
+                    }
+                }
+            }
+
+            let start = Instant::now();
+            let result = io.run(vm);
+            let elapsed = start.elapsed();
+            if let Err(VmError::Interrupted { .. }) = result {
+                eprint!("{}", interrupted_state(vm));
+                return 130;
+            }
+            let result = result.map_err(|error| vm.describe_error(&error));
+            times.push(elapsed);
+            if result != Ok(0) {
+                failed += 1;
+            }
+
+            if json {
+                print_json(Some(vm), &result, start);
+                continue;
+            }
+            let name = input.map_or(String::from("-"), |path| path.display().to_string());
+            let outcome = match &result {
+                Ok(exit_code) => format!("exit {:<3}", exit_code),
+                Err(error) => error.clone(),
+            };
+            let state_hash = vm.state_hash().map_or(String::new(), |hash| format!("  state hash {:016x}", hash));
+            eprintln!("{:>4}  {}  {}  {:>8} instructions  {:.1?}{}", times.len(), name, outcome, vm.instruction_count(), elapsed, state_hash);
+        }
+    }
+
+    if !json {
+        let fastest = times.iter().min().copied().unwrap_or_default();
+        let slowest = times.iter().max().copied().unwrap_or_default();
+        let mean = times.iter().sum::<Duration>() / times.len() as u32;
+        eprintln!("{} runs, {} exited with something other than 0; fastest {:.1?}, slowest {:.1?}, mean {:.1?}",
+            times.len(), failed, fastest, slowest, mean);
+    }
+
+    if failed == 0 { 0 } else { 1 }
+}
+
+/* What vm run exits with for a program's exit code. A process only gets 8 bits of status, so
+ * anything outside 0 to 255 comes out as 255 rather than as its low byte, which would have exit
+ * 256 look like success. 1 is also what a fault gives, and 130 an interrupt; --json says which
+ * it was. */
+fn exit_status(exit_code: i32) -> i32 {
+    match exit_code {
+        0..=255 => exit_code,
+        _ => 255,
+    }
+}
+
+/* vm batch: run a directory of programs and check them against their golden files and an
+ * expectations file, or with --bless, write their golden .out files. --jobs says how many run at
+ * once; by default it's one per core. */
+fn batch(options: BatchArgs) -> i32 {
+    let config = VmConfig { layout_seed: options.layout_seed, ..options.machine.config() };
+    let dir = options.dir.as_path();
+
+    if options.bless {
+        return match harness::bless(dir, &config) {
+            Ok(written) => {
+                for path in written {
+                    println!("wrote {}", path.display());
+                }
+                0
+            },
+            Err(err) => {
+                eprintln!("{}", err);
+                1
+            }
+        };
+    }
+
+    let expectations = match &options.expect {
+        Some(path) => {
+            let parsed = fs::read_to_string(path)
+                .map_err(|e| format!("Couldn't read {}: {}", path.display(), e))
+                .and_then(|text| harness::parse_expectations(&text).map_err(|e| format!("{}: {}", path.display(), e)));
+
+            match parsed {
+                Ok(expectations) => expectations,
+                Err(err) => {
+                    eprintln!("{}", err);
+                    return 1;
+                }
+            }
+        },
+        None => Default::default(),
+    };
+
+    /* No --jobs is 0, which the harness takes as one per core. */
+    let jobs = options.jobs.unwrap_or(0) as usize;
+    match harness::run_batch(dir, &expectations, &config, jobs) {
+        Ok(results) => {
+            print!("{}", harness::format_table(&results));
+            if results.iter().all(|r| r.passed) { 0 } else { 1 }
+        },
+        Err(err) => {
+            eprintln!("{}", err);
+            1
+        }
+    }
+}
+
+/* vm assert: run a program, then check expressions over the state it finished in. */
+fn assert(options: AssertArgs) -> i32 {
+    let loaded = VirtualMachine::from_file(&options.program, options.machine.config())
+        .and_then(|mut vm| options.io.redirect(&mut vm).map(|_| vm));
+    let mut vm = match loaded {
+        Ok(vm) => vm,
+        Err(err) => {
+            eprintln!("{}", err);
+            return 1;
+        }
+    };
+
+    /* A program that faults still gets checked; anything about its exit code just fails. */
+    if let Err(err) = options.io.run(&mut vm) {
+        eprintln!("{}", err);
+    }
+
+    let results = harness::check_assertions(&vm, &options.after_run);
+    for result in &results {
+        match &result.failure {
+            None => println!("pass  {}", result.expression),
+            Some(failure) => println!("FAIL  {}  ({})", result.expression, failure),
+        }
+    }
+
+    let passed = results.iter().filter(|r| r.passed).count();
+    println!("{} passed, {} failed", passed, results.len() - passed);
+
+    if passed == results.len() { 0 } else { 1 }
+}
+
+/* vm debug: step through a program interactively. */
+fn debug(options: DebugArgs) -> i32 {
+    let path = &options.program;
+
+    /* Assembly source gets debugged as is, and reloaded when it changes. */
+    let debugger = if Path::new(path).extension().is_some_and(|ext| ext == "s") {
+        Debugger::from_source(PathBuf::from(path))
+    } else {
+        fs::read(path)
+            .map_err(|_| String::from("Couldn't open file."))
+            .and_then(Debugger::new)
+    };
+
+    let input = match &options.input {
+        Some(input) => fs::read(input).map(Some).map_err(|e| format!("Couldn't read {}: {}", input.display(), e)),
+        None => Ok(None),
+    };
+    let debugger = debugger.and_then(|debugger| debugger.with_config(options.machine.config())).and_then(|debugger| {
+        input.map(|input| match input {
+            Some(input) => debugger.with_input(input),
+            None => debugger,
+        })
+    });
+
+    let mut debugger = match debugger {
+        Ok(debugger) => debugger,
+        Err(err) => {
+            eprintln!("{}", err);
+            return 1;
+        }
+    };
+    debugger.set_interrupt(interrupt::install());
+
+    let result = match &options.script {
+        Some(script) => fs::read_to_string(script)
+            .map_err(|e| format!("Couldn't read {}: {}", script.display(), e))
+            .and_then(|commands| {
+                debugger.script(&commands, &mut io::stdout()).map_err(|e| format!("{}: {}", script.display(), e))
+            }),
+        None => debugger.repl(&mut vm::StdinLines, &mut io::stdout()),
+    };
+
+    match result {
+        Ok(()) => 0,
+        Err(err) => {
+            eprintln!("{}", err);
+            1
+        }
+    }
+}
+
+/* vm tui: the full-screen monitor. A .s file is assembled with its labels so they show up in
+ * the code. */
+fn tui(options: TuiArgs) -> i32 {
+    let image = read_image(&options.program);
+    let input = match &options.input {
+        Some(input) => fs::read(input).map_err(|e| format!("Couldn't read {}: {}", input.display(), e)),
+        None => Ok(Vec::new()),
+    };
+
+    let result = image
+        .and_then(|image| VirtualMachine::from_bytes(image, options.machine.config()))
+        .and_then(|vm| input.and_then(|input| run_tui(vm, input)));
+    match result {
+        Ok(()) => 0,
+        Err(err) => {
+            eprintln!("{}", err);
+            1
+        }
+    }
+}
+
+/* A .v file, or a .s file assembled with its labels. */
+fn read_image(path: &str) -> Result<Vec<u8>, String> {
+    if Path::new(path).extension().is_some_and(|ext| ext == "s") {
+        fs::read_to_string(path)
+            .map_err(|e| format!("Couldn't read {}: {}", path, e))
+            .and_then(|source| assemble_with(&source, path, &mut IncludePath::default(), &Default::default()).map_err(|e| format!("{}: {}", path, e)))
+            .map(|mut program| {
+                program.features |= Header::DEBUG_INFO;
+                program.image()
+            })
+    } else {
+        VirtualMachine::read_program(path)
+    }
+}
+
+#[cfg(feature = "tui")]
+fn run_tui(vm: VirtualMachine, input: Vec<u8>) -> Result<(), String> {
+    vm::tui::run(vm, input)
+}
+
+#[cfg(not(feature = "tui"))]
+fn run_tui(_vm: VirtualMachine, _input: Vec<u8>) -> Result<(), String> {
+    Err(String::from("This vm was built without the tui feature; build it with --features tui."))
+}
+
+/* vm difftest: run a program on the VM and on the reference interpreter side by side, and
+ * say where they first disagree. */
+fn difftest(args: DifftestArgs) -> i32 {
+    let input = match &args.input {
+        Some(input) => match fs::read(input) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                eprintln!("Couldn't read {}: {}", input.display(), e);
+                return 1;
+            }
+        },
+        None => Vec::new(),
+    };
+    let options = DiffOptions { input, seed: args.seed, threaded: args.threaded, max_steps: args.steps };
+
+    let report = match read_image(&args.program).and_then(|image| reference::difftest(&image, &options)) {
+        Ok(report) => report,
+        Err(err) => {
+            eprintln!("{}", err);
+            return 1;
+        }
+    };
+
+    match report.outcome {
+        DiffOutcome::Exited(code) => println!("{} instructions, no differences; both exited with {}", report.steps, code),
+        DiffOutcome::Faulted { vm, reference } => {
+            println!("{} instructions, no differences; both faulted", report.steps);
+            println!("  vm:        {}", vm);
+            println!("  reference: {}", reference);
+        },
+        DiffOutcome::Stopped(why) => println!("{} instructions, no differences; stopped because {}", report.steps, why),
+        DiffOutcome::Diverged { pc, word, what } => {
+            println!("diverged after {} instructions, at {:04x}: {:08x}  {}", report.steps + 1, pc, word, isa::disassemble(word));
+            println!("  {}", what);
+            return 1;
+        },
+    }
+    0
+}
+
+/* vm analyze: look through a program for code nothing reaches, calls that never come back and
+ * branches that go nowhere, and say where each is, in the source if it was assembled with -g.
+ * Self-recursive calls that could be tailcalls are listed too, though they aren't problems.
+ * --graph prints the control-flow graph first. Exits 1 if anything else turned up. */
+fn analyze(args: AnalyzeArgs) -> i32 {
+    let parsed = read_image(&args.program).and_then(|image| {
+        let (_, _, debug_info) = Header::parse(&image)?;
+        Ok((Program::from_image(&image)?, debug_info))
+    });
+    let (program, debug_info) = match parsed {
+        Ok(parsed) => parsed,
+        Err(err) => {
+            eprintln!("{}", err);
+            return 1;
         }
+    };
+
+    let cfg = cfg::build(&program);
+    if args.graph {
+        print!("{}", cfg);
     }
+    let problems = cfg.problems();
+    for problem in &problems {
+        let address = match *problem {
+            Problem::Unreachable { start, .. } => start,
+            Problem::NoReturn { call_site, .. } => call_site,
+            Problem::BadTarget { from, .. } => from,
+        };
+        match debug_info.as_ref().and_then(|info| info.describe(address)) {
+            Some(location) => println!("{}  at {}", problem, location),
+            None => println!("{}", problem),
+        }
+    }
+    /* Not problems, but worth knowing: vm asm --opt --tailcalls can turn these. */
+    for candidate in analysis::find_tail_recursion(program.code()) {
+        match debug_info.as_ref().and_then(|info| info.describe(candidate.call_site)) {
+            Some(location) => println!("{}  at {}", candidate, location),
+            None => println!("{}", candidate),
+        }
+    }
+    println!("{} blocks, {} reachable, {} problems", cfg.blocks.len(), cfg.reachable.iter().filter(|&&r| r).count(), problems.len());
+
+    if problems.is_empty() { 0 } else { 1 }
+}
+
+/* vm addr2line: where in the source each address came from, from the line table in its debug
+ * info, a line each:
+ *
+ *     0128  stdlib/math.s:12  mulshift+8
+ *
+ * with ??:0 for an address no line is known for, and the label it's under if there is one, or
+ * ?? if the address is outside the code and data, where no label reaches. */
+fn addr2line(args: Addr2lineArgs) -> i32 {
+    let debug_info = read_image(&args.program).and_then(|image| {
+        let (_, body, debug_info) = Header::parse(&image)?;
+        let debug_info = debug_info.ok_or_else(|| format!("{} has no debug info; assemble it with vm asm -g", args.program))?;
+        Ok((debug_info, body.len() as i32))
+    });
+    let (debug_info, end) = match debug_info {
+        Ok(debug_info) => debug_info,
+        Err(err) => {
+            eprintln!("{}", err);
+            return 1;
+        }
+    };
+
+    for &address in &args.addresses {
+        let location = match debug_info.location(address) {
+            Some((file, line)) => format!("{}:{}", file.unwrap_or("??"), line),
+            None => String::from("??:0"),
+        };
+        match debug_info.symbolize(address) {
+            _ if !(0..=end).contains(&address) => println!("{:04x}  {}  ??", address, location),
+            Some((label, 0)) => println!("{:04x}  {}  {}", address, location, label),
+            Some((label, offset)) => println!("{:04x}  {}  {}+{}", address, location, label, offset),
+            None => println!("{:04x}  {}", address, location),
+        }
+    }
+    0
+}
+
+/* vm fmt: rewrite each source the way vm::fmt lays it out, or with --check, say which ones it
+ * would change. */
+fn fmt(args: FmtArgs) -> i32 {
+    let mut status = 0;
+
+    for path in &args.sources {
+        let source = match fs::read_to_string(path) {
+            Ok(source) => source,
+            Err(e) => {
+                eprintln!("Couldn't read {}: {}", path.display(), e);
+                status = 1;
+                continue;
+            }
+        };
+        let formatted = vm::fmt::format(&source);
+        if formatted == source {
+            continue;
+        }
+
+        if args.check {
+            println!("{}", path.display());
+            status = 1;
+        } else if let Err(e) = fs::write(path, formatted) {
+            eprintln!("Couldn't write {}: {}", path.display(), e);
+            status = 1;
+        }
+    }
+    status
+}
+
+/* vm lsp: serve an editor until it says to exit. */
+fn lsp() -> i32 {
+    match vm::lsp::serve(&mut io::stdin().lock(), &mut io::stdout().lock()) {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("{}", err);
+            1
+        }
+    }
+}
+
+/* vm disasm: list a program's header and then its code, a word to a line, and any data after
+ * it in bytes; or, with labels from its debug info or from --symbols, as assembly that
+ * assembles back to it (see vm::disasm). */
+fn disasm(args: DisasmArgs) -> i32 {
+    let path = &args.program;
+    let listing = fs::read(path)
+        .map_err(|e| format!("Couldn't read {}: {}", path.display(), e))
+        .and_then(|image| {
+            let (_, _, debug_info) = Header::parse(&image)?;
+            let debug_info = match &args.symbols {
+                Some(symbols) => Some(read_image(symbols).and_then(|image| {
+                    let (_, _, debug_info) = Header::parse(&image)?;
+                    debug_info.ok_or_else(|| format!("{} has no debug info; assemble it with vm asm -g", symbols))
+                })?),
+                None => debug_info,
+            };
+            disasm::listing(&image, debug_info.as_ref().map(|debug_info| &debug_info.labels), args.color)
+        });
+
+    match listing {
+        Ok(listing) => {
+            print!("{}", listing);
+            0
+        },
+        Err(err) => {
+            eprintln!("{}", err);
+            1
+        }
+    }
+}
+
+/* vm roundtrip: list a program the way vm disasm does, assemble the listing and check it comes
+ * back the same, saying where it doesn't if it doesn't. */
+fn roundtrip(args: RoundtripArgs) -> i32 {
+    match read_image(&args.program).and_then(|image| disasm::roundtrip(&image)) {
+        Ok(()) => {
+            println!("{}: the listing assembles back to the same program", args.program);
+            0
+        },
+        Err(err) => {
+            eprintln!("{}: {}", args.program, err);
+            1
+        }
+    }
+}
+
+/* vm hexdump: every byte of a .v file, under a line for each part of the file saying where it
+ * is and, for the code and data, where it gets loaded:
+ *
+ *     header      0000..001c  28 bytes  version 4  features 0x2  entry 0000  sp 1000  data 0048  heap 1024
+ *       0000  de ad ca fe 04 1c 00 00 02 00 00 00 00 00 00 00  |................|
+ *     code        001c..0064  72 bytes, loaded at 0000
+ *       001c  00 00 00 f0  0000  push 0
+ *       ...
+ *       0028  03 00 b0 b1  000c  jumptable 3
+ *       002c  14 00 00 00  0010  .table 0024
+ *     data        0064..006c  8 bytes, loaded at 0048
+ *       0064  68 65 6c 6c 6f 0a 00 00                          0048  |hello...|
+ *
+ * Zeros the program ends with, past an exit or a string's terminating 0, are padding, and only
+ * get the one line. Unlike disasm, it shows bytes that don't decode as well as ones that do. A
+ * jumptable's entries show where they go, as in disasm. */
+fn hexdump(args: HexdumpArgs) -> i32 {
+    let path = &args.program;
+    let file = match fs::read(path) {
+        Ok(file) => file,
+        Err(e) => {
+            eprintln!("Couldn't read {}: {}", path.display(), e);
+            return 1;
+        }
+    };
+    let (header, body) = match Header::parse(&file) {
+        Ok((header, body, _)) => (header, body),
+        Err(err) => {
+            eprintln!("{}: {}", path.display(), err);
+            return 1;
+        }
+    };
+
+    let header_size = Header::length(&file);
+    let code_size = header.code_size.map_or(body.len(), |size| size as usize);
+
+    /* Keep the zero after the last byte that isn't one: the word of an exit in the code, the
+     * byte of a terminator in the data. */
+    let padding = match body.iter().rposition(|&byte| byte != 0) {
+        Some(last) if last < code_size => (last / 4 * 4 + 8).min(code_size),
+        Some(last) => last + 2,
+        None => 4,
+    }.min(body.len());
+
+    let part = |name: &str, range: Range<usize>| {
+        print!("{:<10}  {:04x}..{:04x}  {} bytes", name, range.start, range.end, range.len());
+    };
+    part("header", 0..header_size);
+    println!("  {}", header);
+    hex_rows(&file[..header_size], 0, None);
+
+    if code_size.min(padding) > 0 {
+        part("code", header_size..header_size + code_size.min(padding));
+        println!(", loaded at 0000");
+        let entries = analysis::table_entries(&body[..code_size]);
+        for (i, word) in body[..code_size.min(padding)].chunks(4).enumerate() {
+            let bytes: Vec<String> = word.iter().map(|byte| format!("{:02x}", byte)).collect();
+            let instruction = <[u8; 4]>::try_from(word).map_or(String::new(), |word| match entries.get(i) {
+                Some(true) => disasm::table_entry(i as i32 * 4, u32::from_le_bytes(word)),
+                _ => isa::disassemble(u32::from_le_bytes(word)),
+            });
+            println!("  {:04x}  {:<11}  {:04x}  {}", header_size + i * 4, bytes.join(" "), i * 4, instruction);
+        }
+    }
+    if padding > code_size {
+        part("data", header_size + code_size..header_size + padding);
+        println!(", loaded at {:04x}", code_size);
+        hex_rows(&body[code_size..padding], header_size + code_size, Some(code_size));
+    }
+    if padding < body.len() {
+        part("zeros", header_size + padding..header_size + body.len());
+        println!(", loaded at {:04x}", padding);
+    }
+    if header_size + body.len() < file.len() {
+        part("debug info", header_size + body.len()..file.len());
+        println!();
+        hex_rows(&file[header_size + body.len()..], header_size + body.len(), None);
+    }
+    0
+}
+
+/* Bytes sixteen to a line, from offset in the file and address in memory if they're loaded, with
+ * the printable ones beside them. */
+fn hex_rows(bytes: &[u8], offset: usize, address: Option<usize>) {
+    for (i, row) in bytes.chunks(16).enumerate() {
+        let hex: Vec<String> = row.iter().map(|byte| format!("{:02x}", byte)).collect();
+        let text: String = row.iter().map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' }).collect();
+        let address = address.map_or(String::new(), |address| format!("{:04x}  ", address + i * 16));
+        println!("  {:04x}  {:<47}  {}|{}|", offset + i * 16, hex.join(" "), address, text);
+    }
+}
+
+/* vm asm: assemble a program into a .v file, or with -c, into a .vo object file for vm link.
+ * -g puts the labels and line numbers in the file, for errors to point at. --opt runs the
+ * peephole optimizer over it, and --inline and --tailcalls, the inliner and the tail call
+ * rewrite before that, listing each call they changed. -D gives the names its .ifs test. */
+fn asm(args: AsmArgs) -> i32 {
+    let AsmArgs { source: source_path, output: output_path, object, debug_info, optimize, inline, tailcalls, include, defines } = args;
+    let defines = defines.into_iter().collect();
+    let source_path = source_path.as_path();
+    /* The linker needs the branches it fixes up to stay where they are. */
+    if object && optimize {
+        eprintln!("--opt only works on whole programs, not with -c");
+        return 1;
+    }
+    let extension = if object { "vo" } else { "v" };
+    let output_path = output_path.unwrap_or_else(|| source_path.with_extension(extension));
+
+    let output = fs::read_to_string(source_path)
+        .map_err(|e| format!("Couldn't read {}: {}", source_path.display(), e))
+        .and_then(|source| {
+            let features = if debug_info { Header::DEBUG_INFO } else { 0 };
+            let name = source_path.to_string_lossy();
+            let mut includes = IncludePath { dirs: include };
+            let output = if object {
+                assemble_object_with(&source, &name, &mut includes, &defines).map(|mut object| {
+                    object.features |= features;
+                    object.to_bytes()
+                })
+            } else {
+                assemble_with(&source, &name, &mut includes, &defines).map(|mut program| {
+                    program.features |= features;
+                    if let Some(max_instructions) = inline {
+                        match program.inline(max_instructions) {
+                            Ok(report) => report.iter().for_each(|call| println!("{}", call)),
+                            Err(err) => eprintln!("{}: {}", source_path.display(), err),
+                        }
+                    }
+                    if tailcalls {
+                        program.tail_calls().iter().for_each(|call| println!("{}", call));
+                    }
+                    if optimize {
+                        if let Err(err) = program.peephole() {
+                            eprintln!("{}: {}", source_path.display(), err);
+                        }
+                    }
+                    program.image()
+                })
+            };
+            output.map_err(|e| format!("{}: {}", source_path.display(), e))
+        });
+
+    let result = output.and_then(|bytes| {
+        fs::write(&output_path, bytes)
+            .map_err(|e| format!("Couldn't write {}: {}", output_path.display(), e))
+    });
+
+    match result {
+        Ok(()) => 0,
+        Err(err) => {
+            eprintln!("{}", err);
+            1
+        }
+    }
+}
+
+/* vm link: put object files together into a program, and with --gc, list what got left out. */
+fn link(args: LinkArgs) -> i32 {
+    let LinkArgs { inputs, output: output_path, gc, export } = args;
+
+    let objects: Result<Vec<Object>, String> = inputs.iter()
+        .map(|path| {
+            fs::read(path)
+                .map_err(|e| format!("Couldn't read {}: {}", path, e))
+                .and_then(|bytes| Object::from_bytes(&bytes).map_err(|e| format!("{}: {}", path, e)))
+        })
+        .collect();
+
+    let result = objects
+        .and_then(|objects| {
+            linker::link(&objects).map_err(|e| match e.object {
+                Some(object) => format!("{}: {}", inputs[object], e.message),
+                None => e.message,
+            })
+        })
+        .and_then(|program| {
+            if !gc {
+                return Ok(program);
+            }
+            let (program, removed) = linker::gc(&program, &export).map_err(|e| e.message)?;
+            for piece in &removed {
+                println!("removed {}", piece);
+            }
+            println!("saved {} bytes", removed.iter().map(|piece| piece.size).sum::<i32>());
+            Ok(program)
+        })
+        .and_then(|program| {
+            fs::write(&output_path, program.image()).map_err(|e| format!("Couldn't write {}: {}", output_path.display(), e))
+        });
+
+    match result {
+        Ok(()) => 0,
+        Err(err) => {
+            eprintln!("{}", err);
+            1
+        }
+    }
+}
+
+/* vm compile: compile a program in the little language (see the lang module) into a .v file,
+ * or with --asm, into the assembly it turns into. */
+fn compile(args: CompileArgs) -> i32 {
+    let CompileArgs { source: source_path, output: output_path, emit_asm } = args;
+    let extension = if emit_asm { "s" } else { "v" };
+    let output_path = output_path.unwrap_or_else(|| source_path.with_extension(extension));
+
+    let source = match fs::read_to_string(&source_path) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("Couldn't read {}: {}", source_path.display(), e);
+            return 1;
+        }
+    };
+
+    let output = if emit_asm {
+        lang::compile_to_assembly(&source).map(String::into_bytes)
+    } else {
+        lang::compile(&source).map(|program| program.image())
+    };
+
+    let result = output
+        .map_err(|e| format!("{}: {}", source_path.display(), e))
+        .and_then(|bytes| {
+            fs::write(&output_path, bytes)
+                .map_err(|e| format!("Couldn't write {}: {}", output_path.display(), e))
+        });
+
+    match result {
+        Ok(()) => 0,
+        Err(err) => {
+            eprintln!("{}", err);
+            1
+        }
+    }
+}
+
+/* vm selftest: check every instruction's handler against the instruction set's definition, and
+ * run the example programs against their golden files. */
+fn selftest(args: SelftestArgs) -> i32 {
+    let verbose = args.verbose;
+
+    let cases = selftest::cases();
+    let mut failed = 0;
+    for case in &cases {
+        match case.check() {
+            Ok(()) if verbose => println!("ok    {}", case.name),
+            Ok(()) => (),
+            Err(reason) => {
+                println!("FAIL  {}  ({})", case.name, reason);
+                failed += 1;
+            }
+        }
+    }
+
+    /* Then the example programs, end to end. */
+    let programs = harness::run_shipped();
+    for result in &programs {
+        match &result.failure {
+            None if verbose => println!("ok    {}", result.name),
+            None => (),
+            Some(reason) => {
+                println!("FAIL  {}  ({})", result.name, reason);
+                failed += 1;
+            }
+        }
+    }
+
+    println!("{} passed, {} failed", cases.len() + programs.len() - failed, failed);
+
+    if failed == 0 { 0 } else { 1 }
+}
+
+/* vm fuzz: throw random programs at the VM until one panics it or time's up. */
+fn fuzz(args: FuzzArgs) -> i32 {
+    let seconds = args.seconds;
+    let seed = args.seed.unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs()));
+
+    let report = vm::fuzz::fuzz(seed, Duration::from_secs(seconds));
+    let Some(crash) = report.crash else {
+        println!("{} programs, no panics (seed {})", report.runs, seed);
+        return 0;
+    };
+
+    println!("panic after {} programs (seed {}): {}", report.runs, seed, crash.message);
+    if crash.features != 0 {
+        println!("features: {:#x}", crash.features);
+    }
+    for (i, &word) in crash.program.iter().enumerate() {
+        println!("{:04x}: {:08x}  {}", i * 4, word, isa::disassemble(word));
+    }
+    if !crash.input.is_empty() {
+        println!("input: {:?}", String::from_utf8_lossy(&crash.input));
+    }
+    1
+}
+
+fn main() {
+    let mut args: Vec<OsString> = std::env::args_os().collect();
+
+    /* Anything that isn't a subcommand or asking for help is a program to run. */
+    let first = args.get(1).and_then(|arg| arg.to_str()).unwrap_or("");
+    let command = Cli::command();
+    let is_subcommand = command.get_subcommands().any(|subcommand| subcommand.get_name() == first);
+    if args.len() > 1 && !is_subcommand && !matches!(first, "-h" | "--help" | "-V" | "--version" | "help") {
+        args.insert(1, OsString::from("run"));
+    }
+
+    let cli = match Cli::try_parse_from(args) {
+        Ok(cli) => cli,
+        Err(err) => {
+            let _ = err.print();
+            process::exit(if err.use_stderr() { 1 } else { 0 });
+        }
+    };
+
+    let exit_code = match cli.command {
+        Command::Run(args) => run(args),
+        Command::Batch(args) => batch(args),
+        Command::Assert(args) => assert(args),
+        Command::Debug(args) => debug(args),
+        Command::Tui(args) => tui(args),
+        Command::Difftest(args) => difftest(args),
+        Command::Asm(args) => asm(args),
+        Command::Fmt(args) => fmt(args),
+        Command::Disasm(args) => disasm(args),
+        Command::Roundtrip(args) => roundtrip(args),
+        Command::Hexdump(args) => hexdump(args),
+        Command::Analyze(args) => analyze(args),
+        Command::Addr2line(args) => addr2line(args),
+        Command::Lsp => lsp(),
+        Command::Link(args) => link(args),
+        Command::Compile(args) => compile(args),
+        Command::Selftest(args) => selftest(args),
+        Command::Fuzz(args) => fuzz(args),
+    };
+
+    process::exit(exit_code);
 }
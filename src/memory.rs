@@ -0,0 +1,177 @@
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use crate::{Endianness, VmError};
+
+/* Size of the machine's memory in bytes. */
+pub(crate) const MEMORY_SIZE: usize = 4096;
+
+/* It lives on the heap normally; with the fixed-memory feature it's an array inside the
+ * VirtualMachine itself, for targets where a 4K allocation is a lot to ask. */
+#[cfg(not(feature = "fixed-memory"))]
+type Storage = Vec<u8>;
+#[cfg(feature = "fixed-memory")]
+type Storage = [u8; MEMORY_SIZE];
+
+/* The machine's memory: code at the bottom, stack at the top. Every access is bounds checked
+ * here, and anything that strays outside comes back as VmError::OutOfBounds. Instructions are
+ * always little-endian, and stack words in the order the config says, so both kinds of access
+ * are here. */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Memory {
+    bytes: Storage,
+    endianness: Endianness,
+}
+
+impl Memory {
+    /* Fresh memory with the code at the bottom and zeroes above it. */
+    #[cfg(not(feature = "fixed-memory"))]
+    pub(crate) fn load(mut code: Vec<u8>, endianness: Endianness) -> Memory {
+        code.resize(MEMORY_SIZE, 0);
+        Memory { bytes: code, endianness }
+    }
+
+    #[cfg(feature = "fixed-memory")]
+    pub(crate) fn load(code: Vec<u8>, endianness: Endianness) -> Memory {
+        let mut bytes = [0; MEMORY_SIZE];
+        bytes[..code.len()].copy_from_slice(&code);
+        Memory { bytes, endianness }
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /* The order stack words go in. */
+    pub fn endianness(&self) -> Endianness {
+        self.endianness
+    }
+
+    /* All of it, for looking through. */
+    pub fn as_slice(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /* Where size bytes starting at address live, if they're all inside memory. */
+    fn range(&self, address: i32, size: usize) -> Result<Range<usize>, VmError> {
+        let out_of_bounds = VmError::OutOfBounds { address, size };
+        let start = usize::try_from(address).map_err(|_| out_of_bounds.clone())?;
+        let end = start.checked_add(size).ok_or_else(|| out_of_bounds.clone())?;
+
+        if end > self.bytes.len() {
+            return Err(out_of_bounds);
+        }
+
+        Ok(start..end)
+    }
+
+    pub fn slice(&self, address: i32, size: usize) -> Result<&[u8], VmError> {
+        let range = self.range(address, size)?;
+        Ok(&self.bytes[range])
+    }
+
+    pub fn slice_mut(&mut self, address: i32, size: usize) -> Result<&mut [u8], VmError> {
+        let range = self.range(address, size)?;
+        Ok(&mut self.bytes[range])
+    }
+
+    pub fn read_u8(&self, address: i32) -> Result<u8, VmError> {
+        Ok(self.slice(address, 1)?[0])
+    }
+
+    pub fn write_u8(&mut self, address: i32, value: u8) -> Result<(), VmError> {
+        self.slice_mut(address, 1)?[0] = value;
+        Ok(())
+    }
+
+    /* An instruction. */
+    pub fn read_u32(&self, address: i32) -> Result<u32, VmError> {
+        let bytes = self.slice(address, 4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub fn write_u32(&mut self, address: i32, value: u32) -> Result<(), VmError> {
+        self.slice_mut(address, 4)?.copy_from_slice(&value.to_le_bytes());
+        Ok(())
+    }
+
+    /* A stack word of size bytes, zero extended. */
+    pub fn read_word(&self, address: i32, size: usize) -> Result<u64, VmError> {
+        Ok(self.endianness.read(self.slice(address, size)?))
+    }
+
+    /* Store the low size bytes of a value as a stack word. */
+    pub fn write_word(&mut self, address: i32, size: usize, value: u64) -> Result<(), VmError> {
+        let endianness = self.endianness;
+        endianness.write(value, self.slice_mut(address, size)?);
+        Ok(())
+    }
+
+    /* The atomics. Contexts only ever switch between instructions, so doing the read and the
+     * write here in one go is all it takes for nothing to come between them. Both give back
+     * the word that was there before. */
+
+    /* Write new over a word if it holds old, comparing only the low size bytes of old. */
+    pub fn compare_and_swap(&mut self, address: i32, size: usize, old: u64, new: u64) -> Result<u64, VmError> {
+        let current = self.read_word(address, size)?;
+        if current == old & word_mask(size) {
+            self.write_word(address, size, new)?;
+        }
+        Ok(current)
+    }
+
+    /* Add to a word, wrapping around within its size. */
+    pub fn fetch_add(&mut self, address: i32, size: usize, delta: u64) -> Result<u64, VmError> {
+        let current = self.read_word(address, size)?;
+        self.write_word(address, size, current.wrapping_add(delta))?;
+        Ok(current)
+    }
+
+    /* size bytes starting at address as text, a line at a time with the address of the first
+     * byte in front: sixteen bytes to a line in hex, or eight in binary. */
+    pub fn dump(&self, address: i32, size: usize, binary: bool) -> Result<String, VmError> {
+        let bytes = self.slice(address, size)?;
+        let per_line = if binary { 8 } else { 16 };
+        let mut text = String::new();
+
+        for (i, line) in bytes.chunks(per_line).enumerate() {
+            text.push_str(&format!(" {:04x} | ", address as usize + i * per_line));
+            for byte in line {
+                match binary {
+                    true => text.push_str(&format!("  {:08b}", byte)),
+                    false => text.push_str(&format!("  {:02x}", byte)),
+                }
+            }
+            text.push('\n');
+        }
+
+        Ok(text)
+    }
+
+    /* Every address the bytes of pattern start at. An empty pattern is nowhere. */
+    pub fn find(&self, pattern: &[u8]) -> Vec<i32> {
+        if pattern.is_empty() {
+            return Vec::new();
+        }
+
+        self.bytes.windows(pattern.len())
+            .enumerate()
+            .filter(|(_, window)| *window == pattern)
+            .map(|(address, _)| address as i32)
+            .collect()
+    }
+}
+
+/* The bits a word of size bytes keeps. */
+fn word_mask(size: usize) -> u64 {
+    match size {
+        8 => u64::MAX,
+        size => (1 << (size * 8)) - 1,
+    }
+}
@@ -0,0 +1,26 @@
+/* Memory observers, for tools that want to follow what a program does to memory as it happens,
+ * a visualization animating the stack say, without stepping it and diffing. An observer added
+ * with VirtualMachine::add_observer hears about:
+ *
+ *     read        every read the program makes: pops, peeks, loads, strings and the rest
+ *     write       every write, with the bytes that were there before and the ones after
+ *     pc_changed  every time the pc moves, after each instruction and on a context switch
+ *
+ * Reads and writes of memory all go through one place in the machine, so nothing the program
+ * does is missed. What isn't the program isn't reported: fetching instructions, dump and debug
+ * output, devices, and anything done from outside with patch, word_at or memory. Observers are
+ * called in the order they were added, and are kept across reset. */
+
+/* Each method does nothing unless it's implemented, so an observer only needs the ones it
+ * wants. */
+pub trait MemoryObserver {
+    /* The program read bytes starting at address. A word is word_size bytes, in the
+     * memory's byte order. */
+    fn read(&mut self, _address: i32, _bytes: &[u8]) {}
+
+    /* The program changed the bytes starting at address from old to new. */
+    fn write(&mut self, _address: i32, _old: &[u8], _new: &[u8]) {}
+
+    /* The pc went from from to to. */
+    fn pc_changed(&mut self, _from: i32, _to: i32) {}
+}
@@ -0,0 +1,507 @@
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::analysis::{self, Function, TailCallCandidate};
+use crate::isa::{BinaryOp, Instruction};
+use crate::rng::Rng;
+
+/* One call that got replaced by a copy of the function it called. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InlinedCall {
+    /* Address of the call in the original program. */
+    pub call_site: i32,
+    /* Address of the function in the original program. */
+    pub function: i32,
+    /* How many instructions the call turned into. */
+    pub instructions: usize,
+}
+
+impl fmt::Display for InlinedCall {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04x}: inlined call to {:04x} ({} instructions)",
+            self.call_site, self.function, self.instructions)
+    }
+}
+
+/* Whether a function is simple enough to paste in place of a call: small, no calls of its own,
+ * a single ret at the very end, no branches that leave it, and no jumptable, whose entries
+ * aren't checked for where they go. */
+fn is_inlinable(words: &[u32], function: &Function, max_instructions: usize) -> bool {
+    let size = function.size();
+    if size == 0 || size > max_instructions {
+        return false;
+    }
+
+    let first = (function.start / 4) as usize;
+    let body = &words[first..first + size];
+
+    for (i, &instruction) in body.iter().enumerate() {
+        let address = function.start + (i as i32) * 4;
+        let is_last = i == size - 1;
+
+        match instruction >> 28 {
+            5 => return false,
+            _ if instruction >> 20 == 0xB1B => return false,
+            6 if !is_last => return false,
+            _ if is_last && instruction >> 28 != 6 => return false,
+            _ => (),
+        }
+
+        if let Some(target) = analysis::branch_target(address, instruction) {
+            if !(function.start..function.end).contains(&target) {
+                return false;
+            }
+        }
+    }
+
+    true
+}
+
+/* Replace calls to functions of at most max_instructions instructions with the body of the
+ * function. The call becomes a push of the return address and the ret becomes a pop of the
+ * frame plus that slot, so the function sees exactly the stack it would have seen before. Every
+ * branch in the program is then pointed at the new location of its target.
+ *
+ * The original functions are left where they are. Any words in the code that aren't
+ * instructions (data tucked in after an exit, say) could get mistaken for branches, so only run
+ * this over pure code. */
+pub fn inline_small_functions(code: &[u8], max_instructions: usize) -> (Vec<u8>, Vec<InlinedCall>) {
+    let word_count = code.len() / 4;
+    let words: Vec<u32> = (0..word_count)
+        .map(|i| analysis::instruction_at(code, (i * 4) as i32).unwrap_or(0))
+        .collect();
+
+    let inlinable: Vec<Function> = analysis::functions(code)
+        .into_iter()
+        .filter(|function| function.start != 0 && is_inlinable(&words, function, max_instructions))
+        .collect();
+
+    /* Lay out the new program. Each emitted word remembers which old address it came from and
+     * whether it's part of an inlined copy, whose internal branches don't need fixing. */
+    let mut emitted: Vec<(u32, i32, bool)> = Vec::new();
+    let mut new_addresses: BTreeMap<i32, i32> = BTreeMap::new();
+    let mut report = Vec::new();
+    let entries = analysis::table_entries(code);
+
+    for (i, &instruction) in words.iter().enumerate() {
+        let address = (i * 4) as i32;
+        let new_address = (emitted.len() * 4) as i32;
+        new_addresses.insert(address, new_address);
+
+        let target = match instruction >> 28 {
+            5 if !entries[i] && !analysis::is_tail_call(instruction) => analysis::branch_target(address, instruction),
+            _ => None,
+        };
+        let function = target.and_then(|target| inlinable.iter().find(|f| f.start == target));
+
+        let Some(function) = function else {
+            emitted.push((instruction, address, false));
+            continue;
+        };
+
+        let first = (function.start / 4) as usize;
+        let body = &words[first..first + function.size()];
+        let (ret, body) = body.split_last().expect("inlinable functions aren't empty");
+        let frame_size = ret & 0x0FFF_FFFC;
+
+        /* push <return address>, the body, pop <frame + return address> */
+        let return_address = new_address + (body.len() as i32 + 2) * 4;
+        emitted.push((0xF000_0000 | (return_address as u32 & 0x0FFF_FFFF), address, true));
+        for (j, &body_instruction) in body.iter().enumerate() {
+            emitted.push((body_instruction, function.start + (j as i32) * 4, true));
+        }
+        emitted.push((0x1000_0000 | (frame_size + 4), function.start + (body.len() as i32) * 4, true));
+
+        report.push(InlinedCall {
+            call_site: address,
+            function: function.start,
+            instructions: body.len() + 2,
+        });
+    }
+
+    (relocate(code, &emitted, &new_addresses), report)
+}
+
+/* Turn the self-recursive calls analysis::find_tail_recursion finds into tailcalls, so the
+ * recursion runs in the one frame instead of a return address deeper each time. Only the ones
+ * whose ret frees nothing are turned, since a tailcall can't free anything once the function's
+ * done, and that's only the same program as long as the function takes nothing from under its
+ * return address, which is all it would find there once the call is a tailcall. Returns the
+ * new code and the calls it turned. */
+pub fn rewrite_tail_calls(code: &[u8]) -> (Vec<u8>, Vec<TailCallCandidate>) {
+    let mut rewritten = code.to_vec();
+    let candidates: Vec<TailCallCandidate> = analysis::find_tail_recursion(code)
+        .into_iter()
+        .filter(|candidate| candidate.frame_size == 0)
+        .collect();
+
+    for candidate in &candidates {
+        let start = candidate.call_site as usize;
+        let word = analysis::instruction_at(code, candidate.call_site).expect("a call site is on a word of the code");
+        rewritten[start..start + 4].copy_from_slice(&(word | 1).to_le_bytes());
+    }
+
+    (rewritten, candidates)
+}
+
+/* Turn a new layout back into bytes, pointing every branch that came from the old program at
+ * wherever its target ended up, and every jumptable entry too. Each emitted word carries the
+ * old address it came from and whether to leave it alone. */
+fn relocate(code: &[u8], emitted: &[(u32, i32, bool)], new_addresses: &BTreeMap<i32, i32>) -> Vec<u8> {
+    let word_count = code.len() / 4;
+    let entries = analysis::table_entries(code);
+    let mut relocated = Vec::with_capacity(emitted.len() * 4 + code.len() % 4);
+
+    for (i, &(instruction, old_address, keep)) in emitted.iter().enumerate() {
+        let new_address = (i * 4) as i32;
+        let mut fixed = instruction;
+
+        if !keep {
+            let entry = entries.get((old_address / 4) as usize).copied().unwrap_or(false);
+            /* Whatever comes after the code, data or a heap, moves with the end of it. */
+            let (old_end, new_end) = ((word_count * 4) as i32, (emitted.len() * 4) as i32);
+            let new_target = target(old_address, instruction, entry).and_then(|target| match target {
+                _ if target >= old_end => Some(new_end + (target - old_end)),
+                _ => new_addresses.get(&target).copied(),
+            });
+
+            /* A table entry is a plain byte offset from itself. */
+            match new_target {
+                Some(new_target) if entry => fixed = new_target.wrapping_sub(new_address) as u32,
+                Some(new_target) => fixed = analysis::retarget(new_address, instruction, new_target),
+                None => (),
+            }
+        }
+
+        relocated.extend_from_slice(&fixed.to_le_bytes());
+    }
+
+    /* Keep any stray bytes past the last whole word. */
+    relocated.extend_from_slice(&code[word_count * 4..]);
+
+    relocated
+}
+
+/* Whether execution can carry on past an instruction. ret, goto, tailcall, jumpi and exit never
+ * do. */
+fn falls_through(instruction: u32) -> bool {
+    !matches!(instruction >> 28, 6 | 7) && instruction >> 24 != 0
+        && !analysis::is_tail_call(instruction) && instruction >> 20 != 0xB1A
+}
+
+/* Where a word sends execution: a table entry to its own address plus the offset in it, and
+ * anything else wherever analysis::branch_target says. */
+fn target(address: i32, word: u32, entry: bool) -> Option<i32> {
+    match entry {
+        true => Some(address.wrapping_add(word as i32)),
+        false => analysis::branch_target(address, word),
+    }
+}
+
+/* Like analysis::functions, but also starts a new piece after every instruction that doesn't
+ * fall through, so code that nothing calls still ends up in a piece of its own. A jumptable and
+ * its entries stay in one piece, and since a selector past the table carries on after it, the
+ * last entry counts as falling through. */
+fn pieces(code: &[u8], words: &[u32]) -> Vec<Function> {
+    let mut starts: Vec<i32> = analysis::functions(code).iter().map(|f| f.start).collect();
+    let entries = analysis::table_entries(code);
+
+    for (i, &instruction) in words.iter().enumerate() {
+        let next = (i as i32 + 1) * 4;
+        if !entries[i] && !falls_through(instruction) && (next as usize) < words.len() * 4 && !starts.contains(&next) {
+            starts.push(next);
+        }
+    }
+
+    /* A call that lands inside a table doesn't split it. */
+    starts.retain(|&start| !entries.get((start / 4) as usize).copied().unwrap_or(false));
+
+    starts.sort();
+
+    let code_end = (words.len() * 4) as i32;
+    starts.iter().enumerate()
+        .map(|(i, &start)| Function { start, end: starts.get(i + 1).copied().unwrap_or(code_end) })
+        .collect()
+}
+
+/* Drop every function that can't be reached from the given entry points, by calls, branches or
+ * falling off the end of the code before it. Returns the smaller program along with the pieces
+ * of code (at their old addresses) that were removed. The same caveat about data mixed in with
+ * the code applies as for inlining. */
+pub fn remove_unreachable_functions(code: &[u8], entry_points: &[i32]) -> (Vec<u8>, Vec<Function>) {
+    let word_count = code.len() / 4;
+    let words: Vec<u32> = (0..word_count)
+        .map(|i| analysis::instruction_at(code, (i * 4) as i32).unwrap_or(0))
+        .collect();
+    let functions = pieces(code, &words);
+    let entries = analysis::table_entries(code);
+    let containing = |address: i32| functions.iter().position(|f| (f.start..f.end).contains(&address));
+
+    let mut reachable = vec![false; functions.len()];
+    let mut pending: Vec<usize> = entry_points.iter().filter_map(|&entry| containing(entry)).collect();
+
+    while let Some(index) = pending.pop() {
+        if reachable[index] {
+            continue;
+        }
+        reachable[index] = true;
+
+        let function = functions[index];
+        for address in (function.start..function.end).step_by(4) {
+            let i = (address / 4) as usize;
+            if let Some(target_index) = target(address, words[i], entries[i]).and_then(containing) {
+                pending.push(target_index);
+            }
+        }
+
+        let last = (function.end / 4) as usize - 1;
+        if (entries[last] || falls_through(words[last])) && index + 1 < functions.len() {
+            pending.push(index + 1);
+        }
+    }
+
+    let mut emitted = Vec::new();
+    let mut new_addresses = BTreeMap::new();
+    let mut removed = Vec::new();
+
+    for (function, &keep) in functions.iter().zip(&reachable) {
+        if !keep {
+            removed.push(*function);
+            continue;
+        }
+
+        for address in (function.start..function.end).step_by(4) {
+            new_addresses.insert(address, (emitted.len() * 4) as i32);
+            emitted.push((words[(address / 4) as usize], address, false));
+        }
+    }
+
+    (relocate(code, &emitted, &new_addresses), removed)
+}
+
+/* Move the program's functions around into a random (but seeded, so repeatable) order, fixing
+ * up every branch to match. The code at address 0 stays put, as does any code that runs off its
+ * end into the next piece. A program that still behaves the same under every seed doesn't
+ * depend on where its code lives. */
+pub fn shuffle_functions(code: &[u8], seed: u64) -> Vec<u8> {
+    let word_count = code.len() / 4;
+    let words: Vec<u32> = (0..word_count)
+        .map(|i| analysis::instruction_at(code, (i * 4) as i32).unwrap_or(0))
+        .collect();
+
+    /* Glue pieces that fall through into the one after them. */
+    let entries = analysis::table_entries(code);
+    let mut chains: Vec<Vec<Function>> = Vec::new();
+    let mut glued = false;
+    for piece in pieces(code, &words) {
+        match chains.last_mut() {
+            Some(chain) if glued => chain.push(piece),
+            _ => chains.push(vec![piece]),
+        }
+
+        let last = (piece.end / 4) as usize - 1;
+        glued = entries[last] || falls_through(words[last]);
+    }
+
+    /* Fisher-Yates over everything but the first chain. */
+    let mut rng = Rng::new(seed);
+    for i in (2..chains.len()).rev() {
+        let j = 1 + rng.below(i as u64) as usize;
+        chains.swap(i, j);
+    }
+
+    let mut emitted = Vec::new();
+    let mut new_addresses = BTreeMap::new();
+
+    for piece in chains.iter().flatten() {
+        for address in (piece.start..piece.end).step_by(4) {
+            new_addresses.insert(address, (emitted.len() * 4) as i32);
+            emitted.push((words[(address / 4) as usize], address, false));
+        }
+    }
+
+    relocate(code, &emitted, &new_addresses)
+}
+
+/* What the peephole pass did to a program. */
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Peephole {
+    pub instructions: Vec<Instruction>,
+    /* Where each of the original instructions went, as an index into instructions. One that
+     * was dropped goes to whatever came after it, and one folded into a push goes to the push. */
+    pub moved: Vec<usize>,
+    /* push a; push b; op sequences turned into a single push. */
+    pub folded: usize,
+    /* push or dup straight followed by pop, both dropped. */
+    pub pairs_removed: usize,
+    /* Branches pointed past a goto at wherever the goto goes, and gotos to the very next
+     * instruction dropped. */
+    pub jumps_collapsed: usize,
+}
+
+/* The binary operations that give the same answer in every word size and arithmetic mode, as
+ * long as nothing overflows a push. */
+fn fold(op: BinaryOp, left: i64, right: i64) -> Option<i64> {
+    let result = match op {
+        BinaryOp::Add => left + right,
+        BinaryOp::Sub => left - right,
+        BinaryOp::Mul => left * right,
+        BinaryOp::Div if right != 0 => left / right,
+        BinaryOp::Rem if right != 0 => left % right,
+        BinaryOp::And => left & right,
+        BinaryOp::Or => left | right,
+        BinaryOp::Xor => left ^ right,
+        _ => return None,
+    };
+
+    (PUSH_MIN..=PUSH_MAX).contains(&result).then_some(result)
+}
+
+const PUSH_MIN: i64 = -(1 << 27);
+const PUSH_MAX: i64 = (1 << 27) - 1;
+
+fn with_offset(instruction: Instruction, offset: i32) -> Instruction {
+    match instruction {
+        Instruction::Call(_) => Instruction::Call(offset),
+        Instruction::TailCall(_) => Instruction::TailCall(offset),
+        Instruction::Goto(_) => Instruction::Goto(offset),
+        Instruction::BinaryIf(condition, _) => Instruction::BinaryIf(condition, offset),
+        Instruction::UnaryIf(condition, _) => Instruction::UnaryIf(condition, offset),
+        Instruction::Spawn(_) => Instruction::Spawn(offset),
+        Instruction::Lea(_) => Instruction::Lea(offset),
+        Instruction::Assert(_) => Instruction::Assert(offset),
+        other => other,
+    }
+}
+
+/* An instruction still in the program, where it came from, and for a branch, the original
+ * instruction it goes to. */
+struct Item {
+    instruction: Instruction,
+    origin: usize,
+    target: Option<usize>,
+}
+
+/* Small local clean-ups over a program starting at address 0: fold constant arithmetic, drop
+ * pushes that are popped straight away, and collapse jump chains. Every branch is fixed up to
+ * match. Nothing is folded away if a branch lands in the middle of it. A branch that doesn't
+ * land on an instruction of the program means it can't be known what's safe to touch, so the
+ * program comes back as it was. */
+pub fn peephole(program: &[Instruction]) -> Peephole {
+    let unchanged = Peephole { instructions: program.to_vec(), moved: (0..program.len()).collect(), ..Peephole::default() };
+    let mut report = Peephole::default();
+
+    let mut items = Vec::with_capacity(program.len());
+    for (index, &instruction) in program.iter().enumerate() {
+        let target = match instruction.branch_offset() {
+            Some(offset) if offset % 4 != 0 => return unchanged,
+            Some(offset) => match usize::try_from(index as i64 + offset as i64 / 4) {
+                Ok(target) if target < program.len() => Some(target),
+                _ => return unchanged,
+            },
+            None => None,
+        };
+        items.push(Item { instruction, origin: index, target });
+    }
+
+    /* Follow chains of gotos to where they end up, minding loops. */
+    for i in 0..items.len() {
+        let Some(mut target) = items[i].target else {
+            continue;
+        };
+
+        let mut seen = Vec::from([i]);
+        while let (Instruction::Goto(_), Some(next)) = (program[target], items[target].target) {
+            if seen.contains(&target) {
+                break;
+            }
+            seen.push(target);
+            target = next;
+        }
+
+        if Some(target) != items[i].target {
+            items[i].target = Some(target);
+            report.jumps_collapsed += 1;
+        }
+    }
+
+    /* Dropped instructions, and ones absorbed into a fold. */
+    let mut removed = vec![false; program.len()];
+    loop {
+        let targets: Vec<usize> = items.iter().filter_map(|item| item.target).collect();
+        let landed_on = |item: &Item| targets.contains(&item.origin);
+        let mut changed = false;
+
+        let mut i = 0;
+        while i < items.len() {
+            let window = &items[i..(i + 3).min(items.len())];
+            match window.iter().map(|item| item.instruction).collect::<Vec<_>>().as_slice() {
+                [Instruction::Push(left), Instruction::Push(right), Instruction::Binary(op), ..]
+                    if !landed_on(&window[1]) && !landed_on(&window[2]) =>
+                {
+                    if let Some(value) = fold(*op, *left as i64, *right as i64) {
+                        items[i].instruction = Instruction::Push(value as i32);
+                        items.drain(i + 1..i + 3);
+                        report.folded += 1;
+                        changed = true;
+                        continue;
+                    }
+                },
+                [Instruction::Push(_) | Instruction::Dup(_), Instruction::Pop(4), ..] if !landed_on(&window[1]) => {
+                    removed[items[i].origin] = true;
+                    removed[items[i + 1].origin] = true;
+                    items.drain(i..i + 2);
+                    report.pairs_removed += 1;
+                    changed = true;
+                    continue;
+                },
+                _ => (),
+            }
+            i += 1;
+        }
+
+        /* A goto to whatever comes next does nothing. */
+        let mut i = 0;
+        while i < items.len() {
+            let next = items.get(i + 1).map(|item| item.origin);
+            let lands_next = |target: usize| (target..).find(|&index| index >= program.len() || !removed[index]) == next;
+            if let (Instruction::Goto(_), Some(target)) = (items[i].instruction, items[i].target) {
+                if next.is_some() && target > items[i].origin && lands_next(target) {
+                    removed[items[i].origin] = true;
+                    items.remove(i);
+                    report.jumps_collapsed += 1;
+                    changed = true;
+                    continue;
+                }
+            }
+            i += 1;
+        }
+
+        if !changed {
+            break;
+        }
+    }
+
+    /* An original instruction that's gone moves to the next one still there; one folded away
+     * stays with the push it became part of. */
+    let mut moved = vec![items.len(); program.len()];
+    let mut position = 0;
+    for (index, slot) in moved.iter_mut().enumerate() {
+        while position < items.len() && items[position].origin < index {
+            position += 1;
+        }
+        let here = position < items.len() && items[position].origin == index;
+        *slot = if here || removed[index] { position } else { position - 1 };
+    }
+
+    report.instructions = items.iter().enumerate()
+        .map(|(position, item)| match item.target {
+            Some(target) => with_offset(item.instruction, (moved[target] as i32 - position as i32) * 4),
+            None => item.instruction,
+        })
+        .collect();
+    report.moved = moved;
+    report
+}
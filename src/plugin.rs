@@ -0,0 +1,82 @@
+/* Plugin opcodes: instructions from outside the crate. An opcode the VM doesn't use itself
+ * (only 10, for now) can be claimed with VirtualMachine::register_opcode, and from then on every
+ * word with that opcode goes to the handler, all 32 bits of it, so the handler decides what the
+ * other 28 mean. Without a handler they're bad instructions, as before.
+ *
+ * A handler gets at the machine through a Machine, which has the stack, memory and output, and
+ * can branch. */
+
+use alloc::format;
+use alloc::string::String;
+
+use crate::{VirtualMachine, VmError};
+
+/* Opcodes the VM doesn't have instructions for. */
+pub const FREE_OPCODES: [u8; 1] = [10];
+
+pub trait OpcodeHandler {
+    fn execute(&mut self, instruction: u32, machine: &mut Machine<'_>) -> Result<(), VmError>;
+}
+
+/* What a handler may do to the machine while its instruction runs. */
+pub struct Machine<'a> {
+    vm: &'a mut VirtualMachine,
+}
+
+impl<'a> Machine<'a> {
+    pub(crate) fn new(vm: &'a mut VirtualMachine) -> Machine<'a> {
+        Machine { vm }
+    }
+
+    pub fn push(&mut self, value: i64) -> Result<(), VmError> {
+        let value = self.vm.wrap_word(value);
+        self.vm.push_int_onto_stack(value)
+    }
+
+    pub fn pop(&mut self) -> Result<i64, VmError> {
+        self.vm.pop_int_from_stack()
+    }
+
+    /* The word offset bytes above the stack pointer, without popping it. */
+    pub fn peek(&self, offset: i32) -> Result<i64, VmError> {
+        self.vm.peek_int_from_stack(offset)
+    }
+
+    pub fn load(&self, address: i32) -> Result<i64, VmError> {
+        self.vm.read_word(address)
+    }
+
+    pub fn store(&mut self, address: i32, value: i64) -> Result<(), VmError> {
+        self.vm.write_word(address, value)
+    }
+
+    /* Bytes in a stack word. */
+    pub fn word_bytes(&self) -> i32 {
+        self.vm.word_bytes()
+    }
+
+    /* The address of the instruction being run. */
+    pub fn pc(&self) -> i32 {
+        self.vm.program_counter
+    }
+
+    /* Carry on at a byte offset from the instruction being run, instead of the next one. */
+    pub fn branch(&mut self, offset: i32) {
+        self.vm.program_counter += offset - 4;
+    }
+
+    pub fn print(&mut self, text: &str) -> Result<(), VmError> {
+        Ok(self.vm.write_output(text)?)
+    }
+}
+
+/* Check an opcode is one a plugin can have. */
+pub(crate) fn check_opcode(opcode: u8) -> Result<(), String> {
+    if FREE_OPCODES.contains(&opcode) {
+        Ok(())
+    } else if opcode < 16 {
+        Err(format!("Opcode {} is already one of the VM's own.", opcode))
+    } else {
+        Err(format!("There's no opcode {}; they only go up to 15.", opcode))
+    }
+}
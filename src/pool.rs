@@ -0,0 +1,105 @@
+/* Running a lot of programs at once, for graders and the like. Each program in a VmPool is a
+ * machine of its own, with its own config (so its own fuel and timeout) and its own input, and
+ * everything it prints is kept. run hands the programs out to a few threads and gives back what
+ * each one did, in the order they were added. */
+
+use std::io::Cursor;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+use crate::harness::SharedBuffer;
+use crate::{RunReport, VirtualMachine, VmConfig, VmError};
+
+/* A program waiting for its turn. */
+struct Job {
+    name: String,
+    vm: VirtualMachine,
+    input: Vec<u8>,
+}
+
+/* How one program in the pool went. */
+#[derive(Debug)]
+pub struct PoolRun {
+    pub name: String,
+    pub report: Result<RunReport, VmError>,
+    pub stdout: String,
+    /* What went to the diagnostics: debug and dump output, mostly. */
+    pub diagnostics: String,
+}
+
+pub struct VmPool {
+    threads: usize,
+    jobs: Vec<Job>,
+}
+
+impl VmPool {
+    /* A pool running up to threads programs at a time, or as many as the host has cores for
+     * 0. */
+    pub fn new(threads: usize) -> VmPool {
+        let threads = match threads {
+            0 => thread::available_parallelism().map_or(1, |n| n.get()),
+            n => n,
+        };
+
+        VmPool { threads, jobs: Vec::new() }
+    }
+
+    /* Add a loaded program, to be run with input as its stdin. */
+    pub fn add(&mut self, name: &str, vm: VirtualMachine, input: Vec<u8>) {
+        self.jobs.push(Job { name: String::from(name), vm, input });
+    }
+
+    /* Load a .v file and add it. */
+    pub fn add_file(&mut self, path: &str, config: VmConfig, input: Vec<u8>) -> Result<(), String> {
+        let vm = VirtualMachine::from_file(path, config).map_err(|e| format!("{}: {}", path, e))?;
+        self.add(path, vm, input);
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.jobs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.jobs.is_empty()
+    }
+
+    /* Run everything, each thread taking the next program as it finishes the last. */
+    pub fn run(self) -> Vec<PoolRun> {
+        let count = self.jobs.len();
+        let jobs: Vec<Mutex<Option<Job>>> = self.jobs.into_iter().map(|job| Mutex::new(Some(job))).collect();
+        let runs: Vec<Mutex<Option<PoolRun>>> = (0..count).map(|_| Mutex::new(None)).collect();
+        let next = AtomicUsize::new(0);
+
+        thread::scope(|scope| {
+            for _ in 0..self.threads.min(count) {
+                scope.spawn(|| loop {
+                    let index = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(slot) = jobs.get(index) else {
+                        break;
+                    };
+
+                    let job = slot.lock().expect("pool job poisoned").take().expect("each job is taken once");
+                    *runs[index].lock().expect("pool run poisoned") = Some(run_job(job));
+                });
+            }
+        });
+
+        runs.into_iter()
+            .map(|run| run.into_inner().expect("pool run poisoned").expect("every job was run"))
+            .collect()
+    }
+}
+
+fn run_job(job: Job) -> PoolRun {
+    let Job { name, mut vm, input } = job;
+    let (stdout, diagnostics) = (SharedBuffer::new(), SharedBuffer::new());
+    vm.set_input(Box::new(Cursor::new(input)));
+    vm.set_output(Box::new(stdout.clone()));
+    vm.set_diagnostics(Box::new(diagnostics.clone()));
+
+    let report = vm.run_with_report();
+
+    PoolRun { name, report, stdout: stdout.text(), diagnostics: diagnostics.text() }
+}
@@ -0,0 +1,415 @@
+/* What the assembler does to the source before it looks at the instructions: expanding macros
+ * and turning aliases into the mnemonics they stand for. What comes out is still source, a line
+ * at a time, each with the number of the line it came from so errors point at what was written.
+ *
+ * A macro is the lines between .macro and .endm, with parameters written \name in them:
+ *
+ *     .macro inc by
+ *             push \by
+ *             add
+ *     .endm
+ *
+ *             inc 3           # push 3, add
+ *
+ * It's used like an instruction, with one operand for each parameter, and can use other macros
+ * but not define them. \@ is a different number every time a macro is expanded, for labels
+ * inside one, as in loop\@:. Every line a macro expands to counts as the line it was used on.
+ *
+ * The aliases are jmp for goto, ret for return, jz and jnz for ifez and ifnz, and if.eq,
+ * cmp.eq and the like for every if and cmp.
+ *
+ * .include "file" puts a file's lines in place of the line, the same as if they'd been written
+ * there, macros and all; asm::Includes says where the file comes from, and failing that the
+ * standard library (see the stdlib module). Each file's lines get
+ * numbers of their own, after every file's before them, so an error can still be traced back to
+ * the file and line it's on.
+ *
+ * .if puts in the lines up to its .endif only when its condition isn't 0, and the ones between an
+ * .else and the .endif only when it is. The condition is an expression of the names defined for
+ * the whole assembly, such as with vm asm --define, where a name that isn't defined is 0; .equ
+ * constants and labels aren't known yet. They can go inside each other, and in macros, but each
+ * has to end in the file or macro it started in:
+ *
+ *     .if WORDS64
+ *             .feature words64
+ *     .else
+ *             push 4
+ *     .endif */
+
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::asm::{error, parse_line, parse_string, strip_comment, AsmError, Includes};
+use crate::expr::{Expr, State};
+use crate::stdlib;
+
+/* How deep macros can use macros before it's taken to be one using itself. */
+const MAX_DEPTH: usize = 64;
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<String>,
+}
+
+/* The source once it's been through here. */
+pub(crate) struct Expanded {
+    /* Every line, with its number. */
+    pub(crate) lines: Vec<(usize, String)>,
+    /* The number before the first line of each file, and its name, in order, starting with the
+     * file assembled. */
+    files: Vec<(usize, String)>,
+}
+
+impl Expanded {
+    /* Which file a line number is in, as an index into files, and its line in there. */
+    pub(crate) fn position(&self, number: usize) -> (usize, usize) {
+        let i = self.files.partition_point(|&(base, _)| base < number).saturating_sub(1);
+        (i, number - self.files[i].0)
+    }
+
+    /* Which file a line number is in, or None for the file assembled, and its line in there. */
+    pub(crate) fn locate(&self, number: usize) -> (Option<&str>, usize) {
+        let (i, line) = self.position(number);
+        ((i > 0).then_some(self.files[i].1.as_str()), line)
+    }
+
+    /* The name of every file, the one assembled first and then the ones it included, in the
+     * order they were. */
+    pub(crate) fn files(&self) -> Vec<String> {
+        self.files.iter().map(|(_, name)| name.clone()).collect()
+    }
+
+    /* A line number the way an error would put it, as in line 3 of lib.s. */
+    pub(crate) fn describe(&self, number: usize) -> String {
+        match self.locate(number) {
+            (Some(file), line) => format!("line {} of {}", line, file),
+            (None, line) => format!("line {}", line),
+        }
+    }
+
+    /* An error with its line number traced back to the file it's in. */
+    pub(crate) fn trace(&self, err: AsmError) -> AsmError {
+        let (file, line) = self.locate(err.line);
+        AsmError { line, file: file.map(String::from), message: err.message }
+    }
+}
+
+/* An .if still open: where it was, whether the lines around it were being assembled, what its
+ * condition came to, and whether its .else has been reached. */
+struct Condition {
+    line: usize,
+    outer: bool,
+    value: bool,
+    in_else: bool,
+}
+
+/* The names an .if can use, which are the ones defined for the whole assembly. One that isn't
+ * defined is 0, so .if DEBUG is false unless DEBUG is given. */
+struct Defines<'a>(&'a BTreeMap<String, i64>);
+
+impl State for Defines<'_> {
+    fn variable(&self, name: &str) -> Result<i64, String> {
+        Ok(self.0.get(name).copied().unwrap_or(0))
+    }
+
+    fn word(&self, _: i64) -> Result<i64, String> {
+        Err(String::from("there's no memory to read while assembling"))
+    }
+
+    fn byte(&self, _: i64) -> Result<i64, String> {
+        Err(String::from("there's no memory to read while assembling"))
+    }
+
+    fn slot(&self, _: i64) -> Result<i64, String> {
+        Err(String::from("there's no stack to read while assembling"))
+    }
+}
+
+struct Expander<'i> {
+    macros: BTreeMap<String, Macro>,
+    lines: Vec<(usize, String)>,
+    expansions: usize,
+    files: Vec<(usize, String)>,
+    /* Where the next file's numbers start. */
+    next_base: usize,
+    /* The files being included, innermost last, to catch one that includes itself. */
+    including: Vec<String>,
+    includes: &'i mut dyn Includes,
+    defines: &'i BTreeMap<String, i64>,
+    conditions: Vec<Condition>,
+}
+
+/* The source with its macros expanded, files included, aliases replaced and only the lines
+ * .if picks kept. name is what the source is called, for finding files it includes, and defines
+ * are the names .if can use. */
+pub(crate) fn expand(source: &str, name: &str, includes: &mut dyn Includes, defines: &BTreeMap<String, i64>)
+    -> Result<Expanded, AsmError> {
+    let mut expander = Expander {
+        macros: BTreeMap::new(),
+        lines: Vec::new(),
+        expansions: 0,
+        files: Vec::from([(0, String::from(name))]),
+        next_base: source.lines().count(),
+        including: Vec::from([String::from(name)]),
+        includes,
+        defines,
+        conditions: Vec::new(),
+    };
+
+    match expander.file(source, 0) {
+        Ok(()) => Ok(Expanded { lines: expander.lines, files: expander.files }),
+        Err(err) => Err(Expanded { lines: Vec::new(), files: expander.files }.trace(err)),
+    }
+}
+
+/* The first word of a line after any label, without picking the rest apart, since a macro's
+ * lines aren't source yet. */
+fn directive(text: &str) -> Option<&str> {
+    let text = strip_comment(text).trim();
+    let text = match text.split_once(':') {
+        Some((label, rest)) if !label.contains(char::is_whitespace) && !label.contains('"') => rest,
+        _ => text,
+    };
+    text.split_whitespace().next()
+}
+
+impl Expander<'_> {
+    /* The lines of one file, numbered from after base. */
+    fn file(&mut self, source: &str, base: usize) -> Result<(), AsmError> {
+        let mut lines = source.lines().enumerate().map(|(i, text)| (base + i + 1, text));
+        let open = self.conditions.len();
+
+        while let Some((number, text)) = lines.next() {
+            if self.conditional(number, text, open)? {
+                continue;
+            }
+            if directive(text) != Some(".macro") {
+                self.line(number, text, 0)?;
+                continue;
+            }
+
+            let (_, Some(line)) = parse_line(number, text)? else {
+                unreachable!("a .macro line has an instruction");
+            };
+            let Some((&name, params)) = line.operands.split_first() else {
+                return Err(error(number, String::from(".macro needs a name")));
+            };
+            if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.') {
+                return Err(error(number, format!("bad macro name {}", name)));
+            }
+            if self.macros.contains_key(name) {
+                return Err(error(number, format!("macro {} is already defined", name)));
+            }
+
+            let mut body = Vec::new();
+            loop {
+                let Some((inner, text)) = lines.next() else {
+                    return Err(error(number, format!("macro {} has no .endm", name)));
+                };
+                match directive(text) {
+                    Some(".endm") => break,
+                    Some(".macro") => return Err(error(inner, format!("a macro can't be defined inside another, {}", name))),
+                    _ => body.push(String::from(text)),
+                }
+            }
+            let params = params.iter().map(|&param| String::from(param)).collect();
+            self.macros.insert(String::from(name), Macro { params, body });
+        }
+
+        match self.conditions.get(open) {
+            Some(condition) => Err(error(condition.line, String::from(".if without an .endif"))),
+            None => Ok(()),
+        }
+    }
+
+    /* Whether the lines here are being assembled, rather than left out by an .if. */
+    fn assembling(&self) -> bool {
+        self.conditions.last().is_none_or(|condition| condition.outer && condition.value != condition.in_else)
+    }
+
+    /* Deal with a line if it's .if, .else or .endif, or one they leave out, saying whether it
+     * was. open is how many .ifs were open before the file or macro the line is in, which it
+     * can't close. */
+    fn conditional(&mut self, number: usize, text: &str, open: usize) -> Result<bool, AsmError> {
+        let directive = match directive(text) {
+            Some(directive @ (".if" | ".else" | ".endif")) => directive,
+            _ => return Ok(!self.assembling()),
+        };
+        let (label, Some(line)) = parse_line(number, text)? else {
+            unreachable!("an {} line has an instruction", directive);
+        };
+        if let Some(label) = label.filter(|_| self.assembling()) {
+            self.lines.push((number, format!("{}:", label)));
+        }
+
+        if directive == ".if" {
+            let outer = self.assembling();
+            let value = if outer { self.condition(number, &line.operands)? } else { false };
+            self.conditions.push(Condition { line: number, outer, value, in_else: false });
+            return Ok(true);
+        }
+
+        if self.conditions.len() == open {
+            return Err(error(number, format!("{} without an .if", directive)));
+        }
+        if !line.operands.is_empty() {
+            return Err(error(number, format!("{} takes no operands", directive)));
+        }
+        if directive == ".endif" {
+            self.conditions.pop();
+            return Ok(true);
+        }
+
+        let condition = self.conditions.last_mut().expect("an .if is open");
+        if condition.in_else {
+            return Err(error(number, String::from("a second .else for the same .if")));
+        }
+        condition.in_else = true;
+        Ok(true)
+    }
+
+    /* Whether an .if's condition holds, which is when it isn't 0. */
+    fn condition(&self, number: usize, operands: &[&str]) -> Result<bool, AsmError> {
+        if operands.is_empty() {
+            return Err(error(number, String::from(".if needs a condition")));
+        }
+        let text = operands.join(" ");
+        Expr::parse(&text)
+            .and_then(|expr| expr.evaluate(&Defines(self.defines)))
+            .map(|value| value != 0)
+            .map_err(|err| error(number, format!("bad condition {}: {}", text, err)))
+    }
+
+    fn line(&mut self, number: usize, text: &str, depth: usize) -> Result<(), AsmError> {
+        match directive(text) {
+            Some(".endm") => return Err(error(number, String::from(".endm without a .macro"))),
+            Some(".include") => return self.include(number, text),
+            _ => (),
+        }
+
+        let (label, Some(line)) = parse_line(number, text)? else {
+            self.lines.push((number, String::from(text)));
+            return Ok(());
+        };
+
+        if let Some(alias) = alias(line.mnemonic) {
+            let label = label.map_or(String::new(), |label| format!("{}: ", label));
+            self.lines.push((number, format!("{}{} {}", label, alias, line.operands.join(" "))));
+            return Ok(());
+        }
+        let Some(definition) = self.macros.get(line.mnemonic) else {
+            self.lines.push((number, String::from(text)));
+            return Ok(());
+        };
+
+        if depth == MAX_DEPTH {
+            return Err(error(number, format!("macros nested too deep; does {} use itself?", line.mnemonic)));
+        }
+        if line.operands.len() != definition.params.len() {
+            return Err(error(number, format!("{} takes {} operands, not {}", line.mnemonic, definition.params.len(), line.operands.len())));
+        }
+        if let Some(label) = label {
+            self.lines.push((number, format!("{}:", label)));
+        }
+
+        self.expansions += 1;
+        let expansion = format!("{}", self.expansions);
+        let body: Vec<String> = definition.body.iter()
+            .map(|text| substitute(text, &definition.params, &line.operands, &expansion))
+            .collect();
+        let open = self.conditions.len();
+        for text in body {
+            if !self.conditional(number, &text, open)? {
+                self.line(number, &text, depth + 1)?;
+            }
+        }
+        if self.conditions.len() > open {
+            return Err(error(number, format!("{} has an .if without an .endif", line.mnemonic)));
+        }
+        Ok(())
+    }
+
+    /* A .include line, replaced with the lines of the file it names. */
+    fn include(&mut self, number: usize, text: &str) -> Result<(), AsmError> {
+        let (label, Some(line)) = parse_line(number, text)? else {
+            unreachable!("an .include line has an instruction");
+        };
+        if let Some(label) = label {
+            self.lines.push((number, format!("{}:", label)));
+        }
+        let [operand] = line.operands.as_slice() else {
+            return Err(error(number, String::from(".include needs a file name in quotes")));
+        };
+        if !operand.starts_with('"') {
+            return Err(error(number, String::from(".include needs a file name in quotes")));
+        }
+
+        let name = parse_string(operand, number)?;
+        let i = self.files.partition_point(|&(base, _)| base < number).saturating_sub(1);
+        let (found, source) = match (self.includes.read(&name, &self.files[i].1), stdlib::file(&name)) {
+            (Ok(file), _) => file,
+            (Err(_), Some(text)) => (name, String::from(text)),
+            (Err(err), None) => return Err(error(number, format!("can't include {}: {}", name, err))),
+        };
+        if self.including.contains(&found) {
+            return Err(error(number, format!("{} includes itself", found)));
+        }
+
+        let base = self.next_base;
+        self.next_base += source.lines().count();
+        self.files.push((base, found.clone()));
+        self.including.push(found);
+        self.file(&source, base)?;
+        self.including.pop();
+        Ok(())
+    }
+}
+
+/* A macro's line with \name replaced by the operand for that parameter, and \@ by the
+ * expansion's number. Anything else after a backslash is left as it is. */
+fn substitute(text: &str, params: &[String], operands: &[&str], expansion: &str) -> String {
+    let mut out = String::new();
+    let mut rest = text;
+
+    while let Some(at) = rest.find('\\') {
+        out.push_str(&rest[..at]);
+        let after = &rest[at + 1..];
+        if let Some(after) = after.strip_prefix('@') {
+            out.push_str(expansion);
+            rest = after;
+            continue;
+        }
+
+        let end = after.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(after.len());
+        match params.iter().position(|param| param == &after[..end]) {
+            Some(i) if end > 0 => {
+                out.push_str(operands[i]);
+                rest = &after[end..];
+            },
+            _ => {
+                out.push('\\');
+                rest = after;
+            },
+        }
+    }
+
+    out.push_str(rest);
+    out
+}
+
+/* The mnemonic an alias stands for. */
+pub(crate) fn alias(mnemonic: &str) -> Option<String> {
+    let alias = match mnemonic {
+        "jmp" => "goto",
+        "ret" => "return",
+        "jz" => "ifez",
+        "jnz" => "ifnz",
+        _ => {
+            return mnemonic.strip_prefix("if.").map(|condition| format!("if{}", condition))
+                .or_else(|| mnemonic.strip_prefix("cmp.").map(|condition| format!("cmp{}", condition)));
+        },
+    };
+    Some(String::from(alias))
+}
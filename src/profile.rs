@@ -0,0 +1,157 @@
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::time::Duration;
+
+use crate::isa::Instruction;
+use crate::MEMORY_SIZE;
+
+/* Execution counts gathered while a program runs with VmConfig::profile set. Instructions are
+ * always word aligned, so there's one slot per word of memory for each kind of count. */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Profile {
+    executed: Vec<u64>,
+    called: Vec<u64>,
+    /* Nanoseconds spent in each instruction's handler, when there's a clock to tell. */
+    nanos: Vec<u64>,
+}
+
+/* The time spent on one kind of instruction, as in every push, whatever it pushed. */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpcodeTime {
+    pub mnemonic: String,
+    pub count: u64,
+    pub total: Duration,
+}
+
+impl OpcodeTime {
+    /* Nanoseconds for each one on average. */
+    pub fn average_nanos(&self) -> u64 {
+        (self.total.as_nanos() / self.count.max(1) as u128) as u64
+    }
+}
+
+impl Default for Profile {
+    fn default() -> Profile {
+        Profile::new()
+    }
+}
+
+impl Profile {
+    pub fn new() -> Profile {
+        Profile {
+            executed: vec![0; MEMORY_SIZE / 4],
+            called: vec![0; MEMORY_SIZE / 4],
+            nanos: vec![0; MEMORY_SIZE / 4],
+        }
+    }
+
+    pub(crate) fn record_instruction(&mut self, pc: i32) {
+        if let Some(count) = self.executed.get_mut((pc / 4) as usize) {
+            *count += 1;
+        }
+    }
+
+    /* How long the handler for the instruction at pc took this time. */
+    pub(crate) fn record_time(&mut self, pc: i32, time: Duration) {
+        if let Some(nanos) = self.nanos.get_mut((pc / 4) as usize) {
+            *nanos += time.as_nanos() as u64;
+        }
+    }
+
+    pub(crate) fn record_call(&mut self, target: i32) {
+        if let Some(count) = self.called.get_mut((target / 4) as usize) {
+            *count += 1;
+        }
+    }
+
+    /* Every instruction that ran, as (address, times executed), hottest first. */
+    pub fn hottest_instructions(&self) -> Vec<(i32, u64)> {
+        Profile::sorted(&self.executed)
+    }
+
+    /* Every function that got called, as (address, times called), most called first. */
+    pub fn hottest_call_targets(&self) -> Vec<(i32, u64)> {
+        Profile::sorted(&self.called)
+    }
+
+    /* The time spent on each kind of instruction, most first, worked out from the times for each
+     * address and what's at that address in code. Each handler is timed on its own, so this
+     * leaves out the VM's work between instructions but not the cost of reading the clock,
+     * which evens out the cheapest instructions. Empty without a clock. */
+    pub fn opcode_times(&self, code: &[u8]) -> Vec<OpcodeTime> {
+        let mut times: BTreeMap<String, OpcodeTime> = BTreeMap::new();
+        if self.nanos.iter().all(|&nanos| nanos == 0) {
+            return Vec::new();
+        }
+        for (i, (&count, &nanos)) in self.executed.iter().zip(&self.nanos).enumerate() {
+            if count == 0 {
+                continue;
+            }
+            let mnemonic = crate::analysis::instruction_at(code, (i * 4) as i32)
+                .and_then(Instruction::decode)
+                .map_or(String::from("?"), |instruction| {
+                    instruction.to_string().split(' ').next().unwrap_or_default().to_string()
+                });
+            let time = times.entry(mnemonic.clone()).or_insert(OpcodeTime { mnemonic, count: 0, total: Duration::ZERO });
+            time.count += count;
+            time.total += Duration::from_nanos(nanos);
+        }
+
+        let mut times: Vec<OpcodeTime> = times.into_values().collect();
+        times.sort_by(|a, b| b.total.cmp(&a.total).then_with(|| a.mnemonic.cmp(&b.mnemonic)));
+        times
+    }
+
+    fn sorted(counts: &[u64]) -> Vec<(i32, u64)> {
+        let mut sorted: Vec<(i32, u64)> = counts.iter().enumerate()
+            .filter(|(_, &count)| count > 0)
+            .map(|(i, &count)| ((i * 4) as i32, count))
+            .collect();
+
+        /* Ties go to the lower address so the report doesn't shuffle between runs. */
+        sorted.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        sorted
+    }
+
+    /* A summary of the top entries of each list. code is the program, for showing what each
+     * hot instruction was. */
+    pub fn report(&self, code: &[u8], top: usize) -> String {
+        let total: u64 = self.executed.iter().sum();
+        let mut report = format!("profile: {} instructions executed\n", total);
+
+        report.push_str("\nhottest instructions:\n");
+        report.push_str(&format!("  {:<4}  {:<8}  {:>10}  {:>5}\n", "ADDR", "INSTR", "COUNT", "%"));
+        for (address, count) in self.hottest_instructions().into_iter().take(top) {
+            let instruction = crate::analysis::instruction_at(code, address)
+                .map(|word| format!("{:08x}", word))
+                .unwrap_or_else(|| String::from("--------"));
+            let percent = count as f64 * 100.0 / total as f64;
+            report.push_str(&format!("  {:04x}  {}  {:>10}  {:5.1}\n", address, instruction, count, percent));
+        }
+
+        report.push_str("\nhottest call targets:\n");
+        report.push_str(&format!("  {:<4}  {:>10}\n", "ADDR", "CALLS"));
+        let targets = self.hottest_call_targets();
+        if targets.is_empty() {
+            report.push_str("  (no calls)\n");
+        }
+        for (address, count) in targets.into_iter().take(top) {
+            report.push_str(&format!("  {:04x}  {:>10}\n", address, count));
+        }
+
+        let times = self.opcode_times(code);
+        if !times.is_empty() {
+            report.push_str("\ntime by instruction:\n");
+            report.push_str(&format!("  {:<10}  {:>10}  {:>12}  {:>8}\n", "INSTR", "COUNT", "TOTAL", "NS/OP"));
+            for time in &times {
+                report.push_str(&format!("  {:<10}  {:>10}  {:>12}  {:>8}\n",
+                    time.mnemonic, time.count, format!("{:.3?}", time.total), time.average_nanos()));
+            }
+        }
+
+        report
+    }
+}
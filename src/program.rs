@@ -0,0 +1,196 @@
+/* A program as the instructions in it rather than as bytes, for tools that look at code without
+ * running it: linters, optimizers, visualizers. Program does the decoding once, the way the VM
+ * would, and knows where the code stops and the data starts, and which words after a jumptable
+ * are its table rather than instructions.
+ *
+ *     let program = Program::from_image(&fs::read("prog.v")?)?;
+ *     for (address, instruction) in program.instructions() {
+ *         println!("{:04x}: {}", address, instruction);
+ *     }
+ */
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use crate::isa::Instruction;
+use crate::Header;
+
+/* The code of a loaded program and where it starts running. */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Program {
+    code: Vec<u8>,
+    entry: i32,
+}
+
+/* A run of instructions that's only ever entered at the top and only leaves at the bottom. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BasicBlock {
+    pub start: i32,
+    /* Just past the last instruction. */
+    pub end: i32,
+}
+
+impl BasicBlock {
+    pub fn contains(&self, address: i32) -> bool {
+        (self.start..self.end).contains(&address)
+    }
+
+    /* How many words the block spans. */
+    pub fn size(&self) -> usize {
+        ((self.end - self.start) / 4) as usize
+    }
+}
+
+impl Program {
+    /* Code that starts running at address 0. Anything after the last whole word is ignored. */
+    pub fn new(code: Vec<u8>) -> Program {
+        Program { code, entry: 0 }
+    }
+
+    /* The code of a .v file, leaving out its data. */
+    pub fn from_image(image: &[u8]) -> Result<Program, String> {
+        let (header, body, _) = Header::parse(image)?;
+        let code_end = header.code_size.map_or(body.len(), |size| size as usize);
+        let code = body.get(..code_end).ok_or("The header says there's more code than the file has.")?;
+
+        Ok(Program { code: code.to_vec(), entry: header.entry as i32 })
+    }
+
+    pub fn code(&self) -> &[u8] {
+        &self.code
+    }
+
+    pub fn entry(&self) -> i32 {
+        self.entry
+    }
+
+    /* Where the code ends, at the last whole word. */
+    pub fn end(&self) -> i32 {
+        (self.code.len() / 4 * 4) as i32
+    }
+
+    /* The word at an address, if it's a whole word inside the code. */
+    pub fn word_at(&self, address: i32) -> Option<u32> {
+        let start = usize::try_from(address).ok()?;
+        let bytes = self.code.get(start..start.checked_add(4)?)?;
+        Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /* Every instruction with its address, in order. Words that don't decode are skipped, and so
+     * are jumptable entries, which are offsets rather than instructions. */
+    pub fn instructions(&self) -> Instructions<'_> {
+        Instructions { program: self, address: 0 }
+    }
+
+    /* Every place control can go besides the next instruction, as (from, to) pairs in address
+     * order: calls, tail calls, gotos, ifs, spawns and each jumptable entry. Targets aren't
+     * checked, so a target outside the code shows up as it is. Returns, jumpi and exit go
+     * somewhere only the stack knows, so they have none. */
+    pub fn branch_targets(&self) -> Vec<(i32, i32)> {
+        let mut targets = Vec::new();
+
+        for (address, instruction) in self.instructions() {
+            match instruction {
+                Instruction::JumpTable(entries) => {
+                    for entry in self.table(address, entries).step_by(4) {
+                        if let Some(offset) = self.word_at(entry) {
+                            targets.push((address, entry.wrapping_add(offset as i32)));
+                        }
+                    }
+                },
+                _ => {
+                    if let Some(offset) = branch_offset(&instruction) {
+                        targets.push((address, address.wrapping_add(offset)));
+                    }
+                },
+            }
+        }
+
+        targets
+    }
+
+    /* The code cut up into basic blocks, in address order. A block starts at address 0, the
+     * entry, every branch target and every address a lea takes (which a jumpi may go to later),
+     * and after every instruction that can go somewhere other than the next one. Jumptable
+     * entries belong to the jumptable's block, and words that aren't instructions to whichever
+     * block they fall in. */
+    pub fn basic_blocks(&self) -> Vec<BasicBlock> {
+        let end = self.end();
+        if end == 0 {
+            return Vec::new();
+        }
+
+        let mut leaders = vec![0, self.entry];
+        leaders.extend(self.branch_targets().into_iter().map(|(_, target)| target));
+        for (address, instruction) in self.instructions() {
+            match instruction {
+                Instruction::Lea(offset) => leaders.push(address.wrapping_add(offset)),
+                Instruction::JumpTable(entries) => leaders.push(self.table(address, entries).end),
+                _ if ends_block(&instruction) => leaders.push(address + 4),
+                _ => (),
+            }
+        }
+
+        /* Only word-aligned addresses inside the code can start a block. */
+        leaders.retain(|&leader| (0..end).contains(&leader) && leader % 4 == 0);
+        leaders.sort_unstable();
+        leaders.dedup();
+
+        leaders.iter().enumerate()
+            .map(|(i, &start)| BasicBlock { start, end: leaders.get(i + 1).copied().unwrap_or(end) })
+            .collect()
+    }
+
+    /* The addresses of the entries of the jumptable at address, cut short at the end of the
+     * code. */
+    fn table(&self, address: i32, entries: u32) -> Range<i32> {
+        let start = address + 4;
+        let end = (start as i64 + entries as i64 * 4).min(self.end() as i64) as i32;
+        start..end.max(start)
+    }
+}
+
+/* See Program::instructions. */
+pub struct Instructions<'a> {
+    program: &'a Program,
+    address: i32,
+}
+
+impl Iterator for Instructions<'_> {
+    type Item = (i32, Instruction);
+
+    fn next(&mut self) -> Option<(i32, Instruction)> {
+        loop {
+            let address = self.address;
+            let word = self.program.word_at(address)?;
+            self.address += 4;
+
+            if let Some(instruction) = Instruction::decode(word) {
+                if let Instruction::JumpTable(entries) = instruction {
+                    self.address = self.program.table(address, entries).end;
+                }
+                return Some((address, instruction));
+            }
+        }
+    }
+}
+
+/* The byte offset from an instruction to where it branches, for the ones that branch somewhere
+ * fixed. */
+fn branch_offset(instruction: &Instruction) -> Option<i32> {
+    match *instruction {
+        Instruction::Call(offset) | Instruction::TailCall(offset) | Instruction::Goto(offset) | Instruction::BinaryIf(_, offset)
+            | Instruction::UnaryIf(_, offset) | Instruction::Spawn(offset) => Some(offset),
+        _ => None,
+    }
+}
+
+/* Whether control can leave an instruction for anywhere but the next one. A call counts, since
+ * the next instruction only runs once the callee returns. */
+pub fn ends_block(instruction: &Instruction) -> bool {
+    matches!(instruction,
+        Instruction::Exit(_) | Instruction::Call(_) | Instruction::TailCall(_) | Instruction::Return(_) | Instruction::Goto(_)
+            | Instruction::BinaryIf(..) | Instruction::UnaryIf(..) | Instruction::JumpI | Instruction::JumpTable(_))
+}
@@ -0,0 +1,164 @@
+/* Python bindings, so test harnesses and visualizations can be scripted in a notebook against
+ * this exact interpreter. Build the module with maturin, or by hand:
+ *
+ *     cargo rustc --release --lib --crate-type cdylib --features python
+ *     cp target/release/libvm.so vm.so        # vm.pyd on Windows
+ *
+ * and then:
+ *
+ *     import vm
+ *     machine = vm.PyVm.assemble(open("fizzbuzz.s").read())
+ *     machine.run()           # the exit code
+ *     machine.stdout          # everything it printed
+ *
+ *     machine = vm.PyVm.load("echo.v", stdin="hello\n")
+ *     while machine.step():
+ *         print(machine.pc, machine.stack)
+ *
+ * A program's output is kept rather than printed, and its input is the string it was given.
+ * Anything that stops the machine with an error raises RuntimeError. */
+
+use std::fs;
+use std::io::Cursor;
+
+use pyo3::exceptions::PyRuntimeError;
+use pyo3::prelude::*;
+
+use crate::harness::SharedBuffer;
+use crate::{asm, StepResult, VirtualMachine, VmConfig};
+
+fn runtime_error(message: impl ToString) -> PyErr {
+    PyRuntimeError::new_err(message.to_string())
+}
+
+/* A machine with a program loaded. */
+#[pyclass(unsendable, module = "vm")]
+pub struct PyVm {
+    vm: VirtualMachine,
+    stdout: SharedBuffer,
+}
+
+impl PyVm {
+    fn with_input(mut vm: VirtualMachine, stdin: &str) -> PyVm {
+        let stdout = SharedBuffer::new();
+        vm.set_input(Box::new(Cursor::new(stdin.as_bytes().to_vec())));
+        vm.set_output(Box::new(stdout.clone()));
+        PyVm { vm, stdout }
+    }
+}
+
+#[pymethods]
+impl PyVm {
+    /* From the contents of a .v file. */
+    #[new]
+    #[pyo3(signature = (program, stdin = ""))]
+    fn new(program: Vec<u8>, stdin: &str) -> PyResult<PyVm> {
+        let vm = VirtualMachine::from_bytes(program, VmConfig::default()).map_err(runtime_error)?;
+        Ok(PyVm::with_input(vm, stdin))
+    }
+
+    /* From a .v file on disk. */
+    #[staticmethod]
+    #[pyo3(signature = (path, stdin = ""))]
+    fn load(path: &str, stdin: &str) -> PyResult<PyVm> {
+        let bytes = fs::read(path).map_err(|e| runtime_error(format!("Couldn't read {}: {}", path, e)))?;
+        PyVm::new(bytes, stdin)
+    }
+
+    /* From assembly source. */
+    #[staticmethod]
+    #[pyo3(signature = (source, stdin = ""))]
+    fn assemble(source: &str, stdin: &str) -> PyResult<PyVm> {
+        let program = asm::assemble(source).map_err(runtime_error)?;
+        PyVm::new(program.image(), stdin)
+    }
+
+    /* Run to the end and give back the exit code, or with max_steps, stop after that many
+     * instructions and give back None if it hasn't finished. */
+    #[pyo3(signature = (max_steps = None))]
+    fn run(&mut self, max_steps: Option<u64>) -> PyResult<Option<i32>> {
+        let Some(max_steps) = max_steps else {
+            return self.vm.run().map(Some).map_err(runtime_error);
+        };
+
+        for _ in 0..max_steps {
+            if !self.step()? {
+                break;
+            }
+        }
+        Ok(self.vm.exit_code())
+    }
+
+    /* Run one instruction. True while the program is still running, brk or not. */
+    fn step(&mut self) -> PyResult<bool> {
+        match self.vm.step().map_err(runtime_error)? {
+            StepResult::Running | StepResult::Breakpoint => Ok(true),
+            StepResult::Exited(_) => Ok(false),
+        }
+    }
+
+    /* The word at an address. */
+    fn peek(&self, address: i32) -> PyResult<i64> {
+        self.vm.word_at(address).ok_or_else(|| runtime_error(format!("There's no word at {:#x}.", address)))
+    }
+
+    /* Write a word at an address, the way a debugger would, code and all. */
+    fn poke(&mut self, address: i32, value: i64) -> PyResult<()> {
+        let mut bytes = vec![0; self.vm.config().word_size.bytes() as usize];
+        self.vm.memory().endianness().write(value as u64, &mut bytes);
+        self.vm.patch(address, &bytes).map_err(runtime_error)
+    }
+
+    /* Everything the program has printed so far. */
+    #[getter]
+    fn stdout(&self) -> String {
+        self.stdout.text()
+    }
+
+    #[getter]
+    fn pc(&self) -> i32 {
+        self.vm.program_counter()
+    }
+
+    #[getter]
+    fn sp(&self) -> i32 {
+        self.vm.stack_pointer()
+    }
+
+    /* The words on the stack, the top first. */
+    #[getter]
+    fn stack(&self) -> Vec<i64> {
+        let word_bytes = self.vm.config().word_size.bytes();
+        (self.vm.stack_pointer()..self.vm.memory().len() as i32)
+            .step_by(word_bytes as usize)
+            .filter_map(|address| self.vm.word_at(address))
+            .collect()
+    }
+
+    /* The exit code, once the program has exited. */
+    #[getter]
+    fn exit_code(&self) -> Option<i32> {
+        self.vm.exit_code()
+    }
+
+    #[getter]
+    fn instructions(&self) -> u64 {
+        self.vm.instruction_count()
+    }
+
+    /* The calls that haven't returned yet, as (call site, target) pairs, outermost first. */
+    #[getter]
+    fn call_stack(&self) -> Vec<(i32, i32)> {
+        self.vm.call_stack().iter().map(|frame| (frame.call_site, frame.target)).collect()
+    }
+
+    /* The same as text, innermost first, the way a debugger shows it. */
+    fn backtrace(&self) -> String {
+        self.vm.backtrace()
+    }
+}
+
+#[pymodule]
+fn vm(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_class::<PyVm>()
+}
@@ -0,0 +1,613 @@
+/* A second interpreter for the same instructions, written to be obviously right rather than
+ * fast, and `vm difftest`, which runs a program on it and on the VM side by side. After every
+ * instruction the two have to agree on the pc, the stack pointer, every byte of the stack and
+ * everything printed; the first place they don't is reported, which is usually the instruction
+ * the VM's dispatcher (or its threaded code) has got wrong.
+ *
+ * The reference goes by isa::Instruction rather than picking words apart itself, keeps memory
+ * as a plain Vec and words as i32, and only knows the default configuration: 32-bit words,
+ * wrapping arithmetic, packed strings, one stack and protected code. Programs that need
+ * anything else are turned away. Some instructions it leaves alone on purpose, because they
+ * can't come out the same twice (clock), need the scheduler (spawn, yield, join, lock and
+ * unlock) or read the VM's own bookkeeping (perfctr); a difftest stops comparing when it gets
+ * to one. Files, arguments and the environment aren't given to either machine, so the
+ * instructions that want them just fault, as they would in the VM. */
+
+use std::io::{self, Cursor};
+
+use crate::harness::SharedBuffer;
+use crate::isa::{EofMode, Instruction};
+use crate::memory::MEMORY_SIZE;
+use crate::rng::Rng;
+use crate::{asm, strings, Header, StepResult, VirtualMachine, VmConfig};
+
+const TOP: i32 = MEMORY_SIZE as i32;
+
+pub struct Reference {
+    memory: Vec<u8>,
+    pc: i32,
+    sp: i32,
+    code_end: i32,
+    data_end: i32,
+    readonly_data: bool,
+    /* The input not read yet. */
+    input: Vec<u8>,
+    output: Vec<u8>,
+    rng: Rng,
+    executed: u64,
+    exit_code: Option<i32>,
+}
+
+impl Reference {
+    /* Load the contents of a .v file, with input for the program to read and a seed for rand. */
+    pub fn load(image: &[u8], input: Vec<u8>, seed: u64) -> Result<Reference, String> {
+        let (header, code, _) = Header::parse(image)?;
+        if header.has(Header::WORDS_64) || header.has(Header::DUAL_STACK) || header.has(Header::BYTE_STRINGS)
+            || header.has(Header::LITTLE_ENDIAN) || header.has(Header::HEAP) {
+            return Err(String::from("The reference only runs programs with 32-bit big-endian words, packed strings, one stack and no heap."));
+        }
+        if code.len() > MEMORY_SIZE {
+            return Err(String::from("The program doesn't fit in memory."));
+        }
+
+        let mut memory = code.to_vec();
+        memory.resize(MEMORY_SIZE, 0);
+        let data_end = code.len() as i32;
+
+        Ok(Reference {
+            memory,
+            pc: header.entry as i32,
+            sp: header.stack_pointer as i32,
+            code_end: header.code_size.map_or(data_end, |size| size as i32),
+            data_end,
+            readonly_data: header.has(Header::READONLY_DATA),
+            input,
+            output: Vec::new(),
+            rng: Rng::new(seed),
+            executed: 0,
+            exit_code: None,
+        })
+    }
+
+    pub fn pc(&self) -> i32 {
+        self.pc
+    }
+
+    pub fn sp(&self) -> i32 {
+        self.sp
+    }
+
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+
+    /* The word at the pc, if it's in memory. */
+    pub fn word_at_pc(&self) -> Option<u32> {
+        let bytes = self.memory.get(usize::try_from(self.pc).ok()?..)?.get(..4)?;
+        Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /* The instruction about to run, if it's one the reference leaves alone. */
+    pub fn unsupported(&self) -> Option<Instruction> {
+        if self.exit_code.is_some() || self.pc + 4 > self.code_end {
+            return None;
+        }
+
+        let instruction = Instruction::decode(self.word_at_pc()?)?;
+        match instruction {
+            Instruction::Clock | Instruction::Spawn(_) | Instruction::Yield | Instruction::Join | Instruction::Lock
+                | Instruction::Unlock | Instruction::PerfCtr(_) => Some(instruction),
+            _ => None,
+        }
+    }
+
+    /* Run one instruction. Err is a fault, after which the reference is wherever it got to. */
+    pub fn step(&mut self) -> Result<StepResult, String> {
+        if let Some(code) = self.exit_code {
+            return Ok(StepResult::Exited(code));
+        }
+        if self.pc < 0 || self.pc + 4 > TOP {
+            return Err(format!("pc {:#x} is outside memory", self.pc));
+        }
+        if self.pc + 4 > self.code_end {
+            self.exit_code = Some(0);
+            return Ok(StepResult::Exited(0));
+        }
+
+        let word = self.word_at_pc().ok_or("pc outside memory")?;
+        let instruction = Instruction::decode(word).ok_or_else(|| format!("{:#010x} isn't an instruction", word))?;
+        self.executed += 1;
+        self.execute(instruction)
+    }
+
+    fn execute(&mut self, instruction: Instruction) -> Result<StepResult, String> {
+        let pc = self.pc;
+        let mut next = pc + 4;
+
+        match instruction {
+            Instruction::Exit(code) => {
+                self.pc = next;
+                self.exit_code = Some(code as i32);
+                return Ok(StepResult::Exited(code as i32));
+            },
+            Instruction::Swap { from, to } => {
+                let (from, to) = (self.sp + from * 4, self.sp + to * 4);
+                let (a, b) = (self.read(from)?, self.read(to)?);
+                self.write(from, b)?;
+                self.write(to, a)?;
+            },
+            Instruction::Nop | Instruction::Dump => (),
+            Instruction::Input { eof, retry } => self.input_number(eof, retry)?,
+            Instruction::StInput(max) => {
+                let line = self.read_line()?.unwrap_or_default();
+                let text = line.trim().as_bytes();
+                self.push_string(&text[..text.len().min(max as usize)])?;
+            },
+            Instruction::Debug { bytes, .. } => {
+                if bytes != 0 {
+                    let address = self.pop()?;
+                    self.bytes(address, bytes as i64)?;
+                }
+            },
+            Instruction::Pop(bytes) => {
+                if bytes % 4 != 0 {
+                    return Err(String::from("pop of a part of a word"));
+                }
+                self.sp = (self.sp + bytes as i32).min(TOP);
+            },
+            Instruction::Binary(op) => {
+                let right = self.pop()?;
+                let left = self.pop()?;
+                self.push(op.apply(left, right).ok_or("divide by zero")?)?;
+            },
+            Instruction::Cmp(condition) => {
+                let right = self.pop()?;
+                let left = self.pop()?;
+                self.push(condition.holds(left, right) as i32)?;
+            },
+            Instruction::Unary(op) => {
+                let operand = self.pop()?;
+                self.push(op.apply(operand))?;
+            },
+            Instruction::StPrint(offset) => {
+                let (text, _) = self.read_string(self.sp + offset)?;
+                let text: String = text.iter().map(|&byte| byte as char).collect();
+                self.output.extend_from_slice(text.as_bytes());
+            },
+            Instruction::Call(offset) => {
+                self.push(pc + 4)?;
+                next = pc + offset;
+            },
+            Instruction::TailCall(offset) | Instruction::Goto(offset) => next = pc + offset,
+            Instruction::Return(bytes) => {
+                self.sp += bytes as i32;
+                next = self.pop()?;
+            },
+            /* A missing operand counts as 0 here rather than faulting. */
+            Instruction::BinaryIf(condition, offset) => {
+                let (left, right) = (self.read(self.sp + 4).unwrap_or(0), self.read(self.sp).unwrap_or(0));
+                if condition.holds(left, right) {
+                    if offset % 4 != 0 {
+                        return Err(String::from("if to a part of a word"));
+                    }
+                    next = pc + offset;
+                }
+            },
+            Instruction::UnaryIf(condition, offset) => {
+                if condition.holds(self.read(self.sp)?) {
+                    if offset % 4 != 0 {
+                        return Err(String::from("if to a part of a word"));
+                    }
+                    next = pc + offset;
+                }
+            },
+            Instruction::ToReturnStack | Instruction::FromReturnStack => return Err(String::from("no return stack")),
+            Instruction::StrLen(offset) => {
+                let (text, _) = self.read_string(self.sp + offset)?;
+                self.push(text.len() as i32)?;
+            },
+            Instruction::StrCat => {
+                let (mut left, right) = self.pop_two_strings()?;
+                left.extend(right);
+                self.push_string(&left)?;
+            },
+            Instruction::StrCmp => {
+                let (left, right) = self.pop_two_strings()?;
+                self.push(left.cmp(&right) as i32)?;
+            },
+            Instruction::ReadFile | Instruction::WriteFile(_) | Instruction::Arg | Instruction::GetEnv => {
+                return Err(String::from("no files, arguments or environment"));
+            },
+            Instruction::Cycles => self.push((self.executed - 1) as i32)?,
+            Instruction::Rand => {
+                let random = self.rng.next_u64();
+                self.push(random as i32)?;
+            },
+            Instruction::Load => {
+                let address = self.pop()?;
+                let word = self.read(address)?;
+                self.push(word)?;
+            },
+            Instruction::Store => {
+                let address = self.pop()?;
+                let word = self.pop()?;
+                self.write(address, word)?;
+            },
+            Instruction::Cas => {
+                let address = self.pop()?;
+                let new = self.pop()?;
+                let old = self.pop()?;
+                self.writable(address, 4)?;
+                let found = self.read(address)?;
+                if found == old {
+                    self.write(address, new)?;
+                }
+                self.push(found)?;
+            },
+            Instruction::FetchAdd => {
+                let address = self.pop()?;
+                let delta = self.pop()?;
+                self.writable(address, 4)?;
+                let found = self.read(address)?;
+                self.write(address, found.wrapping_add(delta))?;
+                self.push(found)?;
+            },
+            Instruction::StPrintN => {
+                let address = self.pop()?;
+                let length = self.pop()?;
+                let bytes = self.bytes(address, length as i64)?.to_vec();
+                self.output.extend_from_slice(&bytes);
+            },
+            Instruction::PushByte => self.push_narrow(1)?,
+            Instruction::PushHalf => self.push_narrow(2)?,
+            Instruction::PopByte { signed } => self.pop_narrow(1, signed)?,
+            Instruction::PopHalf { signed } => self.pop_narrow(2, signed)?,
+            Instruction::JumpI => {
+                let address = self.pop()?;
+                if address < 0 || address % 4 != 0 || address + 4 > self.code_end {
+                    return Err(format!("jumpi to {:#x}", address));
+                }
+                next = address;
+            },
+            Instruction::JumpTable(entries) => {
+                let selector = self.pop()?;
+                let table = pc as i64 + 4;
+                if table + entries as i64 * 4 > self.code_end as i64 {
+                    return Err(String::from("jumptable past the end of the code"));
+                }
+                next = match u32::try_from(selector) {
+                    Ok(selector) if selector < entries => {
+                        let entry = table as i32 + selector as i32 * 4;
+                        let bytes = self.bytes(entry, 4)?;
+                        entry.wrapping_add(i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+                    },
+                    _ => table as i32 + entries as i32 * 4,
+                };
+            },
+            Instruction::Lea(offset) => self.push(pc + offset)?,
+            Instruction::Pick(depth) => {
+                let end = self.words_end(depth as i64 + 1)?;
+                let word = self.read(end - 4)?;
+                self.push(word)?;
+            },
+            Instruction::Roll(count) => {
+                let end = self.words_end(count as i64)?;
+                let mut words: Vec<i32> = (self.sp..end).step_by(4).map(|address| self.read(address)).collect::<Result<_, _>>()?;
+                if words.len() > 1 {
+                    self.writable(self.sp, end - self.sp)?;
+                    words.rotate_right(1);
+                    for (i, word) in words.into_iter().enumerate() {
+                        self.write(self.sp + i as i32 * 4, word)?;
+                    }
+                }
+            },
+            Instruction::Drop(count) => self.sp = self.words_end(count as i64)?,
+            Instruction::Atoi => {
+                let (text, used) = self.read_string(self.sp)?;
+                self.sp += used;
+                match number(&text) {
+                    Some(n) => {
+                        self.push(n)?;
+                        self.push(1)?;
+                    },
+                    None => {
+                        self.push(0)?;
+                        self.push(0)?;
+                    },
+                }
+            },
+            Instruction::Itoa => {
+                let n = self.pop()?;
+                self.push_string(n.to_string().as_bytes())?;
+            },
+            Instruction::PrintF(spec) => {
+                let value = self.read(self.sp)?;
+                self.output.extend_from_slice(spec.format(value as i64, value as u32 as u64).as_bytes());
+            },
+            Instruction::Brk => {
+                self.pc = next;
+                return Ok(StepResult::Breakpoint);
+            },
+            Instruction::Assert(_) => {
+                if self.pop()? == 0 {
+                    return Err(String::from("assertion failed"));
+                }
+            },
+            /* With one context and no guard the stack can grow right down to address 0. */
+            Instruction::StackDepth => self.push(self.sp)?,
+            Instruction::Dup(offset) => {
+                let word = self.read(self.sp + offset)?;
+                self.push(word)?;
+            },
+            Instruction::Print(offset, format) => {
+                let value = self.read(self.sp + offset)?;
+                self.output.extend_from_slice(format.format(value).as_bytes());
+            },
+            Instruction::Push(value) => self.push(value)?,
+            Instruction::Clock | Instruction::Spawn(_) | Instruction::Yield | Instruction::Join | Instruction::Lock
+                | Instruction::Unlock | Instruction::PerfCtr(_) => return Err(format!("the reference doesn't run {}", instruction)),
+        }
+
+        self.pc = next;
+        Ok(StepResult::Running)
+    }
+
+    /* length bytes of memory at address, all of them inside it. */
+    fn bytes(&self, address: i32, length: i64) -> Result<&[u8], String> {
+        usize::try_from(address).ok()
+            .zip(usize::try_from(length).ok())
+            .and_then(|(start, length)| self.memory.get(start..start.checked_add(length)?))
+            .ok_or_else(|| format!("{} bytes at {:#x} aren't all in memory", length, address))
+    }
+
+    fn read(&self, address: i32) -> Result<i32, String> {
+        let bytes = self.bytes(address, 4)?;
+        Ok(i32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /* Whether size bytes at address may be written: not in the code, nor in read-only data. */
+    fn writable(&self, address: i32, size: i32) -> Result<(), String> {
+        let end = address + size;
+        if address < self.code_end && 0 < end {
+            return Err(format!("write to the code at {:#x}", address));
+        }
+        if self.readonly_data && address < self.data_end && self.code_end < end {
+            return Err(format!("write to read-only data at {:#x}", address));
+        }
+        self.bytes(address, size as i64).map(|_| ())
+    }
+
+    fn write_bytes(&mut self, address: i32, bytes: &[u8]) -> Result<(), String> {
+        self.writable(address, bytes.len() as i32)?;
+        let start = address as usize;
+        self.memory[start..start + bytes.len()].copy_from_slice(bytes);
+        Ok(())
+    }
+
+    fn write(&mut self, address: i32, word: i32) -> Result<(), String> {
+        self.write_bytes(address, &word.to_be_bytes())
+    }
+
+    fn push(&mut self, word: i32) -> Result<(), String> {
+        if self.sp - 4 < 0 {
+            return Err(String::from("out of memory"));
+        }
+        self.write(self.sp - 4, word)?;
+        self.sp -= 4;
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<i32, String> {
+        if self.sp + 4 > TOP {
+            return Err(String::from("pop from an empty stack"));
+        }
+        let word = self.read(self.sp)?;
+        self.sp += 4;
+        Ok(word)
+    }
+
+    /* The address past the top count words, which all have to be on the stack. */
+    fn words_end(&self, count: i64) -> Result<i32, String> {
+        let end = self.sp as i64 + count * 4;
+        if end > TOP as i64 {
+            return Err(format!("the stack doesn't have {} words", count));
+        }
+        Ok(end as i32)
+    }
+
+    fn push_narrow(&mut self, size: i32) -> Result<(), String> {
+        let word = self.pop()?;
+        if self.sp - size < 0 {
+            return Err(String::from("out of memory"));
+        }
+        self.write_bytes(self.sp - size, &word.to_be_bytes()[4 - size as usize..])?;
+        self.sp -= size;
+        Ok(())
+    }
+
+    fn pop_narrow(&mut self, size: i32, signed: bool) -> Result<(), String> {
+        if self.sp + size > TOP {
+            return Err(String::from("pop from an empty stack"));
+        }
+        let value = self.bytes(self.sp, size as i64)?.iter().fold(0u32, |word, &byte| (word << 8) | byte as u32);
+        let shift = 32 - size * 8;
+        let value = if signed { ((value << shift) as i32) >> shift } else { value as i32 };
+        self.sp += size;
+        self.push(value)
+    }
+
+    /* A packed string (see the strings module): its characters and the bytes it takes up. */
+    fn read_string(&self, address: i32) -> Result<(Vec<u8>, i32), String> {
+        let mut text = Vec::new();
+        let mut end = address;
+        loop {
+            let word = self.read(end)? as i64;
+            text.extend(strings::chars(word));
+            end += 4;
+            if !strings::continues(word) || end + 4 > TOP {
+                return Ok((text, end - address));
+            }
+        }
+    }
+
+    fn push_string(&mut self, text: &[u8]) -> Result<(), String> {
+        for word in strings::pack(text).into_iter().rev() {
+            self.push(word as i32)?;
+        }
+        Ok(())
+    }
+
+    fn pop_two_strings(&mut self) -> Result<(Vec<u8>, Vec<u8>), String> {
+        let (right, right_size) = self.read_string(self.sp)?;
+        let (left, left_size) = self.read_string(self.sp + right_size)?;
+        self.sp += right_size + left_size;
+        Ok((left, right))
+    }
+
+    /* The next line of input, newline and all, or None at the end. */
+    fn read_line(&mut self) -> Result<Option<String>, String> {
+        if self.input.is_empty() {
+            return Ok(None);
+        }
+        let length = self.input.iter().position(|&byte| byte == b'\n').map_or(self.input.len(), |newline| newline + 1);
+        let line: Vec<u8> = self.input.drain(..length).collect();
+        String::from_utf8(line).map(Some).map_err(|_| String::from("input isn't UTF-8"))
+    }
+
+    fn input_number(&mut self, eof: EofMode, retry: bool) -> Result<(), String> {
+        let n = loop {
+            let Some(line) = self.read_line()? else {
+                break None;
+            };
+            match number(line.as_bytes()) {
+                Some(n) => break Some(n),
+                None if retry => (),
+                None => return Err(String::from("bad input")),
+            }
+        };
+
+        match (n, eof) {
+            (Some(n), EofMode::Flag) => {
+                self.push(n)?;
+                self.push(1)
+            },
+            (Some(n), _) => self.push(n),
+            (None, EofMode::Sentinel) => self.push(i32::MIN),
+            (None, EofMode::Flag) => {
+                self.push(0)?;
+                self.push(0)
+            },
+            (None, EofMode::Fault) => Err(String::from("no more input")),
+        }
+    }
+}
+
+/* A number the way input and atoi read one, if it fits in a word. */
+fn number(text: &[u8]) -> Option<i32> {
+    let n = asm::parse_number(core::str::from_utf8(text).ok()?.trim())?;
+    i32::try_from(n).ok()
+}
+
+/* What to run a difftest with. */
+#[derive(Debug, Clone)]
+pub struct DiffOptions {
+    /* What the program reads. */
+    pub input: Vec<u8>,
+    /* For rand, the same in both. */
+    pub seed: u64,
+    /* Run the VM as threaded code. */
+    pub threaded: bool,
+    /* Give up after this many instructions. */
+    pub max_steps: u64,
+}
+
+/* How a difftest ended. */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiffOutcome {
+    /* Both exited, the same way. */
+    Exited(i32),
+    /* Both faulted on the same instruction, saying these. */
+    Faulted { vm: String, reference: String },
+    /* Comparing stopped before the end without them disagreeing: the reference doesn't run an
+     * instruction, or the step limit was reached. */
+    Stopped(String),
+    /* They disagreed after running the instruction at pc. */
+    Diverged { pc: i32, word: u32, what: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiffReport {
+    /* Instructions both ran and agreed on. */
+    pub steps: u64,
+    pub outcome: DiffOutcome,
+}
+
+/* Run a .v file on the VM and the reference in lockstep and report the first instruction after
+ * which they disagree. */
+pub fn difftest(image: &[u8], options: &DiffOptions) -> Result<DiffReport, String> {
+    let config = VmConfig { seed: Some(options.seed), threaded: options.threaded, ..VmConfig::default() };
+    let mut vm = VirtualMachine::from_bytes(image.to_vec(), config)?;
+    let output = SharedBuffer::new();
+    vm.set_input(Box::new(Cursor::new(options.input.clone())));
+    vm.set_output(Box::new(output.clone()));
+    vm.set_diagnostics(Box::new(io::sink()));
+    let mut reference = Reference::load(image, options.input.clone(), options.seed)?;
+
+    let report = |steps, outcome| Ok(DiffReport { steps, outcome });
+    for steps in 0..options.max_steps {
+        let (pc, word) = (reference.pc(), reference.word_at_pc().unwrap_or(0));
+        if let Some(instruction) = reference.unsupported() {
+            return report(steps, DiffOutcome::Stopped(format!("the reference doesn't run {} at {:04x}", instruction, pc)));
+        }
+        let diverged = |what: String| Ok(DiffReport { steps, outcome: DiffOutcome::Diverged { pc, word, what } });
+
+        let result = match (vm.step(), reference.step()) {
+            (Err(vm), Err(reference)) => return report(steps, DiffOutcome::Faulted { vm: vm.to_string(), reference }),
+            (Err(err), Ok(_)) => return diverged(format!("the VM faulted ({}) but the reference didn't", err)),
+            (Ok(_), Err(err)) => return diverged(format!("the reference faulted ({}) but the VM didn't", err)),
+            (Ok(ours), Ok(theirs)) if ours != theirs => {
+                return diverged(format!("the VM gave {:?} but the reference {:?}", ours, theirs));
+            },
+            (Ok(result), Ok(_)) => result,
+        };
+
+        if let Some(what) = disagreement(&vm, &reference, &output) {
+            return diverged(what);
+        }
+        if let StepResult::Exited(code) = result {
+            return report(steps + 1, DiffOutcome::Exited(code));
+        }
+    }
+
+    report(options.max_steps, DiffOutcome::Stopped(format!("neither finished in {} instructions", options.max_steps)))
+}
+
+/* The first thing the two machines don't agree on, if there is one. */
+fn disagreement(vm: &VirtualMachine, reference: &Reference, output: &SharedBuffer) -> Option<String> {
+    if vm.program_counter() != reference.pc() {
+        return Some(format!("the pc is {:04x} in the VM but {:04x} in the reference", vm.program_counter(), reference.pc()));
+    }
+    let sp = vm.stack_pointer();
+    if sp != reference.sp() {
+        return Some(format!("the sp is {:04x} in the VM but {:04x} in the reference", sp, reference.sp()));
+    }
+
+    let ours = vm.memory().as_slice().get(sp.max(0) as usize..).unwrap_or_default();
+    let theirs = reference.memory().get(sp.max(0) as usize..).unwrap_or_default();
+    if let Some(i) = ours.iter().zip(theirs).position(|(a, b)| a != b) {
+        let address = sp + i as i32 / 4 * 4;
+        return Some(format!("the stack word at {:04x} is {:08x} in the VM but {:08x} in the reference", address,
+            vm.word_at(address).unwrap_or(0) as u32, reference.read(address).unwrap_or(0) as u32));
+    }
+
+    if output.contents() != reference.output() {
+        return Some(format!("the VM has printed {:?} but the reference {:?}", output.text(),
+            String::from_utf8_lossy(reference.output())));
+    }
+
+    None
+}
@@ -0,0 +1,212 @@
+/* Coroutines: more than one context of execution in the same machine, each with its own pc,
+ * stack and call stack, taking turns round-robin. The program starts out in context 0, the main
+ * one. spawn starts another, yield hands over to the next one that can run, and join waits for
+ * one to finish and collects its exit code. Turns only change at yield, join and exit, or every
+ * VmConfig::time_slice instructions when that's set, so the same program always interleaves the
+ * same way.
+ *
+ * Memory is shared. Each spawned context's stack is a region of VmConfig::context_stack bytes,
+ * the regions one after another starting just past the code. The main context keeps the top of
+ * memory, and once anything has been spawned its stack can't grow down into the regions, nor any
+ * context's past the bottom of its own. For sharing it, cas, fetchadd, lock and unlock each
+ * read and write a word in one instruction, so no other context can get in between; lock waits
+ * its turn like join does.
+ *
+ * exit in a spawned context only ends that context, holding on to its exit code until something
+ * joins it, which frees its region for the next spawn. exit in the main context ends the whole
+ * machine, whatever else is still running. */
+
+use alloc::format;
+use alloc::vec::Vec;
+use core::mem;
+
+use crate::{CallFrame, VmError};
+
+/* How big a spawned context's stack is when the config doesn't say. */
+pub const DEFAULT_CONTEXT_STACK: usize = 256;
+
+/* Where a context is at. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContextState {
+    Ready,
+    /* Waiting in join for another context to finish. */
+    Joining(usize),
+    Finished(i32),
+}
+
+/* What the machine keeps for whichever context is running. */
+#[derive(Debug, Clone, Default)]
+pub struct Registers {
+    pub pc: i32,
+    pub sp: i32,
+    pub call_stack: Vec<CallFrame>,
+    pub return_stack: Vec<i64>,
+}
+
+struct Context {
+    /* Out of date while the context is running; the machine has the real ones. */
+    registers: Registers,
+    state: ContextState,
+    /* The stack's region: it starts at top and may go down to base. */
+    base: i32,
+    top: i32,
+}
+
+pub struct Scheduler {
+    /* Indexed by context id. None is a region that's free again. */
+    contexts: Vec<Option<Context>>,
+    current: usize,
+    /* Instructions run since the current context got its turn. */
+    ran: u64,
+    regions_start: i32,
+    region_size: i32,
+}
+
+impl Scheduler {
+    pub fn new(code_end: usize, region_size: Option<usize>, memory_end: i32) -> Scheduler {
+        let main = Context {
+            registers: Registers::default(),
+            state: ContextState::Ready,
+            base: 0,
+            top: memory_end,
+        };
+
+        /* Regions start on an 8-byte boundary so they suit either word size. */
+        let region_size = region_size.unwrap_or(DEFAULT_CONTEXT_STACK).next_multiple_of(8);
+        Scheduler {
+            contexts: Vec::from([Some(main)]),
+            current: 0,
+            ran: 0,
+            regions_start: code_end.next_multiple_of(8) as i32,
+            region_size: region_size.min(i32::MAX as usize) as i32,
+        }
+    }
+
+    /* The id of the context that's running. */
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    fn context(&self, id: usize) -> &Context {
+        self.contexts[id].as_ref().expect("the running context exists")
+    }
+
+    fn context_mut(&mut self, id: usize) -> &mut Context {
+        self.contexts[id].as_mut().expect("the running context exists")
+    }
+
+    /* The top of the running context's stack, where it's empty. */
+    pub fn top(&self) -> i32 {
+        self.context(self.current).top
+    }
+
+    /* The lowest the running context's stack may go, or None while there's only the main
+     * context and it has the whole of memory. */
+    pub fn limit(&self) -> Option<i32> {
+        if self.contexts.len() == 1 {
+            return None;
+        }
+
+        match self.current {
+            0 => Some(self.regions_start + (self.contexts.len() as i32 - 1) * self.region_size),
+            id => Some(self.context(id).base),
+        }
+    }
+
+    /* Set up a new context starting at pc, with room for one word on its stack, and give back
+     * its id and its stack pointer. The machine's sp is needed to make sure a new region
+     * doesn't land on the main context's stack. */
+    pub fn spawn(&mut self, pc: i32, sp: i32, word_bytes: i32) -> Result<(usize, i32), VmError> {
+        let id = match self.contexts.iter().skip(1).position(Option::is_none) {
+            Some(free) => free + 1,
+            None => self.contexts.len(),
+        };
+
+        let base = self.regions_start as i64 + (id as i64 - 1) * self.region_size as i64;
+        let top = base + self.region_size as i64;
+        let main_sp = if self.current == 0 { sp } else { self.context(0).registers.sp };
+        if top > main_sp as i64 || self.region_size < word_bytes {
+            return Err(VmError::from(format!("There's no room in memory for context {}.", id)));
+        }
+
+        let (base, top) = (base as i32, top as i32);
+        let registers = Registers { pc, sp: top - word_bytes, ..Registers::default() };
+        let context = Context { registers, state: ContextState::Ready, base, top };
+        if id == self.contexts.len() {
+            self.contexts.push(Some(context));
+        } else {
+            self.contexts[id] = Some(context);
+        }
+
+        Ok((id, top - word_bytes))
+    }
+
+    /* The running context has exited with code. */
+    pub fn finish(&mut self, code: i32) {
+        self.context_mut(self.current).state = ContextState::Finished(code);
+    }
+
+    /* Join a context: its exit code if it's finished, which frees it, or None after marking the
+     * running context as waiting for it. */
+    pub fn join(&mut self, id: i64) -> Result<Option<i32>, VmError> {
+        let target = usize::try_from(id).ok()
+            .filter(|&target| target != 0 && target != self.current)
+            .filter(|&target| self.contexts.get(target).is_some_and(Option::is_some));
+        let Some(target) = target else {
+            return Err(VmError::from(format!("There's no context {} to join.", id)));
+        };
+
+        match self.context(target).state {
+            ContextState::Finished(code) => {
+                self.contexts[target] = None;
+                self.context_mut(self.current).state = ContextState::Ready;
+                Ok(Some(code))
+            },
+            _ => {
+                self.context_mut(self.current).state = ContextState::Joining(target);
+                Ok(None)
+            },
+        }
+    }
+
+    /* Count an instruction against the running context's turn, returning how many it's had. */
+    pub fn tick(&mut self) -> u64 {
+        self.ran += 1;
+        self.ran
+    }
+
+    fn runnable(&self, id: usize) -> bool {
+        match self.contexts[id].as_ref().map(|context| context.state) {
+            Some(ContextState::Ready) => true,
+            /* Something else got to the target first, and join will say so when it runs again. */
+            Some(ContextState::Joining(target)) => {
+                !matches!(self.contexts[target].as_ref().map(|context| context.state), Some(ContextState::Ready | ContextState::Joining(_)))
+            },
+            _ => false,
+        }
+    }
+
+    /* Put away the running context's registers and hand over to the next context that can
+     * run, counting round from the one after it, which may turn out to be the same one. Every
+     * context being stuck in join is a deadlock. */
+    pub fn switch(&mut self, registers: Registers) -> Result<Registers, VmError> {
+        self.context_mut(self.current).registers = registers;
+        self.ran = 0;
+
+        let count = self.contexts.len();
+        let Some(next) = (1..=count).map(|step| (self.current + step) % count).find(|&id| self.runnable(id)) else {
+            let pc = self.context(self.current).registers.pc;
+            return Err(VmError::Deadlock { pc });
+        };
+
+        self.current = next;
+        Ok(mem::take(&mut self.context_mut(next).registers))
+    }
+
+    /* Each context and where it's at, by id, for the debugger. */
+    pub fn states(&self) -> Vec<(usize, ContextState)> {
+        self.contexts.iter().enumerate()
+            .filter_map(|(id, context)| Some((id, context.as_ref()?.state)))
+            .collect()
+    }
+}
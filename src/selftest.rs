@@ -0,0 +1,1038 @@
+/* vm selftest: a battery of tiny programs, each built around one instruction, that checks the
+ * VM's handlers against the isa module. Every case checks three things: that the word under test
+ * decodes to what the isa module says it is, that it disassembles to something that assembles
+ * back to it, as does the listing of the whole program, and that running it leaves the stack,
+ * the output and the exit code where the isa module's semantics say they should be, both
+ * interpreted and as threaded code. Cases on the default config are also run side by side
+ * with the reference interpreter. The operands lean on the edges: the biggest and smallest
+ * immediates, negative offsets, the ends of the stack and the values where 32-bit arithmetic
+ * wraps. */
+
+use std::io::Cursor;
+
+use crate::asm::{packed_string, string_pushes};
+use crate::disasm;
+use crate::harness::SharedBuffer;
+use crate::reference::{self, DiffOptions, DiffOutcome};
+use crate::rng::Rng;
+use crate::isa::{self, BinaryOp, Condition, EofMode, Instruction, PerfCounter, PrintFormat, PrintSpec, UnaryOp, ZeroCondition};
+use crate::strings;
+use crate::{DebugMode, Endianness, Header, Ops, StringFormat, VirtualMachine, VmConfig, MEMORY_SIZE};
+
+/* What a case should end with. */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Expected {
+    /* None if the program should fault. */
+    pub exit_code: Option<i32>,
+    /* The whole stack, top first. Not checked after a fault. */
+    pub stack: Vec<i32>,
+    /* Everything printed, diagnostics included, if it matters. */
+    pub output: Option<String>,
+}
+
+impl Expected {
+    fn stack(stack: Vec<i32>) -> Expected {
+        Expected { exit_code: Some(0), stack, output: None }
+    }
+
+    fn output(stack: Vec<i32>, output: String) -> Expected {
+        Expected { exit_code: Some(0), stack, output: Some(output) }
+    }
+
+    fn fault() -> Expected {
+        Expected { exit_code: None, stack: Vec::new(), output: None }
+    }
+}
+
+/* One program in the battery. */
+#[derive(Debug, Clone)]
+pub struct Case {
+    pub name: String,
+    /* The word being tested and what it should decode to; None for words the VM should reject. */
+    pub word: u32,
+    pub decoded: Option<Instruction>,
+    /* The whole program, the word under test included. */
+    pub program: Vec<u32>,
+    pub input: String,
+    pub config: VmConfig,
+    pub expected: Expected,
+    /* Run it on the reference interpreter too, which only knows the default config. */
+    pub reference: bool,
+}
+
+impl Case {
+    /* A program of setup, the instruction under test, then whatever follows it. */
+    fn new(name: String, setup: &[Instruction], instruction: Instruction, tail: &[Instruction], expected: Expected) -> Case {
+        let program = setup.iter()
+            .chain([instruction].iter())
+            .chain(tail.iter())
+            .map(Instruction::encode)
+            .collect();
+
+        Case {
+            name,
+            word: instruction.encode(),
+            decoded: Some(instruction),
+            program,
+            input: String::new(),
+            config: VmConfig::default(),
+            expected,
+            reference: true,
+        }
+    }
+
+    /* The usual shape: setup, the instruction, exit 0. */
+    fn simple(name: String, setup: &[Instruction], instruction: Instruction, expected: Expected) -> Case {
+        Case::new(name, setup, instruction, &[Instruction::Exit(0)], expected)
+    }
+
+    fn with_input(mut self, input: &str) -> Case {
+        self.input = String::from(input);
+        self
+    }
+
+    fn with_config(mut self, config: VmConfig) -> Case {
+        self.config = config;
+        self.reference = false;
+        self
+    }
+
+    fn image(&self) -> Vec<u8> {
+        let mut image = Vec::from([0xde, 0xad, 0xbe, 0xef]);
+        for word in &self.program {
+            image.extend_from_slice(&word.to_le_bytes());
+        }
+        image
+    }
+
+    /* Run the case. Err says what didn't match. */
+    pub fn check(&self) -> Result<(), String> {
+        let decoded = Instruction::decode(self.word);
+        if decoded != self.decoded {
+            return Err(format!("{:#010x} decodes to {:?}, expected {:?}", self.word, decoded, self.decoded));
+        }
+        if let Some(instruction) = decoded {
+            if instruction.encode() != self.word {
+                return Err(format!("{:?} encodes to {:#010x}, expected {:#010x}", instruction, instruction.encode(), self.word));
+            }
+        }
+
+        let text = isa::disassemble(self.word);
+        match isa::assemble_line(&text) {
+            Ok(word) if word == self.word => (),
+            Ok(word) => return Err(format!("{} assembles to {:#010x}, expected {:#010x}", text, word, self.word)),
+            Err(err) => return Err(format!("{} doesn't assemble: {}", text, err)),
+        }
+        disasm::roundtrip(&self.image()).map_err(|err| format!("the program's listing: {}", err))?;
+
+        self.run(self.config.clone())?;
+        let threaded = VmConfig { threaded: true, ..self.config.clone() };
+        self.run(threaded).map_err(|err| format!("threaded: {}", err))?;
+        match self.reference {
+            true => self.difftest().map_err(|err| format!("reference: {}", err)),
+            false => Ok(()),
+        }
+    }
+
+    /* Run the program on the VM and the reference in lockstep; they mustn't disagree. */
+    fn difftest(&self) -> Result<(), String> {
+        let options = DiffOptions { input: self.input.clone().into_bytes(), seed: 0, threaded: false, max_steps: 100_000 };
+        match reference::difftest(&self.image(), &options)?.outcome {
+            DiffOutcome::Diverged { pc, what, .. } => Err(format!("diverged at {:04x}: {}", pc, what)),
+            _ => Ok(()),
+        }
+    }
+
+    /* Run the program and compare where it ends up with what's expected. */
+    fn run(&self, config: VmConfig) -> Result<(), String> {
+        let mut vm = VirtualMachine::from_bytes(self.image(), config)?;
+        let output = SharedBuffer::new();
+        vm.set_input(Box::new(Cursor::new(self.input.clone().into_bytes())));
+        vm.set_output(Box::new(output.clone()));
+        vm.set_diagnostics(Box::new(output.clone()));
+
+        let result = vm.run();
+        let expected = &self.expected;
+
+        match (&result, expected.exit_code) {
+            (Err(_), None) => return Ok(()),
+            (Ok(code), None) => return Err(format!("exited with {}, expected a fault", code)),
+            (Err(err), Some(_)) => return Err(format!("faulted: {}", err)),
+            (Ok(code), Some(expected_code)) if *code != expected_code => {
+                return Err(format!("exited with {}, expected {}", code, expected_code));
+            },
+            _ => (),
+        }
+
+        let stack: Vec<i32> = (vm.stack_pointer()..MEMORY_SIZE as i32)
+            .step_by(4)
+            .map(|address| vm.word_at(address).unwrap_or(0) as i32)
+            .collect();
+        if stack != expected.stack {
+            return Err(format!("stack is {:?}, expected {:?}", stack, expected.stack));
+        }
+
+        match &expected.output {
+            Some(text) if output.text() != *text => {
+                Err(format!("printed {:?}, expected {:?}", output.text(), text))
+            },
+            _ => Ok(()),
+        }
+    }
+}
+
+/* Operands for the arithmetic, comparisons and ifs. */
+const OPERANDS: [i32; 9] = [0, 1, -1, 7, -7, 31, 32, i32::MAX, i32::MIN];
+
+const PUSH_MAX: i32 = (1 << 27) - 1;
+const PUSH_MIN: i32 = -(1 << 27);
+
+/* Instructions that push any 32-bit value: push on its own when it fits, otherwise the top half
+ * shifted up and the bottom half ored in. */
+fn constant(value: i32) -> Vec<Instruction> {
+    if (PUSH_MIN..=PUSH_MAX).contains(&value) {
+        return Vec::from([Instruction::Push(value)]);
+    }
+
+    Vec::from([
+        Instruction::Push(value >> 16),
+        Instruction::Push(16),
+        Instruction::Binary(BinaryOp::Lsl),
+        Instruction::Push(value & 0xFFFF),
+        Instruction::Binary(BinaryOp::Or),
+    ])
+}
+
+fn pair(left: i32, right: i32) -> Vec<Instruction> {
+    let mut setup = constant(left);
+    setup.extend(constant(right));
+    setup
+}
+
+fn misc_cases(cases: &mut Vec<Case>) {
+    for code in [0, 1, 255, isa::MAX_EXIT_CODE] {
+        cases.push(Case::new(format!("exit {}", code), &[], Instruction::Exit(code), &[], Expected {
+            exit_code: Some(code as i32),
+            stack: Vec::new(),
+            output: None,
+        }));
+    }
+
+    cases.push(Case::simple(String::from("nop"), &[Instruction::Push(1)], Instruction::Nop, Expected::stack(Vec::from([1]))));
+    for binary in [false, true] {
+        cases.push(Case::simple(format!("debug binary={}", binary), &[Instruction::Push(1)], Instruction::Debug { bytes: 0, binary },
+            Expected::stack(Vec::from([1]))));
+    }
+    let top = MEMORY_SIZE as i32 - 4;
+    cases.push(Case::simple(String::from("debug 4"), &[Instruction::Push(0x41_4243), Instruction::Push(top)], Instruction::Debug { bytes: 4, binary: false },
+        Expected::output(Vec::from([0x41_4243]), String::from(" 0ffc |   00  41  42  43\n"))));
+    cases.push(Case::simple(String::from("debugb 2"), &[Instruction::Push(0x41_4243), Instruction::Push(top + 2)], Instruction::Debug { bytes: 2, binary: true },
+        Expected::output(Vec::from([0x41_4243]), String::from(" 0ffe |   01000010  01000011\n"))));
+    /* The popped address is still in memory, just past the top of the stack. */
+    cases.push(Case::simple(String::from("debug 20 bytes"), &[Instruction::Push(0), Instruction::Push(top - 16)], Instruction::Debug { bytes: 20, binary: false },
+        Expected::output(Vec::from([0]), format!(" 0fec | {}  0f  ec\n 0ffc | {}\n", "  00".repeat(14), "  00".repeat(4)))));
+    cases.push(Case::simple(String::from("debug past the end"), &[Instruction::Push(top)], Instruction::Debug { bytes: 5, binary: false },
+        Expected::fault()));
+    cases.push(Case::simple(String::from("debug without an address"), &[], Instruction::Debug { bytes: 4, binary: false }, Expected::fault()));
+    /* Muted, it still pops the address and checks it. */
+    let silent = VmConfig { debug_instruction: DebugMode::Silent, ..VmConfig::default() };
+    cases.push(Case::simple(String::from("debug 4 muted"), &[Instruction::Push(0x41_4243), Instruction::Push(top)], Instruction::Debug { bytes: 4, binary: false },
+        Expected::output(Vec::from([0x41_4243]), String::new())).with_config(silent.clone()));
+    cases.push(Case::simple(String::from("debug muted"), &[Instruction::Push(1)], Instruction::Debug { bytes: 0, binary: false },
+        Expected::output(Vec::from([1]), String::new())).with_config(silent.clone()));
+    cases.push(Case::simple(String::from("debug past the end muted"), &[Instruction::Push(top)], Instruction::Debug { bytes: 5, binary: false },
+        Expected::fault()).with_config(silent));
+
+    let three = [Instruction::Push(1), Instruction::Push(2), Instruction::Push(3)];
+    let swaps = [
+        (0, 1, Expected::stack(Vec::from([2, 3, 1]))),
+        (0, 2, Expected::stack(Vec::from([1, 2, 3]))),
+        (2, 0, Expected::stack(Vec::from([1, 2, 3]))),
+        (1, 1, Expected::stack(Vec::from([3, 2, 1]))),
+        /* Below the stack pointer is fair game; memory there is still zero. */
+        (0, -1, Expected::stack(Vec::from([0, 2, 1]))),
+        /* Slot 3 is past the end of memory. */
+        (0, 3, Expected::fault()),
+        (-2048, 0, Expected::fault()),
+        (2047, 0, Expected::fault()),
+    ];
+    for (from, to, expected) in swaps {
+        cases.push(Case::simple(format!("swap {} {}", from, to), &three, Instruction::Swap { from, to }, expected));
+    }
+
+    let input = Instruction::Input { eof: EofMode::Fault, retry: false };
+    let inputs = [
+        ("42\n", Expected::stack(Vec::from([42]))),
+        ("-42\n", Expected::stack(Vec::from([-42]))),
+        ("+42\n", Expected::stack(Vec::from([42]))),
+        ("0x7fffffff\n", Expected::stack(Vec::from([i32::MAX]))),
+        ("-0x10\n", Expected::stack(Vec::from([-16]))),
+        ("0b101\n", Expected::stack(Vec::from([5]))),
+        ("-0B101\n", Expected::stack(Vec::from([-5]))),
+        ("0o17\n", Expected::stack(Vec::from([15]))),
+        ("-2147483648\n", Expected::stack(Vec::from([i32::MIN]))),
+        ("-0x80000000\n", Expected::stack(Vec::from([i32::MIN]))),
+        ("2147483648\n", Expected::fault()),
+        ("--5\n", Expected::fault()),
+        ("0x\n", Expected::fault()),
+        ("junk\n", Expected::fault()),
+        ("", Expected::fault()),
+    ];
+    for (text, expected) in inputs {
+        cases.push(Case::simple(format!("input {:?}", text), &[], input, expected).with_input(text));
+    }
+
+    let modes = [
+        (EofMode::Sentinel, false, "7\n", Expected::stack(Vec::from([7]))),
+        (EofMode::Sentinel, false, "", Expected::stack(Vec::from([i32::MIN]))),
+        (EofMode::Sentinel, false, "junk\n", Expected::fault()),
+        (EofMode::Flag, false, "7\n", Expected::stack(Vec::from([1, 7]))),
+        (EofMode::Flag, false, "", Expected::stack(Vec::from([0, 0]))),
+        (EofMode::Fault, true, "junk\n0x\n7\n", Expected::stack(Vec::from([7]))),
+        (EofMode::Fault, true, "junk\n", Expected::fault()),
+        (EofMode::Sentinel, true, "junk\n", Expected::stack(Vec::from([i32::MIN]))),
+        (EofMode::Flag, true, "junk\n-3\n", Expected::stack(Vec::from([1, -3]))),
+    ];
+    for (eof, retry, text, expected) in modes {
+        let instruction = Instruction::Input { eof, retry };
+        cases.push(Case::simple(format!("{} {:?}", instruction, text), &[], instruction, expected).with_input(text));
+    }
+
+    let string_inputs = [("hello", 0xFF_FFFF), ("hello", 2), ("abc", 0xFF_FFFF), ("", 0xFF_FFFF), ("hello", 0)];
+    for (input, max) in string_inputs {
+        let kept = &input[..input.len().min(max as usize)];
+        cases.push(Case::simple(format!("stinput {} {:?}", max, input), &[], Instruction::StInput(max), Expected::stack(on_stack(kept)))
+            .with_input(&format!("{}\n", input)));
+    }
+}
+
+fn stack_cases(cases: &mut Vec<Case>) {
+    for value in [0, 1, -1, PUSH_MAX, PUSH_MIN] {
+        cases.push(Case::simple(format!("push {}", value), &[], Instruction::Push(value), Expected::stack(Vec::from([value]))));
+    }
+
+    let two = [Instruction::Push(1), Instruction::Push(2)];
+    let pops = [
+        (0, Vec::from([2, 1])),
+        (4, Vec::from([1])),
+        (8, Vec::new()),
+        /* Popping past the end just empties the stack. */
+        (12, Vec::new()),
+        (0x0FFF_FFFC, Vec::new()),
+    ];
+    for (bytes, stack) in pops {
+        cases.push(Case::simple(format!("pop {}", bytes), &two, Instruction::Pop(bytes), Expected::stack(stack)));
+    }
+    cases.push(Case::simple(String::from("pop on an empty stack"), &[], Instruction::Pop(4), Expected::stack(Vec::new())));
+
+    let dups = [
+        (0, Expected::stack(Vec::from([2, 2, 1]))),
+        (4, Expected::stack(Vec::from([1, 2, 1]))),
+        (-4, Expected::stack(Vec::from([0, 2, 1]))),
+        (8, Expected::fault()),
+        (PUSH_MAX, Expected::fault()),
+        (PUSH_MIN, Expected::fault()),
+    ];
+    for (offset, expected) in dups {
+        cases.push(Case::simple(format!("dup {}", offset), &two, Instruction::Dup(offset), expected));
+    }
+
+    let dump = String::from("0ff8: ffffffff\n0ffc: 00000001\n");
+    cases.push(Case::simple(String::from("dump"), &[Instruction::Push(1), Instruction::Push(-1)], Instruction::Dump,
+        Expected::output(Vec::from([-1, 1]), dump)));
+    cases.push(Case::simple(String::from("dump an empty stack"), &[], Instruction::Dump, Expected::output(Vec::new(), String::new())));
+
+    let dual = VmConfig { return_stack_depth: Some(1), ..VmConfig::default() };
+    cases.push(Case::new(String::from(">r"), &[Instruction::Push(1), Instruction::Push(2)], Instruction::ToReturnStack,
+        &[Instruction::Push(3), Instruction::FromReturnStack, Instruction::Exit(0)],
+        Expected::stack(Vec::from([2, 3, 1]))).with_config(dual.clone()));
+    cases.push(Case::simple(String::from(">r past the return stack's depth"), &[Instruction::Push(1), Instruction::ToReturnStack, Instruction::Push(2)],
+        Instruction::ToReturnStack, Expected::fault()).with_config(dual.clone()));
+    cases.push(Case::simple(String::from("r> on an empty return stack"), &[], Instruction::FromReturnStack, Expected::fault())
+        .with_config(dual));
+    cases.push(Case::simple(String::from(">r outside dual-stack mode"), &[Instruction::Push(1)], Instruction::ToReturnStack, Expected::fault()));
+}
+
+fn arithmetic_cases(cases: &mut Vec<Case>) {
+    for op in BinaryOp::ALL {
+        for left in OPERANDS {
+            for right in OPERANDS {
+                let expected = match op.apply(left, right) {
+                    Some(result) => Expected::stack(Vec::from([result])),
+                    None => Expected::fault(),
+                };
+                let name = format!("{} {} {}", op.mnemonic(), left, right);
+                cases.push(Case::simple(name, &pair(left, right), Instruction::Binary(op), expected));
+            }
+        }
+        cases.push(Case::simple(format!("{} with one operand", op.mnemonic()), &[Instruction::Push(1)], Instruction::Binary(op), Expected::fault()));
+    }
+
+    for condition in Condition::ALL {
+        for left in OPERANDS {
+            for right in OPERANDS {
+                let name = format!("cmp{} {} {}", condition.suffix(), left, right);
+                let expected = Expected::stack(Vec::from([condition.holds(left, right) as i32]));
+                cases.push(Case::simple(name, &pair(left, right), Instruction::Cmp(condition), expected));
+            }
+        }
+    }
+
+    for op in UnaryOp::ALL {
+        for operand in OPERANDS {
+            let name = format!("{} {}", op.mnemonic(), operand);
+            let expected = Expected::stack(Vec::from([op.apply(operand)]));
+            cases.push(Case::simple(name, &constant(operand), Instruction::Unary(op), expected));
+        }
+        cases.push(Case::simple(format!("{} on an empty stack", op.mnemonic()), &[], Instruction::Unary(op), Expected::fault()));
+    }
+}
+
+/* A branch forwards over one push, and one backwards into code it jumped over to get there:
+ *
+ *     branch +8          goto +12
+ *     push 1             push 3
+ *     push 2             exit 0
+ *     exit 0             branch -8
+ *                        push 4
+ *                        exit 0
+ */
+fn branch_cases(cases: &mut Vec<Case>, name: &str, setup: &[Instruction], below: &[i32], branch: &dyn Fn(i32) -> Instruction, taken: bool) {
+    let with = |top: &[i32]| top.iter().chain(below).copied().collect::<Vec<i32>>();
+
+    let expected = Expected::stack(if taken { with(&[2]) } else { with(&[2, 1]) });
+    cases.push(Case::new(format!("{} forwards", name), setup, branch(8),
+        &[Instruction::Push(1), Instruction::Push(2), Instruction::Exit(0)], expected));
+
+    let mut backwards_setup = Vec::from(setup);
+    backwards_setup.extend([Instruction::Goto(12), Instruction::Push(3), Instruction::Exit(0)]);
+    let expected = Expected::stack(if taken { with(&[3]) } else { with(&[4]) });
+    cases.push(Case::new(format!("{} backwards", name), &backwards_setup, branch(-8),
+        &[Instruction::Push(4), Instruction::Exit(0)], expected));
+}
+
+fn control_cases(cases: &mut Vec<Case>) {
+    for condition in Condition::ALL {
+        for left in OPERANDS {
+            for right in OPERANDS {
+                let name = format!("if{} {} {}", condition.suffix(), left, right);
+                let taken = condition.holds(left, right);
+                branch_cases(cases, &name, &pair(left, right), &[right, left], &|offset| Instruction::BinaryIf(condition, offset), taken);
+            }
+        }
+    }
+
+    for condition in ZeroCondition::ALL {
+        for value in OPERANDS {
+            let name = format!("if{} {}", condition.suffix(), value);
+            let taken = condition.holds(value);
+            branch_cases(cases, &name, &constant(value), &[value], &|offset| Instruction::UnaryIf(condition, offset), taken);
+        }
+        cases.push(Case::simple(format!("if{} on an empty stack", condition.suffix()), &[], Instruction::UnaryIf(condition, 8), Expected::fault()));
+    }
+
+    branch_cases(cases, "goto", &[], &[], &Instruction::Goto, true);
+
+    /* Offsets at the ends of their fields land far outside memory. */
+    let far = [
+        ("goto", Instruction::Goto((1 << 27) - 4)),
+        ("goto", Instruction::Goto(-(1 << 27))),
+        ("ifeq", Instruction::BinaryIf(Condition::Eq, (1 << 24) - 4)),
+        ("ifeq", Instruction::BinaryIf(Condition::Eq, -(1 << 24))),
+        ("ifez", Instruction::UnaryIf(ZeroCondition::Zero, (1 << 24) - 4)),
+        ("ifez", Instruction::UnaryIf(ZeroCondition::Zero, -(1 << 24))),
+    ];
+    for (name, instruction) in far {
+        let offset = match instruction {
+            Instruction::Goto(offset) | Instruction::BinaryIf(_, offset) | Instruction::UnaryIf(_, offset) => offset,
+            _ => 0,
+        };
+        cases.push(Case::simple(format!("{} {}", name, offset), &[Instruction::Push(0)], instruction, Expected::fault()));
+    }
+
+    /*     call +12
+     *     push 7
+     *     exit 0
+     *     push 5       (the function)
+     *     push 6
+     *     return 8 */
+    let function = [Instruction::Push(7), Instruction::Exit(0), Instruction::Push(5), Instruction::Push(6), Instruction::Return(8)];
+    cases.push(Case::new(String::from("call and return"), &[], Instruction::Call(12), &function, Expected::stack(Vec::from([7]))));
+
+    let dual = VmConfig { return_stack_depth: Some(1), ..VmConfig::default() };
+    let function = [Instruction::Push(7), Instruction::Exit(0), Instruction::Push(5), Instruction::Return(0)];
+    cases.push(Case::new(String::from("call and return, dual-stack"), &[], Instruction::Call(12), &function,
+        Expected::stack(Vec::from([7, 5]))).with_config(dual));
+
+    let shallow = VmConfig { max_call_depth: Some(1), ..VmConfig::default() };
+    cases.push(Case::new(String::from("call past the maximum depth"), &[], Instruction::Call(0), &[], Expected::fault()).with_config(shallow));
+    cases.push(Case::simple(String::from("return on an empty stack"), &[], Instruction::Return(0), Expected::fault()));
+    cases.push(Case::simple(String::from("call out of memory"), &[], Instruction::Call(-(1 << 27)), Expected::fault()));
+
+    /*     call +12
+     *     push 7
+     *     exit 0
+     *     tailcall +8  (the function)
+     *     push 99
+     *     push 5       (what it tail calls)
+     *     return 0
+     * No deeper than the call, so the limit doesn't stop it. */
+    let caller = [Instruction::Call(12), Instruction::Push(7), Instruction::Exit(0)];
+    let callee = [Instruction::Push(99), Instruction::Push(5), Instruction::Return(0)];
+    let config = VmConfig { return_stack_depth: Some(1), max_call_depth: Some(1), ..VmConfig::default() };
+    cases.push(Case::new(String::from("tailcall and return"), &caller, Instruction::TailCall(8), &callee,
+        Expected::stack(Vec::from([7, 5]))).with_config(config));
+    cases.push(Case::new(String::from("tailcall outside a function"), &[], Instruction::TailCall(8), &[Instruction::Exit(1), Instruction::Exit(0)],
+        Expected::stack(Vec::new())));
+
+    cases.push(Case::new(String::from("jumpi"), &[Instruction::Push(12)], Instruction::JumpI, &[Instruction::Push(99), Instruction::Push(5), Instruction::Exit(0)],
+        Expected::stack(Vec::from([5]))));
+    for address in [-4, 2, 12, 4096] {
+        cases.push(Case::simple(format!("jumpi to {}", address), &[Instruction::Push(address)], Instruction::JumpI, Expected::fault()));
+    }
+    cases.push(Case::simple(String::from("jumpi on an empty stack"), &[], Instruction::JumpI, Expected::fault()));
+
+    /*     push <selector>
+     *     jumptable 2
+     *     .table 16 20   (to push 5 and push 6; the words happen to read as exits)
+     *     push 9
+     *     exit 0
+     *     push 5
+     *     exit 0
+     *     push 6
+     *     exit 0 */
+    let table = [Instruction::Exit(16), Instruction::Exit(20), Instruction::Push(9), Instruction::Exit(0),
+        Instruction::Push(5), Instruction::Exit(0), Instruction::Push(6), Instruction::Exit(0)];
+    for (selector, expected) in [(0, 5), (1, 6), (2, 9), (-1, 9)] {
+        cases.push(Case::new(format!("jumptable with {}", selector), &[Instruction::Push(selector)], Instruction::JumpTable(2), &table,
+            Expected::stack(Vec::from([expected]))));
+    }
+    cases.push(Case::simple(String::from("jumptable past the end of the code"), &[Instruction::Push(0)], Instruction::JumpTable(2), Expected::fault()));
+    cases.push(Case::new(String::from("jumptable on an empty stack"), &[], Instruction::JumpTable(2), &table, Expected::fault()));
+
+    let three = [Instruction::Push(1), Instruction::Push(2), Instruction::Push(3)];
+    for (depth, expected) in [(0, 3), (1, 2), (2, 1)] {
+        cases.push(Case::simple(format!("pick {}", depth), &three, Instruction::Pick(depth), Expected::stack(Vec::from([expected, 3, 2, 1]))));
+    }
+    cases.push(Case::simple(String::from("pick past the stack"), &three, Instruction::Pick(3), Expected::fault()));
+    for (count, expected) in [(0, [3, 2, 1]), (1, [3, 2, 1]), (2, [2, 3, 1]), (3, [1, 3, 2])] {
+        cases.push(Case::simple(format!("roll {}", count), &three, Instruction::Roll(count), Expected::stack(Vec::from(expected))));
+    }
+    cases.push(Case::simple(String::from("roll past the stack"), &three, Instruction::Roll(4), Expected::fault()));
+    for count in 0..=3 {
+        cases.push(Case::simple(format!("drop {}", count), &three, Instruction::Drop(count),
+            Expected::stack(Vec::from(&[3, 2, 1][count as usize..]))));
+    }
+    cases.push(Case::simple(String::from("drop past the stack"), &three, Instruction::Drop(4), Expected::fault()));
+
+    /* lea is at 4, after the push. */
+    for (offset, expected) in [(0, 4), (8, 12), (-4, 0), (1000, 1004)] {
+        cases.push(Case::simple(format!("lea {}", offset), &[Instruction::Push(1)], Instruction::Lea(offset),
+            Expected::stack(Vec::from([expected, 1]))));
+    }
+
+    /* run carries on past a brk; only step stops for it. */
+    cases.push(Case::simple(String::from("brk"), &[Instruction::Push(1)], Instruction::Brk, Expected::stack(Vec::from([1]))));
+    for value in [1, -1, PUSH_MAX] {
+        cases.push(Case::simple(format!("assert {}", value), &[Instruction::Push(2), Instruction::Push(value)], Instruction::Assert(0),
+            Expected::stack(Vec::from([2]))));
+    }
+    cases.push(Case::simple(String::from("assert 0"), &[Instruction::Push(0)], Instruction::Assert(0), Expected::fault()));
+    /* The message is the exit after it, an empty string as far as assert can tell. */
+    cases.push(Case::simple(String::from("assert 0 with a message"), &[Instruction::Push(0)], Instruction::Assert(4), Expected::fault()));
+    cases.push(Case::simple(String::from("assert on an empty stack"), &[], Instruction::Assert(0), Expected::fault()));
+
+    /* Without a guard the stack can grow all the way down to address 0, so what's left is sp. */
+    let top = MEMORY_SIZE as i32;
+    cases.push(Case::simple(String::from("stackdepth"), &[], Instruction::StackDepth, Expected::stack(Vec::from([top]))));
+    cases.push(Case::simple(String::from("stackdepth after a push"), &[Instruction::Push(1)], Instruction::StackDepth,
+        Expected::stack(Vec::from([top - 4, 1]))));
+    cases.push(Case::simple(String::from("stackdepth with no room for it"), &[], Instruction::StackDepth, Expected::fault())
+        .with_config(VmConfig { stack_guard: Some(MEMORY_SIZE), ..VmConfig::default() }));
+
+    /* Each counter as it stood before the perfctr. */
+    let counted = [Instruction::Push(5), Instruction::Push(6), Instruction::Pop(4)];
+    for (counter, count) in [(PerfCounter::Instructions, 3), (PerfCounter::Branches, 0), (PerfCounter::StackMax, 8), (PerfCounter::IoBytes, 0)] {
+        cases.push(Case::simple(format!("perfctr {}", counter.name()), &counted, Instruction::PerfCtr(counter),
+            Expected::stack(Vec::from([count, 5]))));
+    }
+}
+
+fn print_cases(cases: &mut Vec<Case>) {
+    for format in PrintFormat::ALL {
+        for value in OPERANDS {
+            let name = format!("print{} {}", format.suffix(), value);
+            cases.push(Case::simple(name, &constant(value), Instruction::Print(0, format), Expected::output(Vec::from([value]), format.format(value))));
+        }
+    }
+
+    let two = [Instruction::Push(1), Instruction::Push(2)];
+    cases.push(Case::simple(String::from("print 4"), &two, Instruction::Print(4, PrintFormat::Decimal),
+        Expected::output(Vec::from([2, 1]), String::from("1\n"))));
+    cases.push(Case::simple(String::from("print -4"), &two, Instruction::Print(-4, PrintFormat::Decimal),
+        Expected::output(Vec::from([2, 1]), String::from("0\n"))));
+    cases.push(Case::simple(String::from("print 8"), &two, Instruction::Print(8, PrintFormat::Decimal), Expected::fault()));
+
+    let specs = [
+        (PrintSpec { format: PrintFormat::Decimal, unsigned: false, width: 5, zero_pad: false, newline: true }, ["    0", "   42", "   -7", "-2147483648"]),
+        (PrintSpec { format: PrintFormat::Decimal, unsigned: false, width: 5, zero_pad: true, newline: false }, ["00000", "00042", "-0007", "-2147483648"]),
+        (PrintSpec { format: PrintFormat::Decimal, unsigned: true, width: 0, zero_pad: false, newline: true }, ["0", "42", "4294967289", "2147483648"]),
+        (PrintSpec { format: PrintFormat::Hex, unsigned: false, width: 8, zero_pad: true, newline: true }, ["00000000", "0000002a", "fffffff9", "80000000"]),
+        (PrintSpec { format: PrintFormat::Binary, unsigned: false, width: 8, zero_pad: false, newline: false }, ["       0", "  101010", "11111111111111111111111111111001", "10000000000000000000000000000000"]),
+    ];
+    for (spec, texts) in specs {
+        for (value, text) in [0, 42, -7, i32::MIN].into_iter().zip(texts) {
+            let expected = format!("{}{}", text, if spec.newline { "\n" } else { "" });
+            cases.push(Case::simple(format!("printf {} {}", spec, value), &constant(value), Instruction::PrintF(spec),
+                Expected::output(Vec::from([value]), expected)));
+        }
+    }
+    cases.push(Case::simple(String::from("printf on an empty stack"), &[], Instruction::PrintF(specs[0].0), Expected::fault()));
+
+    for text in ["", "hi", "abc", "hello", "hello, world"] {
+        cases.push(Case::simple(format!("stprint {:?}", text), &pushed(text), Instruction::StPrint(0),
+            Expected::output(on_stack(text), String::from(text))));
+    }
+
+    let setup = [Instruction::Push(0x6968), Instruction::Push(7)];
+    cases.push(Case::simple(String::from("stprint 4"), &setup, Instruction::StPrint(4),
+        Expected::output(Vec::from([7, 0x6968]), String::from("hi"))));
+    cases.push(Case::simple(String::from("stprint past the end"), &setup, Instruction::StPrint(8), Expected::fault()));
+    cases.push(Case::simple(format!("stprint {}", PUSH_MIN), &setup, Instruction::StPrint(PUSH_MIN), Expected::fault()));
+}
+
+fn pushed(text: &str) -> Vec<Instruction> {
+    packed_string(text).into_iter().map(|word| Instruction::Push(word as i32)).collect()
+}
+
+/* A string as it sits on the stack, top first. */
+fn on_stack(text: &str) -> Vec<i32> {
+    packed_string(text).into_iter().rev().map(|word| word as i32).collect()
+}
+
+fn string_cases(cases: &mut Vec<Case>) {
+    for text in ["", "hi", "abc", "hello", "hello, world"] {
+        cases.push(Case::simple(format!("strlen {:?}", text), &pushed(text), Instruction::StrLen(0),
+            Expected::stack([text.len() as i32].into_iter().chain(on_stack(text)).collect())));
+    }
+
+    let mut setup = pushed("abcd");
+    setup.push(Instruction::Push(9));
+    cases.push(Case::simple(String::from("strlen 4"), &setup, Instruction::StrLen(4),
+        Expected::stack([4, 9].into_iter().chain(on_stack("abcd")).collect())));
+    cases.push(Case::simple(String::from("strlen past the end"), &[], Instruction::StrLen(0), Expected::fault()));
+    cases.push(Case::simple(format!("strlen {}", -(1 << 19)), &[Instruction::Push(0)], Instruction::StrLen(-(1 << 19)), Expected::fault()));
+
+    let pairs = [("", ""), ("ab", "c"), ("abc", "def"), ("hello", ", world"), ("abc", ""), ("", "abc"), ("abcd", "abce"), ("b", "a"), ("ab", "abc")];
+    for (left, right) in pairs {
+        let mut setup = Vec::from([Instruction::Push(7)]);
+        setup.extend(pushed(left));
+        setup.extend(pushed(right));
+
+        let joined = format!("{}{}", left, right);
+        cases.push(Case::simple(format!("strcat {:?} {:?}", left, right), &setup, Instruction::StrCat,
+            Expected::stack(on_stack(&joined).into_iter().chain([7]).collect())));
+
+        let order = left.cmp(right) as i32;
+        cases.push(Case::simple(format!("strcmp {:?} {:?}", left, right), &setup, Instruction::StrCmp,
+            Expected::stack(Vec::from([order, 7]))));
+    }
+
+    /* Stack words are big-endian by default, so the bytes of 0x414243 are 0, 'A', 'B', 'C'. */
+    let top = MEMORY_SIZE as i32 - 4;
+    for (length, text) in [(0, ""), (2, "AB"), (3, "ABC")] {
+        cases.push(Case::simple(format!("stprintn {}", length), &[Instruction::Push(0x41_4243), Instruction::Push(length), Instruction::Push(top + 1)],
+            Instruction::StPrintN, Expected::output(Vec::from([0x41_4243]), String::from(text))));
+    }
+    cases.push(Case::simple(String::from("stprintn a zero byte"), &[Instruction::Push(0x41), Instruction::Push(2), Instruction::Push(top + 2)],
+        Instruction::StPrintN, Expected::output(Vec::from([0x41]), String::from("\0A"))));
+    for (length, address) in [(4, top + 1), (-1, top), (1, -1), (1, MEMORY_SIZE as i32)] {
+        cases.push(Case::simple(format!("stprintn {} at {}", length, address), &[Instruction::Push(0), Instruction::Push(length), Instruction::Push(address)],
+            Instruction::StPrintN, Expected::fault()));
+    }
+
+    cases.push(Case::simple(String::from("strcat with one string"), &pushed("hi"), Instruction::StrCat, Expected::fault()));
+
+    /* Only the program's arguments can be opened, and these cases have none. */
+    cases.push(Case::simple(String::from("readfile without arguments"), &pushed("data.txt"), Instruction::ReadFile, Expected::fault()));
+    let mut setup = pushed("out");
+    setup.extend(pushed("out.txt"));
+    cases.push(Case::simple(String::from("writefile without arguments"), &setup, Instruction::WriteFile(0), Expected::fault()));
+
+    let missing = "/nonexistent/data.txt";
+    let args = VmConfig { args: Some(Vec::from([String::from("a"), String::from(missing)])), ..VmConfig::default() };
+    cases.push(Case::simple(String::from("nop with arguments"), &[], Instruction::Nop,
+        Expected::stack([2].into_iter().chain(on_stack("a")).chain(on_stack(missing)).collect()))
+        .with_config(args.clone()));
+    cases.push(Case::simple(String::from("readfile of an argument that isn't there"), &pushed(missing), Instruction::ReadFile, Expected::fault())
+        .with_config(args.clone()));
+
+    let on_start: Vec<i32> = [2].into_iter().chain(on_stack("a")).chain(on_stack(missing)).collect();
+    for (index, arg) in [(0, "a"), (1, missing)] {
+        cases.push(Case::simple(format!("arg {}", index), &[Instruction::Push(index)], Instruction::Arg,
+            Expected::stack(on_stack(arg).into_iter().chain(on_start.iter().copied()).collect()))
+            .with_config(args.clone()));
+    }
+    cases.push(Case::simple(String::from("arg past the last"), &[Instruction::Push(2)], Instruction::Arg, Expected::fault())
+        .with_config(args.clone()));
+    cases.push(Case::simple(String::from("arg -1"), &[Instruction::Push(-1)], Instruction::Arg, Expected::fault())
+        .with_config(args));
+    cases.push(Case::simple(String::from("arg without arguments"), &[Instruction::Push(0)], Instruction::Arg, Expected::fault()));
+
+    /* The environment differs from machine to machine, so only the allowlist is checked. */
+    cases.push(Case::simple(String::from("getenv of a variable not on the allowlist"), &pushed("PATH"), Instruction::GetEnv, Expected::fault()));
+    let allowed = VmConfig { env_allowlist: Vec::from([String::from("VM_SELFTEST_UNSET")]), ..VmConfig::default() };
+    cases.push(Case::simple(String::from("getenv of the wrong variable"), &pushed("PATH"), Instruction::GetEnv, Expected::fault())
+        .with_config(allowed.clone()));
+    cases.push(Case::simple(String::from("getenv of an unset variable"), &pushed("VM_SELFTEST_UNSET"), Instruction::GetEnv,
+        Expected::stack(on_stack(""))).with_config(allowed));
+
+    for (text, expected) in [("123", 123), (" -42\n", -42), ("0x1f", 31), ("0b101", 5), ("2147483647", i32::MAX), ("-2147483648", i32::MIN)] {
+        cases.push(Case::simple(format!("atoi {:?}", text), &pushed(text), Instruction::Atoi, Expected::stack(Vec::from([1, expected]))));
+    }
+    for text in ["", "twelve", "12x", "2147483648", "--1"] {
+        cases.push(Case::simple(format!("atoi {:?}", text), &pushed(text), Instruction::Atoi, Expected::stack(Vec::from([0, 0]))));
+    }
+    cases.push(Case::simple(String::from("atoi below the stack"), &[], Instruction::Atoi, Expected::fault()));
+    for value in [0, 7, -1, 100_000, i32::MAX, i32::MIN] {
+        cases.push(Case::simple(format!("itoa {}", value), &constant(value), Instruction::Itoa, Expected::stack(on_stack(&value.to_string()))));
+    }
+    cases.push(Case::simple(String::from("itoa on an empty stack"), &[], Instruction::Itoa, Expected::fault()));
+}
+
+/* The clock can't be pinned down, but the cycle counter and a seeded rand can. */
+fn counter_cases(cases: &mut Vec<Case>) {
+    for seed in [0, 1, u64::MAX] {
+        let expected = Rng::new(seed).next_u64() as i32;
+        cases.push(Case::simple(format!("rand with seed {}", seed), &[], Instruction::Rand, Expected::stack(Vec::from([expected])))
+            .with_config(VmConfig { seed: Some(seed), ..VmConfig::default() }));
+    }
+
+    cases.push(Case::simple(String::from("cycles at the start"), &[], Instruction::Cycles, Expected::stack(Vec::from([0]))));
+    cases.push(Case::simple(String::from("cycles after three"), &[Instruction::Push(5), Instruction::Nop, Instruction::Nop],
+        Instruction::Cycles, Expected::stack(Vec::from([3, 5]))));
+}
+
+/* load and store on memory. Devices need a VirtualMachine::map_device call, which a case can't
+ * make, so only the empty mmio range is checked here. */
+fn memory_cases(cases: &mut Vec<Case>) {
+    let top = MEMORY_SIZE as i32 - 4;
+    cases.push(Case::simple(String::from("load the top word"), &[Instruction::Push(7), Instruction::Push(top)], Instruction::Load,
+        Expected::stack(Vec::from([7, 7]))));
+    cases.push(Case::simple(String::from("load an instruction"), &[Instruction::Push(0)], Instruction::Load,
+        Expected::stack(Vec::from([Instruction::Push(0).encode().swap_bytes() as i32]))));
+    cases.push(Case::simple(String::from("store over the top word"), &[Instruction::Push(1), Instruction::Push(9), Instruction::Push(top)],
+        Instruction::Store, Expected::stack(Vec::from([9]))));
+    /* Stack words are big-endian by default and instructions always little-endian, so this turns exit 1 into nop. */
+    let nop = Instruction::Nop.encode().swap_bytes() as i32;
+    let unprotected = VmConfig { protect_code: false, ..VmConfig::default() };
+    cases.push(Case::new(String::from("store into the code"), &[Instruction::Push(nop), Instruction::Push(12)],
+        Instruction::Store, &[Instruction::Exit(1), Instruction::Exit(0)], Expected::stack(Vec::new())).with_config(unprotected.clone()));
+    cases.push(Case::new(String::from("store into protected code"), &[Instruction::Push(nop), Instruction::Push(12)],
+        Instruction::Store, &[Instruction::Exit(1), Instruction::Exit(0)], Expected::fault()));
+    cases.push(Case::simple(String::from("swap into protected code"), &[Instruction::Push(1)], Instruction::Swap { from: 0, to: -1022 },
+        Expected::fault()));
+
+    for address in [-4, -1, MEMORY_SIZE as i32 - 3, MEMORY_SIZE as i32] {
+        cases.push(Case::simple(format!("load from {}", address), &[Instruction::Push(address)], Instruction::Load, Expected::fault()));
+        cases.push(Case::simple(format!("store to {}", address), &[Instruction::Push(1), Instruction::Push(address)], Instruction::Store,
+            Expected::fault()));
+    }
+    cases.push(Case::simple(String::from("store without a value"), &[Instruction::Push(top)], Instruction::Store, Expected::fault()));
+
+    let mmio = VmConfig { mmio: Some(0x10000..0x10100), ..VmConfig::default() };
+    cases.push(Case::simple(String::from("load with no device mapped"), &[Instruction::Push(0x10000)], Instruction::Load,
+        Expected::fault()).with_config(mmio.clone()));
+    cases.push(Case::simple(String::from("store with no device mapped"), &[Instruction::Push(1), Instruction::Push(0x100FC)],
+        Instruction::Store, Expected::fault()).with_config(mmio));
+}
+
+/* pushb, pushh, popb and poph, which move the stack pointer by less than a word. */
+fn narrow_cases(cases: &mut Vec<Case>) {
+    for (value, signed, unsigned) in [(0x41, 0x41, 0x41), (0x1C1, -63, 0xC1), (-1, -1, 0xFF), (0x100, 0, 0)] {
+        for (signed_pop, expected) in [(true, signed), (false, unsigned)] {
+            cases.push(Case::new(format!("pushb then popb{} {}", if signed_pop { "" } else { "u" }, value), &[Instruction::Push(value)],
+                Instruction::PushByte, &[Instruction::PopByte { signed: signed_pop }, Instruction::Exit(0)], Expected::stack(Vec::from([expected]))));
+        }
+    }
+    for (value, signed, unsigned) in [(0x4142, 0x4142, 0x4142), (0x18001, -32767, 0x8001), (-2, -2, 0xFFFE)] {
+        for (signed_pop, expected) in [(true, signed), (false, unsigned)] {
+            cases.push(Case::new(format!("pushh then poph{} {}", if signed_pop { "" } else { "u" }, value), &[Instruction::Push(value)],
+                Instruction::PushHalf, &[Instruction::PopHalf { signed: signed_pop }, Instruction::Exit(0)], Expected::stack(Vec::from([expected]))));
+        }
+    }
+
+    /* Four bytes make a word, the first one pushed lowest in it, being highest in memory. */
+    let mut setup = Vec::new();
+    for byte in 1..=3 {
+        setup.extend([Instruction::Push(byte), Instruction::PushByte]);
+    }
+    setup.push(Instruction::Push(4));
+    cases.push(Case::simple(String::from("four pushb make a word"), &setup, Instruction::PushByte, Expected::stack(Vec::from([0x0403_0201]))));
+    cases.push(Case::new(String::from("two pushh make a word"), &[Instruction::Push(0x0102), Instruction::PushHalf, Instruction::Push(0x0304)],
+        Instruction::PushHalf, &[Instruction::Exit(0)], Expected::stack(Vec::from([0x0304_0102]))));
+    cases.push(Case::new(String::from("popb and poph off a word"), &[Instruction::Push(0x0102_0304)], Instruction::PopByte { signed: true },
+        &[Instruction::Pop(4), Instruction::PopHalf { signed: true }, Instruction::Pop(4), Instruction::PopByte { signed: true }, Instruction::Exit(0)],
+        Expected::stack(Vec::from([0x04]))));
+
+    cases.push(Case::simple(String::from("pushb on an empty stack"), &[], Instruction::PushByte, Expected::fault()));
+    cases.push(Case::simple(String::from("popb on an empty stack"), &[], Instruction::PopByte { signed: true }, Expected::fault()));
+    cases.push(Case::simple(String::from("poph with one byte"), &[Instruction::Push(1), Instruction::PushByte], Instruction::PopHalf { signed: false },
+        Expected::fault()));
+    cases.push(Case::simple(String::from("pushh past the guard"), &[Instruction::Push(1)], Instruction::PushHalf, Expected::fault())
+        .with_config(VmConfig { stack_guard: Some(MEMORY_SIZE), ..VmConfig::default() }));
+}
+
+/* spawn, yield and join. Only the main context's stack is checked; the others live in their
+ * own regions. */
+fn context_cases(cases: &mut Vec<Case>) {
+    cases.push(Case::new(String::from("spawn pushes the new context's id"), &[Instruction::Push(5)], Instruction::Spawn(8),
+        &[Instruction::Exit(0), Instruction::Exit(1)], Expected::stack(Vec::from([1]))));
+    cases.push(Case::new(String::from("join collects the exit code"), &[Instruction::Push(5)], Instruction::Spawn(12),
+        &[Instruction::Join, Instruction::Exit(0), Instruction::Exit(3)], Expected::stack(Vec::from([3]))));
+    /* The new context starts with the word spawn took, and doubles it into the main stack. */
+    cases.push(Case::new(String::from("spawn passes a word"), &[Instruction::Push(0), Instruction::Push(21)], Instruction::Spawn(16),
+        &[Instruction::Join, Instruction::Pop(4), Instruction::Exit(0),
+            Instruction::Push(2), Instruction::Binary(BinaryOp::Mul), Instruction::Push(MEMORY_SIZE as i32 - 4), Instruction::Store, Instruction::Exit(0)],
+        Expected::stack(Vec::from([42]))));
+    cases.push(Case::simple(String::from("yield with nothing else to run"), &[Instruction::Push(1)], Instruction::Yield,
+        Expected::stack(Vec::from([1]))));
+    /* The other context pushes 7 over the main one's 0 when main yields. */
+    cases.push(Case::new(String::from("yield lets another context run"), &[Instruction::Push(0), Instruction::Push(0), Instruction::Spawn(16), Instruction::Pop(4)],
+        Instruction::Yield, &[Instruction::Exit(0), Instruction::Push(7), Instruction::Push(MEMORY_SIZE as i32 - 4), Instruction::Store, Instruction::Exit(0)],
+        Expected::stack(Vec::from([7]))));
+    for id in [0, 1, -1] {
+        cases.push(Case::simple(format!("join {} with nothing spawned", id), &[Instruction::Push(id)], Instruction::Join, Expected::fault()));
+    }
+    cases.push(Case::simple(String::from("join without an id"), &[], Instruction::Join, Expected::fault()));
+    cases.push(Case::simple(String::from("spawn without a word"), &[], Instruction::Spawn(0), Expected::fault()));
+    cases.push(Case::new(String::from("spawn with no room for a stack"), &[Instruction::Push(5)], Instruction::Spawn(8),
+        &[Instruction::Exit(0), Instruction::Exit(1)], Expected::fault())
+        .with_config(VmConfig { context_stack: Some(0), ..VmConfig::default() }));
+
+    /* Two contexts each joining the other while the main one joins the second. */
+    let joins = |id| [Instruction::Pop(4), Instruction::Push(id), Instruction::Join, Instruction::Exit(0)];
+    let tail: Vec<Instruction> = [Instruction::Exit(0)].into_iter().chain(joins(2)).chain(joins(1)).collect();
+    cases.push(Case::new(String::from("join deadlock"), &[Instruction::Push(0), Instruction::Spawn(20), Instruction::Push(0), Instruction::Spawn(28)],
+        Instruction::Join, &tail, Expected::fault()));
+
+    /* The other context never yields, but a time slice takes turns away from it anyway. */
+    let spin = [Instruction::Push(9), Instruction::Push(MEMORY_SIZE as i32 - 4), Instruction::Store, Instruction::Goto(0)];
+    let tail: Vec<Instruction> = [Instruction::Exit(0)].into_iter().chain(spin).collect();
+    cases.push(Case::new(String::from("time slice"), &[Instruction::Push(0), Instruction::Push(0), Instruction::Spawn(16), Instruction::Pop(4)],
+        Instruction::Yield, &tail, Expected::stack(Vec::from([9])))
+        .with_config(VmConfig { time_slice: Some(3), ..VmConfig::default() }));
+}
+
+/* cas, fetchadd, lock and unlock, on the word at the top of memory. */
+fn atomic_cases(cases: &mut Vec<Case>) {
+    let top = MEMORY_SIZE as i32 - 4;
+    cases.push(Case::simple(String::from("cas that matches"), &[Instruction::Push(5), Instruction::Push(5), Instruction::Push(9), Instruction::Push(top)],
+        Instruction::Cas, Expected::stack(Vec::from([5, 9]))));
+    cases.push(Case::simple(String::from("cas that doesn't match"), &[Instruction::Push(5), Instruction::Push(4), Instruction::Push(9), Instruction::Push(top)],
+        Instruction::Cas, Expected::stack(Vec::from([5, 5]))));
+    cases.push(Case::simple(String::from("cas outside memory"), &[Instruction::Push(0), Instruction::Push(1), Instruction::Push(MEMORY_SIZE as i32)],
+        Instruction::Cas, Expected::fault()));
+
+    for (word, delta, sum) in [(5, 3, 8), (5, -6, -1), (i32::MAX, 1, i32::MIN)] {
+        let mut setup = constant(word);
+        setup.extend([Instruction::Push(delta), Instruction::Push(top)]);
+        cases.push(Case::simple(format!("fetchadd {} to {}", delta, word), &setup, Instruction::FetchAdd, Expected::stack(Vec::from([word, sum]))));
+    }
+
+    cases.push(Case::simple(String::from("lock a free mutex"), &[Instruction::Push(0), Instruction::Push(top)], Instruction::Lock,
+        Expected::stack(Vec::from([1]))));
+    cases.push(Case::simple(String::from("unlock"), &[Instruction::Push(0), Instruction::Push(top), Instruction::Lock, Instruction::Push(top)],
+        Instruction::Unlock, Expected::stack(Vec::from([0]))));
+    cases.push(Case::simple(String::from("lock twice"), &[Instruction::Push(0), Instruction::Push(top), Instruction::Lock, Instruction::Push(top)],
+        Instruction::Lock, Expected::fault()));
+    for word in [0, 2] {
+        cases.push(Case::simple(format!("unlock a mutex holding {}", word), &[Instruction::Push(word), Instruction::Push(top)], Instruction::Unlock,
+            Expected::fault()));
+    }
+
+    /* The other context takes the lock and yields with it held; main waits until it's let go. */
+    let holder = [Instruction::Pop(4), Instruction::Push(top), Instruction::Lock, Instruction::Yield, Instruction::Push(top), Instruction::Unlock, Instruction::Exit(0)];
+    let tail: Vec<Instruction> = [Instruction::Exit(0)].into_iter().chain(holder).collect();
+    cases.push(Case::new(String::from("lock waits for another context"),
+        &[Instruction::Push(0), Instruction::Push(0), Instruction::Spawn(24), Instruction::Pop(4), Instruction::Yield, Instruction::Push(top)],
+        Instruction::Lock, &tail, Expected::stack(Vec::from([1]))));
+}
+
+/* Words no handler accepts. */
+fn bad_cases(cases: &mut Vec<Case>) {
+    let words = [0x0300_0000, 0x0400_0003, 0x0600_0000, 0x0E00_0000, 0x1000_0002, 0x2AA0_0000, 0x3200_0000, 0xA000_0000, 0xB180_0002, 0xB220_0005, 0xB260_0004, 0xB270_0000, 0xBFF0_0000];
+
+    for word in words {
+        cases.push(Case {
+            name: format!("bad word {:#010x}", word),
+            word,
+            decoded: None,
+            program: Vec::from([Instruction::Push(1).encode(), word, Instruction::Exit(0).encode()]),
+            input: String::new(),
+            config: VmConfig::default(),
+            expected: Expected::fault(),
+            reference: true,
+        });
+    }
+}
+
+/* The string instructions again, with length-prefixed strings. */
+fn byte_string_cases(cases: &mut Vec<Case>) {
+    let bytes = VmConfig { string_format: StringFormat::LengthPrefixed, ..VmConfig::default() };
+    let pushed = |text: &str| string_pushes(text, Header::BYTE_STRINGS);
+    let on_stack = |text: &str| -> Vec<i32> { strings::prefixed(text.as_bytes(), 4, Endianness::Big).into_iter().map(|word| word as i32).collect() };
+
+    for text in ["", "hi", "abcd", "hello, world", "a\0b"] {
+        cases.push(Case::simple(format!("stprint {:?} as bytes", text), &pushed(text), Instruction::StPrint(0),
+            Expected::output(on_stack(text), String::from(text))).with_config(bytes.clone()));
+        cases.push(Case::simple(format!("strlen {:?} as bytes", text), &pushed(text), Instruction::StrLen(0),
+            Expected::stack([text.len() as i32].into_iter().chain(on_stack(text)).collect())).with_config(bytes.clone()));
+    }
+
+    for (left, right) in [("", ""), ("abc", "def"), ("hello", ", world"), ("ab", "abc")] {
+        let mut setup = Vec::from([Instruction::Push(7)]);
+        setup.extend(pushed(left));
+        setup.extend(pushed(right));
+
+        let joined = format!("{}{}", left, right);
+        cases.push(Case::simple(format!("strcat {:?} {:?} as bytes", left, right), &setup, Instruction::StrCat,
+            Expected::stack(on_stack(&joined).into_iter().chain([7]).collect())).with_config(bytes.clone()));
+        cases.push(Case::simple(format!("strcmp {:?} {:?} as bytes", left, right), &setup, Instruction::StrCmp,
+            Expected::stack(Vec::from([left.cmp(right) as i32, 7]))).with_config(bytes.clone()));
+    }
+
+    cases.push(Case::simple(String::from("stinput as bytes"), &[], Instruction::StInput(8),
+        Expected::stack(on_stack("hello, w"))).with_input("hello, world\n").with_config(bytes.clone()));
+    /* A length that runs off the end of memory. */
+    cases.push(Case::simple(String::from("stprint a string too long as bytes"), &[Instruction::Push(5)], Instruction::StPrint(0),
+        Expected::fault()).with_config(bytes.clone()));
+    cases.push(Case::simple(String::from("strlen a negative length as bytes"), &[Instruction::Push(-1)], Instruction::StrLen(0),
+        Expected::fault()).with_config(bytes));
+}
+
+/* Little-endian words. The words on the stack are the same either way; what changes is where
+ * their bytes are, which load of an instruction, stores into the code, stprintn and the narrow
+ * instructions all see. */
+fn little_endian_cases(cases: &mut Vec<Case>) {
+    let little = VmConfig { endianness: Endianness::Little, ..VmConfig::default() };
+    let top = MEMORY_SIZE as i32 - 4;
+
+    /* The same order as instructions, so an instruction loads as itself and stores as itself. */
+    cases.push(Case::simple(String::from("load an instruction little-endian"), &[Instruction::Push(0)], Instruction::Load,
+        Expected::stack(Vec::from([Instruction::Push(0).encode() as i32]))).with_config(little.clone()));
+    let unprotected = VmConfig { protect_code: false, ..little.clone() };
+    cases.push(Case::new(String::from("store into the code little-endian"), &[Instruction::Push(Instruction::Nop.encode() as i32), Instruction::Push(12)],
+        Instruction::Store, &[Instruction::Exit(1), Instruction::Exit(0)], Expected::stack(Vec::new())).with_config(unprotected));
+
+    /* The bytes of 0x414243 are 'C', 'B', 'A', 0. */
+    for (length, text) in [(1, "C"), (3, "CBA"), (4, "CBA\0")] {
+        cases.push(Case::simple(format!("stprintn {} little-endian", length), &[Instruction::Push(0x41_4243), Instruction::Push(length), Instruction::Push(top)],
+            Instruction::StPrintN, Expected::output(Vec::from([0x41_4243]), String::from(text))).with_config(little.clone()));
+    }
+
+    /* The first byte pushed is highest in memory and now the most significant. */
+    let mut setup = Vec::new();
+    for byte in 1..=3 {
+        setup.extend([Instruction::Push(byte), Instruction::PushByte]);
+    }
+    setup.push(Instruction::Push(4));
+    cases.push(Case::simple(String::from("four pushb make a word little-endian"), &setup, Instruction::PushByte,
+        Expected::stack(Vec::from([0x0102_0304]))).with_config(little.clone()));
+    cases.push(Case::new(String::from("two pushh make a word little-endian"), &[Instruction::Push(0x0102), Instruction::PushHalf, Instruction::Push(0x0304)],
+        Instruction::PushHalf, &[Instruction::Exit(0)], Expected::stack(Vec::from([0x0102_0304]))).with_config(little.clone()));
+    cases.push(Case::new(String::from("popb and poph off a word little-endian"), &[Instruction::Push(0x0102_0304)], Instruction::PopByte { signed: true },
+        &[Instruction::Pop(4), Instruction::PopHalf { signed: true }, Instruction::Pop(4), Instruction::PopByte { signed: true }, Instruction::Exit(0)],
+        Expected::stack(Vec::from([0x01]))).with_config(little.clone()));
+    for (value, expected) in [(0x41, 0x41), (-2, -2), (0x1_8001, -32767)] {
+        cases.push(Case::new(format!("pushh then poph {} little-endian", value), &[Instruction::Push(value)], Instruction::PushHalf,
+            &[Instruction::PopHalf { signed: true }, Instruction::Exit(0)], Expected::stack(Vec::from([expected]))).with_config(little.clone()));
+    }
+
+    /* A byte string's text is in memory in order whichever way round its words are. */
+    let bytes = VmConfig { string_format: StringFormat::LengthPrefixed, ..little };
+    let on_stack = |text: &str| -> Vec<i32> { strings::prefixed(text.as_bytes(), 4, Endianness::Little).into_iter().map(|word| word as i32).collect() };
+    for text in ["", "hi", "abcd", "hello, world"] {
+        let mut setup = string_pushes(text, Header::BYTE_STRINGS | Header::LITTLE_ENDIAN);
+        cases.push(Case::simple(format!("stprint {:?} as little-endian bytes", text), &setup, Instruction::StPrint(0),
+            Expected::output(on_stack(text), String::from(text))).with_config(bytes.clone()));
+
+        let text_address = MEMORY_SIZE - text.len().next_multiple_of(4);
+        setup.extend([Instruction::Push(text.len() as i32), Instruction::Push(text_address as i32)]);
+        cases.push(Case::simple(format!("stprintn {:?} as little-endian bytes", text), &setup, Instruction::StPrintN,
+            Expected::output(on_stack(text), String::from(text))).with_config(bytes.clone()));
+    }
+}
+
+/* allowed_ops. An instruction faults without its group, and runs as usual with every other
+ * group gone. */
+fn permission_cases(cases: &mut Vec<Case>) {
+    let allowing = |ops: Ops| VmConfig { allowed_ops: ops, ..VmConfig::default() };
+    let groups = [
+        (Ops::IO, "io", Vec::from([Instruction::Push(7)]), Instruction::Print(0, PrintFormat::Decimal), Vec::from([7])),
+        (Ops::IO, "io", Vec::from([Instruction::Push(0x41), Instruction::Push(1), Instruction::Push(MEMORY_SIZE as i32 - 1)]), Instruction::StPrintN, Vec::from([0x41])),
+        (Ops::DEBUG, "debug", Vec::from([Instruction::Push(1)]), Instruction::Dump, Vec::from([1])),
+        (Ops::DEBUG, "debug", Vec::new(), Instruction::Debug { bytes: 0, binary: false }, Vec::new()),
+        (Ops::FILES, "files", Vec::new(), Instruction::ReadFile, Vec::new()),
+        (Ops::HOST, "host", Vec::new(), Instruction::Clock, Vec::new()),
+    ];
+
+    for (group, name, setup, instruction, stack) in groups {
+        cases.push(Case::simple(format!("{} without {}", instruction, name), &setup, instruction, Expected::fault())
+            .with_config(allowing(Ops::ALL.without(group))));
+        /* readfile and clock have nothing to show for it that a case can check. */
+        if !stack.is_empty() || group == Ops::DEBUG {
+            cases.push(Case::simple(format!("{} with only {}", instruction, name), &setup, instruction, Expected::stack(stack))
+                .with_config(allowing(group)));
+        }
+    }
+
+    cases.push(Case::simple(String::from("add with nothing allowed"), &[Instruction::Push(1), Instruction::Push(2)],
+        Instruction::Binary(BinaryOp::Add), Expected::stack(Vec::from([3]))).with_config(allowing(Ops::NONE)));
+}
+
+/* Heap and stack sizes. The heap starts where the program ends, which for these is right after
+ * the exit. */
+fn segment_cases(cases: &mut Vec<Case>) {
+    let sized = |heap_size, stack_size| VmConfig { heap_size, stack_size, ..VmConfig::default() };
+    let top = MEMORY_SIZE as i32;
+
+    /* The stack stops at the top of the heap, so there's that much less room. */
+    cases.push(Case::simple(String::from("stackdepth with a heap"), &[], Instruction::StackDepth,
+        Expected::stack(Vec::from([top - 8 - 1024]))).with_config(sized(Some(1024), None)));
+    cases.push(Case::simple(String::from("stackdepth with a stack size"), &[], Instruction::StackDepth,
+        Expected::stack(Vec::from([256]))).with_config(sized(None, Some(256))));
+    cases.push(Case::simple(String::from("push past the stack's segment"), &[Instruction::Push(1)], Instruction::Push(2), Expected::fault())
+        .with_config(sized(None, Some(4))));
+
+    let heap = 24;
+    cases.push(Case::new(String::from("store into the heap"), &[Instruction::Push(9), Instruction::Push(heap)], Instruction::Store,
+        &[Instruction::Push(heap), Instruction::Load, Instruction::Exit(0)], Expected::stack(Vec::from([9]))).with_config(sized(Some(64), None)));
+    cases.push(Case::simple(String::from("store between the heap and the stack"), &[Instruction::Push(1), Instruction::Push(2048)],
+        Instruction::Store, Expected::fault()).with_config(sized(Some(64), Some(256))));
+    /* With only the stack sized, the heap is everything under it. */
+    cases.push(Case::simple(String::from("store into a heap the stack leaves"), &[Instruction::Push(1), Instruction::Push(2048)],
+        Instruction::Store, Expected::stack(Vec::new())).with_config(sized(None, Some(256))));
+}
+
+/* The whole battery. */
+pub fn cases() -> Vec<Case> {
+    let mut cases = Vec::new();
+    misc_cases(&mut cases);
+    stack_cases(&mut cases);
+    arithmetic_cases(&mut cases);
+    control_cases(&mut cases);
+    print_cases(&mut cases);
+    string_cases(&mut cases);
+    byte_string_cases(&mut cases);
+    little_endian_cases(&mut cases);
+    permission_cases(&mut cases);
+    segment_cases(&mut cases);
+    counter_cases(&mut cases);
+    memory_cases(&mut cases);
+    narrow_cases(&mut cases);
+    context_cases(&mut cases);
+    atomic_cases(&mut cases);
+    bad_cases(&mut cases);
+    cases
+}
@@ -0,0 +1,35 @@
+/* A running hash of every step a program takes: the pc, the stack pointer and the instruction
+ * word, each time an instruction runs. Two runs that end with the same hash ran the same
+ * instructions from the same places with the same stack, which is how a grader can tell a
+ * submission did what it says it did, or a replay that it went the way the recording did.
+ *
+ * It's 64-bit FNV-1a over the three as little-endian bytes, so it comes out the same on any
+ * host, and doesn't depend on whether the program ran as threaded code. It isn't a
+ * cryptographic hash: it shows two honest runs agree, not that nobody made them agree. */
+
+const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const PRIME: u64 = 0x0000_0100_0000_01b3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct StateHash {
+    state: u64,
+}
+
+impl StateHash {
+    pub(crate) fn new() -> StateHash {
+        StateHash { state: OFFSET_BASIS }
+    }
+
+    pub(crate) fn record(&mut self, pc: i32, sp: i32, instruction: u32) {
+        let bytes = pc.to_le_bytes().into_iter()
+            .chain(sp.to_le_bytes())
+            .chain(instruction.to_le_bytes());
+        for byte in bytes {
+            self.state = (self.state ^ byte as u64).wrapping_mul(PRIME);
+        }
+    }
+
+    pub(crate) fn digest(&self) -> u64 {
+        self.state
+    }
+}
@@ -0,0 +1,25 @@
+/* The guest standard library: the assembly routines in stdlib/, built into the crate so any
+ * program can include them by name wherever it's assembled, as in
+ *
+ *     .include "stdlib/math.s"
+ *
+ * A file of that name beside the program or on the include path comes first. Each file says
+ * what's in it at the top. The routines take their arguments on the stack, pushed in order
+ * before the call, and leave any result in their place, so
+ *
+ *     push -5
+ *     call abs        # 5 on top
+ *
+ * They're code, so they go where the program won't run into them, after its exit. */
+
+/* Every file, by the name it's included as. */
+pub const FILES: [(&str, &str); 3] = [
+    ("stdlib/math.s", include_str!("../stdlib/math.s")),
+    ("stdlib/print.s", include_str!("../stdlib/print.s")),
+    ("stdlib/string.s", include_str!("../stdlib/string.s")),
+];
+
+/* The text of a file, if there's one by that name. */
+pub fn file(name: &str) -> Option<&'static str> {
+    FILES.iter().find(|&&(file, _)| file == name).map(|&(_, text)| text)
+}
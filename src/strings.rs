@@ -0,0 +1,76 @@
+/* Packed strings, the way stinput reads them and stprint prints them: three characters to a word,
+ * the first in the lowest byte, with bit 24 set when another chunk follows. The first chunk is
+ * on top of the stack and the rest run up towards the end of memory.
+ *
+ * A string always ends with a chunk of fewer than three characters, even if that means an empty
+ * one: "hello" is two chunks, "abc" is "abc" and then an empty chunk. The full chunk before an
+ * empty last one doesn't get the continuation bit, so a full chunk also means more follows.
+ *
+ * That's awkward to work with, and can't hold every byte, so with VmConfig::string_format set to
+ * LengthPrefixed (which the byte_strings header feature asks for) strings are instead a word
+ * holding the length, on top of the stack, and then the bytes themselves in order, running up
+ * towards the end of memory and padded with zeros to a whole word. The first byte is the
+ * highest in the word after the length when words are big-endian, and the lowest when they're
+ * little-endian. */
+
+use alloc::vec::Vec;
+
+use crate::Endianness;
+
+/* Set on every chunk but the last. */
+pub(crate) const CONTINUES: i64 = 1 << 24;
+
+/* The chunks for some text, first chunk first. Push them in reverse to get the string on the
+ * stack. */
+pub(crate) fn pack(text: &[u8]) -> Vec<u32> {
+    let mut words = Vec::new();
+
+    for (i, chunk) in text.chunks(3).enumerate() {
+        let mut word = 0u32;
+        for (j, &byte) in chunk.iter().enumerate() {
+            word |= (byte as u32) << (8 * j);
+        }
+        if (i + 1) * 3 < text.len() {
+            word |= CONTINUES as u32;
+        }
+        words.push(word);
+    }
+
+    if text.len().is_multiple_of(3) {
+        words.push(0);
+    }
+
+    words
+}
+
+/* The characters in a chunk. Zero bytes are padding; ones are skipped too, as they always have
+ * been by stprint. */
+pub(crate) fn chars(word: i64) -> impl Iterator<Item = u8> {
+    (0..3)
+        .map(move |shift| ((word >> (8 * shift)) & 0xff) as u8)
+        .filter(|&byte| byte > 1)
+}
+
+/* Whether the string carries on past this chunk. */
+pub(crate) fn continues(word: i64) -> bool {
+    word & CONTINUES != 0 || (word >> 16) & 0xff != 0
+}
+
+/* The words for a length-prefixed string, the length first. Push them in reverse to get the
+ * string on the stack. */
+pub(crate) fn prefixed(text: &[u8], word_bytes: usize, endianness: Endianness) -> Vec<u64> {
+    let mut words = Vec::from([text.len() as u64]);
+
+    for chunk in text.chunks(word_bytes) {
+        let mut bytes = Vec::from(chunk);
+        bytes.resize(word_bytes, 0);
+        words.push(endianness.read(&bytes));
+    }
+
+    words
+}
+
+/* How many bytes of memory a length-prefixed string of length bytes takes up, length included. */
+pub(crate) fn prefixed_size(length: usize, word_bytes: usize) -> usize {
+    word_bytes + length.next_multiple_of(word_bytes)
+}
@@ -0,0 +1,83 @@
+/* Threaded code, for VmConfig::threaded. The interpreter takes every instruction apart again
+ * each time it runs it; this goes through the code once, up front, and pairs each word with
+ * the handler for it, so running an instruction is a lookup and a call. The handlers are the
+ * interpreter's own, so the two can't disagree about what an instruction does.
+ *
+ * Anything written into the code marks the table stale, and it's built again before the next
+ * instruction, so self-modifying programs and the debugger's patches still work, just slowly.
+ *
+ * What it saves is the decoding, so it helps most where the instructions themselves are cheap;
+ * benches/dispatch.rs compares the two. */
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::{Memory, VirtualMachine, VmError};
+
+pub(crate) type Handler = fn(&mut VirtualMachine, u32) -> Result<(), VmError>;
+
+pub(crate) struct Threaded {
+    ops: Vec<(Handler, u32)>,
+    stale: bool,
+}
+
+fn bad(_: &mut VirtualMachine, _: u32) -> Result<(), VmError> {
+    Err(VmError::from(String::from("Bad instruction.")))
+}
+
+/* The handler for a word, picked the same way execute_instruction picks. */
+fn handler(word: u32) -> Handler {
+    match word >> 28 {
+        0 => match word >> 24 {
+            0x0 => VirtualMachine::exit,
+            0x1 => VirtualMachine::swap,
+            0x2 => |_, _| Ok(()),
+            0x4 => VirtualMachine::input,
+            0x5 => VirtualMachine::stinput,
+            0xF => VirtualMachine::debug,
+            _ => bad,
+        },
+        1 => VirtualMachine::pop,
+        2 => |vm, word| vm.binary_arithmetic(word).map(|_| ()),
+        3 => VirtualMachine::unary_arithmetic,
+        4 => VirtualMachine::stprint,
+        5 => VirtualMachine::call,
+        6 => VirtualMachine::ret,
+        7 => VirtualMachine::goto,
+        8 => VirtualMachine::binary_if,
+        9 => VirtualMachine::unary_if,
+        10 => VirtualMachine::plugin,
+        11 => VirtualMachine::extended,
+        12 => VirtualMachine::dup,
+        13 => VirtualMachine::print,
+        14 => |vm, _| vm.dump(),
+        15 => VirtualMachine::push,
+        _ => bad,
+    }
+}
+
+impl Threaded {
+    pub(crate) fn new() -> Threaded {
+        Threaded { ops: Vec::new(), stale: true }
+    }
+
+    /* Something has written over the code. */
+    pub(crate) fn invalidate(&mut self) {
+        self.stale = true;
+    }
+
+    /* The handler and word for the instruction at pc, which has to be in the code. */
+    pub(crate) fn op(&mut self, memory: &Memory, code_end: usize, pc: i32) -> (Handler, u32) {
+        if self.stale {
+            self.ops = memory.as_slice()[..code_end / 4 * 4].chunks(4)
+                .map(|bytes| {
+                    let word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+                    (handler(word), word)
+                })
+                .collect();
+            self.stale = false;
+        }
+
+        self.ops[pc as usize / 4]
+    }
+}
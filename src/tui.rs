@@ -0,0 +1,261 @@
+/* A full-screen monitor for `vm tui`, for watching a program run rather than asking after it
+ * the way the debugger does. The screen is split into the code around the pc, disassembled,
+ * the memory around the stack pointer in hex, the registers, and everything the program has
+ * printed, and all of it is redrawn after every step:
+ *
+ *     ┌code──────────────────────────┐┌stack─────────────────────────────┐
+ *     │  0000  f000000a  push 10     ││ 0ff0  00 00 00 00 00 00 00 00  ..│
+ *     │▶ 0004  d0000000  print 0     ││>0ff8  00 00 00 00 00 00 00 0a  ..│
+ *     │● 0008  ffffffff  push -1     │└──────────────────────────────────┘
+ *     │  ...                         │┌machine───────────────────────────┐
+ *     └──────────────────────────────┘│ pc 0004  sp 0ffc  cycles 1  ...  │
+ *     ┌output──────────────────────────────────────────────────────────────┐
+ *     │10                                                                  │
+ *
+ * The keys: s or space steps, c runs until a breakpoint or the end (any key stops it early),
+ * the arrows move the cursor in the code, b sets or clears a breakpoint at the cursor, r starts
+ * over and q leaves. The program's input comes from a file given with --input, since the
+ * terminal is taken; without one it reads nothing. */
+
+use std::collections::BTreeSet;
+use std::io;
+use std::time::Duration;
+
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Modifier, Stylize};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Paragraph};
+use ratatui::Frame;
+
+use crate::harness::SharedBuffer;
+use crate::{isa, StepResult, VirtualMachine};
+
+/* How many instructions run between looks at the keyboard while continuing. */
+const SLICE: usize = 10_000;
+/* Bytes to a row of the stack view. */
+const ROW: i32 = 8;
+
+/* Where the program has got to. */
+enum Status {
+    Paused,
+    Running,
+    Exited(i32),
+    Fault(String),
+}
+
+struct Monitor {
+    vm: VirtualMachine,
+    output: SharedBuffer,
+    input: Vec<u8>,
+    breakpoints: BTreeSet<i32>,
+    /* The instruction b toggles a breakpoint on; it follows the pc as the program runs. */
+    cursor: i32,
+    status: Status,
+}
+
+/* Run the monitor on a loaded program until q. input is what the program reads. */
+pub fn run(vm: VirtualMachine, input: Vec<u8>) -> Result<(), String> {
+    let mut monitor = Monitor {
+        cursor: vm.program_counter(),
+        vm,
+        output: SharedBuffer::new(),
+        input,
+        breakpoints: BTreeSet::new(),
+        status: Status::Paused,
+    };
+    monitor.hook_up();
+
+    let mut terminal = ratatui::init();
+    let result = monitor.event_loop(&mut terminal);
+    ratatui::restore();
+    result.map_err(|e| format!("Terminal error: {}", e))
+}
+
+impl Monitor {
+    fn hook_up(&mut self) {
+        self.vm.set_output(Box::new(self.output.clone()));
+        self.vm.set_diagnostics(Box::new(self.output.clone()));
+        self.vm.set_input(Box::new(io::Cursor::new(self.input.clone())));
+    }
+
+    fn event_loop(&mut self, terminal: &mut ratatui::DefaultTerminal) -> io::Result<()> {
+        loop {
+            terminal.draw(|frame| self.draw(frame))?;
+
+            /* While running, only wait for a key as long as it takes to notice one. */
+            let wait = if matches!(self.status, Status::Running) { Duration::ZERO } else { Duration::from_millis(250) };
+            if event::poll(wait)? {
+                if let Event::Key(key) = event::read()? {
+                    if key.kind == KeyEventKind::Press && !self.key(key.code) {
+                        return Ok(());
+                    }
+                }
+            }
+
+            if matches!(self.status, Status::Running) {
+                self.run_slice();
+            }
+        }
+    }
+
+    /* Act on a key. False to quit. */
+    fn key(&mut self, code: KeyCode) -> bool {
+        if matches!(self.status, Status::Running) {
+            self.status = Status::Paused;
+            return code != KeyCode::Char('q');
+        }
+
+        match code {
+            KeyCode::Char('q') | KeyCode::Esc => return false,
+            KeyCode::Char('s') | KeyCode::Char(' ') if !self.finished() => self.step(),
+            KeyCode::Char('c') if !self.finished() => {
+                /* Off a breakpoint first, or it would stop straight away. */
+                self.step();
+                if !self.finished() {
+                    self.status = Status::Running;
+                }
+            },
+            KeyCode::Char('b') => {
+                if self.breakpoints.contains(&self.cursor) {
+                    self.breakpoints.remove(&self.cursor);
+                } else {
+                    self.breakpoints.insert(self.cursor);
+                }
+            },
+            KeyCode::Char('r') => {
+                self.vm.reset();
+                self.output = SharedBuffer::new();
+                self.hook_up();
+                self.cursor = self.vm.program_counter();
+                self.status = Status::Paused;
+            },
+            KeyCode::Up | KeyCode::Char('k') => self.cursor = (self.cursor - 4).max(0),
+            KeyCode::Down | KeyCode::Char('j') => self.cursor = (self.cursor + 4).min(self.vm.code_end() as i32 - 4).max(0),
+            _ => (),
+        }
+        true
+    }
+
+    fn finished(&self) -> bool {
+        matches!(self.status, Status::Exited(_) | Status::Fault(_))
+    }
+
+    fn step(&mut self) {
+        match self.vm.step() {
+            Ok(StepResult::Running) => (),
+            Ok(StepResult::Breakpoint) => self.status = Status::Paused,
+            Ok(StepResult::Exited(code)) => self.status = Status::Exited(code),
+            Err(err) => self.status = Status::Fault(err.to_string()),
+        }
+        self.cursor = self.vm.program_counter();
+    }
+
+    /* Carry on running for a while, stopping at a breakpoint. */
+    fn run_slice(&mut self) {
+        for _ in 0..SLICE {
+            if self.breakpoints.contains(&self.vm.program_counter()) {
+                self.status = Status::Paused;
+                return;
+            }
+            self.step();
+            if !matches!(self.status, Status::Running) {
+                return;
+            }
+        }
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let [top, output] = Layout::vertical([Constraint::Min(8), Constraint::Length(8)]).areas(frame.area());
+        let [code, right] = Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)]).areas(top);
+        let [stack, machine] = Layout::vertical([Constraint::Min(4), Constraint::Length(7)]).areas(right);
+
+        frame.render_widget(self.code(code), code);
+        frame.render_widget(self.stack(stack), stack);
+        frame.render_widget(self.machine(), machine);
+        frame.render_widget(self.output(output), output);
+    }
+
+    /* The code around the cursor, a word to a line. */
+    fn code(&self, area: Rect) -> Paragraph<'static> {
+        let rows = area.height.saturating_sub(2) as i32;
+        let code_end = self.vm.code_end() as i32;
+        let first = (self.cursor / 4 - rows / 2).clamp(0, (code_end / 4 - rows).max(0)) * 4;
+        let labels = self.vm.debug_info().map(|info| &info.labels);
+
+        let mut lines = Vec::new();
+        for address in (first..code_end).step_by(4).take(rows as usize) {
+            let word = self.vm.memory().read_u32(address).unwrap_or(0);
+            let marker = match (address == self.vm.program_counter(), self.breakpoints.contains(&address)) {
+                (true, _) => Span::raw("▶ ").green().bold(),
+                (false, true) => Span::raw("● ").red(),
+                (false, false) => Span::raw("  "),
+            };
+            let label = labels
+                .and_then(|labels| labels.iter().find(|(_, &at)| at == address))
+                .map_or(String::new(), |(name, _)| format!("  # {}", name));
+
+            let mut line = Line::from(vec![marker, Span::raw(format!("{:04x}  {:08x}  {}", address, word, isa::disassemble(word))),
+                Span::raw(label).dark_gray()]);
+            if address == self.cursor {
+                line = line.add_modifier(Modifier::REVERSED);
+            }
+            lines.push(line);
+        }
+
+        Paragraph::new(lines).block(Block::bordered().title("code"))
+    }
+
+    /* Memory around the stack pointer in hex, ROW bytes to a line. */
+    fn stack(&self, area: Rect) -> Paragraph<'static> {
+        let rows = area.height.saturating_sub(2) as i32;
+        let sp = self.vm.stack_pointer();
+        let end = self.vm.memory().len() as i32;
+        let first = (sp / ROW - 1).clamp(0, (end / ROW - rows).max(0)) * ROW;
+
+        let mut lines = Vec::new();
+        for row in (first..end).step_by(ROW as usize).take(rows as usize) {
+            let bytes = self.vm.memory().slice(row, ROW as usize).unwrap_or_default();
+            let mut spans = Vec::from([Span::raw(format!("{}{:04x} ", if (row..row + ROW).contains(&sp) { ">" } else { " " }, row))]);
+            for (i, byte) in bytes.iter().enumerate() {
+                let text = format!(" {:02x}", byte);
+                spans.push(if row + i as i32 >= sp { Span::raw(text).yellow() } else { Span::raw(text).dark_gray() });
+            }
+            let ascii: String = bytes.iter().map(|&b| if b.is_ascii_graphic() { b as char } else { '.' }).collect();
+            spans.push(Span::raw(format!("  {}", ascii)).dark_gray());
+            lines.push(Line::from(spans));
+        }
+
+        Paragraph::new(lines).block(Block::bordered().title("stack"))
+    }
+
+    /* The registers, where the program has got to and the keys. */
+    fn machine(&self) -> Paragraph<'static> {
+        let state = self.vm.state();
+        let status = match &self.status {
+            Status::Paused => Span::raw("paused"),
+            Status::Running => Span::raw("running").green(),
+            Status::Exited(code) => Span::raw(format!("exited with {}", code)).bold(),
+            Status::Fault(err) => Span::raw(format!("fault: {}", err)).red(),
+        };
+        let breakpoints: Vec<String> = self.breakpoints.iter().map(|address| format!("{:04x}", address)).collect();
+
+        Paragraph::new(Vec::from([
+            Line::from(format!("pc {:04x}  sp {:04x}  cycles {}  depth {}", state.pc, state.sp, state.cycles, state.depth)),
+            Line::from(vec![Span::raw("status "), status]),
+            Line::from(format!("breakpoints {}", if breakpoints.is_empty() { String::from("none") } else { breakpoints.join(" ") })),
+            Line::from(""),
+            Line::from("s step  c continue  b breakpoint  r restart  q quit").dark_gray(),
+        ]))
+        .block(Block::bordered().title("machine"))
+    }
+
+    /* The end of what the program has printed. */
+    fn output(&self, area: Rect) -> Paragraph<'static> {
+        let text = self.output.text();
+        let lines: Vec<Line> = text.lines().map(|line| Line::from(String::from(line))).collect();
+        let skip = lines.len().saturating_sub(area.height.saturating_sub(2) as usize);
+
+        Paragraph::new(lines.into_iter().skip(skip).collect::<Vec<_>>()).block(Block::bordered().title("output"))
+    }
+}
@@ -0,0 +1,236 @@
+/* Embedding API for running the VM in a browser. Build it with
+ *
+ *     cargo rustc --release --lib --crate-type cdylib --features wasm --target wasm32-unknown-unknown
+ *
+ * From Rust (or anything wrapping it), WasmVm takes the bytes of a .v file and callbacks for
+ * output and input, then gets stepped along one instruction at a time so a page can stay
+ * responsive. On wasm32 the same thing is exported as plain functions that JS can call without
+ * any glue:
+ *
+ *     const { instance } = await WebAssembly.instantiate(wasmBytes, { env: {
+ *         vm_host_write: (ptr, len) => console.log(readString(ptr, len)),
+ *         vm_host_read_line: (ptr, cap) => writeLine(prompt(), ptr, cap),  // bytes written, -1 at EOF
+ *     }});
+ *     const buf = instance.exports.wasm_vm_alloc(program.length);
+ *     new Uint8Array(instance.exports.memory.buffer, buf, program.length).set(program);
+ *     const vm = instance.exports.wasm_vm_new(buf, program.length);
+ *     while (instance.exports.wasm_vm_step(vm) === 0) {}
+ *
+ * There's no clock to seed rand from in a browser, so wasm_vm_new always starts it the same way.
+ * wasm_vm_new_seeded takes a seed as a BigInt after the length.
+ *
+ * tests/wasm_smoke.mjs does just that in node for each of the example programs. */
+
+use std::io::{self, BufRead, Read, Write};
+
+use crate::{StepResult, VirtualMachine, VmConfig};
+
+type OutputCallback = Box<dyn FnMut(&[u8]) + Send>;
+type InputCallback = Box<dyn FnMut() -> Option<String> + Send>;
+
+/* Hands everything the program writes to a callback. */
+struct CallbackOutput(OutputCallback);
+
+impl Write for CallbackOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (self.0)(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/* Asks a callback for a line whenever the program wants input. None means end of input. */
+struct CallbackInput {
+    callback: InputCallback,
+    line: Vec<u8>,
+    position: usize,
+}
+
+impl Read for CallbackInput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl BufRead for CallbackInput {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.position >= self.line.len() {
+            self.position = 0;
+            self.line = match (self.callback)() {
+                Some(mut line) => {
+                    if !line.ends_with('\n') {
+                        line.push('\n');
+                    }
+                    line.into_bytes()
+                },
+                None => Vec::new(),
+            };
+        }
+
+        Ok(&self.line[self.position..])
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.position += amount;
+    }
+}
+
+/* A VM with its I/O wired up to callbacks. Errors come back as strings since that's all JS
+ * wants to show anyway. */
+pub struct WasmVm {
+    vm: VirtualMachine,
+    exit_code: Option<i32>,
+}
+
+impl WasmVm {
+    /* Load a program from the contents of a .v file. Output is thrown away and input is empty
+     * until callbacks are set. There's no clock to seed rand from, so it always starts the
+     * same; use with_seed for something else. */
+    pub fn new(bytes: &[u8]) -> Result<WasmVm, String> {
+        WasmVm::load(bytes, VmConfig::default())
+    }
+
+    /* The same, with rand seeded, say from Math.random on the JS side. */
+    pub fn with_seed(bytes: &[u8], seed: u64) -> Result<WasmVm, String> {
+        WasmVm::load(bytes, VmConfig { seed: Some(seed), ..VmConfig::default() })
+    }
+
+    fn load(bytes: &[u8], config: VmConfig) -> Result<WasmVm, String> {
+        let mut vm = VirtualMachine::from_bytes(bytes.to_vec(), config)?;
+        vm.set_output(Box::new(io::sink()));
+        vm.set_diagnostics(Box::new(io::sink()));
+        vm.set_input(Box::new(io::empty()));
+
+        Ok(WasmVm { vm, exit_code: None })
+    }
+
+    /* Called with each chunk of output the program writes. */
+    pub fn on_output(&mut self, callback: impl FnMut(&[u8]) + Send + 'static) {
+        self.vm.set_output(Box::new(CallbackOutput(Box::new(callback))));
+    }
+
+    /* Called for a line of input whenever the program reads; return None for end of input. */
+    pub fn on_input(&mut self, callback: impl FnMut() -> Option<String> + Send + 'static) {
+        self.vm.set_input(Box::new(CallbackInput {
+            callback: Box::new(callback),
+            line: Vec::new(),
+            position: 0,
+        }));
+    }
+
+    /* Execute one instruction. Returns true while the program is still running. */
+    pub fn step(&mut self) -> Result<bool, String> {
+        match self.vm.step().map_err(|err| err.to_string())? {
+            StepResult::Running | StepResult::Breakpoint => Ok(true),
+            StepResult::Exited(code) => {
+                self.exit_code = Some(code);
+                Ok(false)
+            },
+        }
+    }
+
+    /* Execute up to max_steps instructions, so a page can run in slices between frames. */
+    pub fn run_for(&mut self, max_steps: u32) -> Result<bool, String> {
+        for _ in 0..max_steps {
+            if !self.step()? {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    /* The exit code, once the program has exited. */
+    pub fn exit_code(&self) -> Option<i32> {
+        self.exit_code
+    }
+
+    pub fn stack_pointer(&self) -> i32 {
+        self.vm.stack_pointer()
+    }
+
+    pub fn program_counter(&self) -> i32 {
+        self.vm.program_counter()
+    }
+}
+
+/* The raw exports for JS. Only built for wasm32, since the host functions they lean on only
+ * exist when JS provides them. */
+#[cfg(target_arch = "wasm32")]
+mod exports {
+    use super::WasmVm;
+
+    extern "C" {
+        fn vm_host_write(ptr: *const u8, len: usize);
+        fn vm_host_read_line(ptr: *mut u8, cap: usize) -> isize;
+    }
+
+    /* Room for JS to copy a program into before calling wasm_vm_new. */
+    #[no_mangle]
+    pub extern "C" fn wasm_vm_alloc(len: usize) -> *mut u8 {
+        let mut buffer = vec![0u8; len];
+        let ptr = buffer.as_mut_ptr();
+        std::mem::forget(buffer);
+        ptr
+    }
+
+    /* Load the program in a buffer from wasm_vm_alloc, which this frees. Null if it's not a
+     * valid program. */
+    #[no_mangle]
+    pub unsafe extern "C" fn wasm_vm_new(ptr: *mut u8, len: usize) -> *mut WasmVm {
+        connect(WasmVm::new(&Vec::from_raw_parts(ptr, len, len)))
+    }
+
+    /* The same, with a seed for rand, which JS passes as a BigInt. */
+    #[no_mangle]
+    pub unsafe extern "C" fn wasm_vm_new_seeded(ptr: *mut u8, len: usize, seed: u64) -> *mut WasmVm {
+        connect(WasmVm::with_seed(&Vec::from_raw_parts(ptr, len, len), seed))
+    }
+
+    /* Hook a freshly loaded VM up to the host functions. */
+    fn connect(vm: Result<WasmVm, String>) -> *mut WasmVm {
+        let Ok(mut vm) = vm else {
+            return std::ptr::null_mut();
+        };
+
+        vm.on_output(|bytes| unsafe { vm_host_write(bytes.as_ptr(), bytes.len()) });
+        vm.on_input(|| {
+            let mut line = vec![0u8; 1024];
+            let n = unsafe { vm_host_read_line(line.as_mut_ptr(), line.len()) };
+            if n < 0 {
+                return None;
+            }
+            line.truncate(n as usize);
+            Some(String::from_utf8_lossy(&line).into_owned())
+        });
+
+        Box::into_raw(Box::new(vm))
+    }
+
+    /* 0 while running, 1 once the program has exited, -1 if it faulted. */
+    #[no_mangle]
+    pub unsafe extern "C" fn wasm_vm_step(vm: *mut WasmVm) -> i32 {
+        match (*vm).step() {
+            Ok(true) => 0,
+            Ok(false) => 1,
+            Err(_) => -1,
+        }
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn wasm_vm_exit_code(vm: *const WasmVm) -> i32 {
+        (*vm).exit_code().unwrap_or(0)
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn wasm_vm_free(vm: *mut WasmVm) {
+        drop(Box::from_raw(vm));
+    }
+}
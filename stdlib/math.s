@@ -0,0 +1,63 @@
+# Arithmetic the instruction set leaves out, or that's worth having without the instruction.
+#
+#     abs        x -> |x|, with abs of the smallest word being itself
+#     min max    a b -> the smaller or the larger, as signed words
+#     mulshift   a b -> a * b, by shifting and adding rather than mul, wrapping the same way
+#
+# Push the arguments in order and call; the result is left where they were.
+
+abs:    swap 4 0                # ra x
+        ifpl abs.done
+        neg
+abs.done:
+        swap 4 0
+        return
+
+min:    roll 3
+        roll 3                  # ra a b
+        iflt min.left
+        swap 4 0                # ra b a
+min.left:
+        pop 4                   # ra min
+        swap 4 0
+        return
+
+max:    roll 3
+        roll 3                  # ra a b
+        ifgt max.left
+        swap 4 0                # ra b a
+max.left:
+        pop 4                   # ra max
+        swap 4 0
+        return
+
+mulshift:
+        roll 3
+        roll 3                  # ra a b
+        push 0                  # ra a b product
+mulshift.loop:
+        pick 1
+        ifez mulshift.done      # no bits of b left
+        push 1
+        and
+        neg                     # all ones if b's low bit is set, else 0
+        pick 3
+        and
+        add                     # the product, plus a if the bit was set
+        pick 2
+        push 1
+        lsl
+        swap 12 0
+        pop 4                   # ra a<<1 b product
+        pick 1
+        push 1
+        lsr
+        swap 8 0
+        pop 4                   # ra a b>>1 product
+        goto mulshift.loop
+mulshift.done:
+        pop 4                   # ra a b product
+        swap 8 0
+        pop 8                   # ra product
+        swap 4 0
+        return
@@ -0,0 +1,45 @@
+# Printing a character or a number a character at a time with stprint, for when the print
+# instructions won't do: no newline after a number, say.
+#
+#     putchar    c -> nothing; prints the character c
+#     putnum     n -> nothing; prints n in decimal, with a - if it's negative
+#
+# Push the arguments in order and call; both take theirs off the stack. They need the packed
+# strings stprint reads by default, not the byte_strings feature.
+
+putchar:
+        swap 4 0                # ra c
+        stprint 0               # a word with one character in its low byte is a string of it
+        pop 4
+        return
+
+putnum:
+        swap 4 0                # ra n
+        ifmi putnum.minus
+        neg                     # the digits are worked out from -|n|, which always fits
+        goto putnum.split
+putnum.minus:
+        push 45                 # -
+        stprint 0
+        pop 4
+putnum.split:
+        push -1
+        swap 4 0                # ra -1 m, with -1 under the digits to say where they stop
+putnum.digit:
+        dup 0
+        push 10
+        rem
+        neg
+        push 48
+        add                     # ra -1 digits... m digit, lowest first
+        swap 4 0
+        push 10
+        div
+        ifnz putnum.digit
+        pop 4                   # ra -1 digits..., highest on top
+putnum.print:
+        stprint 0
+        pop 4
+        ifpl putnum.print
+        pop 4                   # ra
+        return
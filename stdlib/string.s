@@ -0,0 +1,29 @@
+# Strings the way .string lays them out in memory, a byte per character and a 0 after them, in
+# a big-endian program.
+#
+#     strlen.z   address -> how many bytes there are before the 0
+#
+# Push the arguments in order and call; the result is left where they were. strlen is already
+# an instruction, for the packed strings on the stack, hence the name.
+
+strlen.z:
+        swap 4 0                # ra address
+        push 0                  # ra address length
+strlen.z.loop:
+        pick 1
+        pick 1
+        add
+        load
+        push 24
+        lsr                     # ra address length byte, the first byte of the word loaded
+        ifez strlen.z.done
+        pop 4
+        push 1
+        add
+        goto strlen.z.loop
+strlen.z.done:
+        pop 4                   # ra address length
+        swap 4 0
+        pop 4                   # ra length
+        swap 4 0
+        return
@@ -0,0 +1,688 @@
+/* Properties of the instruction set, checked on random cases with proptest rather than the
+ * hand-picked edges vm selftest uses:
+ *
+ *     every Instruction encodes to a word that decodes back to it, and disassembles to text
+ *     that assembles to the same word
+ *
+ *     Program::instructions gives back every instruction of a program put together from them,
+ *     at the address it was put at, and the basic blocks cover the code end to end
+ *
+ *     the same program hashes the same however often it's run, as threaded code or not
+ *
+ *     the profile's time by instruction counts every instruction that ran, once each
+ *
+ *     the line table made from a file and line for each word of code says the same of every
+ *     word once it's been written into a file's debug info and read back, and nothing of the
+ *     address past the code
+ *
+ *     an operand written as an expression over .equ constants assembles to the same code as
+ *     the number it comes to
+ *
+ *     .if keeps the lines it should for the names defined, and no others, with a name that
+ *     isn't defined counting as 0
+ *
+ *     vm fmt's layout of a source assembles to the same code as the source, and laying it out
+ *     again changes nothing
+ *
+ *     vm disasm's listing of a program with its labels assembles back to the same code, data
+ *     and header, and is already laid out the way vm fmt would
+ *
+ *     any words at all, instructions or not, pass vm roundtrip's check, listed with no labels
+ *
+ *     a function of two instructions that's called gets pasted in place of every call to it
+ *     by vm asm --opt --inline and left out, and the program prints the same
+ *
+ *     a self-recursive call in tail position is found, and once vm asm --opt --tailcalls has
+ *     made it a tailcall, the program prints the same, but as deep as it likes
+ *
+ *     a program linked with the stdlib's maths and vm link --gc keeps the routines it calls
+ *     and none of the others, and prints the same as it does with all of them
+ *
+ *     a program under the debugger reads the input it was given, not the debugger's commands,
+ *     and reads it again from the start after a restart
+ *
+ *     vm lsp answers every request about a file once, whatever is in the file and wherever
+ *     in it the request points, and exits cleanly when told to after a shutdown
+ *
+ *     a branch offset the instruction's field can hold assembles to exactly that offset, and
+ *     any other is an error giving the distance rather than being cut down to fit; an if
+ *     taken to the middle of a word stops the machine
+ *
+ *     the segments of an address space come in order without overlapping, end at the top of
+ *     memory, and every address in one translates back to it
+ *
+ *     pop and return count 4 bytes to a word, so they drop the same words for both word sizes
+ *
+ *     the arithmetic instructions give what the same sum done in i128, which can't overflow
+ *     for any two words, says they should once it's been fitted back into a word by the
+ *     arithmetic mode, for both word sizes and all three modes
+ *
+ * A failure is shrunk by proptest to the smallest case it can find, and its seed saved in
+ * properties.proptest-regressions beside this file so it's tried first next time. */
+
+use proptest::prelude::*;
+use proptest::sample::select;
+
+use vm::debugger::Debugger;
+use vm::isa::{self, BinaryOp, Condition, EofMode, Instruction, OffsetField, PerfCounter, PrintFormat, PrintSpec, UnaryOp, ZeroCondition};
+use vm::{AddressSpace, ArithmeticMode, DebugInfo, Header, Program, Segment, VirtualMachine, VmConfig, WordSize};
+
+/* Any value that fits in a signed field this many bits wide. */
+fn signed(bits: u32) -> impl Strategy<Value = i32> + Clone {
+    -(1i64 << (bits - 1)) as i32..=((1i64 << (bits - 1)) - 1) as i32
+}
+
+/* Any value that fits in an unsigned field this many bits wide. */
+fn unsigned(bits: u32) -> impl Strategy<Value = u32> + Clone {
+    0..=(u32::MAX >> (32 - bits))
+}
+
+/* A byte offset kept as a word count in a field this many bits wide. */
+fn word_offset(bits: u32) -> impl Strategy<Value = i32> + Clone {
+    signed(bits).prop_map(|words| words * 4)
+}
+
+fn print_spec() -> impl Strategy<Value = PrintSpec> {
+    (select(PrintFormat::ALL.to_vec()), any::<bool>(), any::<u8>(), any::<bool>(), any::<bool>())
+        .prop_map(|(format, unsigned, width, zero_pad, newline)| PrintSpec {
+            format,
+            /* Only decimal has an unsigned version. */
+            unsigned: unsigned && format == PrintFormat::Decimal,
+            width,
+            zero_pad,
+            newline,
+        })
+}
+
+/* Any instruction the ISA has, with every operand in range for its field. */
+fn instruction() -> impl Strategy<Value = Instruction> {
+    let operandless = select(Vec::from([
+        Instruction::Nop, Instruction::Dump, Instruction::ToReturnStack, Instruction::FromReturnStack, Instruction::StrCat,
+        Instruction::StrCmp, Instruction::ReadFile, Instruction::Arg, Instruction::GetEnv, Instruction::Clock, Instruction::Cycles,
+        Instruction::Rand, Instruction::Load, Instruction::Store, Instruction::Yield, Instruction::Join, Instruction::Cas,
+        Instruction::FetchAdd, Instruction::Lock, Instruction::Unlock, Instruction::StPrintN, Instruction::PushByte,
+        Instruction::PushHalf, Instruction::JumpI, Instruction::Atoi, Instruction::Itoa, Instruction::Brk,
+        Instruction::StackDepth,
+    ]));
+
+    prop_oneof![
+        operandless,
+        select(PerfCounter::ALL.to_vec()).prop_map(Instruction::PerfCtr),
+        unsigned(24).prop_map(Instruction::Exit),
+        (signed(12), signed(12)).prop_map(|(from, to)| Instruction::Swap { from, to }),
+        (select(EofMode::ALL.to_vec()), any::<bool>()).prop_map(|(eof, retry)| Instruction::Input { eof, retry }),
+        unsigned(24).prop_map(Instruction::StInput),
+        (unsigned(23), any::<bool>()).prop_map(|(bytes, binary)| Instruction::Debug { bytes, binary }),
+        unsigned(26).prop_map(|words| Instruction::Pop(words * 4)),
+        select(BinaryOp::ALL.to_vec()).prop_map(Instruction::Binary),
+        select(Condition::ALL.to_vec()).prop_map(Instruction::Cmp),
+        select(UnaryOp::ALL.to_vec()).prop_map(Instruction::Unary),
+        signed(28).prop_map(Instruction::StPrint),
+        word_offset(26).prop_map(Instruction::Call),
+        word_offset(26).prop_map(Instruction::TailCall),
+        unsigned(26).prop_map(|words| Instruction::Return(words * 4)),
+        word_offset(26).prop_map(Instruction::Goto),
+        /* Only the first eight conditions fit in a binary if. An if's offset is in bytes, but
+         * the assembler only goes to a whole instruction. */
+        (select(Condition::ALL.to_vec()), word_offset(23)).prop_map(|(condition, offset)| Instruction::BinaryIf(condition, offset)),
+        (select(ZeroCondition::ALL.to_vec()), word_offset(23)).prop_map(|(condition, offset)| Instruction::UnaryIf(condition, offset)),
+        signed(20).prop_map(Instruction::StrLen),
+        unsigned(20).prop_map(Instruction::WriteFile),
+        /* The assembler only spawns at a whole instruction. */
+        word_offset(18).prop_map(Instruction::Spawn),
+        any::<bool>().prop_map(|signed| Instruction::PopByte { signed }),
+        any::<bool>().prop_map(|signed| Instruction::PopHalf { signed }),
+        unsigned(20).prop_map(Instruction::JumpTable),
+        signed(20).prop_map(Instruction::Lea),
+        unsigned(20).prop_map(Instruction::Pick),
+        unsigned(20).prop_map(Instruction::Roll),
+        unsigned(20).prop_map(Instruction::Drop),
+        print_spec().prop_map(Instruction::PrintF),
+        signed(20).prop_map(Instruction::Assert),
+        signed(28).prop_map(Instruction::Dup),
+        (word_offset(24), select(PrintFormat::ALL.to_vec())).prop_map(|(offset, format)| Instruction::Print(offset, format)),
+        signed(28).prop_map(Instruction::Push),
+    ]
+}
+
+/* Lines for a source to be formatted, with odd spacing and things a comment could be mistaken
+ * for. {} is the line's index, to keep names from clashing. */
+const SOURCE_LINES: [&str; 10] = [
+    "", "push   1", "add", "swap 4,0", "stpush \"a # b\"", ".equ  N{},2 + 3", "push 2*3-1", "goto\t4", ".word 0x10", "print",
+];
+
+/* Lines of code for a program to disassemble, with {t} for the index of a line or a piece of
+ * data to go to and {w} for any word. */
+const CODE_LINES: [&str; 13] = [
+    "push 7", "call l{t}", "goto l{t}+4", "ifeq l{t}", "ifmi l{t}", "lea d{t}+1", "spawn l{t}", "assert d{t}",
+    "jumptable 2\n.table l{t} l{t}", ".word {w}", "dup2", "stpush \"abc\"", "exit",
+];
+
+const DATA_LINES: [&str; 4] = [".byte 1 2 3", ".string \"hello, world\"", ".word 5", ".byte 0x80"];
+
+const HEADER_LINES: [&str; 5] = [".entry l1", ".sp 0x800", ".heap 64", ".feature little_endian", ".feature words64"];
+
+/* Print the word at n and count it down to 0, calling itself for each one in tail position. */
+const COUNTDOWN: &str = "
+count:  lea n
+        load
+        ifez count.done
+        print
+        push -1
+        add
+        lea n
+        store
+count.again:
+        call count
+        return
+count.done:
+        pop 4
+        return
+.data
+n:      .word 0
+";
+
+/* A call to each routine in stdlib/math.s, printing what it gives back. */
+const MATH_CALLS: [(&str, &str); 4] = [
+    ("abs", "push -7\ncall abs\nprint"),
+    ("min", "push 3\npush -4\ncall min\nprint"),
+    ("max", "push 3\npush -4\ncall max\nprint"),
+    ("mulshift", "push -6\npush 7\ncall mulshift\nprint"),
+];
+
+fn word_size() -> impl Strategy<Value = WordSize> {
+    select(Vec::from([WordSize::Bits32, WordSize::Bits64]))
+}
+
+fn arithmetic_mode() -> impl Strategy<Value = ArithmeticMode> {
+    select(Vec::from([ArithmeticMode::Wrapping, ArithmeticMode::Saturating, ArithmeticMode::Trapping]))
+}
+
+/* A word of either size, leaning on the ones around the edges, where the overflows are. */
+fn operand(word_size: WordSize) -> impl Strategy<Value = i64> {
+    let (min, max) = (word_size.min(), word_size.max());
+    prop_oneof![
+        select(Vec::from([0, 1, -1, 2, min, min + 1, max, max - 1, word_size.bits() - 1, word_size.bits()])),
+        -64i64..64,
+        min..=max,
+    ]
+}
+
+fn bits(word_size: WordSize) -> u32 {
+    word_size.bits() as u32
+}
+
+/* The low bits of an exact result as a signed word. */
+fn wrap(exact: i128, word_size: WordSize) -> i64 {
+    let shift = 128 - bits(word_size);
+    ((exact << shift) >> shift) as i64
+}
+
+/* A word's bits as an unsigned number. */
+fn unsigned_word(word: i64, word_size: WordSize) -> i128 {
+    word as i128 & ((1i128 << bits(word_size)) - 1)
+}
+
+/* Fit an exact result into a word the way the mode says; None where it traps. */
+fn fit(exact: i128, word_size: WordSize, mode: ArithmeticMode) -> Option<i64> {
+    let (min, max) = (word_size.min() as i128, word_size.max() as i128);
+    if (min..=max).contains(&exact) {
+        return Some(exact as i64);
+    }
+
+    match mode {
+        ArithmeticMode::Wrapping => Some(wrap(exact, word_size)),
+        ArithmeticMode::Saturating => Some(exact.clamp(min, max) as i64),
+        ArithmeticMode::Trapping => None,
+    }
+}
+
+/* What left op right should leave on the stack, worked out in i128; None for a fault. */
+fn expected_binary(op: BinaryOp, left: i64, right: i64, word_size: WordSize, mode: ArithmeticMode) -> Option<i64> {
+    let (l, r) = (left as i128, right as i128);
+    let (unsigned_l, unsigned_r) = (unsigned_word(left, word_size), unsigned_word(right, word_size));
+    let n = bits(word_size);
+    let amount = r.rem_euclid(n as i128) as u32;
+
+    let exact = match op {
+        BinaryOp::Add => return fit(l + r, word_size, mode),
+        BinaryOp::Sub => return fit(l - r, word_size, mode),
+        BinaryOp::Mul => return fit(l * r, word_size, mode),
+        BinaryOp::Div if r == 0 => return None,
+        BinaryOp::Div => return fit(l / r, word_size, mode),
+        BinaryOp::Rem | BinaryOp::Divu | BinaryOp::Remu if r == 0 => return None,
+        BinaryOp::Rem => l % r,
+        BinaryOp::Divu => unsigned_l / unsigned_r,
+        BinaryOp::Remu => unsigned_l % unsigned_r,
+        BinaryOp::And => l & r,
+        BinaryOp::Or => l | r,
+        BinaryOp::Xor => l ^ r,
+        BinaryOp::Lsl => l << amount,
+        BinaryOp::Lsr => unsigned_l >> amount,
+        BinaryOp::Asr => l >> amount,
+        BinaryOp::Rol => (unsigned_l << amount) | (unsigned_l >> (n - amount)),
+        BinaryOp::Ror => (unsigned_l >> amount) | (unsigned_l << (n - amount)),
+    };
+
+    /* None of these overflow; they only need cutting back down to a word. */
+    Some(wrap(exact, word_size))
+}
+
+fn expected_unary(op: UnaryOp, operand: i64, word_size: WordSize, mode: ArithmeticMode) -> Option<i64> {
+    match op {
+        UnaryOp::Neg => fit(-(operand as i128), word_size, mode),
+        UnaryOp::Not => Some(!operand),
+    }
+}
+
+/* Instructions that push a word: the top 16 bits, then each 16 below them shifted in. */
+fn constant(value: i64, word_size: WordSize) -> Vec<Instruction> {
+    let chunks = bits(word_size) / 16;
+    let mut instructions = Vec::from([Instruction::Push((value >> ((chunks - 1) * 16)) as i16 as i32)]);
+    for chunk in (0..chunks - 1).rev() {
+        instructions.extend([
+            Instruction::Push(16),
+            Instruction::Binary(BinaryOp::Lsl),
+            Instruction::Push((value >> (chunk * 16)) as i32 & 0xFFFF),
+            Instruction::Binary(BinaryOp::Or),
+        ]);
+    }
+    instructions
+}
+
+/* Run the instructions and then exit, giving back the word left on top or None for a fault. */
+fn run(instructions: &[Instruction], word_size: WordSize, mode: ArithmeticMode) -> Option<i64> {
+    let code: Vec<u8> = instructions.iter()
+        .chain([Instruction::Exit(0)].iter())
+        .flat_map(|instruction| instruction.encode().to_le_bytes())
+        .collect();
+    let features = if word_size == WordSize::Bits64 { Header::WORDS_64 } else { 0 };
+    let config = VmConfig { arithmetic_mode: mode, ..VmConfig::default() };
+
+    let mut vm = VirtualMachine::from_bytes(Header::new(features).image(&code), config).expect("the program loads");
+    vm.run().ok()?;
+    vm.word_at(vm.stack_pointer())
+}
+
+proptest! {
+    #[test]
+    fn encode_decode_round_trips(instruction in instruction()) {
+        prop_assert_eq!(Instruction::decode(instruction.encode()), Some(instruction));
+    }
+
+    #[test]
+    fn disassembly_assembles_back(instruction in instruction()) {
+        let text = instruction.to_string();
+        prop_assert_eq!(isa::assemble_line(&text).ok(), Some(instruction.encode()), "{}", text);
+    }
+
+    /* A jumptable's entries would be whatever words come after it, so there are none here. */
+    #[test]
+    fn program_instructions_round_trip(instructions in prop::collection::vec(instruction(), 0..64)) {
+        let instructions: Vec<Instruction> = instructions.into_iter().filter(|i| !matches!(i, Instruction::JumpTable(_))).collect();
+        let program = Program::new(instructions.iter().flat_map(|i| i.encode().to_le_bytes()).collect());
+
+        let expected: Vec<(i32, Instruction)> = instructions.iter().enumerate().map(|(i, &instruction)| (i as i32 * 4, instruction)).collect();
+        prop_assert_eq!(program.instructions().collect::<Vec<_>>(), expected);
+
+        let blocks = program.basic_blocks();
+        prop_assert_eq!(blocks.first().map_or(0, |block| block.start), 0);
+        prop_assert_eq!(blocks.last().map_or(0, |block| block.end), program.end());
+        prop_assert!(blocks.windows(2).all(|pair| pair[0].end == pair[1].start && pair[0].start < pair[0].end));
+    }
+
+    #[test]
+    fn state_hash_is_repeatable(sums in prop::collection::vec((operand(WordSize::Bits32), select(BinaryOp::ALL.to_vec())), 1..16)) {
+        let mut instructions = constant(1, WordSize::Bits32);
+        for (value, op) in sums {
+            instructions.extend(constant(value, WordSize::Bits32));
+            instructions.push(Instruction::Binary(op));
+        }
+        let code: Vec<u8> = instructions.iter()
+            .chain([Instruction::Exit(0)].iter())
+            .flat_map(|instruction| instruction.encode().to_le_bytes())
+            .collect();
+
+        let hash = |threaded| {
+            let config = VmConfig { state_hash: true, threaded, ..VmConfig::default() };
+            let mut vm = VirtualMachine::from_bytes(Header::new(0).image(&code), config).expect("the program loads");
+            let _ = vm.run();
+            vm.state_hash()
+        };
+        let first = hash(false);
+        prop_assert!(first.is_some());
+        prop_assert_eq!(hash(false), first);
+        prop_assert_eq!(hash(true), first);
+    }
+
+    #[test]
+    fn opcode_times_count_every_instruction(sums in prop::collection::vec((operand(WordSize::Bits32), select(BinaryOp::ALL.to_vec())), 1..16)) {
+        let mut instructions = constant(1, WordSize::Bits32);
+        for (value, op) in sums {
+            instructions.extend(constant(value, WordSize::Bits32));
+            instructions.push(Instruction::Binary(op));
+        }
+        let code: Vec<u8> = instructions.iter()
+            .chain([Instruction::Exit(0)].iter())
+            .flat_map(|instruction| instruction.encode().to_le_bytes())
+            .collect();
+
+        let config = VmConfig { profile: true, ..VmConfig::default() };
+        let mut vm = VirtualMachine::from_bytes(Header::new(0).image(&code), config).expect("the program loads");
+        let _ = vm.run();
+        let times = vm.profile().expect("profiling is on").opcode_times(vm.code());
+        prop_assert_eq!(times.iter().map(|time| time.count).sum::<u64>(), vm.instruction_count());
+        let mut mnemonics: Vec<&str> = times.iter().map(|time| time.mnemonic.as_str()).collect();
+        mnemonics.sort_unstable();
+        mnemonics.dedup();
+        prop_assert_eq!(mnemonics.len(), times.len());
+    }
+
+    #[test]
+    fn line_table_locates_every_word(words in prop::collection::vec((0..3usize, 0..4usize), 0..64)) {
+        let files = vec![String::from("main.s"), String::from("a.s"), String::from("b.s")];
+        let written = DebugInfo { files: files.clone(), rows: DebugInfo::line_table(&words), ..DebugInfo::default() };
+        let mut image = Vec::new();
+        written.append_to(&mut image);
+        let (_, read) = DebugInfo::split_off(&image).expect("it reads back");
+
+        for (i, &(file, line)) in words.iter().enumerate() {
+            let expected = (line != 0).then_some((Some(files[file].as_str()), line));
+            prop_assert_eq!(read.location(i as i32 * 4), expected);
+        }
+        prop_assert_eq!(read.location(words.len() as i32 * 4), None);
+    }
+
+    #[test]
+    fn expressions_assemble_to_their_value(a in -1000..1000i64, b in -100..100i64, c in 1..100i64) {
+        let value = a * b - a / c;
+        let source = format!(".equ A, {}\n.equ B, {}\npush A*B-A/{}\n", a, b, c);
+        prop_assert_eq!(vm::asm::assemble(&source).map(|assembled| assembled.code), vm::asm::assemble(&format!("push {}", value)).map(|assembled| assembled.code));
+    }
+
+    #[test]
+    fn conditions_keep_the_lines_they_should(a in prop::option::of(-5..5i64), b in -5..5i64) {
+        let source = ".if A < B\npush 1\n.if A == 0\npush 2\n.endif\n.else\npush 3\n.endif\n";
+        let defines = a.map(|a| (String::from("A"), a)).into_iter().chain([(String::from("B"), b)]).collect();
+        let assembled = vm::asm::assemble_with(source, "", &mut vm::asm::IncludePath::default(), &defines);
+
+        let a = a.unwrap_or(0);
+        let expected = match (a < b, a == 0) {
+            (true, true) => "push 1\npush 2",
+            (true, false) => "push 1",
+            (false, _) => "push 3",
+        };
+        prop_assert_eq!(assembled.map(|assembled| assembled.code), vm::asm::assemble(expected).map(|assembled| assembled.code));
+    }
+
+    #[test]
+    fn formatting_keeps_the_code(
+        lines in prop::collection::vec((0..12usize, any::<bool>(), select(SOURCE_LINES.to_vec()), prop::option::of("[ a-z#]{0,8}")), 0..20),
+    ) {
+        let source: String = lines.iter().enumerate().map(|(i, (indent, labelled, text, comment))| {
+            let label = if *labelled { format!("l{}: ", i) } else { String::new() };
+            let comment = comment.as_ref().map_or(String::new(), |comment| format!(" #{}", comment));
+            format!("{:indent$}{}{}{}\n", "", label, text.replace("{}", &i.to_string()), comment, indent = indent)
+        }).collect();
+        let formatted = vm::fmt::format(&source);
+        /* The labels can go on lines of their own, so only the line numbers change. */
+        let assemble = |source: &str| vm::asm::assemble(source).ok().map(|assembled| (assembled.code, assembled.labels, assembled.data));
+
+        prop_assert_eq!(assemble(&formatted), assemble(&source));
+        prop_assert_eq!(vm::fmt::format(&formatted), formatted);
+    }
+
+    #[test]
+    fn listings_assemble_back(
+        code in prop::collection::vec((select(CODE_LINES.to_vec()), 0..64usize, any::<u32>()), 2..16),
+        data in prop::collection::vec((select(DATA_LINES.to_vec()), 0..64usize), 1..6),
+        header in prop::sample::subsequence(HEADER_LINES.to_vec(), 0..=HEADER_LINES.len()),
+    ) {
+        let mut source: String = header.iter().map(|line| format!("{}\n", line)).collect();
+        for (i, (line, target, word)) in code.iter().enumerate() {
+            let line = line.replace("d{t}", &format!("d{}", target % data.len()))
+                .replace("{t}", &(target % code.len()).to_string())
+                .replace("{w}", &word.to_string());
+            source += &format!("l{}: {}\n", i, line);
+        }
+        source += ".data\n";
+        for (i, (line, _)) in data.iter().enumerate() {
+            source += &format!("d{}: {}\n", i, line);
+        }
+
+        let mut assembled = vm::asm::assemble(&source).expect("the program assembles");
+        assembled.features |= Header::DEBUG_INFO;
+        let listing = vm::disasm::listing(&assembled.image(), Some(&assembled.labels), false).expect("the image loads");
+        let reassembled = vm::asm::assemble(&listing).expect("the listing assembles");
+
+        prop_assert_eq!(&reassembled.code, &assembled.code, "{}", listing);
+        prop_assert_eq!(&reassembled.data, &assembled.data);
+        prop_assert_eq!(&reassembled.labels, &assembled.labels);
+        prop_assert_eq!((reassembled.features, reassembled.entry, reassembled.stack_pointer, reassembled.heap_size),
+            (assembled.features, assembled.entry, assembled.stack_pointer, assembled.heap_size));
+        prop_assert_eq!(vm::fmt::format(&listing), listing);
+    }
+
+    #[test]
+    fn any_words_round_trip(words in prop::collection::vec(prop_oneof![any::<u32>(), instruction().prop_map(|i| i.encode())], 0..32)) {
+        let code: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+        prop_assert_eq!(vm::disasm::roundtrip(&Header::new(0).image(&code)), Ok(()));
+    }
+
+    #[test]
+    fn inlining_keeps_the_output(values in prop::collection::vec(-1000..1000i32, 1..8), max_instructions in 2..6usize) {
+        let calls: String = values.iter().map(|value| format!("push {}\ncall show\npop 4\n", value)).collect();
+        let source = format!("{}lea answer\nload\nprint\nexit\nshow: print 4\nreturn\n.data\nanswer: .word 42\n", calls);
+        let assembled = vm::asm::assemble(&source).expect("the program assembles");
+        let mut inlined = assembled.clone();
+        let report = inlined.inline(max_instructions).expect("the code is all instructions");
+
+        prop_assert_eq!(report.len(), values.len());
+        prop_assert!(!inlined.labels.contains_key("show"));
+        prop_assert_eq!(inlined.code.len(), assembled.code.len() + values.len() * 8 - 8);
+        prop_assert!(!Program::new(inlined.code.clone()).instructions().any(|(_, instruction)| matches!(instruction, Instruction::Call(_))));
+
+        let run = |assembled: &vm::asm::Assembled| {
+            let vm = VirtualMachine::from_bytes(assembled.image(), VmConfig::default()).expect("the program loads");
+            vm::harness::run_captured(vm, b"").stdout
+        };
+        prop_assert_eq!(run(&inlined), run(&assembled));
+    }
+
+    #[test]
+    fn tail_calls_run_in_one_frame(n in 0..3000i32) {
+        let source = format!("push {}\nlea n\nstore\ncall count\nexit\n{}", n, COUNTDOWN);
+        let assembled = vm::asm::assemble(&source).expect("the program assembles");
+        let call_site = assembled.labels["count.again"];
+        let candidates = vm::analysis::find_tail_recursion(&assembled.code);
+        prop_assert_eq!(candidates.iter().map(|candidate| (candidate.call_site, candidate.function)).collect::<Vec<_>>(),
+            [(call_site, assembled.labels["count"])]);
+
+        let mut rewritten = assembled.clone();
+        prop_assert_eq!(rewritten.tail_calls(), candidates);
+        prop_assert_eq!(Instruction::decode(u32::from_le_bytes(rewritten.code[call_site as usize..][..4].try_into().unwrap())),
+            Some(Instruction::TailCall(assembled.labels["count"] - call_site)));
+
+        let run = |assembled: &vm::asm::Assembled| {
+            let vm = VirtualMachine::from_bytes(assembled.image(), VmConfig::default()).expect("the program loads");
+            let run = vm::harness::run_captured(vm, b"");
+            run.result.ok().map(|_| run.stdout)
+        };
+        let expected: String = (1..=n).rev().map(|i| format!("{}\n", i)).collect();
+        prop_assert_eq!(run(&rewritten), Some(expected.clone()));
+        /* Each call takes a word of stack, so only so many fit without the rewrite. */
+        if n < 500 {
+            prop_assert_eq!(run(&assembled), Some(expected));
+        }
+    }
+
+    #[test]
+    fn gc_keeps_what_is_called(calls in prop::sample::subsequence(MATH_CALLS.to_vec(), 0..=MATH_CALLS.len())) {
+        let main: String = calls.iter().map(|(_, call)| *call).chain(["exit"]).collect::<Vec<_>>().join("\n");
+        let math = vm::stdlib::file("stdlib/math.s").expect("the stdlib has maths");
+        let objects = [vm::asm::assemble_object(&main), vm::asm::assemble_object(math)].map(|object| object.expect("the object assembles"));
+        let linked = vm::linker::link(&objects).expect("the objects link");
+        let (collected, removed) = vm::linker::gc(&linked, &[]).expect("there's nothing to export");
+
+        for (routine, _) in MATH_CALLS {
+            let called = calls.iter().any(|(name, _)| *name == routine);
+            prop_assert_eq!(collected.symbols.contains_key(routine), called, "{}", routine);
+        }
+        prop_assert_eq!(collected.code.len() + removed.iter().map(|piece| piece.size as usize).sum::<usize>(), linked.code.len());
+
+        let run = |object: &vm::linker::Object| {
+            let vm = VirtualMachine::from_bytes(object.image(), VmConfig::default()).expect("the program loads");
+            vm::harness::run_captured(vm, b"").stdout
+        };
+        prop_assert_eq!(run(&collected), run(&linked));
+    }
+
+    #[test]
+    fn debugged_programs_read_their_own_input(value in any::<i32>()) {
+        let image = vm::asm::assemble("input\nexit\n").expect("the program assembles").image();
+        let mut debugger = Debugger::new(image).expect("the program loads").with_input(format!("{}\n", value).into_bytes());
+        let mut commands = "step\nprint stack[0]\nrestart\nstep\nprint stack[0]\nquit\n".as_bytes();
+        let mut out = Vec::new();
+        debugger.repl(&mut commands, &mut out).expect("the commands run");
+
+        let out = String::from_utf8(out).expect("the output is text");
+        prop_assert_eq!(out.matches(&format!("(vm) {} (0x", value)).count(), 2, "{}", out);
+    }
+
+    #[test]
+    fn language_server_answers_every_request(text in "[a-z0-9.: \"#\n\\\\@é]{0,200}", line in 0..12usize, character in 0..30usize) {
+        let uri = "file:///nowhere/test.s";
+        let quoted = format!("{:?}", text);
+        let position = format!(r#""textDocument":{{"uri":"{}"}},"position":{{"line":{},"character":{}}}"#, uri, line, character);
+        let messages = [
+            String::from(r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{}}"#),
+            format!(r#"{{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{{"textDocument":{{"uri":"{}","text":{}}}}}}}"#, uri, quoted),
+            format!(r#"{{"jsonrpc":"2.0","id":2,"method":"textDocument/hover","params":{{{}}}}}"#, position),
+            format!(r#"{{"jsonrpc":"2.0","id":3,"method":"textDocument/definition","params":{{{}}}}}"#, position),
+            format!(r#"{{"jsonrpc":"2.0","id":4,"method":"textDocument/completion","params":{{{}}}}}"#, position),
+            String::from(r#"{"jsonrpc":"2.0","id":5,"method":"shutdown"}"#),
+            String::from(r#"{"jsonrpc":"2.0","method":"exit"}"#),
+        ];
+        let input: String = messages.iter().map(|message| format!("Content-Length: {}\r\n\r\n{}", message.len(), message)).collect();
+
+        let mut output = Vec::new();
+        let code = vm::lsp::serve(&mut input.as_bytes(), &mut output).expect("the input is all there");
+        let output = String::from_utf8(output).expect("the output is UTF-8");
+
+        prop_assert_eq!(code, 0);
+        for id in 1..=5 {
+            prop_assert_eq!(output.matches(&format!(r#""id":{},"#, id)).count(), 1);
+        }
+        prop_assert_eq!(output.matches("publishDiagnostics").count(), 1);
+    }
+
+    #[test]
+    fn branch_offsets_fit_or_are_errors(
+        (mnemonic, field) in select(Vec::from([
+            ("goto", OffsetField::JUMP), ("ifeq", OffsetField::IF), ("spawn", OffsetField::SPAWN), ("lea", OffsetField::ADDRESS),
+        ])),
+        offset in prop_oneof![-(1i64 << 29)..(1i64 << 29), -(1i64 << 21)..(1i64 << 21)],
+    ) {
+        let fits = field.range().contains(&offset) && (!field.aligned || offset % 4 == 0);
+        match isa::assemble_line(&format!("{} {}", mnemonic, offset)) {
+            Ok(word) => {
+                prop_assert!(fits);
+                prop_assert_eq!(Instruction::decode(word).and_then(|instruction| instruction.branch_offset()), Some(offset as i32));
+            },
+            Err(err) => {
+                prop_assert!(!fits);
+                prop_assert!(err.message.contains(&format!("{} bytes away", offset)), "{}", err.message);
+            },
+        }
+    }
+
+    #[test]
+    fn ifs_only_go_to_whole_instructions(words in signed(22), bytes in 1..4i32) {
+        let code: Vec<u8> = [Instruction::Push(0), Instruction::UnaryIf(ZeroCondition::Zero, words * 4 + bytes), Instruction::Exit(0)]
+            .iter()
+            .flat_map(|instruction| instruction.encode().to_le_bytes())
+            .collect();
+        let mut vm = VirtualMachine::from_bytes(Header::new(0).image(&code), VmConfig::default()).expect("the program loads");
+
+        let err = vm.run().expect_err("the if stops the machine");
+        prop_assert!(err.to_string().contains("isn't a whole number of instructions"), "{}", err);
+    }
+
+    #[test]
+    fn segments_tile_memory(
+        code in 0..1024usize,
+        data in 0..1024usize,
+        heap in prop::option::of(0..1024usize),
+        stack in prop::option::of(0..1024usize),
+    ) {
+        let space = AddressSpace::new(code, code + data, heap, stack, 4096).expect("it all fits");
+        let mut end = 0;
+        for segment in Segment::ALL {
+            let range = space.segment(segment);
+            prop_assert!(end <= range.start && range.start <= range.end);
+            for address in [range.start, range.end - 1].into_iter().filter(|address| range.contains(address)) {
+                prop_assert_eq!(space.translate(address), Some((segment, address - range.start)));
+            }
+            end = range.end;
+        }
+        prop_assert_eq!(end, 4096);
+        if let Some(heap) = heap {
+            prop_assert_eq!(space.segment(Segment::Heap).len(), heap);
+        }
+        if let Some(stack) = stack {
+            prop_assert_eq!(space.segment(Segment::Stack).len(), stack);
+        }
+    }
+
+    #[test]
+    fn pop_and_return_count_words(word_size in word_size(), values in prop::collection::vec(-1000..1000i32, 1..8), dropped in 0..8usize) {
+        let dropped = dropped % values.len();
+        let pushes = values.iter().map(|&value| Instruction::Push(value));
+        let popped: Vec<Instruction> = pushes.clone().chain([Instruction::Pop(dropped as u32 * 4)]).collect();
+        prop_assert_eq!(run(&popped, word_size, ArithmeticMode::Wrapping), Some(values[values.len() - 1 - dropped] as i64));
+
+        /* A function that pushes them all and frees them as it returns, leaving the caller's 7. */
+        let mut called = Vec::from([Instruction::Push(7), Instruction::Call(8), Instruction::Goto((values.len() as i32 + 2) * 4)]);
+        called.extend(pushes);
+        called.push(Instruction::Return(values.len() as u32 * 4));
+        prop_assert_eq!(run(&called, word_size, ArithmeticMode::Wrapping), Some(7));
+    }
+
+    #[test]
+    fn constants_push_what_they_say((word_size, value) in word_size().prop_flat_map(|size| (Just(size), operand(size)))) {
+        prop_assert_eq!(run(&constant(value, word_size), word_size, ArithmeticMode::Trapping), Some(value));
+    }
+
+    #[test]
+    fn binary_arithmetic_matches_i128(
+        (word_size, left, right) in word_size().prop_flat_map(|size| (Just(size), operand(size), operand(size))),
+        op in select(BinaryOp::ALL.to_vec()),
+        mode in arithmetic_mode(),
+    ) {
+        let mut program = constant(left, word_size);
+        program.extend(constant(right, word_size));
+        program.push(Instruction::Binary(op));
+
+        prop_assert_eq!(run(&program, word_size, mode), expected_binary(op, left, right, word_size, mode));
+    }
+
+    #[test]
+    fn unary_arithmetic_matches_i128(
+        (word_size, operand) in word_size().prop_flat_map(|size| (Just(size), operand(size))),
+        op in select(UnaryOp::ALL.to_vec()),
+        mode in arithmetic_mode(),
+    ) {
+        let mut program = constant(operand, word_size);
+        program.push(Instruction::Unary(op));
+
+        prop_assert_eq!(run(&program, word_size, mode), expected_unary(op, operand, word_size, mode));
+    }
+
+    /* The isa module's own idea of the arithmetic, which selftest and the reference interpreter
+     * go by, is the 32-bit wrapping one. */
+    #[test]
+    fn isa_apply_matches_i128(left in operand(WordSize::Bits32), right in operand(WordSize::Bits32), op in select(BinaryOp::ALL.to_vec())) {
+        let expected = expected_binary(op, left, right, WordSize::Bits32, ArithmeticMode::Wrapping);
+        prop_assert_eq!(op.apply(left as i32, right as i32).map(i64::from), expected);
+    }
+}
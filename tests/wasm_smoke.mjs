@@ -0,0 +1,89 @@
+/* Smoke test for the wasm exports (see src/wasm.rs): each program in examples/programs is
+ * assembled with the vm binary, run through wasm_vm_new and wasm_vm_step the way a page would,
+ * with its .in file as the lines vm_host_read_line hands over, and what vm_host_write got has to
+ * match its .out file. Cargo doesn't run it, being JS; after
+ *
+ *     cargo build
+ *     cargo rustc --release --lib --crate-type cdylib --features wasm --target wasm32-unknown-unknown
+ *
+ * run it with
+ *
+ *     node tests/wasm_smoke.mjs target/wasm32-unknown-unknown/release/vm.wasm target/debug/vm
+ *
+ * It exits 1 if any program came out differently. */
+
+import { execFileSync } from 'node:child_process';
+import { existsSync, mkdtempSync, readdirSync, readFileSync } from 'node:fs';
+import { tmpdir } from 'node:os';
+import { join } from 'node:path';
+
+const [wasmPath, vmPath] = process.argv.slice(2);
+if (!wasmPath || !vmPath) {
+    console.error('usage: node tests/wasm_smoke.mjs <vm.wasm> <vm binary>');
+    process.exit(1);
+}
+
+const programs = 'examples/programs';
+const scratch = mkdtempSync(join(tmpdir(), 'vm-wasm-'));
+const module = new WebAssembly.Module(readFileSync(wasmPath));
+let failed = 0;
+
+for (const name of readdirSync(programs).filter((name) => name.endsWith('.s')).sort()) {
+    const stem = name.slice(0, -2);
+    const image = join(scratch, `${stem}.v`);
+    execFileSync(vmPath, ['asm', join(programs, name), '-o', image]);
+
+    const inPath = join(programs, `${stem}.in`);
+    const lines = existsSync(inPath) ? readFileSync(inPath, 'utf8').split('\n') : [];
+    if (lines.at(-1) === '') {
+        lines.pop();
+    }
+
+    /* The memory can grow while the program runs, so the view is made afresh every time. */
+    let instance;
+    const bytes = (ptr, len) => new Uint8Array(instance.exports.memory.buffer, ptr, len);
+    let output = '';
+    const decoder = new TextDecoder();
+    instance = new WebAssembly.Instance(module, { env: {
+        vm_host_write: (ptr, len) => { output += decoder.decode(bytes(ptr, len), { stream: true }); },
+        vm_host_read_line: (ptr, cap) => {
+            if (lines.length === 0) {
+                return -1;
+            }
+            const line = new TextEncoder().encode(lines.shift()).slice(0, cap);
+            bytes(ptr, line.length).set(line);
+            return line.length;
+        },
+    }});
+
+    const program = readFileSync(image);
+    const buffer = instance.exports.wasm_vm_alloc(program.length);
+    bytes(buffer, program.length).set(program);
+    const vm = instance.exports.wasm_vm_new(buffer, program.length);
+
+    let problem = null;
+    if (vm === 0) {
+        problem = "didn't load";
+    } else {
+        let status;
+        while ((status = instance.exports.wasm_vm_step(vm)) === 0) {}
+        if (status < 0) {
+            problem = 'faulted';
+        }
+        instance.exports.wasm_vm_free(vm);
+    }
+
+    const expected = readFileSync(join(programs, `${stem}.out`), 'utf8');
+    if (problem === null && output !== expected) {
+        problem = `printed\n${output}\ninstead of\n${expected}`;
+    }
+    if (problem === null) {
+        console.log(`ok   ${name}`);
+    } else {
+        console.log(`FAIL ${name}: ${problem}`);
+        failed += 1;
+    }
+}
+
+console.log(failed === 0 ? 'all passed' : `${failed} failed`);
+process.exit(failed === 0 ? 0 : 1);